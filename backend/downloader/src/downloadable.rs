@@ -0,0 +1,220 @@
+use async_trait::async_trait;
+use std::any::Any;
+use std::fmt::Debug;
+use std::path::PathBuf;
+use tokio::sync::mpsc::Sender;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::ftpdownload::FtpDownload;
+use crate::httpdownload::download::{self, DownloadUpdate, HttpDownload};
+use crate::httpdownload::DownloadMetadata;
+use crate::util::file_size;
+
+/// Lifecycle shared by all download types (http, ftp, ...). A download runs until it's done or
+/// its cancellation token is cancelled, which is how it's stopped, and can be resumed afterwards.
+/// Progress is reported as `DownloadUpdate`s so the observer treats all types alike.
+///
+/// The manager keeps downloads as `Box<dyn Downloadable>`, errors of all types are therefore
+/// `download::Error`s (see its `From<ftpdownload::Error>`) and a stopped run always fails with
+/// `download::Error::Cancelled`. Operations only http downloads support are reached through
+/// `as_http`.
+#[async_trait]
+pub trait Downloadable: Send + Sync + Debug + Any {
+    fn id(&self) -> Uuid;
+
+    async fn run(
+        &self,
+        update_ch: Sender<DownloadUpdate>,
+        resume: bool,
+        cancel: CancellationToken,
+    ) -> download::Result<u64>;
+
+    fn metadata(&self) -> DownloadMetadata;
+
+    /// Host the download is fetched from, downloads of a host share its circuit breaker.
+    fn host(&self) -> Option<&str>;
+
+    /// Where the finished download ends up.
+    fn final_path(&self) -> PathBuf;
+
+    /// Files written while the download runs, two running downloads never share one.
+    fn target_paths(&self) -> Vec<PathBuf>;
+
+    /// Files besides `metadata().file_path` the download leaves on disk (partial files,
+    /// sidecars), they are deleted with the download.
+    fn working_files(&self) -> Vec<PathBuf>;
+
+    async fn get_bytes_on_disk(&self) -> u64;
+
+    fn as_any(&self) -> &dyn Any;
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+
+    async fn start(&self, update_ch: Sender<DownloadUpdate>) -> download::Result<u64> {
+        self.run(update_ch, false, CancellationToken::new()).await
+    }
+
+    async fn resume(&self, update_ch: Sender<DownloadUpdate>) -> download::Result<u64> {
+        self.run(update_ch, true, CancellationToken::new()).await
+    }
+}
+
+impl dyn Downloadable {
+    /// The download if it's an `HttpDownload`, for the operations only http downloads have.
+    pub fn as_http(&self) -> Option<&HttpDownload> {
+        self.as_any().downcast_ref()
+    }
+
+    pub fn as_http_mut(&mut self) -> Option<&mut HttpDownload> {
+        self.as_any_mut().downcast_mut()
+    }
+}
+
+#[async_trait]
+impl Downloadable for HttpDownload {
+    fn id(&self) -> Uuid {
+        self.id
+    }
+
+    async fn run(
+        &self,
+        update_ch: Sender<DownloadUpdate>,
+        resume: bool,
+        cancel: CancellationToken,
+    ) -> download::Result<u64> {
+        HttpDownload::run(self, update_ch, resume, cancel).await
+    }
+
+    fn metadata(&self) -> DownloadMetadata {
+        self.get_metadata()
+    }
+
+    fn host(&self) -> Option<&str> {
+        HttpDownload::host(self)
+    }
+
+    fn final_path(&self) -> PathBuf {
+        HttpDownload::final_path(self)
+    }
+
+    fn target_paths(&self) -> Vec<PathBuf> {
+        HttpDownload::target_paths(self)
+    }
+
+    fn working_files(&self) -> Vec<PathBuf> {
+        let mut files = vec![
+            self.download_path(),
+            self.sidecar_path(),
+            self.validators_path(),
+        ];
+        files.extend(self.split_files());
+        files
+    }
+
+    async fn get_bytes_on_disk(&self) -> u64 {
+        HttpDownload::get_bytes_on_disk(self).await
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[async_trait]
+impl Downloadable for FtpDownload {
+    fn id(&self) -> Uuid {
+        self.id
+    }
+
+    async fn run(
+        &self,
+        update_ch: Sender<DownloadUpdate>,
+        resume: bool,
+        cancel: CancellationToken,
+    ) -> download::Result<u64> {
+        Ok(FtpDownload::run(self, update_ch, resume, cancel).await?)
+    }
+
+    fn metadata(&self) -> DownloadMetadata {
+        self.get_metadata()
+    }
+
+    fn host(&self) -> Option<&str> {
+        self.url.host_str()
+    }
+
+    fn final_path(&self) -> PathBuf {
+        self.file_path()
+    }
+
+    fn target_paths(&self) -> Vec<PathBuf> {
+        vec![self.file_path()]
+    }
+
+    fn working_files(&self) -> Vec<PathBuf> {
+        Vec::new()
+    }
+
+    async fn get_bytes_on_disk(&self) -> u64 {
+        file_size(&self.file_path()).await
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::mock::{MockConfig, MockServer};
+    use crate::util::{setup_test_download, TestResult};
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+    use test_log::test;
+    use tokio::sync::mpsc;
+
+    /// Stops the download shortly after starting it and resumes it, only through the trait.
+    async fn stop_and_resume(download: &dyn Downloadable) -> download::Result<u64> {
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        let cancel = CancellationToken::new();
+        let run = download.run(update_sender.clone(), false, cancel.clone());
+        let stop = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            cancel.cancel();
+        };
+        let (result, _) = tokio::join!(run, stop);
+        match result {
+            Err(download::Error::Cancelled(_)) => download.resume(update_sender).await,
+            other => other,
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn http_download_is_downloadable_test() -> TestResult<()> {
+        // given
+        let server = MockServer::start(MockConfig {
+            chunk_delay: Some(Duration::from_millis(20)),
+            ..Default::default()
+        })
+        .await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let download: Box<dyn Downloadable> = Box::new(download);
+        // when
+        let written = stop_and_resume(download.as_ref()).await?;
+        // then
+        assert_eq!(written, server.payload().len() as u64);
+        assert_eq!(download.metadata().download_size, Some(written));
+        assert!(download.as_http().is_some());
+        Ok(())
+    }
+}
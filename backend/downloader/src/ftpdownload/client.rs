@@ -0,0 +1,201 @@
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+use super::{Error, Result};
+
+/// Reply of the server to a command, `code` is the 3 digit status code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub code: u16,
+    pub message: String,
+}
+
+/// Control connection to an FTP server, commands are sent one at a time and each is answered by
+/// a reply. Data is always transferred in passive mode and binary (`TYPE I`).
+#[derive(Debug)]
+pub struct FtpClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    peer: SocketAddr,
+}
+
+impl FtpClient {
+    /// Connects and waits for the server's greeting.
+    pub async fn connect(host: &str, port: u16) -> Result<Self> {
+        let stream = TcpStream::connect((host, port)).await?;
+        let peer = stream.peer_addr()?;
+        let (reader, writer) = stream.into_split();
+        let mut client = FtpClient {
+            reader: BufReader::new(reader),
+            writer,
+            peer,
+        };
+        client.expect_reply(&[220]).await?;
+        Ok(client)
+    }
+
+    /// Logs in, `anonymous` is the conventional user of servers without accounts.
+    pub async fn login(&mut self, user: &str, password: &str) -> Result<()> {
+        let reply = self.command(&format!("USER {}", user), &[230, 331]).await?;
+        if reply.code == 331 {
+            self.command(&format!("PASS {}", password), &[230, 202])
+                .await?;
+        }
+        self.command("TYPE I", &[200]).await?;
+        Ok(())
+    }
+
+    /// Size of the file in bytes.
+    pub async fn size(&mut self, path: &str) -> Result<u64> {
+        let reply = self.command(&format!("SIZE {}", path), &[213]).await?;
+        reply
+            .message
+            .trim()
+            .parse()
+            .map_err(|_| Error::MalformedReply(format!("{} {}", reply.code, reply.message)))
+    }
+
+    /// Opens a data connection and starts sending the file from `offset` on, the transfer is
+    /// complete once the stream ends and `finish_transfer` succeeds.
+    pub async fn retrieve(&mut self, path: &str, offset: u64) -> Result<TcpStream> {
+        let data = self.passive().await?;
+        if offset > 0 {
+            self.command(&format!("REST {}", offset), &[350]).await?;
+        }
+        self.command(&format!("RETR {}", path), &[125, 150]).await?;
+        Ok(data)
+    }
+
+    /// Waits for the server to confirm a transfer after its data connection was closed.
+    pub async fn finish_transfer(&mut self) -> Result<()> {
+        self.expect_reply(&[226, 250]).await?;
+        Ok(())
+    }
+
+    /// Ends the session, errors are ignored since the transfer is over anyway.
+    pub async fn quit(mut self) {
+        let _ = self.command("QUIT", &[221]).await;
+    }
+
+    /// Opens a passive mode data connection. Only the port of the `227` reply is used, the data
+    /// connection goes to the host of the control connection since servers behind NAT often
+    /// announce their private address.
+    async fn passive(&mut self) -> Result<TcpStream> {
+        let reply = self.command("PASV", &[227]).await?;
+        let port = parse_pasv_port(&reply.message)
+            .ok_or_else(|| Error::MalformedReply(format!("{} {}", reply.code, reply.message)))?;
+        Ok(TcpStream::connect(SocketAddr::new(self.peer.ip(), port)).await?)
+    }
+
+    async fn command(&mut self, command: &str, expected: &[u16]) -> Result<Reply> {
+        log::debug!("FTP command to {}: {}", self.peer, redact(command));
+        self.writer
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await?;
+        self.expect_reply(expected).await
+    }
+
+    async fn expect_reply(&mut self, expected: &[u16]) -> Result<Reply> {
+        let reply = self.read_reply().await?;
+        match expected.contains(&reply.code) {
+            true => Ok(reply),
+            false => Err(Error::Reply {
+                code: reply.code,
+                message: reply.message,
+            }),
+        }
+    }
+
+    /// Reads a reply, multi-line replies start with `<code>-` and end with `<code> `.
+    async fn read_reply(&mut self) -> Result<Reply> {
+        let first = self.read_line().await?;
+        let (code, separator, message) = split_reply_line(&first)?;
+        let mut message = message.to_string();
+        if separator == '-' {
+            let last_prefix = format!("{} ", code);
+            loop {
+                let line = self.read_line().await?;
+                if let Some(last) = line.strip_prefix(&last_prefix) {
+                    message = last.to_string();
+                    break;
+                }
+            }
+        }
+        Ok(Reply { code, message })
+    }
+
+    async fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).await? == 0 {
+            return Err(Error::MalformedReply(
+                "control connection closed".to_string(),
+            ));
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+fn split_reply_line(line: &str) -> Result<(u16, char, &str)> {
+    let malformed = || Error::MalformedReply(line.to_string());
+    let code = line.get(..3).and_then(|code| code.parse().ok());
+    let separator = line
+        .get(3..)
+        .and_then(|rest| rest.chars().next())
+        .unwrap_or(' ');
+    match code {
+        Some(code) if separator == ' ' || separator == '-' => {
+            Ok((code, separator, line.get(4..).unwrap_or_default()))
+        }
+        _ => Err(malformed()),
+    }
+}
+
+/// Port of a `227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)` reply.
+fn parse_pasv_port(message: &str) -> Option<u16> {
+    let start = message.find('(')?;
+    let end = message[start..].find(')')? + start;
+    let numbers: Vec<u16> = message[start + 1..end]
+        .split(',')
+        .map(|n| n.trim().parse().ok())
+        .collect::<Option<_>>()?;
+    match numbers[..] {
+        [_, _, _, _, high, low] if high < 256 && low < 256 => Some(high * 256 + low),
+        _ => None,
+    }
+}
+
+/// Hides the password of a `PASS` command in the logs.
+fn redact(command: &str) -> &str {
+    match command.starts_with("PASS ") {
+        true => "PASS ****",
+        false => command,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parse_pasv_port_test() {
+        assert_eq!(
+            parse_pasv_port("Entering Passive Mode (127,0,0,1,195,80)."),
+            Some(195 * 256 + 80)
+        );
+        assert_eq!(parse_pasv_port("Entering Passive Mode"), None);
+        assert_eq!(parse_pasv_port("(127,0,0,1,300,80)"), None);
+    }
+
+    #[test]
+    fn split_reply_line_test() {
+        assert_eq!(split_reply_line("213 1024").unwrap(), (213, ' ', "1024"));
+        assert_eq!(
+            split_reply_line("220-Welcome").unwrap(),
+            (220, '-', "Welcome")
+        );
+        assert!(split_reply_line("hello").is_err());
+    }
+}
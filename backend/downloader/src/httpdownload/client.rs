@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use reqwest::{Client, Proxy, Url};
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Matches the pool idle timeout of a default `Client`
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Address family used to connect to download servers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    /// Dual-stack, IPv4 and IPv6 connection attempts race each other (happy eyeballs), the
+    /// fallback family is tried 300ms after the first attempt if it didn't connect yet.
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+/// Connection settings of the http client shared by all downloads, downloads with an idle timeout
+/// of their own use a client derived from it.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Time a single connection attempt can take before the next address is tried
+    pub connect_timeout: Duration,
+    pub ip_family: IpFamily,
+    /// Hostnames connected to at a fixed address instead of resolving them, the hostname is still
+    /// used for TLS (SNI and certificate validation) and the `Host` header
+    pub dns_overrides: HashMap<String, IpAddr>,
+    /// Time an unused connection is kept open for the next request to the host. Longer avoids
+    /// reconnecting after brief stalls, shorter releases connections sooner, zero closes them
+    /// once their response was read.
+    pub idle_timeout: Duration,
+    /// Proxy every request goes through, `http://` and `https://` proxies with credentials in
+    /// the url if needed. None uses the proxy of the environment (`HTTPS_PROXY` etc.), if any.
+    pub proxy: Option<Url>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            ip_family: IpFamily::Any,
+            dns_overrides: HashMap::new(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            proxy: None,
+        }
+    }
+}
+
+/// Builds the client downloads are created with, hosts with a dead IPv6 (or IPv4) address fall
+/// back to the other family instead of hanging on connect.
+pub fn build_client(config: &ClientConfig) -> reqwest::Result<Client> {
+    let builder = Client::builder()
+        .connect_timeout(config.connect_timeout)
+        .pool_idle_timeout(config.idle_timeout);
+    let builder = match config.idle_timeout.is_zero() {
+        true => builder.pool_max_idle_per_host(0),
+        false => builder,
+    };
+    // Binding to the unspecified address of a family makes the connector skip the addresses of
+    // the other family.
+    let builder = match config.ip_family {
+        IpFamily::Any => builder,
+        IpFamily::V4 => builder.local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        IpFamily::V6 => builder.local_address(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+    };
+    let builder = match &config.proxy {
+        Some(url) => builder.proxy(proxy(url)?),
+        None => builder,
+    };
+    // The port of an override is ignored, connections go to the port of the url
+    let builder = config
+        .dns_overrides
+        .iter()
+        .fold(builder, |builder, (host, ip)| {
+            builder.resolve(host, SocketAddr::new(*ip, 0))
+        });
+    builder.build()
+}
+
+/// Proxy for all requests of a client, fails for schemes that aren't supported.
+pub fn proxy(url: &Url) -> reqwest::Result<Proxy> {
+    Proxy::all(url.as_str())
+}
+
+/// Proxy url with its password masked, secrets are kept out of logs and error messages.
+pub fn redact_proxy(url: &Url) -> Url {
+    let mut url = url.clone();
+    if url.password().is_some() {
+        let _ = url.set_password(Some("***"));
+    }
+    url
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::mock::{MockConfig, MockServer};
+    use test_log::test;
+
+    #[test(tokio::test)]
+    async fn ipv4_only_client_connects_to_ipv4_host() -> anyhow::Result<()> {
+        // given
+        let server = MockServer::start(MockConfig::default()).await;
+        let client = build_client(&ClientConfig {
+            ip_family: IpFamily::V4,
+            ..Default::default()
+        })?;
+        // when
+        let resp = client.head(server.url("file.bin")).send().await?;
+        // then
+        assert!(resp.status().is_success());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn ipv6_only_client_skips_ipv4_host() -> anyhow::Result<()> {
+        // given
+        let server = MockServer::start(MockConfig::default()).await;
+        let client = build_client(&ClientConfig {
+            ip_family: IpFamily::V6,
+            ..Default::default()
+        })?;
+        // when
+        let resp = client.head(server.url("file.bin")).send().await;
+        // then
+        assert!(resp.is_err(), "{:?}", resp);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn overridden_host_connects_to_given_ip() -> anyhow::Result<()> {
+        // given
+        let server = MockServer::start(MockConfig::default()).await;
+        let client = build_client(&ClientConfig {
+            dns_overrides: HashMap::from([(
+                "staging.invalid".to_string(),
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+            )]),
+            ..Default::default()
+        })?;
+        let mut url = server.url("file.bin");
+        url.set_host(Some("staging.invalid"))?;
+        // when
+        let resp = client.head(url).send().await?;
+        // then
+        assert!(resp.status().is_success());
+        assert_eq!(
+            server.requests()[0].headers[reqwest::header::HOST],
+            format!("staging.invalid:{}", server.url("").port().unwrap())
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,394 @@
+use md5::Md5;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+use tokio::sync::mpsc::Sender;
+use tokio_util::sync::CancellationToken;
+
+use crate::util::HALF_SECOND;
+
+use super::{ByteRange, DownloadUpdate, Error, HttpDownload, Result, State};
+
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Md5,
+}
+
+/// Expected or computed digest of a file, written as `<algorithm>:<hex digest>` (e.g.
+/// `sha256:9f86...`) in settings and API requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Checksum {
+    Sha256([u8; 32]),
+    Md5([u8; 16]),
+}
+
+impl Checksum {
+    pub fn algorithm(&self) -> ChecksumAlgorithm {
+        match self {
+            Checksum::Sha256(_) => ChecksumAlgorithm::Sha256,
+            Checksum::Md5(_) => ChecksumAlgorithm::Md5,
+        }
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Checksum::Sha256(digest) => write!(f, "sha256:{}", hex::encode(digest)),
+            Checksum::Md5(digest) => write!(f, "md5:{}", hex::encode(digest)),
+        }
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        let (algorithm, digest) = value
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| format!("expected <algorithm>:<hex digest>, got '{}'", value))?;
+        let digest = hex::decode(digest).map_err(|e| format!("invalid digest: {}", e))?;
+        let wrong_length = |_| format!("wrong digest length for {}", algorithm);
+        match algorithm.to_ascii_lowercase().as_str() {
+            "sha256" => Ok(Checksum::Sha256(digest.try_into().map_err(wrong_length)?)),
+            "md5" => Ok(Checksum::Md5(digest.try_into().map_err(wrong_length)?)),
+            _ => Err(format!("unsupported checksum algorithm '{}'", algorithm)),
+        }
+    }
+}
+
+impl Serialize for Checksum {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Checksum {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Hashes the files one after the other, as if they were a single file.
+pub async fn compute(paths: &[PathBuf], algorithm: ChecksumAlgorithm) -> Result<Checksum> {
+    compute_observed(paths, algorithm, None).await
+}
+
+async fn compute_observed(
+    paths: &[PathBuf],
+    algorithm: ChecksumAlgorithm,
+    progress: Option<&mut VerifyProgress<'_>>,
+) -> Result<Checksum> {
+    match algorithm {
+        ChecksumAlgorithm::Sha256 => {
+            let digest = hash_files::<Sha256>(paths, progress).await?;
+            Ok(Checksum::Sha256(digest.into()))
+        }
+        ChecksumAlgorithm::Md5 => {
+            let digest = hash_files::<Md5>(paths, progress).await?;
+            Ok(Checksum::Md5(digest.into()))
+        }
+    }
+}
+
+/// Hashes the bytes of `range` in the file.
+pub async fn compute_range(
+    path: &Path,
+    range: ByteRange,
+    algorithm: ChecksumAlgorithm,
+) -> Result<Checksum> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(range.start)).await?;
+    let mut reader = file.take(range.len());
+    match algorithm {
+        ChecksumAlgorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            hash_reader(&mut hasher, &mut reader, None).await?;
+            Ok(Checksum::Sha256(hasher.finalize().into()))
+        }
+        ChecksumAlgorithm::Md5 => {
+            let mut hasher = Md5::new();
+            hash_reader(&mut hasher, &mut reader, None).await?;
+            Ok(Checksum::Md5(hasher.finalize().into()))
+        }
+    }
+}
+
+async fn hash_files<D: Digest>(
+    paths: &[PathBuf],
+    mut progress: Option<&mut VerifyProgress<'_>>,
+) -> Result<sha2::digest::Output<D>> {
+    let mut hasher = D::new();
+    for path in paths {
+        let mut file = tokio::fs::File::open(path).await?;
+        hash_reader(&mut hasher, &mut file, progress.as_deref_mut()).await?;
+    }
+    Ok(hasher.finalize())
+}
+
+async fn hash_reader<D: Digest>(
+    hasher: &mut D,
+    reader: &mut (impl AsyncRead + Unpin),
+    mut progress: Option<&mut VerifyProgress<'_>>,
+) -> Result<()> {
+    let mut buf = vec![0u8; READ_BUFFER_SIZE];
+    loop {
+        let read = reader.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        hasher.update(&buf[..read]);
+        if let Some(progress) = progress.as_deref_mut() {
+            progress.record(read as u64)?;
+        }
+    }
+}
+
+/// Hashes the bytes of a sequential transfer as they are written, so the checksum of the finished
+/// download is known without reading the file again.
+pub(super) enum StreamingHasher {
+    Sha256(Sha256),
+    Md5(Md5),
+}
+
+impl StreamingHasher {
+    fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Sha256 => StreamingHasher::Sha256(Sha256::new()),
+            ChecksumAlgorithm::Md5 => StreamingHasher::Md5(Md5::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            StreamingHasher::Sha256(hasher) => hasher.update(data),
+            StreamingHasher::Md5(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Checksum {
+        match self {
+            StreamingHasher::Sha256(hasher) => Checksum::Sha256(hasher.finalize().into()),
+            StreamingHasher::Md5(hasher) => Checksum::Md5(hasher.finalize().into()),
+        }
+    }
+}
+
+/// Publishes `State::Verifying` updates while a finished download is hashed, at most every
+/// HALF_SECOND, and stops the hash once the download is cancelled.
+struct VerifyProgress<'a> {
+    id: uuid::Uuid,
+    bytes_hashed: u64,
+    total: u64,
+    last_update: Option<Instant>,
+    update_ch: &'a Sender<DownloadUpdate>,
+    cancel: &'a CancellationToken,
+}
+
+impl VerifyProgress<'_> {
+    fn record(&mut self, bytes: u64) -> Result<()> {
+        if self.cancel.is_cancelled() {
+            return Err(Error::Cancelled(self.total));
+        }
+        self.bytes_hashed += bytes;
+        self.publish();
+        Ok(())
+    }
+
+    fn publish(&mut self) {
+        let due = match self.last_update {
+            Some(last_update) => last_update.elapsed() > HALF_SECOND,
+            None => true,
+        };
+        if due || self.bytes_hashed == self.total {
+            let state = State::Verifying {
+                bytes_hashed: self.bytes_hashed,
+                total: self.total,
+            };
+            let _ = self
+                .update_ch
+                .try_send(DownloadUpdate { id: self.id, state });
+            self.last_update = Some(Instant::now());
+        }
+    }
+}
+
+impl HttpDownload {
+    /// Files holding the downloaded bytes before they are moved to their final location, the
+    /// parts in order for split downloads.
+    pub(super) fn downloaded_files(&self) -> Vec<PathBuf> {
+        match self.split_size() {
+            Some(part_size) => (0..self.target_length().div_ceil(part_size))
+                .map(|idx| super::split::part_path(&self.download_path(), idx))
+                .collect(),
+            None => vec![self.download_path()],
+        }
+    }
+
+    /// Files of a complete download at their final location, the parts in order for split
+    /// downloads.
+    fn completed_files(&self) -> Vec<PathBuf> {
+        match self.split_size() {
+            Some(part_size) => (0..self.target_length().div_ceil(part_size))
+                .map(|idx| super::split::part_path(&self.file_path(), idx))
+                .collect(),
+            None => vec![self.file_path()],
+        }
+    }
+
+    /// Hashes the files of a complete download against `expected`, e.g. a checksum that became
+    /// known after the download finished. Returns the computed checksum, a mismatch fails with
+    /// `Error::ChecksumMismatch` and leaves the files as they are.
+    pub async fn verify_completed(&self, expected: Checksum) -> Result<Checksum> {
+        let actual = self.compute_checksum(expected.algorithm()).await?;
+        self.compare_checksum(expected, actual)?;
+        Ok(actual)
+    }
+
+    /// Hashes the files of a complete download as they are on disk, the parts of a split
+    /// download in order.
+    pub async fn compute_checksum(&self, algorithm: ChecksumAlgorithm) -> Result<Checksum> {
+        compute(&self.completed_files(), algorithm).await
+    }
+
+    /// Whether a finished transfer is hashed before it's moved to its final location.
+    pub fn needs_verification(&self) -> bool {
+        self.config.checksum.is_some() || self.config.pieces.is_some()
+    }
+
+    /// Hasher for a sequential transfer continuing at `offset`, seeded with the `offset` bytes
+    /// already on disk. None without a configured checksum or if the prefix can't be read, the
+    /// finished download is hashed from disk then.
+    pub(super) async fn streaming_hasher(&self, offset: u64) -> Option<StreamingHasher> {
+        let mut hasher = StreamingHasher::new(self.config.checksum?.algorithm());
+        let mut remaining = offset;
+        let mut buf = vec![0u8; READ_BUFFER_SIZE];
+        for path in self.downloaded_files() {
+            if remaining == 0 {
+                break;
+            }
+            let file = match tokio::fs::File::open(&path).await {
+                Ok(file) => file,
+                Err(e) => {
+                    log::warn!("Can't hash {:?} while downloading: {}", path, e);
+                    return None;
+                }
+            };
+            let mut reader = file.take(remaining);
+            loop {
+                let read = reader.read(&mut buf).await.ok()?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buf[..read]);
+                remaining -= read as u64;
+            }
+        }
+        (remaining == 0).then_some(hasher)
+    }
+
+    /// Verification phase of a finished transfer: corrupted pieces are re-fetched and the whole
+    /// download is hashed against the configured checksum. `streamed` is the checksum computed
+    /// while the bytes were written, it's used unless pieces were re-fetched so the file isn't
+    /// read again. Hashing is reported as `State::Verifying` and stops with `Error::Cancelled`
+    /// once `cancel` is cancelled, the download resumes with verifying then.
+    pub(super) async fn verify(
+        &self,
+        update_ch: &Sender<DownloadUpdate>,
+        cancel: &CancellationToken,
+        streamed: Option<Checksum>,
+    ) -> Result<()> {
+        let refetched = self.verify_pieces(cancel).await?;
+        let Some(expected) = self.config.checksum else {
+            return Ok(());
+        };
+        if let Some(actual) = streamed.filter(|_| !refetched) {
+            return self.compare_checksum(expected, actual);
+        }
+        let files = self.downloaded_files();
+        let mut total = 0;
+        for file in &files {
+            total += tokio::fs::metadata(file).await?.len();
+        }
+        let mut progress = VerifyProgress {
+            id: self.id,
+            bytes_hashed: 0,
+            total,
+            last_update: None,
+            update_ch,
+            cancel,
+        };
+        progress.publish();
+        let actual = compute_observed(&files, expected.algorithm(), Some(&mut progress)).await?;
+        self.compare_checksum(expected, actual)
+    }
+
+    fn compare_checksum(&self, expected: Checksum, actual: Checksum) -> Result<()> {
+        if actual != expected {
+            log::warn!(
+                "Checksum of download {} doesn't match, computed {} but expected {}",
+                self.id,
+                actual,
+                expected
+            );
+            return Err(Error::ChecksumMismatch { expected, actual });
+        }
+        log::info!("Checksum of download {} verified: {}", self.id, actual);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    #[test]
+    fn parse_checksum_test() {
+        let sha256: Checksum =
+            "sha256:9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+                .parse()
+                .unwrap();
+        assert_eq!(sha256.algorithm(), ChecksumAlgorithm::Sha256);
+        assert_eq!(
+            sha256.to_string(),
+            "sha256:9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+        );
+        assert!("MD5:098f6bcd4621d373cade4e832627b4f6"
+            .parse::<Checksum>()
+            .is_ok());
+        assert!("md5:098f".parse::<Checksum>().is_err());
+        assert!("crc32:deadbeef".parse::<Checksum>().is_err());
+        assert!("098f6bcd4621d373cade4e832627b4f6"
+            .parse::<Checksum>()
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn compute_over_files_test() -> anyhow::Result<()> {
+        // given
+        let tmp_dir = TempDir::new()?;
+        let first = tmp_dir.path().join("a");
+        let second = tmp_dir.path().join("b");
+        tokio::fs::write(&first, b"te").await?;
+        tokio::fs::write(&second, b"st").await?;
+        // when
+        let checksum = compute(&[first, second], ChecksumAlgorithm::Md5).await?;
+        // then
+        assert_eq!(checksum.to_string(), "md5:098f6bcd4621d373cade4e832627b4f6");
+        Ok(())
+    }
+}
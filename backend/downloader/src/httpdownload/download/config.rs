@@ -1,14 +1,279 @@
 use reqwest::header::{self, HeaderMap, HeaderValue};
-use std::time::Duration;
+use reqwest::{RequestBuilder, Url};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{mpsc, Semaphore};
+
+use super::checksum::Checksum;
+use super::encoding;
+use super::filetype::FileTypePolicy;
+use super::limiter::{LimitExemption, RateLimiter};
+use super::pieces::PieceHashes;
+use super::refresh::RefreshHook;
+use super::retry::SharedRetryPolicy;
+use super::segmented::SegmentTarget;
+use super::tee::MirrorFailure;
+use super::{ByteRange, ErrorEvent};
 
 pub const DEFAULT_USER_AGENT: &str = "ludownloader";
+/// Takes whatever representation the server prefers, see `HttpDownloadBuilder::accept`
+pub const DEFAULT_ACCEPT: &str = "*/*";
 pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_PERSIST_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the progress of a running download is written to disk. Persisting often keeps the
+/// progress lost on a crash small, at the cost of more disk IO. Pausing, stopping and completing
+/// always persist right away. Segmented downloads persist their progress to their sidecar,
+/// single connection downloads resume from their file size, and the manager's `StateFile` is
+/// written on the same cadence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PersistInterval {
+    /// Persist once this much time passed since the last write
+    pub time: Duration,
+    /// Persist once this many bytes were downloaded since the last write, None disables it
+    pub bytes: Option<u64>,
+}
+
+impl PersistInterval {
+    pub fn is_due(&self, elapsed: Duration, bytes_since: u64) -> bool {
+        elapsed >= self.time || self.bytes.is_some_and(|bytes| bytes_since >= bytes)
+    }
+}
+
+impl Default for PersistInterval {
+    fn default() -> Self {
+        Self {
+            time: DEFAULT_PERSIST_INTERVAL,
+            bytes: None,
+        }
+    }
+}
+
+/// Mode and owner of the files a download creates, applied when a file is created and again once
+/// it's moved to its final location. Only has an effect on Unix.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FilePermissions {
+    /// Permission bits like `0o644`, set explicitly so the umask doesn't apply
+    pub mode: Option<u32>,
+    /// Owner and group, changing them usually requires running as root
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl FilePermissions {
+    #[cfg(unix)]
+    pub async fn apply(&self, path: &Path) -> std::io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        if let Some(mode) = self.mode {
+            tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).await?;
+        }
+        if self.uid.is_some() || self.gid.is_some() {
+            let (path, uid, gid) = (path.to_owned(), self.uid, self.gid);
+            tokio::task::spawn_blocking(move || std::os::unix::fs::chown(path, uid, gid))
+                .await
+                .expect("chown task panicked")?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub async fn apply(&self, _path: &Path) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What a download does when the server reports an empty resource (`Content-Length: 0`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyResponse {
+    /// The download creates an empty file and completes, a checksum has to match the empty file
+    #[default]
+    Complete,
+    /// Probing fails with `Error::EmptyResponse`, for sources that answer errors with an empty
+    /// 200
+    Fail,
+}
+
+/// Credentials sent with every request of a download.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Auth {
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+    Bearer(String),
+}
+
+impl Auth {
+    pub fn apply(&self, request: RequestBuilder) -> RequestBuilder {
+        match self {
+            Auth::Basic { username, password } => request.basic_auth(username, password.as_ref()),
+            Auth::Bearer(token) => request.bearer_auth(token),
+        }
+    }
+}
+
+/// Secrets are kept out of logs
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Auth::Basic { username, .. } => f
+                .debug_struct("Basic")
+                .field("username", username)
+                .finish_non_exhaustive(),
+            Auth::Bearer(_) => f.write_str("Bearer(..)"),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct HttpDownloadConfig {
     pub timeout: Duration,
     pub headers: HeaderMap,
     pub chunk_size: usize,
+    /// Number of parallel range requests, only used when the server supports byte ranges.
+    pub segments: usize,
+    /// Overrides `segments` once set, also while the download runs, see `SegmentTarget`
+    pub segment_target: SegmentTarget,
+    /// Directory the download is written to until it's complete, None writes in place.
+    pub temp_dir: Option<PathBuf>,
+    /// Called when the server rejects the url as expired, see `RefreshHook`
+    pub url_refresher: Option<RefreshHook>,
+    /// Cadence at which the segment progress is persisted while running
+    pub persist_interval: PersistInterval,
+    /// Stops after this many bytes (e.g. to preview a large file), only this prefix is requested
+    /// and the download ends in `State::Partial` instead of `State::Complete`. Zero is ignored.
+    pub max_bytes: Option<u64>,
+    /// Pauses the download once it wrote this many bytes, it ends as `Cancelled` (`PausedByUser`
+    /// in the manager) and stays resumable. The manager clears it once reached when the user
+    /// starts or resumes the download, so it doesn't pause there again. Segmented downloads may
+    /// overshoot it by a chunk per segment, sparse downloads (which fetch all ranges again on
+    /// resume) ignore it.
+    pub pause_at: Option<u64>,
+    pub auth: Option<Auth>,
+    /// Writes the download into numbered files (`file.001`, `file.002`, ...) of at most this many
+    /// bytes plus a `file.manifest` listing them. Split downloads are never segmented.
+    pub split_size: Option<u64>,
+    /// Offers gzip/deflate in `Accept-Encoding` and decodes compressed responses. Byte counts then
+    /// reflect the decompressed size, which doesn't match the content length the server reports,
+    /// so compressed downloads can't be resumed (they restart) or segmented. Disabled (the
+    /// default) asks for `identity` so downloaded bytes always equal the file size on disk.
+    pub compression: bool,
+    pub permissions: FilePermissions,
+    /// Appends an extension matching the `Content-Type` of the response to filenames without one
+    /// (e.g. `report` served as `application/pdf` is saved as `report.pdf`)
+    pub infer_extension: bool,
+    /// Only downloads these ranges, each written at its offset of a sparse file of the full size.
+    /// Progress counts up to the sum of the range sizes, requires byte range support.
+    pub ranges: Option<Vec<ByteRange>>,
+    /// Failures of the download are reported here, including transient ones that were retried.
+    /// The download manager sets it for the downloads it manages.
+    pub error_events: Option<mpsc::UnboundedSender<ErrorEvent>>,
+    /// Shared by downloads to cap their total number of concurrent segment connections, a segment
+    /// holds a permit while its request runs
+    pub segment_limit: Option<Arc<Semaphore>>,
+    /// Checked once the download finished, a mismatching download fails with
+    /// `Error::ChecksumMismatch`
+    pub checksum: Option<Checksum>,
+    /// How often a download failing its checksum is restarted from zero before it fails, e.g.
+    /// because the transfer was corrupted rather than the file being bad
+    pub checksum_retries: u32,
+    /// Hashes of fixed-size pieces checked once the transfer finished, only corrupted pieces are
+    /// fetched again
+    pub pieces: Option<PieceHashes>,
+    /// Wall-clock time the download has to be finished by, it fails with `DeadlineExceeded` once
+    /// it passes
+    pub deadline: Option<SystemTime>,
+    /// Keeps what a failed download wrote so far so it can be resumed, otherwise (e.g. to keep
+    /// the disk clean) it's removed once the download fails for good. Retried failures always
+    /// keep it, and so does a failed checksum since the file is kept for inspection then.
+    pub keep_partial_on_failure: bool,
+    /// Sends the headers and credentials to every url of a redirect chain, cross-host hops
+    /// included. Disabled (the default) they are dropped once a redirect leaves the host, only
+    /// enable it for redirect targets trusted with the credentials (e.g. a CDN signing urls).
+    pub preserve_auth_on_redirect: bool,
+    /// Fails with `Error::LoginRedirect` instead of downloading the page if the url redirects to
+    /// an HTML page although it doesn't name one, which usually means authentication is missing
+    pub reject_login_redirects: bool,
+    /// Caps the speed of the download, shared by downloads to cap their combined speed. The
+    /// download manager sets it for the downloads it manages if it has a bandwidth limit.
+    pub shared_rate_limiter: Option<RateLimiter>,
+    /// Downloads at full speed regardless of `shared_rate_limiter`, e.g. for an urgent download
+    /// while all others stay capped. Can be toggled while the download runs.
+    pub ignore_global_limit: LimitExemption,
+    /// Caps the speed of this download alone, on top of `shared_rate_limiter` and also when
+    /// exempt from it. The segments of the download share it, unlimited by default and can be
+    /// changed while the download runs.
+    pub rate_limit: RateLimiter,
+    /// Retries of transient failures, clones of the config share it so it can be changed while
+    /// the download runs
+    pub retry_policy: SharedRetryPolicy,
+    /// How often a single request (the probe, including its redirects, and every GET of the
+    /// transfer) is repeated when it can't be sent or gets a 429 or 5xx, before the failure
+    /// reaches the download. Unlike `retry_policy` this also keeps a flaky probe from failing
+    /// the creation of a download. None by default.
+    pub request_retries: u32,
+    /// Waits for written data to reach the disk when the download is paused and syncs all its
+    /// files before it's reported complete, so a power loss can't lose bytes the download
+    /// considers written. Costly on slow or network storage, disabled (the default) leaves the
+    /// data to the OS buffers and a crash may lose the tail of a download reported complete.
+    /// Copies out of the temp directory are synced either way since the original is removed.
+    pub sync_writes: bool,
+    /// Further files every chunk is written to, e.g. a local cache and a network share, without
+    /// downloading twice. Mirrored downloads are written sequentially (never segmented) and can't
+    /// be split, sparse or use piece hashes. A resumed download continues a mirror only if it
+    /// holds at least the bytes of the download's own file.
+    pub mirrors: Vec<PathBuf>,
+    /// Whether a mirror that can't be written fails the download or is given up
+    pub mirror_failure: MirrorFailure,
+    /// Checks this often (with some jitter) while the download runs whether the resource changed
+    /// on the server, with a conditional request against the `ETag` and `Last-Modified` it was
+    /// probed with. A changed resource stops the download with `SourceChanged` instead of
+    /// finishing a file mixing old and new bytes. Off by default as every check is a request,
+    /// resources without validators aren't checked.
+    pub revalidate_interval: Option<Duration>,
+    /// Extensions and content types the download may have, checked when it's probed so a
+    /// forbidden download fails with `Error::ForbiddenFileType` before any bytes are fetched.
+    /// Lazily created downloads are checked before their first run. Allows everything by default.
+    pub file_types: FileTypePolicy,
+    /// Whether an empty resource completes as an empty file (the default) or fails the probe
+    pub empty_response: EmptyResponse,
+    /// Segmented downloads buffer the chunks of all their segments until this many bytes (or
+    /// `persist_interval.time` since the oldest one) are pending and write them sorted by
+    /// offset, fewer scattered writes are faster on spinning disks. Buffered bytes don't count
+    /// as written in the segment metadata, a stopped download fetches them again. None (the
+    /// default) writes every chunk as it arrives.
+    pub write_batch_size: Option<usize>,
+    /// Time an unused connection of the download is kept open, see `ClientConfig::idle_timeout`.
+    /// Set, the builder derives a client of the download's own from the `ClientConfig` it was
+    /// given (the default one otherwise) instead of using the shared client. Once built it holds
+    /// the effective value, None if the download was given a client of unknown configuration.
+    pub idle_timeout: Option<Duration>,
+    /// Proxy the requests of the download go through, the probe, the transfer and its resumes,
+    /// see `ClientConfig::proxy`. Set, the builder derives a client of the download's own like
+    /// for `idle_timeout`. SOCKS proxies aren't supported yet and rejected by the builder.
+    pub proxy: Option<Url>,
+}
+
+impl HttpDownloadConfig {
+    /// Adds the configured headers and credentials to a request, `Accept-Encoding` is always set
+    /// according to `compression`.
+    pub fn prepare(&self, request: RequestBuilder) -> RequestBuilder {
+        let accept_encoding = match self.compression {
+            true => encoding::COMPRESSED,
+            false => encoding::IDENTITY,
+        };
+        let request = request
+            .headers(self.headers.clone())
+            .header(header::ACCEPT_ENCODING, accept_encoding);
+        match &self.auth {
+            Some(auth) => auth.apply(request),
+            None => request,
+        }
+    }
 }
 
 impl Default for HttpDownloadConfig {
@@ -17,11 +282,68 @@ impl Default for HttpDownloadConfig {
             timeout: Duration::from_secs(60),
             headers: HeaderMap::new(),
             chunk_size: DEFAULT_CHUNK_SIZE,
+            segments: 1,
+            segment_target: SegmentTarget::default(),
+            temp_dir: None,
+            url_refresher: None,
+            persist_interval: PersistInterval::default(),
+            max_bytes: None,
+            pause_at: None,
+            auth: None,
+            split_size: None,
+            compression: false,
+            permissions: FilePermissions::default(),
+            infer_extension: true,
+            ranges: None,
+            error_events: None,
+            segment_limit: None,
+            checksum: None,
+            checksum_retries: 0,
+            pieces: None,
+            deadline: None,
+            keep_partial_on_failure: true,
+            preserve_auth_on_redirect: false,
+            reject_login_redirects: false,
+            shared_rate_limiter: None,
+            ignore_global_limit: LimitExemption::default(),
+            rate_limit: RateLimiter::default(),
+            retry_policy: SharedRetryPolicy::default(),
+            request_retries: 0,
+            sync_writes: false,
+            mirrors: Vec::new(),
+            mirror_failure: MirrorFailure::default(),
+            revalidate_interval: None,
+            idle_timeout: None,
+            proxy: None,
+            write_batch_size: None,
+            file_types: FileTypePolicy::default(),
+            empty_response: EmptyResponse::default(),
         };
         config.headers.insert(
             header::USER_AGENT,
             HeaderValue::from_str(DEFAULT_USER_AGENT).unwrap(),
         );
         config
+            .headers
+            .insert(header::ACCEPT, HeaderValue::from_static(DEFAULT_ACCEPT));
+        config
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn persist_interval_is_due_test() {
+        let interval = PersistInterval {
+            time: Duration::from_secs(5),
+            bytes: Some(100),
+        };
+        assert!(!interval.is_due(Duration::from_secs(1), 99));
+        assert!(interval.is_due(Duration::from_secs(1), 100));
+        assert!(interval.is_due(Duration::from_secs(5), 0));
+        let time_only = PersistInterval::default();
+        assert!(!time_only.is_due(Duration::from_secs(1), u64::MAX));
     }
 }
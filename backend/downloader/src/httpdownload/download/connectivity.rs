@@ -0,0 +1,175 @@
+use reqwest::header::{CONTENT_RANGE, CONTENT_TYPE, RANGE};
+use reqwest::{Client, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+use super::config::HttpDownloadConfig;
+use super::{redirect, retry, Error, Result};
+use crate::util::{header_content_length, parse_content_range};
+
+/// Outcome of `test_connection`, what a "test connection" button shows before a download is
+/// created.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionTest {
+    /// The server answered the ranged GET with a success status
+    pub ok: bool,
+    /// Status of the ranged GET after redirects, None if no response arrived
+    pub status: Option<u16>,
+    /// Url the request ended up at after following redirects
+    pub final_url: Option<String>,
+    pub redirected: bool,
+    /// The server answered the ranged GET with 206, so the download can be resumed and segmented
+    pub supports_byte_ranges: bool,
+    pub content_length: Option<u64>,
+    pub content_type: Option<String>,
+    /// Time until the response headers arrived, redirects included
+    pub elapsed_ms: u64,
+    /// Same codes as `Error::code`, None if the test succeeded
+    pub code: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Requests the first byte of `url` with the client, headers and credentials a download with
+/// `config` would use, following redirects the same way. Nothing beyond the headers (and at most
+/// the byte) is read, a server ignoring the range has its response dropped unread. Failures are
+/// part of the outcome rather than an error.
+pub async fn test_connection(
+    url: &Url,
+    client: &Client,
+    config: &HttpDownloadConfig,
+) -> ConnectionTest {
+    let sent = Instant::now();
+    let result = send_probe(url, client, config).await;
+    let elapsed_ms = sent.elapsed().as_millis() as u64;
+    match result {
+        Ok(test) => ConnectionTest { elapsed_ms, ..test },
+        Err(e) => ConnectionTest {
+            ok: false,
+            status: None,
+            final_url: None,
+            redirected: false,
+            supports_byte_ranges: false,
+            content_length: None,
+            content_type: None,
+            elapsed_ms,
+            code: Some(e.code().to_owned()),
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+async fn send_probe(
+    url: &Url,
+    client: &Client,
+    config: &HttpDownloadConfig,
+) -> Result<ConnectionTest> {
+    let resolved;
+    let target = match config.preserve_auth_on_redirect {
+        true => {
+            resolved = redirect::resolve_redirects(url, config).await?;
+            &resolved
+        }
+        false => url,
+    };
+    let get = config
+        .prepare(client.get(target.as_ref()))
+        .timeout(config.timeout)
+        .header(RANGE, "bytes=0-0");
+    let resp = retry::send_with_retries(get, config.request_retries).await?;
+    let status = resp.status();
+    let content_type = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    let content_length = match status {
+        StatusCode::PARTIAL_CONTENT => resp
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_content_range)
+            .and_then(|(_, total)| total),
+        _ => header_content_length(resp.headers()),
+    };
+    let error = if !status.is_success() {
+        Some(Error::DownloadNotOk(status, String::new()))
+    } else if config.reject_login_redirects
+        && redirect::is_login_redirect(url, resp.url(), content_type.as_deref())
+    {
+        Some(Error::LoginRedirect(resp.url().clone()))
+    } else {
+        None
+    };
+    Ok(ConnectionTest {
+        ok: error.is_none(),
+        status: Some(status.as_u16()),
+        final_url: Some(resp.url().to_string()),
+        redirected: resp.url() != url,
+        supports_byte_ranges: status == StatusCode::PARTIAL_CONTENT,
+        content_length,
+        content_type,
+        elapsed_ms: 0,
+        code: error.as_ref().map(|e| e.code().to_owned()),
+        error: error.map(|e| e.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::mock::{MockConfig, MockServer};
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    #[test(tokio::test)]
+    async fn connection_test_reports_ranges_and_redirects_test() {
+        // given
+        let server = MockServer::start(MockConfig::default()).await;
+        let redirecting = MockServer::start(MockConfig {
+            redirect: Some(server.url("file.bin")),
+            ..Default::default()
+        })
+        .await;
+        let config = HttpDownloadConfig::default();
+        // when
+        let test = test_connection(&redirecting.url("file.bin"), &Client::new(), &config).await;
+        // then
+        assert!(test.ok);
+        assert_eq!(test.status, Some(206));
+        assert!(test.redirected);
+        assert_eq!(test.final_url, Some(server.url("file.bin").to_string()));
+        assert!(test.supports_byte_ranges);
+        assert_eq!(test.content_length, Some(server.payload().len() as u64));
+        assert_eq!(test.code, None);
+    }
+
+    #[test(tokio::test)]
+    async fn failed_connection_test_has_a_code_test() {
+        // given
+        let server = MockServer::start(MockConfig {
+            accept_ranges: false,
+            ..Default::default()
+        })
+        .await;
+        let config = HttpDownloadConfig::default();
+        // when the server ignores the range
+        let test = test_connection(&server.url("file.bin"), &Client::new(), &config).await;
+        // then
+        assert!(test.ok);
+        assert_eq!(test.status, Some(200));
+        assert!(!test.supports_byte_ranges);
+        // when it fails
+        server.update(|config| config.fail_next.push_back(StatusCode::FORBIDDEN));
+        let test = test_connection(&server.url("file.bin"), &Client::new(), &config).await;
+        // then
+        assert!(!test.ok);
+        assert_eq!(test.status, Some(403));
+        assert_eq!(test.code.as_deref(), Some("bad_status"));
+        // when nothing listens
+        let closed = Url::parse("http://127.0.0.1:1/file.bin").unwrap();
+        let test = test_connection(&closed, &Client::new(), &config).await;
+        // then
+        assert_eq!((test.ok, test.status), (false, None));
+        assert_eq!(test.code.as_deref(), Some("request_failed"));
+    }
+}
@@ -0,0 +1,82 @@
+use async_compression::tokio::bufread::{DeflateDecoder, GzipDecoder};
+use futures_util::stream::BoxStream;
+use futures_util::{StreamExt, TryStreamExt};
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_ENCODING};
+use reqwest::Response;
+use tokio::io::AsyncBufRead;
+use tokio_util::bytes::Bytes;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use super::Result;
+
+/// `Accept-Encoding` sent when compression is disabled, the server has to send the file as is.
+pub const IDENTITY: HeaderValue = HeaderValue::from_static("identity");
+/// `Accept-Encoding` sent when compression is enabled, only encodings we can decode are offered.
+pub const COMPRESSED: HeaderValue = HeaderValue::from_static("gzip, deflate");
+
+/// Encoding of a response body, taken from its `Content-Encoding` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Identity,
+    Gzip,
+    Deflate,
+}
+
+impl ContentEncoding {
+    /// Unknown encodings are treated as identity, the bytes are stored as they were sent.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let value = headers
+            .get(CONTENT_ENCODING)
+            .and_then(|val| val.to_str().ok())
+            .map(|val| val.trim().to_ascii_lowercase());
+        match value.as_deref() {
+            Some("gzip") | Some("x-gzip") => ContentEncoding::Gzip,
+            Some("deflate") => ContentEncoding::Deflate,
+            _ => ContentEncoding::Identity,
+        }
+    }
+}
+
+/// Body of the response with the content encoding undone.
+pub fn decoded_stream(
+    resp: Response,
+    encoding: ContentEncoding,
+) -> BoxStream<'static, Result<Bytes>> {
+    match encoding {
+        ContentEncoding::Identity => resp.bytes_stream().map_err(Into::into).boxed(),
+        ContentEncoding::Gzip => ReaderStream::new(GzipDecoder::new(body_reader(resp)))
+            .map_err(Into::into)
+            .boxed(),
+        ContentEncoding::Deflate => ReaderStream::new(DeflateDecoder::new(body_reader(resp)))
+            .map_err(Into::into)
+            .boxed(),
+    }
+}
+
+fn body_reader(resp: Response) -> impl AsyncBufRead {
+    StreamReader::new(resp.bytes_stream().map_err(std::io::Error::other))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encoding_from_headers_test() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            ContentEncoding::from_headers(&headers),
+            ContentEncoding::Identity
+        );
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("GZIP"));
+        assert_eq!(
+            ContentEncoding::from_headers(&headers),
+            ContentEncoding::Gzip
+        );
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("br"));
+        assert_eq!(
+            ContentEncoding::from_headers(&headers),
+            ContentEncoding::Identity
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+
+/// Extensions and content types a download may have, checked once the server was probed and
+/// before any bytes are fetched. A denied entry always rejects, a non-empty allow list rejects
+/// everything it doesn't contain, including downloads without an extension or content type.
+/// Comparisons ignore case.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FileTypePolicy {
+    /// Extensions without the leading dot, multi-part ones like `tar.gz` match the end of the
+    /// filename
+    pub allowed_extensions: Vec<String>,
+    pub denied_extensions: Vec<String>,
+    /// Media types like `application/pdf`, `image/*` matches every subtype. Parameters of the
+    /// `Content-Type` (`; charset=...`) are ignored.
+    pub allowed_content_types: Vec<String>,
+    pub denied_content_types: Vec<String>,
+}
+
+impl FileTypePolicy {
+    /// Why the download can't be fetched, None if the policy allows it.
+    pub fn violation(&self, filename: &str, content_type: Option<&str>) -> Option<String> {
+        let filename = filename.to_ascii_lowercase();
+        let has_extension = |ext: &String| {
+            let ext = ext.trim_start_matches('.').to_ascii_lowercase();
+            filename
+                .strip_suffix(ext.as_str())
+                .is_some_and(|stem| stem.len() > 1 && stem.ends_with('.'))
+        };
+        if let Some(ext) = self.denied_extensions.iter().find(|ext| has_extension(ext)) {
+            return Some(format!("extension '{}' is denied", ext));
+        }
+        if !self.allowed_extensions.is_empty() && !self.allowed_extensions.iter().any(has_extension)
+        {
+            return Some(format!("extension of '{}' isn't allowed", filename));
+        }
+        let essence = content_type.map(|content_type| {
+            let essence = content_type.split(';').next().unwrap_or_default();
+            essence.trim().to_ascii_lowercase()
+        });
+        let matches = |pattern: &String| {
+            let Some(essence) = &essence else {
+                return false;
+            };
+            let pattern = pattern.trim().to_ascii_lowercase();
+            match pattern.strip_suffix("/*") {
+                Some(kind) => essence.split('/').next() == Some(kind),
+                None => *essence == pattern,
+            }
+        };
+        if let Some(pattern) = self.denied_content_types.iter().find(|p| matches(p)) {
+            return Some(format!("content type '{}' is denied", pattern));
+        }
+        if !self.allowed_content_types.is_empty() && !self.allowed_content_types.iter().any(matches)
+        {
+            return Some(format!(
+                "content type {:?} isn't allowed",
+                essence.unwrap_or_default()
+            ));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn denied_extensions_ignore_case_test() {
+        let policy = FileTypePolicy {
+            denied_extensions: strings(&["exe", ".tar.gz"]),
+            ..Default::default()
+        };
+        assert!(policy.violation("setup.EXE", None).is_some());
+        assert!(policy.violation("backup.tar.gz", None).is_some());
+        assert!(policy.violation("exe", None).is_none());
+        assert!(policy.violation("notes.gz", None).is_none());
+        assert!(policy.violation("readme.exe.txt", None).is_none());
+    }
+
+    #[test]
+    fn allow_lists_reject_everything_else_test() {
+        let policy = FileTypePolicy {
+            allowed_extensions: strings(&["pdf", "png"]),
+            allowed_content_types: strings(&["application/pdf", "image/*"]),
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.violation("report.pdf", Some("application/pdf")),
+            None
+        );
+        assert_eq!(policy.violation("photo.PNG", Some("Image/PNG")), None);
+        assert!(policy
+            .violation("report", Some("application/pdf"))
+            .is_some());
+        assert!(policy
+            .violation("page.pdf", Some("text/html; charset=utf-8"))
+            .is_some());
+        assert!(policy.violation("report.pdf", None).is_some());
+    }
+
+    #[test]
+    fn denied_content_types_win_test() {
+        let policy = FileTypePolicy {
+            allowed_content_types: strings(&["application/*"]),
+            denied_content_types: strings(&["application/x-msdownload"]),
+            ..Default::default()
+        };
+        assert!(policy
+            .violation("tool.bin", Some("application/x-msdownload"))
+            .is_some());
+        assert_eq!(policy.violation("tool.bin", Some("application/zip")), None);
+        assert!(FileTypePolicy::default()
+            .violation("anything", None)
+            .is_none());
+    }
+}
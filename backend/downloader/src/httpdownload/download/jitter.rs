@@ -0,0 +1,38 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Spread applied to retry delays unless a policy sets its own
+pub const DEFAULT_JITTER_PERCENT: u32 = 10;
+
+/// Spreads `duration` by up to `percent` (at most 100) either way, so timers of downloads that
+/// started or failed together don't all fire at the same moment. `seed` picks the point in that
+/// window, zero percent returns `duration` as it is.
+pub fn jittered(duration: Duration, percent: u32, seed: u32) -> Duration {
+    let spread = percent.min(100) as f64 / 100.0;
+    let position = (seed % 10_001) as f64 / 10_000.0;
+    duration.mul_f64(1.0 - spread + 2.0 * spread * position)
+}
+
+/// Seed for `jittered` that differs between calls.
+pub fn seed() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn jitter_stays_within_percent_test() {
+        let duration = Duration::from_secs(100);
+        assert_eq!(jittered(duration, 10, 0), Duration::from_secs(90));
+        assert_eq!(jittered(duration, 10, 10_000), Duration::from_secs(110));
+        assert_eq!(jittered(duration, 10, 5000), duration);
+        assert_eq!(jittered(duration, 10, 10_001), Duration::from_secs(90));
+        assert_eq!(jittered(duration, 0, 1234), duration);
+        assert_eq!(jittered(duration, 500, 0), Duration::ZERO);
+    }
+}
@@ -0,0 +1,183 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+use super::HttpDownload;
+
+/// Token bucket shared by downloads to cap their combined speed. A chunk that exceeds the budget
+/// puts the bucket into debt and the download waits until it's paid off, bursts are capped at one
+/// second worth of bytes. The rate can be changed while downloads run, without a rate (the
+/// default) nothing is throttled.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    inner: Arc<LimiterInner>,
+}
+
+#[derive(Default)]
+struct LimiterInner {
+    /// Bytes per second, 0 means unlimited
+    rate: AtomicU64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Default)]
+struct Bucket {
+    available: f64,
+    last_refill: Option<Instant>,
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("rate", &self.rate())
+            .finish()
+    }
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: Option<u64>) -> Self {
+        let limiter = Self::default();
+        limiter.set_rate(bytes_per_second);
+        limiter
+    }
+
+    /// Bytes per second downloads are limited to, None if unlimited.
+    pub fn rate(&self) -> Option<u64> {
+        match self.inner.rate.load(Ordering::Relaxed) {
+            0 => None,
+            rate => Some(rate),
+        }
+    }
+
+    pub fn set_rate(&self, bytes_per_second: Option<u64>) {
+        self.inner
+            .rate
+            .store(bytes_per_second.unwrap_or_default(), Ordering::Relaxed);
+    }
+
+    /// Whether this is the last handle to the limiter, nobody can use it anymore then.
+    pub fn is_orphaned(&self) -> bool {
+        Arc::strong_count(&self.inner) == 1
+    }
+
+    /// Accounts `bytes` that were received and waits as long as the rate requires.
+    pub async fn consume(&self, bytes: u64) {
+        let Some(rate) = self.rate() else {
+            return;
+        };
+        let wait = {
+            let mut bucket = self.inner.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = bucket
+                .last_refill
+                .map_or(rate as f64, |last| (now - last).as_secs_f64() * rate as f64);
+            bucket.available = (bucket.available + refill).min(rate as f64) - bytes as f64;
+            bucket.last_refill = Some(now);
+            match bucket.available < 0.0 {
+                true => Duration::from_secs_f64(-bucket.available / rate as f64),
+                false => Duration::ZERO,
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Whether a download bypasses its shared rate limiter, it can be toggled while the download
+/// runs and applies from its next chunk on. Clones share the flag.
+#[derive(Debug, Clone, Default)]
+pub struct LimitExemption {
+    inner: Arc<AtomicBool>,
+}
+
+impl LimitExemption {
+    pub fn new(exempt: bool) -> Self {
+        Self {
+            inner: Arc::new(AtomicBool::new(exempt)),
+        }
+    }
+
+    pub fn get(&self) -> bool {
+        self.inner.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, exempt: bool) {
+        self.inner.store(exempt, Ordering::Relaxed);
+    }
+}
+
+impl HttpDownload {
+    /// Waits for the download's own limit and for the shared rate limiter, if the download has
+    /// one and isn't exempt from it, after receiving `bytes`.
+    pub(super) async fn throttle(&self, bytes: usize) {
+        self.config.rate_limit.consume(bytes as u64).await;
+        if self.config.ignore_global_limit.get() {
+            return;
+        }
+        if let Some(limiter) = &self.config.shared_rate_limiter {
+            limiter.consume(bytes as u64).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::httpdownload::download::DownloadUpdate;
+    use crate::util::mock::{payload, MockConfig, MockServer};
+    use crate::util::setup_test_download;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn consume_waits_for_the_rate_test() {
+        // given
+        let limiter = RateLimiter::new(Some(10_000));
+        let started = Instant::now();
+        // when a second worth of burst and 3000 bytes more are consumed
+        for _ in 0..13 {
+            limiter.consume(1000).await;
+        }
+        // then
+        let elapsed = started.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(250) && elapsed < Duration::from_secs(1),
+            "took {:?}",
+            elapsed
+        );
+        limiter.set_rate(None);
+        let started = Instant::now();
+        limiter.consume(1_000_000).await;
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn download_rate_limit_is_shared_by_segments_test() -> anyhow::Result<()> {
+        for segments in [1, 4] {
+            // given 250 KB capped at 100 KB/s
+            let server = MockServer::start(MockConfig::new(payload(250_000))).await;
+            let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+            download.config.segments = segments;
+            download.config.rate_limit = RateLimiter::new(Some(100_000));
+            let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+            let started = Instant::now();
+            // when
+            download.start(update_sender).await?;
+            // then a second of burst and 150 KB at the rate, whatever the number of connections
+            let elapsed = started.elapsed();
+            assert!(
+                elapsed >= Duration::from_millis(1300) && elapsed < Duration::from_secs(3),
+                "{} segments took {:?}",
+                segments,
+                elapsed
+            );
+            assert_eq!(
+                tokio::fs::read(download.file_path()).await?,
+                *server.payload()
+            );
+        }
+        Ok(())
+    }
+}
@@ -1,17 +1,54 @@
+pub mod builder;
+pub mod checksum;
 pub mod config;
+pub mod connectivity;
+pub mod encoding;
+pub mod filetype;
+pub mod jitter;
+pub mod limiter;
+pub mod moving;
+pub mod multipart;
+pub mod pieces;
+pub mod redirect;
+pub mod refresh;
+pub mod retry;
+pub mod revalidate;
+pub mod segmented;
+pub mod sparse;
+pub mod speed;
+pub mod splice;
+pub mod split;
+pub mod stats;
+pub mod tee;
 
 use futures_util::StreamExt;
-use reqwest::header::RANGE;
-use reqwest::{Client, Response, Url};
+use reqwest::header::{CONTENT_RANGE, CONTENT_TYPE, IF_RANGE, RANGE};
+use reqwest::{Client, RequestBuilder, Response, StatusCode, Url};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::fmt;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::fs::{File, OpenOptions};
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tokio::sync::mpsc::Sender;
+use tokio_util::sync::CancellationToken;
 
-use crate::util::{file_size, mb, supports_byte_ranges, HALF_SECOND};
+use crate::util::{
+    file_size, header_content_length, mb, parse_content_range, supports_byte_ranges,
+    with_inferred_extension,
+};
 
-use self::config::HttpDownloadConfig;
+use self::builder::HttpDownloadBuilder;
+use self::checksum::{Checksum, StreamingHasher};
+use self::config::{EmptyResponse, HttpDownloadConfig};
+use self::encoding::{decoded_stream, ContentEncoding};
+use self::multipart::{clip_to_ranges, ByteRangesParser, PartChunk};
+use self::refresh::{is_expired, RefreshHook};
+use self::revalidate::Validators;
+use self::speed::SpeedMeter;
+use self::split::{split_size_on_disk, Output};
+use self::stats::{DownloadDiagnostics, DownloadStats};
 
 use super::DownloadMetadata;
 
@@ -27,19 +64,283 @@ pub enum Error {
     DownloadComplete(u64),
     #[error("Download req did not yield 200, instead: '{0}', body: '{1}'")]
     DownloadNotOk(reqwest::StatusCode, String),
+    #[error("Server answered '{status}' and asked to retry in {wait:?}")]
+    RetryAfter {
+        status: reqwest::StatusCode,
+        wait: Duration,
+    },
     #[error("Download ended before completion, downloaded bytes: '{0}'")]
     StreamEndedBeforeCompletion(u64),
+    #[error("Malformed multipart/byteranges response: '{0}'")]
+    MalformedMultipart(String),
+    #[error("New source doesn't serve the same content: '{0}'")]
+    SourceMismatch(String),
+    #[error("Transfer incomplete, expected {expected} bytes but {written} were written")]
+    IncompleteTransfer { expected: u64, written: u64 },
+    #[error("Invalid download configuration: '{0}'")]
+    InvalidConfig(String),
+    #[error("Download was cancelled, downloaded bytes: '{0}'")]
+    Cancelled(u64),
+    #[error("Directory '{0}' doesn't exist anymore, the partial file can't be located")]
+    DirectoryMissing(PathBuf),
+    #[error("Checksum mismatch, expected {expected} but computed {actual}")]
+    ChecksumMismatch {
+        expected: Checksum,
+        actual: Checksum,
+    },
+    #[error("Pieces {0:?} are still corrupted after re-fetching them")]
+    PieceMismatch(Vec<usize>),
+    #[error("Deadline passed before the download finished")]
+    DeadlineExceeded,
+    #[error("Redirected to an HTML page at '{0}', probably a login, authentication is required")]
+    LoginRedirect(Url),
+    #[error("Mirror '{0}' can't be written: '{1}'")]
+    MirrorFailed(PathBuf, std::io::Error),
+    #[error("Resource changed on the server while downloading: '{0}'")]
+    SourceChanged(String),
+    #[error("File type of '{0}' isn't allowed: {1}")]
+    ForbiddenFileType(String, String),
+    #[error("Server reported an empty resource for url: '{0}'")]
+    EmptyResponse(Url),
+    #[error("Partial download is of an older version of '{0}', it was restarted from zero")]
+    ResumeValidationFailed(Url),
+    /// The proxy url has its password masked, see `client::redact_proxy`
+    #[error("Proxy '{0}' can't be used: {1}")]
+    ProxyError(Url, String),
+    #[error("FTP transfer failed: {0}")]
+    Ftp(crate::ftpdownload::Error),
+}
+
+/// Errors of ftp downloads the manager handles like the http ones (IO, cancellation, ...) are
+/// mapped to them, see `Downloadable`.
+impl From<crate::ftpdownload::Error> for Error {
+    fn from(error: crate::ftpdownload::Error) -> Self {
+        use crate::ftpdownload::Error as FtpError;
+        match error {
+            FtpError::Io(e) => Error::Io(e),
+            FtpError::DownloadComplete(bytes) => Error::DownloadComplete(bytes),
+            FtpError::IncompleteTransfer { expected, written } => {
+                Error::IncompleteTransfer { expected, written }
+            }
+            FtpError::Cancelled(bytes) => Error::Cancelled(bytes),
+            e => Error::Ftp(e),
+        }
+    }
+}
+
+impl Error {
+    /// Stable identifier of the error for API clients to branch on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Io(e) if e.kind() == std::io::ErrorKind::StorageFull => "disk_full",
+            Error::Io(_) => "io_error",
+            Error::Request(_) => "request_failed",
+            Error::MissingContentLength(_) => "missing_content_length",
+            Error::DownloadComplete(_) => "already_complete",
+            Error::DownloadNotOk(..) | Error::RetryAfter { .. } => "bad_status",
+            Error::StreamEndedBeforeCompletion(_) | Error::IncompleteTransfer { .. } => {
+                "incomplete_transfer"
+            }
+            Error::MalformedMultipart(_) => "malformed_response",
+            Error::SourceMismatch(_) => "source_mismatch",
+            Error::InvalidConfig(_) => "invalid_config",
+            Error::Cancelled(_) => "cancelled",
+            Error::DirectoryMissing(_) => "directory_missing",
+            Error::ChecksumMismatch { .. } => "checksum_failed",
+            Error::PieceMismatch(_) => "piece_mismatch",
+            Error::DeadlineExceeded => "deadline_exceeded",
+            Error::LoginRedirect(_) => "login_redirect",
+            Error::MirrorFailed(..) => "mirror_failed",
+            Error::SourceChanged(_) => "source_changed",
+            Error::ForbiddenFileType(..) => "forbidden_file_type",
+            Error::EmptyResponse(_) => "empty_response",
+            Error::ResumeValidationFailed(_) => "resume_validation_failed",
+            Error::ProxyError(..) => "proxy_error",
+            Error::Ftp(_) => "ftp_error",
+        }
+    }
+
+    /// Http status the server answered with, if the error is about a response.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Error::DownloadNotOk(status, _) | Error::RetryAfter { status, .. } => {
+                Some(status.as_u16())
+            }
+            Error::Request(e) => e.status().map(|status| status.as_u16()),
+            _ => None,
+        }
+    }
+
+    /// Whether the failure might go away on its own (network trouble, an overloaded server), such
+    /// downloads are retried according to their `RetryPolicy`.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Request(_)
+            | Error::StreamEndedBeforeCompletion(_)
+            | Error::IncompleteTransfer { .. }
+            | Error::MalformedMultipart(_)
+            | Error::RetryAfter { .. } => true,
+            Error::DownloadNotOk(status, _) => {
+                status.is_server_error()
+                    || *status == StatusCode::REQUEST_TIMEOUT
+                    || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            // 4xx replies are the transient negative ones in FTP
+            Error::Ftp(crate::ftpdownload::Error::Reply { code, .. }) => (400..500).contains(code),
+            _ => false,
+        }
+    }
+}
+
+/// Bytes of the downloaded part compared with a new source, see `HttpDownload::check_source`
+const SOURCE_CHECK_BYTES: u64 = 64 * 1024;
+
+/// What the server told us about the resource when probing it.
+#[derive(Debug, Clone)]
+pub struct ServerMetadata {
+    pub content_length: u64,
+    pub supports_byte_ranges: bool,
+    pub final_url: Url,
+    pub content_type: Option<String>,
+    pub validators: Validators,
+}
+
+/// Inclusive range of bytes, as used in `Range` and `Content-Range` headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn new(start: u64, end: u64) -> Self {
+        Self { start, end }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.end < self.start
+    }
+}
+
+impl fmt::Display for ByteRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum State {
+    /// Created lazily, the server wasn't probed yet. That happens when the download first runs.
+    Created,
     Complete,
-    Paused(u64),
+    /// Stopped at the configured `max_bytes` cap, holds the number of bytes on disk
+    Partial(u64),
+    /// Stopped by the user (or never started), stays paused until the user resumes it
+    PausedByUser(u64),
+    /// Stopped by the manager, it's resumed automatically once the reason is gone
+    PausedBySystem {
+        bytes_downloaded: u64,
+        reason: PauseReason,
+    },
     Running {
         bytes_downloaded: u64,
+        /// Speed over the last update interval
         bytes_per_second: u64,
+        /// Speed since the download (re)started
+        average_bytes_per_second: u64,
+    },
+    /// A transfer failed transiently and is resumed from `bytes_downloaded` after `delay_ms`,
+    /// `attempt` counts the first try so it's at least 2
+    Retrying {
+        bytes_downloaded: u64,
+        attempt: u32,
+        max_attempts: u32,
+        delay_ms: u64,
+        /// Set if the server said how long to wait with `Retry-After`, the delay is that long
+        /// instead of the backoff of the `RetryPolicy`
+        retry_after_ms: Option<u64>,
     },
     Error(String),
+    /// The transfer finished and the file is hashed against the expected checksum
+    Verifying {
+        bytes_hashed: u64,
+        total: u64,
+    },
+    /// The finished file is moved from the temp directory to its final location, progresses
+    /// only if it has to be copied
+    Moving {
+        bytes_moved: u64,
+        total: u64,
+    },
+    /// The downloaded file doesn't match the expected checksum (after all retries), the file is
+    /// kept for inspection
+    ChecksumFailed {
+        expected: String,
+        actual: String,
+    },
+    /// The download completed but its file was deleted outside of the manager since, starting it
+    /// downloads it again
+    Missing,
+    /// Stopped because a revalidation found the resource changed on the server, the partial file
+    /// is kept. Resuming would mix old and new bytes: start it again, or refresh its metadata to
+    /// accept the change and resume.
+    SourceChanged {
+        bytes_downloaded: u64,
+    },
+}
+
+impl State {
+    /// Whether the download is done, successfully or not, and won't change anymore unless it's
+    /// started again.
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            State::Complete
+                | State::Partial(_)
+                | State::Error(_)
+                | State::ChecksumFailed { .. }
+                | State::Missing
+                | State::SourceChanged { .. }
+        )
+    }
+
+    /// Name of the variant as it's serialized, e.g. `Running`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            State::Created => "Created",
+            State::Complete => "Complete",
+            State::Partial(_) => "Partial",
+            State::PausedByUser(_) => "PausedByUser",
+            State::PausedBySystem { .. } => "PausedBySystem",
+            State::Running { .. } => "Running",
+            State::Retrying { .. } => "Retrying",
+            State::Error(_) => "Error",
+            State::Verifying { .. } => "Verifying",
+            State::Moving { .. } => "Moving",
+            State::ChecksumFailed { .. } => "ChecksumFailed",
+            State::Missing => "Missing",
+            State::SourceChanged { .. } => "SourceChanged",
+        }
+    }
+}
+
+/// Why the system paused a download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PauseReason {
+    /// Too many downloads are running
+    QueueLimit,
+    /// The server asked us to back off
+    RateLimited,
+    /// No progress was made for too long
+    Stalled,
+    /// Downloads of the host failed too often, the host's circuit breaker is open
+    HostUnavailable,
+    /// Less than the reserved space is free on the disk, see `DiskReserve`
+    LowDiskSpace,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -50,9 +351,41 @@ pub struct DownloadUpdate {
     pub state: State,
 }
 
+/// Failure of a download, reported separately from state updates so subscribers can alert on
+/// errors without inspecting every state. Transient errors were retried and don't change the
+/// state of the download.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorEvent {
+    pub id: uuid::Uuid,
+    /// Same codes as `Error::code`
+    pub code: String,
+    pub message: String,
+    /// Http status of the failed response, if there was one
+    pub status: Option<u16>,
+    /// Run of the download the error happened in for final errors, the request attempt within
+    /// the run for transient ones, starting at 1
+    pub attempt: u32,
+    pub transient: bool,
+}
+
+impl ErrorEvent {
+    pub fn new(id: uuid::Uuid, error: &Error, attempt: u32, transient: bool) -> Self {
+        Self {
+            id,
+            code: error.code().to_owned(),
+            message: error.to_string(),
+            status: error.status(),
+            attempt,
+            transient,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct HttpDownload {
     pub url: Url,
+    /// Url the probe request ended up at after following redirects
+    pub final_url: Url,
     pub id: uuid::Uuid,
     pub directory: PathBuf,
     pub filename: String,
@@ -60,63 +393,528 @@ pub struct HttpDownload {
     pub content_length: u64,
     pub supports_byte_ranges: bool,
     pub client: Client,
+    /// False while a lazily created download wasn't probed, `content_length`,
+    /// `supports_byte_ranges` and `final_url` are placeholders until then
+    pub probed: bool,
+    /// `ETag` and `Last-Modified` of the resource when it was probed, see
+    /// `HttpDownloadConfig::revalidate_interval`
+    pub validators: Validators,
+    pub stats: DownloadStats,
 }
 
 impl HttpDownload {
     pub async fn start(&self, update_ch: Sender<DownloadUpdate>) -> Result<u64> {
-        let resp = self
-            .client
-            .get(self.url.as_ref())
-            .headers(self.config.headers.clone())
-            .send()
-            .await?;
+        self.run(update_ch, false, CancellationToken::new()).await
+    }
+
+    pub async fn resume(&self, update_ch: Sender<DownloadUpdate>) -> Result<u64> {
+        self.run(update_ch, true, CancellationToken::new()).await
+    }
+
+    /// Starts (or resumes) the download until it's done or `cancel` is cancelled. A cancelled
+    /// download flushes what it received so far and fails with `Error::Cancelled`, it can be
+    /// resumed afterwards. Transient failures are resumed as the `RetryPolicy` allows. A download
+    /// failing its checksum is restarted from zero up to `checksum_retries` times, the corrupted
+    /// file is kept once they are used up. Once the configured deadline passes the download is
+    /// stopped the same way and fails with `Error::DeadlineExceeded`, retries included. A
+    /// download found to be changed on the server by a revalidation is stopped as well and fails
+    /// with `Error::SourceChanged`.
+    pub async fn run(
+        &self,
+        update_ch: Sender<DownloadUpdate>,
+        resume: bool,
+        cancel: CancellationToken,
+    ) -> Result<u64> {
+        let started = Instant::now();
+        let remaining = match self.config.deadline {
+            Some(deadline) => {
+                let remaining = deadline
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default();
+                if remaining.is_zero() {
+                    return Err(Error::DeadlineExceeded);
+                }
+                Some(remaining)
+            }
+            None => None,
+        };
+        let result = match remaining.is_some() || self.config.revalidate_interval.is_some() {
+            true => {
+                let run_cancel = cancel.child_token();
+                let run = self.run_with_retries(update_ch, resume, &run_cancel);
+                tokio::pin!(run);
+                let deadline = async {
+                    match remaining {
+                        Some(remaining) => tokio::time::sleep(remaining).await,
+                        None => std::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    result = &mut run => result,
+                    _ = deadline => {
+                        log::warn!("Download {} exceeded its deadline, stopping it", self.id);
+                        run_cancel.cancel();
+                        // Lets the download flush what it received so far
+                        let _ = run.await;
+                        Err(Error::DeadlineExceeded)
+                    }
+                    changed = self.watch_source() => {
+                        run_cancel.cancel();
+                        let _ = run.await;
+                        Err(changed)
+                    }
+                }
+            }
+            false => self.run_with_retries(update_ch, resume, &cancel).await,
+        };
+        self.stats.record_active(started.elapsed());
+        if let Err(e) = &result {
+            let failed = !matches!(
+                e,
+                Error::Cancelled(_)
+                    | Error::DownloadComplete(_)
+                    | Error::ChecksumMismatch { .. }
+                    | Error::SourceChanged(_)
+            );
+            if failed && !self.config.keep_partial_on_failure {
+                self.discard_partial().await;
+            }
+        }
+        result
+    }
+
+    async fn run_with_retries(
+        &self,
+        update_ch: Sender<DownloadUpdate>,
+        resume: bool,
+        cancel: &CancellationToken,
+    ) -> Result<u64> {
+        let mut result = self.transfer(update_ch.clone(), resume, cancel).await;
+        for retry in 1..=self.config.checksum_retries {
+            let Err(Error::ChecksumMismatch { expected, actual }) = &result else {
+                break;
+            };
+            log::warn!(
+                "Download {} computed {} but expected {}, restarting it (retry {}/{})",
+                self.id,
+                actual,
+                expected,
+                retry,
+                self.config.checksum_retries
+            );
+            self.report_error(result.as_ref().unwrap_err(), retry, true);
+            result = self.transfer(update_ch.clone(), false, cancel).await;
+        }
+        result
+    }
+
+    /// Runs the transfer, resuming it after transient failures while the retry policy allows. The
+    /// policy is read again before every retry so changes apply to a running download.
+    async fn transfer(
+        &self,
+        update_ch: Sender<DownloadUpdate>,
+        resume: bool,
+        cancel: &CancellationToken,
+    ) -> Result<u64> {
+        let mut result = match resume {
+            true => self.resume_with(update_ch.clone(), cancel).await,
+            false => self.start_with(update_ch.clone(), cancel).await,
+        };
+        let mut attempt = 1;
+        while let Err(e) = &result {
+            let policy = self.config.retry_policy.get();
+            if !e.is_transient() || attempt >= policy.max_attempts {
+                break;
+            }
+            let retry_after = match e {
+                Error::RetryAfter { wait, .. } if *wait <= policy.max_retry_after() => Some(*wait),
+                Error::RetryAfter { wait, .. } => {
+                    log::warn!(
+                        "Retry-After of {:?} for download {} exceeds {:?}, backing off instead",
+                        wait,
+                        self.id,
+                        policy.max_retry_after()
+                    );
+                    None
+                }
+                _ => None,
+            };
+            let delay = retry_after.unwrap_or_else(|| policy.jittered_delay(attempt));
+            log::warn!(
+                "Download {} failed with {}, retrying in {:?} (attempt {}/{})",
+                self.id,
+                e,
+                delay,
+                attempt + 1,
+                policy.max_attempts
+            );
+            self.report_error(e, attempt, true);
+            let state = State::Retrying {
+                bytes_downloaded: self.get_bytes_on_disk().await,
+                attempt: attempt + 1,
+                max_attempts: policy.max_attempts,
+                delay_ms: delay.as_millis() as u64,
+                retry_after_ms: retry_after.map(|wait| wait.as_millis() as u64),
+            };
+            let _ = update_ch.try_send(DownloadUpdate { id: self.id, state });
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = cancel.cancelled() => {
+                    return Err(Error::Cancelled(self.get_bytes_on_disk().await));
+                }
+            }
+            attempt += 1;
+            result = self.resume_with(update_ch.clone(), cancel).await;
+        }
+        result
+    }
+
+    async fn start_with(
+        &self,
+        update_ch: Sender<DownloadUpdate>,
+        cancel: &CancellationToken,
+    ) -> Result<u64> {
+        if let Some(ranges) = self.sparse_ranges() {
+            return self.download_sparse(ranges, update_ch, cancel).await;
+        }
+        if self.is_segmented() {
+            return self.download_segmented(update_ch, false, cancel).await;
+        }
+        let range = match self.is_capped() && self.supports_byte_ranges {
+            true => Some(format!("bytes=0-{}", self.target_length() - 1)),
+            false => None,
+        };
+        let resp = self.send_request(range.as_deref(), None).await?;
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(retry::status_error(resp).await);
+        }
+        self.store_validators(&Validators::from_headers(resp.headers()))
+            .await;
         log::info!(
             "Starting new download for url {}, creating file at {:?}",
             self.url,
-            self.file_path()
+            self.download_path()
+        );
+        let output = self.open_output(0).await?;
+        self.progress(resp, output, update_ch, 0, cancel).await
+    }
+
+    /// Number of bytes the download ends with, the content length unless `max_bytes` caps it.
+    pub fn target_length(&self) -> u64 {
+        match self.config.max_bytes {
+            Some(cap) if cap > 0 => cap.min(self.content_length),
+            _ => self.content_length,
+        }
+    }
+
+    /// The `pause_at` threshold if the download hasn't reached it yet at `written` bytes, a
+    /// download resumed past it keeps running.
+    pub(super) fn pause_threshold(&self, written: u64) -> Option<u64> {
+        self.config.pause_at.filter(|&pause_at| pause_at > written)
+    }
+
+    /// Whether only a prefix of the file is downloaded because of `max_bytes`.
+    pub fn is_capped(&self) -> bool {
+        self.target_length() < self.content_length
+    }
+
+    /// Url requests are sent to, the latest refreshed url if the refresh hook had to be used.
+    /// Downloads preserving their credentials across redirects go straight to the end of the
+    /// redirect chain resolved when probing.
+    pub fn current_url(&self) -> Url {
+        self.config
+            .url_refresher
+            .as_ref()
+            .and_then(RefreshHook::latest)
+            .unwrap_or_else(|| match self.config.preserve_auth_on_redirect {
+                true => self.final_url.clone(),
+                false => self.url.clone(),
+            })
+    }
+
+    /// Sends a GET for the download with an optional `Range` header value, sent with `If-Range`
+    /// if a validator is given. If the server rejects the url as expired and a refresh hook is
+    /// registered, the request is repeated once against the refreshed url.
+    async fn send_request(&self, range: Option<&str>, if_range: Option<&str>) -> Result<Response> {
+        let url = self.current_url();
+        let sent = Instant::now();
+        let resp = self.send(self.request(&url, range, if_range)).await?;
+        self.stats.record_request(sent.elapsed());
+        let Some(hook) = &self.config.url_refresher else {
+            return Ok(resp);
+        };
+        if !is_expired(resp.status()) {
+            return Ok(resp);
+        }
+        log::warn!(
+            "Url of download {} was rejected with {}, refreshing it",
+            self.id,
+            resp.status()
+        );
+        self.report_error(
+            &Error::DownloadNotOk(resp.status(), "url expired, refreshing it".to_string()),
+            1,
+            true,
         );
-        let file_handler = File::create(self.file_path()).await?;
-        self.progress(resp, file_handler, update_ch, 0).await
+        match hook.refresh(&url).await {
+            Some(refreshed) => {
+                let sent = Instant::now();
+                let resp = self.send(self.request(&refreshed, range, if_range)).await?;
+                self.stats.record_request(sent.elapsed());
+                Ok(resp)
+            }
+            None => {
+                log::warn!("Couldn't refresh url of download {}", self.id);
+                Ok(resp)
+            }
+        }
+    }
+
+    async fn send(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        retry::send_with_retries(request, self.config.request_retries).await
+    }
+
+    fn request(&self, url: &Url, range: Option<&str>, if_range: Option<&str>) -> RequestBuilder {
+        let request = self.config.prepare(self.client.get(url.as_ref()));
+        match (range, if_range) {
+            (Some(range), Some(validator)) => {
+                request.header(RANGE, range).header(IF_RANGE, validator)
+            }
+            (Some(range), None) => request.header(RANGE, range),
+            (None, _) => request,
+        }
     }
 
+    /// Final location of the downloaded file.
     pub fn file_path(&self) -> PathBuf {
         self.directory.join(&self.filename)
     }
 
-    pub async fn resume(&self, update_ch: Sender<DownloadUpdate>) -> Result<u64> {
+    /// Where the finished download ends up after all post-processing, the manifest for split
+    /// downloads (it lists the parts next to it) and the file itself otherwise.
+    pub fn final_path(&self) -> PathBuf {
+        match self.split_size() {
+            Some(_) => self.manifest_path(),
+            None => self.file_path(),
+        }
+    }
+
+    /// Location the bytes are written to while downloading, a `.part` file in the temp
+    /// directory if one is configured, otherwise the final location itself.
+    pub fn download_path(&self) -> PathBuf {
+        match &self.config.temp_dir {
+            Some(temp_dir) => temp_dir.join(format!("{}.part", self.filename)),
+            None => self.file_path(),
+        }
+    }
+
+    /// Every file the download writes to, while downloading and once finished.
+    pub fn target_paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![self.download_path(), self.file_path()];
+        paths.extend(self.split_files());
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    /// Changes the filename of the download, data already on disk (the partial or the finished
+    /// file) is moved to the new name. Split downloads can't be renamed.
+    pub async fn rename(&mut self, filename: String) -> Result<()> {
+        if filename.is_empty() || filename.contains(['/', '\\']) || filename == ".." {
+            return Err(Error::InvalidConfig(format!(
+                "'{}' is not a valid filename",
+                filename
+            )));
+        }
+        if self.split_size().is_some() {
+            return Err(Error::InvalidConfig(
+                "split downloads can't be renamed".to_string(),
+            ));
+        }
+        let mut renamed = self.clone();
+        renamed.filename = filename;
+        for (from, to) in [
+            (self.download_path(), renamed.download_path()),
+            (self.file_path(), renamed.file_path()),
+        ] {
+            if from != to && tokio::fs::try_exists(&from).await.unwrap_or(false) {
+                log::info!("Renaming {:?} to {:?}", from, to);
+                tokio::fs::rename(&from, &to).await?;
+            }
+        }
+        self.filename = renamed.filename;
+        Ok(())
+    }
+
+    /// Moves a finished (and verified) download from the temp directory to its final location,
+    /// reported as `State::Moving`. Falls back to copying if a rename isn't possible (e.g. temp
+    /// and final directory are on different filesystems).
+    pub async fn finalize(&self, update_ch: &Sender<DownloadUpdate>) -> Result<()> {
+        if self.config.sync_writes {
+            self.sync_files().await?;
+        }
+        // Complete downloads aren't resumed, their version doesn't need checking anymore
+        self.store_validators(&Validators::default()).await;
+        if let Some(part_size) = self.split_size() {
+            return self.finalize_split(part_size, update_ch).await;
+        }
+        let download_path = self.download_path();
+        let file_path = self.file_path();
+        if download_path != file_path {
+            let mut progress = self.move_progress(update_ch).await?;
+            moving::move_file(&download_path, &file_path, &mut progress).await?;
+        }
+        // A copy gets the mode but not the owner of the original
+        self.config.permissions.apply(&file_path).await?;
+        Ok(())
+    }
+
+    /// Syncs every file the download wrote to disk.
+    async fn sync_files(&self) -> Result<()> {
+        for path in self.downloaded_files() {
+            OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .await?
+                .sync_all()
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Removes what a failed download wrote so far, the partial file (or its parts) and the
+    /// segment progress.
+    async fn discard_partial(&self) {
+        let mut files = self.downloaded_files();
+        files.push(self.sidecar_path());
+        files.push(self.validators_path());
+        for file in files {
+            match tokio::fs::remove_file(&file).await {
+                Ok(()) => log::info!("Removed {:?} of failed download {}", file, self.id),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => log::warn!("Couldn't remove {:?} of download {}: {}", file, self.id, e),
+            }
+        }
+    }
+
+    /// Moves the download to `directory` if its own directory doesn't exist anymore (e.g. the
+    /// download directory was changed in the settings and the files were moved) and its file is
+    /// found there. Returns whether the download was moved.
+    pub async fn relocate(&mut self, directory: &Path) -> bool {
+        let exists =
+            |path: PathBuf| async move { tokio::fs::try_exists(path).await.unwrap_or(false) };
+        if self.directory == directory || exists(self.directory.clone()).await {
+            return false;
+        }
+        let file_path = directory.join(&self.filename);
+        if !exists(file_path.clone()).await && !exists(split::part_path(&file_path, 0)).await {
+            return false;
+        }
+        log::info!(
+            "Directory {:?} of download {} is gone, its file was found in {:?}",
+            self.directory,
+            self.id,
+            directory
+        );
+        self.directory = directory.to_owned();
+        true
+    }
+
+    /// Sends an `ErrorEvent` to the configured error channel, if any. Transient errors are
+    /// retried and counted in the stats.
+    pub fn report_error(&self, error: &Error, attempt: u32, transient: bool) {
+        if transient {
+            self.stats.record_retry();
+        }
+        if let Some(error_events) = &self.config.error_events {
+            let _ = error_events.send(ErrorEvent::new(self.id, error, attempt, transient));
+        }
+    }
+
+    /// Host serving the download, taken from the url resolved after redirects.
+    pub fn host(&self) -> Option<&str> {
+        self.final_url.host_str()
+    }
+
+    async fn resume_with(
+        &self,
+        update_ch: Sender<DownloadUpdate>,
+        cancel: &CancellationToken,
+    ) -> Result<u64> {
+        let download_dir = self.download_path().parent().map(Path::to_owned);
+        if let Some(dir) = download_dir.filter(|dir| !dir.as_os_str().is_empty()) {
+            if !tokio::fs::try_exists(&dir).await.unwrap_or(false) {
+                return Err(Error::DirectoryMissing(dir));
+            }
+        }
+        if let Some(ranges) = self.sparse_ranges() {
+            return self.download_sparse(ranges, update_ch, cancel).await;
+        }
+        if self.is_segmented() {
+            return match self
+                .download_segmented(update_ch.clone(), true, cancel)
+                .await
+            {
+                Err(e @ Error::ResumeValidationFailed(_)) => {
+                    log::warn!("{}, download {}", e, self.id);
+                    self.report_error(&e, 1, true);
+                    self.download_segmented(update_ch, false, cancel).await
+                }
+                result => result,
+            };
+        }
         let bytes_on_disk = self.get_bytes_on_disk().await;
-        if bytes_on_disk == self.content_length {
+        if bytes_on_disk == self.target_length() && self.needs_verification() {
+            log::info!("Transfer of {} is complete, verifying it", self.url);
+            self.verify(&update_ch, cancel, None).await?;
+            self.finalize(&update_ch).await?;
+            return Ok(bytes_on_disk);
+        }
+        if bytes_on_disk == self.target_length() {
             log::warn!(
                 "Tried downloading a file that was already completely downloaded: {}",
                 self.url
             );
             return Err(Error::DownloadComplete(bytes_on_disk));
         }
-        if !self.supports_byte_ranges {
+        if !self.supports_byte_ranges || self.config.compression {
             log::warn!(
-                "Tried resuming a download that doesn't support byte ranges: {}",
+                "Tried resuming a download that doesn't support byte ranges or is compressed: {}",
                 self.url
             );
             log::info!("Starting from scratch: {}", self.url);
-            return self.start(update_ch).await;
+            return self.start_with(update_ch, cancel).await;
         }
-        let file_handler = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .open(self.file_path())
-            .await?;
-
+        let output = self.open_output(bytes_on_disk).await?;
+        let range = match self.is_capped() {
+            true => format!("bytes={}-{}", bytes_on_disk, self.target_length() - 1),
+            false => format!("bytes={}-", bytes_on_disk),
+        };
+        if bytes_on_disk > 0 {
+            self.stats.record_resume();
+            self.stats.record_reconnect();
+        }
+        let validators = self.partial_validators().await;
         let resp = self
-            .client
-            .get(self.url.as_ref())
-            .headers(self.config.headers.clone())
-            .header(RANGE, format!("bytes={}-", bytes_on_disk))
-            .send()
+            .send_request(Some(&range), validators.if_range())
             .await?;
-        self.progress(resp, file_handler, update_ch, bytes_on_disk)
+        // The whole resource instead of the range, the partial file is of another version
+        if resp.status() == StatusCode::OK && bytes_on_disk > 0 {
+            let e = Error::ResumeValidationFailed(self.url.clone());
+            log::warn!("{}, download {}", e, self.id);
+            self.report_error(&e, 1, true);
+            self.store_validators(&Validators::from_headers(resp.headers()))
+                .await;
+            drop(output);
+            let output = self.open_output(0).await?;
+            return self.progress(resp, output, update_ch, 0, cancel).await;
+        }
+        self.progress(resp, output, update_ch, bytes_on_disk, cancel)
             .await
     }
 
+    /// Probes the url and creates a download for it, see `HttpDownload::builder` for more options.
     pub async fn create(
         url: Url,
         directory: PathBuf,
@@ -125,85 +923,525 @@ impl HttpDownload {
         config: Option<HttpDownloadConfig>,
     ) -> Result<Self> {
         // If no configuration is passed the default one is copied
+        HttpDownload::builder()
+            .url(url)
+            .directory(directory)
+            .filename(filename)
+            .client(client)
+            .config(config.unwrap_or_default())
+            .build()
+            .await
+    }
+
+    /// Like `create` with `segments` parallel range requests, a server without byte range support
+    /// or a known length is downloaded over a single connection, see `effective_segments`.
+    pub async fn create_segmented(
+        url: Url,
+        directory: PathBuf,
+        filename: String,
+        client: Client,
+        segments: usize,
+        config: Option<HttpDownloadConfig>,
+    ) -> Result<Self> {
+        let config = HttpDownloadConfig {
+            segments: segments.max(1),
+            ..config.unwrap_or_default()
+        };
+        HttpDownload::create(url, directory, filename, client, Some(config)).await
+    }
+
+    /// Like `create` with the checksum the finished download has to match, the bytes are hashed
+    /// as they are written and a mismatch fails the download with `Error::ChecksumMismatch`, see
+    /// `HttpDownloadConfig::checksum`.
+    pub async fn create_verified(
+        url: Url,
+        directory: PathBuf,
+        filename: String,
+        client: Client,
+        expected_checksum: Option<Checksum>,
+        config: Option<HttpDownloadConfig>,
+    ) -> Result<Self> {
         let config = config.unwrap_or_default();
-        let id = uuid::Uuid::new_v4();
-        let resp = client
-            .get(url.as_ref())
-            .timeout(config.timeout)
-            .headers(config.headers.clone())
-            .send()
-            .await?;
+        let config = HttpDownloadConfig {
+            checksum: expected_checksum.or(config.checksum),
+            ..config
+        };
+        HttpDownload::create(url, directory, filename, client, Some(config)).await
+    }
 
-        let status = resp.status();
-        match status {
-            reqwest::StatusCode::OK => {}
-            _ => {
-                let body = resp.text().await.unwrap_or_default();
-                return Err(Error::DownloadNotOk(status, body));
+    pub fn builder() -> HttpDownloadBuilder {
+        HttpDownloadBuilder::default()
+    }
+
+    /// Requests the url to find out what the server knows about the resource. Uses a HEAD request,
+    /// servers that reject HEAD are asked for the first byte with a ranged GET instead.
+    pub async fn probe(
+        url: &Url,
+        client: &Client,
+        config: &HttpDownloadConfig,
+    ) -> Result<ServerMetadata> {
+        let requested = url;
+        let resolved;
+        let url = match config.preserve_auth_on_redirect {
+            true => {
+                resolved = redirect::resolve_redirects(url, config).await?;
+                &resolved
+            }
+            false => url,
+        };
+        let head = config
+            .prepare(client.head(url.as_ref()))
+            .timeout(config.timeout);
+        let resp = retry::send_with_retries(head, config.request_retries).await?;
+        let resp = match resp.status() {
+            StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED => {
+                log::info!(
+                    "{} rejected HEAD with {}, probing with a ranged GET",
+                    url,
+                    resp.status()
+                );
+                let get = config
+                    .prepare(client.get(url.as_ref()))
+                    .timeout(config.timeout)
+                    .header(RANGE, "bytes=0-0");
+                retry::send_with_retries(get, config.request_retries).await?
             }
+            _ => resp,
         };
 
-        let content_length = match resp.content_length() {
+        let status = resp.status();
+        let content_type = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|val| val.to_str().ok())
+            .map(str::to_owned);
+        if config.reject_login_redirects
+            && redirect::is_login_redirect(requested, resp.url(), content_type.as_deref())
+        {
+            log::warn!(
+                "{} redirected to the HTML page {}, treating it as a login",
+                requested,
+                resp.url()
+            );
+            return Err(Error::LoginRedirect(resp.url().clone()));
+        }
+        let (content_length, supports_byte_ranges) = match status {
+            // The content length header of a HEAD response describes the body a GET would get
+            StatusCode::OK => (
+                header_content_length(resp.headers()),
+                supports_byte_ranges(resp.headers()),
+            ),
+            // Only the ranged GET fallback gets here, the total size is in the Content-Range
+            StatusCode::PARTIAL_CONTENT => {
+                let total = resp
+                    .headers()
+                    .get(CONTENT_RANGE)
+                    .and_then(|val| val.to_str().ok())
+                    .and_then(parse_content_range)
+                    .and_then(|(_, total)| total);
+                (total, true)
+            }
+            _ => return Err(retry::status_error(resp).await),
+        };
+        let content_length = match content_length {
             Some(val) => Ok(val),
             None => Err(Error::MissingContentLength(url.clone())),
         }?;
-        let supports_byte_ranges = supports_byte_ranges(resp.headers());
-        let download = HttpDownload {
-            id,
-            url,
-            directory,
-            filename: filename.to_string(),
-            config,
-            client,
-            supports_byte_ranges,
+        Ok(ServerMetadata {
             content_length,
-        };
-        Ok(download)
+            supports_byte_ranges,
+            final_url: resp.url().clone(),
+            content_type,
+            validators: Validators::from_headers(resp.headers()),
+        })
     }
 
-    async fn progress(
-        &self,
-        resp: Response,
-        mut file_handler: File,
-        update_ch: Sender<DownloadUpdate>,
-        mut downloaded_bytes: u64,
-    ) -> Result<u64> {
-        let mut stream = resp.bytes_stream();
-        let mut last_update = std::time::Instant::now();
-        let mut previous_bytes = 0u64;
-        while let Some(chunk) = stream.next().await {
-            let item = chunk?;
-            let bytes_written = file_handler.write(&item).await? as u64;
-            downloaded_bytes += bytes_written;
-            previous_bytes += bytes_written;
-            let elapsed = last_update.elapsed();
-            if elapsed > HALF_SECOND {
-                let _ = update_ch.try_send(DownloadUpdate {
-                    id: self.id,
-                    state: State::Running {
-                        bytes_downloaded: downloaded_bytes,
-                        bytes_per_second: previous_bytes / last_update.elapsed().as_millis() as u64
-                            * 1000,
-                    },
-                });
-                last_update = std::time::Instant::now();
-                previous_bytes = 0u64;
-            }
-        }
-        if downloaded_bytes < self.content_length {
-            log::error!(
-                "Download stream ended before completion, downloaded bytes: {}, content length: {}",
-                downloaded_bytes,
-                self.content_length
-            );
-            return Err(Error::StreamEndedBeforeCompletion(downloaded_bytes));
+    /// Probes the server and takes over what it reports, done on create unless the download is
+    /// created lazily, then before the download first runs.
+    pub async fn fetch_metadata(&mut self) -> Result<()> {
+        let server_metadata = Self::probe(&self.url, &self.client, &self.config).await?;
+        if self.config.infer_extension {
+            self.filename =
+                with_inferred_extension(&self.filename, server_metadata.content_type.as_deref());
+        }
+        if let Some(violation) = self
+            .config
+            .file_types
+            .violation(&self.filename, server_metadata.content_type.as_deref())
+        {
+            return Err(Error::ForbiddenFileType(self.filename.clone(), violation));
+        }
+        if server_metadata.content_length == 0 && self.config.empty_response == EmptyResponse::Fail
+        {
+            return Err(Error::EmptyResponse(server_metadata.final_url));
+        }
+        self.final_url = server_metadata.final_url;
+        self.supports_byte_ranges = server_metadata.supports_byte_ranges;
+        self.content_length = server_metadata.content_length;
+        self.validators = server_metadata.validators;
+        self.probed = true;
+        self.validate_ranges()?;
+        self.validate_pieces()?;
+        self.validate_mirrors()
+    }
+
+    /// Probes the server again and takes over what it reports now, e.g. after the headers or
+    /// credentials of a download that couldn't be probed were fixed or the resource changed.
+    /// Returns the names of the fields that changed. The filename is kept, a partial file of a
+    /// resource whose size changed should be downloaded again from the start.
+    pub async fn refresh_metadata(&mut self) -> Result<Vec<&'static str>> {
+        let server_metadata = Self::probe(&self.url, &self.client, &self.config).await?;
+        let mut changed = Vec::new();
+        if server_metadata.content_length != self.content_length {
+            changed.push("content_length");
+        }
+        if server_metadata.supports_byte_ranges != self.supports_byte_ranges {
+            changed.push("supports_byte_ranges");
+        }
+        if server_metadata.final_url != self.final_url {
+            changed.push("final_url");
+        }
+        if server_metadata.validators != self.validators {
+            changed.push("validators");
         }
         log::info!(
-            "Download completed successfully: {}, {}MB",
-            self.url,
-            mb(downloaded_bytes)
+            "Refreshed metadata of download {}, changed: {:?}",
+            self.id,
+            changed
         );
-        Ok(downloaded_bytes)
+        self.final_url = server_metadata.final_url;
+        self.supports_byte_ranges = server_metadata.supports_byte_ranges;
+        self.content_length = server_metadata.content_length;
+        self.validators = server_metadata.validators;
+        self.probed = true;
+        if changed.contains(&"validators") {
+            self.accept_validators().await;
+        }
+        self.validate_ranges()?;
+        self.validate_pieces()?;
+        Ok(changed)
+    }
+
+    /// Points the download at a different source for the same content, see `check_source`.
+    pub async fn change_url(&mut self, url: Url) -> Result<()> {
+        let server_metadata = self.check_source(&url).await?;
+        self.set_source(url, server_metadata).await
+    }
+
+    /// Probes `url` as a new source of the download, it has to serve the same amount of bytes
+    /// and, if part of the file is already on disk, support byte ranges so the download can
+    /// continue where it left off. Unless both servers send the same strong ETag, the last bytes
+    /// of the part on disk are also requested from the new source and have to match. Doesn't
+    /// change the download, so it can run while the download does.
+    pub async fn check_source(&self, url: &Url) -> Result<ServerMetadata> {
+        let server_metadata = Self::probe(url, &self.client, &self.config).await?;
+        if server_metadata.content_length != self.content_length {
+            return Err(Error::SourceMismatch(format!(
+                "content length {} differs from {}",
+                server_metadata.content_length, self.content_length
+            )));
+        }
+        let bytes_on_disk = self.get_bytes_on_disk().await;
+        if bytes_on_disk > 0 && !server_metadata.supports_byte_ranges {
+            return Err(Error::SourceMismatch(format!(
+                "{} doesn't support byte ranges, can't continue at {} bytes",
+                url, bytes_on_disk
+            )));
+        }
+        let strong_etag = |validators: &Validators| {
+            validators
+                .etag
+                .clone()
+                .filter(|etag| !etag.starts_with("W/"))
+        };
+        let same_etag = strong_etag(&self.validators)
+            .is_some_and(|etag| Some(etag) == strong_etag(&server_metadata.validators));
+        if !same_etag {
+            if let Some((path, prefix)) = self.readable_prefix().await {
+                self.compare_tail(url, &path, prefix).await?;
+            }
+        }
+        Ok(server_metadata)
+    }
+
+    /// Requests up to the last `SOURCE_CHECK_BYTES` of the first `prefix` bytes of `path` from
+    /// `url` and fails with `SourceMismatch` unless they are the same.
+    async fn compare_tail(&self, url: &Url, path: &Path, prefix: u64) -> Result<()> {
+        if prefix == 0 {
+            return Ok(());
+        }
+        let window = prefix.min(SOURCE_CHECK_BYTES);
+        let range = ByteRange::new(prefix - window, prefix - 1);
+        let request = self
+            .config
+            .prepare(self.client.get(url.as_ref()))
+            .timeout(self.config.timeout)
+            .header(RANGE, format!("bytes={}", range));
+        let resp = retry::send_with_retries(request, self.config.request_retries).await?;
+        if resp.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(Error::SourceMismatch(format!(
+                "{} answered the request for bytes {} with {}",
+                url,
+                range,
+                resp.status()
+            )));
+        }
+        let served = resp.bytes().await?;
+        let mut on_disk = vec![0; window as usize];
+        let mut file = File::open(path).await?;
+        file.seek(SeekFrom::Start(range.start)).await?;
+        file.read_exact(&mut on_disk).await?;
+        if served[..] != on_disk[..] {
+            return Err(Error::SourceMismatch(format!(
+                "bytes {} of {} differ from the downloaded ones",
+                range, url
+            )));
+        }
+        Ok(())
+    }
+
+    /// Switches the download to `url` once `check_source` accepted it. The range support is
+    /// checked again, the download may have written its first bytes since.
+    pub async fn set_source(&mut self, url: Url, server_metadata: ServerMetadata) -> Result<()> {
+        let bytes_on_disk = self.get_bytes_on_disk().await;
+        if bytes_on_disk > 0 && !server_metadata.supports_byte_ranges {
+            return Err(Error::SourceMismatch(format!(
+                "{} doesn't support byte ranges, can't continue at {} bytes",
+                url, bytes_on_disk
+            )));
+        }
+        log::info!("Changing url of download {} to {}", self.id, url);
+        self.url = url;
+        self.final_url = server_metadata.final_url;
+        self.supports_byte_ranges = server_metadata.supports_byte_ranges;
+        self.validators = server_metadata.validators;
+        Ok(())
+    }
+
+    async fn progress(
+        &self,
+        resp: Response,
+        mut output: Output,
+        update_ch: Sender<DownloadUpdate>,
+        mut downloaded_bytes: u64,
+        cancel: &CancellationToken,
+    ) -> Result<u64> {
+        let encoding = match self.config.compression {
+            true => ContentEncoding::from_headers(resp.headers()),
+            false => ContentEncoding::Identity,
+        };
+        // The decompressed size isn't known, a decoded body is written until it ends
+        let target_length = match encoding {
+            ContentEncoding::Identity => self.target_length(),
+            _ => u64::MAX,
+        };
+        let connection = self.stats.open_connection();
+        let mut stream = decoded_stream(resp, encoding);
+        let mut hasher = self.streaming_hasher(downloaded_bytes).await;
+        let mut speed = SpeedMeter::new();
+        loop {
+            let chunk = tokio::select! {
+                chunk = stream.next() => chunk,
+                _ = cancel.cancelled() => {
+                    log::info!("Download {} was cancelled", self.id);
+                    output.flush_synced(self.config.sync_writes).await?;
+                    return Err(Error::Cancelled(downloaded_bytes));
+                }
+            };
+            let Some(chunk) = chunk else {
+                break;
+            };
+            let item = chunk?;
+            // A server without range support sends everything, only the capped prefix is kept
+            let remaining = self
+                .pause_threshold(downloaded_bytes)
+                .unwrap_or(target_length)
+                .min(target_length)
+                .saturating_sub(downloaded_bytes);
+            let data = &item[..(item.len() as u64).min(remaining) as usize];
+            output.write_all(data).await?;
+            if let Some(hasher) = &mut hasher {
+                hasher.update(data);
+            }
+            // Charged what's kept, a capped or decoded chunk doesn't use up the full item
+            tokio::select! {
+                _ = self.throttle(data.len()) => {}
+                _ = cancel.cancelled() => {}
+            }
+            downloaded_bytes += data.len() as u64;
+            speed.record(data.len() as u64);
+            if let Some(state) = speed.tick(downloaded_bytes) {
+                let _ = update_ch.try_send(DownloadUpdate { id: self.id, state });
+            }
+            if self.is_capped() && downloaded_bytes == target_length {
+                break;
+            }
+            if self.config.pause_at == Some(downloaded_bytes) && downloaded_bytes < target_length {
+                log::info!(
+                    "Download {} reached {} bytes, pausing it",
+                    self.id,
+                    downloaded_bytes
+                );
+                output.flush_synced(self.config.sync_writes).await?;
+                return Err(Error::Cancelled(downloaded_bytes));
+            }
+        }
+        drop((stream, connection));
+        output.flush_synced(self.config.sync_writes).await?;
+        self.verify_complete(downloaded_bytes, encoding != ContentEncoding::Identity)
+            .await?;
+        let streamed = hasher.map(StreamingHasher::finalize);
+        self.verify(&update_ch, cancel, streamed).await?;
+        self.finalize(&update_ch).await?;
+        log::info!(
+            "Download completed successfully: {}, {}MB",
+            self.url,
+            mb(downloaded_bytes)
+        );
+        Ok(downloaded_bytes)
+    }
+
+    /// A connection that is closed early without an error ends the stream before the whole file
+    /// was transferred, this compares what was written to disk against the content length (or
+    /// the cap) so such a download fails (and stays resumable) instead of being reported complete.
+    /// The decompressed size of a `decoded` body is unknown upfront, only the bytes on disk are
+    /// checked then.
+    async fn verify_complete(&self, downloaded_bytes: u64, decoded: bool) -> Result<()> {
+        let bytes_on_disk = match self.split_size() {
+            Some(_) => split_size_on_disk(&self.download_path()).await,
+            None => file_size(&self.download_path()).await,
+        };
+        let target_length = match decoded {
+            true => downloaded_bytes,
+            false => self.target_length(),
+        };
+        if downloaded_bytes != target_length || bytes_on_disk != target_length {
+            log::error!(
+                "Download {} ended with {} bytes written ({} on disk), expected: {}",
+                self.id,
+                downloaded_bytes,
+                bytes_on_disk,
+                target_length
+            );
+            return Err(Error::IncompleteTransfer {
+                expected: target_length,
+                written: bytes_on_disk,
+            });
+        }
+        Ok(())
+    }
+
+    /// Downloads only the given ranges with a single multi-range request, writing every byte at
+    /// its offset in the target file. Depending on the server the response can be a
+    /// `multipart/byteranges` body, a single coalesced range or the full resource (200), all three
+    /// are handled. Returns the number of bytes written, fails with `Cancelled` once `cancel` is
+    /// cancelled.
+    pub async fn fetch_ranges(
+        &self,
+        ranges: &[ByteRange],
+        update_ch: Sender<DownloadUpdate>,
+        cancel: &CancellationToken,
+    ) -> Result<u64> {
+        let range_header = ranges
+            .iter()
+            .map(|range| range.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let resp = self
+            .send_request(Some(&format!("bytes={}", range_header)), None)
+            .await?;
+        let status = resp.status();
+        let content_type = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|val| val.to_str().ok())
+            .map(str::to_owned);
+        let content_range = resp
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|val| val.to_str().ok())
+            .and_then(parse_content_range);
+        let file_handler = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.download_path())
+            .await?;
+        self.config.permissions.apply(&self.download_path()).await?;
+
+        enum Body {
+            Multipart(ByteRangesParser),
+            Single(u64),
+            Full(u64),
+        }
+        let mut body = match (
+            status,
+            content_type
+                .as_deref()
+                .and_then(ByteRangesParser::from_content_type),
+            content_range,
+        ) {
+            (StatusCode::PARTIAL_CONTENT, Some(parser), _) => Body::Multipart(parser),
+            (StatusCode::PARTIAL_CONTENT, None, Some((range, _))) => Body::Single(range.start),
+            (StatusCode::OK, _, _) => {
+                log::warn!(
+                    "Server ignored the multi-range request for {}, extracting ranges from the full body",
+                    self.url
+                );
+                Body::Full(0)
+            }
+            _ => {
+                let body = resp.text().await.unwrap_or_default();
+                return Err(Error::DownloadNotOk(status, body));
+            }
+        };
+
+        let mut writer = RangeWriter::new(self.id, file_handler, update_ch);
+        let mut stream = resp.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let item = chunk?;
+            let parts = match &mut body {
+                Body::Multipart(parser) => parser.feed(&item)?,
+                Body::Single(offset) => {
+                    let part = PartChunk {
+                        offset: *offset,
+                        data: item.to_vec(),
+                    };
+                    *offset += item.len() as u64;
+                    vec![part]
+                }
+                Body::Full(offset) => {
+                    let parts = clip_to_ranges(*offset, &item, ranges);
+                    *offset += item.len() as u64;
+                    parts
+                }
+            };
+            let written: usize = parts.iter().map(|part| part.data.len()).sum();
+            for part in parts {
+                writer.write(part).await?;
+            }
+            tokio::select! {
+                _ = self.throttle(written) => {}
+                _ = cancel.cancelled() => return Err(Error::Cancelled(writer.written)),
+            }
+        }
+        if let Body::Multipart(parser) = body {
+            if !parser.is_finished() {
+                return Err(Error::StreamEndedBeforeCompletion(writer.written));
+            }
+        }
+        Ok(writer.written)
+    }
+
+    /// Connection level metrics of the download, they can be read while it runs.
+    pub fn diagnostics(&self) -> DownloadDiagnostics {
+        DownloadDiagnostics {
+            idle_timeout_ms: self
+                .config
+                .idle_timeout
+                .map(|timeout| timeout.as_millis() as u64),
+            ..self.stats.diagnostics()
+        }
     }
 
     pub fn get_metadata(&self) -> DownloadMetadata {
@@ -211,12 +1449,106 @@ impl HttpDownload {
             id: self.id,
             url: self.url.to_string(),
             file_path: self.file_path(),
-            download_size: self.content_length,
+            download_size: self.probed.then_some(self.content_length),
+            total_retries: self.stats.retries(),
+            resume_count: self.stats.resumes(),
+            active_duration_ms: self.stats.active_ms(),
+            preserve_auth_on_redirect: self.config.preserve_auth_on_redirect,
+            retry_policy: self.config.retry_policy.get(),
+            mirrors: self.config.mirrors.clone(),
+            ignore_global_limit: self.config.ignore_global_limit.get(),
+            rate_limit: self.config.rate_limit.rate(),
+            segments: self.effective_segments(),
+            duplicate_of: None,
         }
     }
 
+    /// Bytes downloaded so far, for segmented downloads the file is preallocated so the
+    /// progress is read from the sidecar metadata instead.
     pub async fn get_bytes_on_disk(&self) -> u64 {
-        file_size(&self.file_path()).await
+        if self.is_segmented() {
+            if let Some(meta) =
+                segmented::PartMeta::load(&self.sidecar_path(), self.target_length()).await
+            {
+                return meta.written();
+            }
+        }
+        let download_path = self.download_path();
+        if self.split_size().is_some() {
+            return match split_size_on_disk(&download_path).await {
+                0 if download_path != self.file_path() => {
+                    split_size_on_disk(&self.file_path()).await
+                }
+                size => size,
+            };
+        }
+        match file_size(&download_path).await {
+            // Nothing in the temp directory, the download might have been moved already
+            0 if download_path != self.file_path() => file_size(&self.file_path()).await,
+            size => size,
+        }
+    }
+
+    /// The file the download is written to and how many bytes from its start are on disk without
+    /// a gap, the part of a running download that can already be read. For segmented downloads
+    /// that's what the segment progress last persisted. None for split and sparse downloads,
+    /// they don't write a single contiguous file.
+    pub async fn readable_prefix(&self) -> Option<(PathBuf, u64)> {
+        if self.split_size().is_some() || self.sparse_ranges().is_some() {
+            return None;
+        }
+        let download_path = self.download_path();
+        if self.is_segmented() {
+            if let Some(meta) =
+                segmented::PartMeta::load(&self.sidecar_path(), self.target_length()).await
+            {
+                return Some((download_path, meta.contiguous()));
+            }
+        }
+        match file_size(&download_path).await {
+            0 if download_path != self.file_path() => {
+                let file_path = self.file_path();
+                let size = file_size(&file_path).await;
+                Some((file_path, size))
+            }
+            size => Some((download_path, size)),
+        }
+    }
+}
+
+/// Writes chunks at arbitrary offsets of a file and reports throttled progress updates.
+struct RangeWriter {
+    id: uuid::Uuid,
+    file_handler: File,
+    update_ch: Sender<DownloadUpdate>,
+    written: u64,
+    speed: SpeedMeter,
+}
+
+impl RangeWriter {
+    fn new(id: uuid::Uuid, file_handler: File, update_ch: Sender<DownloadUpdate>) -> Self {
+        Self {
+            id,
+            file_handler,
+            update_ch,
+            written: 0,
+            speed: SpeedMeter::new(),
+        }
+    }
+
+    async fn write(&mut self, chunk: PartChunk) -> Result<()> {
+        self.file_handler
+            .seek(SeekFrom::Start(chunk.offset))
+            .await?;
+        self.file_handler.write_all(&chunk.data).await?;
+        self.written += chunk.data.len() as u64;
+        self.speed.record(chunk.data.len() as u64);
+        if let Some(state) = self.speed.tick(self.written) {
+            let _ = self
+                .update_ch
+                .try_send(DownloadUpdate { id: self.id, state });
+        }
+        Ok(())
     }
 }
 
@@ -226,20 +1558,22 @@ mod test {
     use test_log::test;
 
     use pretty_assertions::assert_eq;
+    use reqwest::header::{HeaderValue, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING};
+    use std::time::Duration;
+    use tokio::sync::mpsc;
 
+    use crate::util::mock::{self, MockConfig, MockServer};
     use crate::util::{parse_filename, setup_test_download};
 
     use super::*;
 
     type Test<T> = std::result::Result<T, Box<dyn Error>>;
-    const TEST_DOWNLOAD_URL: &str =
-        "https://dl.google.com/linux/direct/google-chrome-stable_current_amd64.deb";
 
     #[test(tokio::test)]
     async fn server_data_is_requested_on_create_test() -> Test<()> {
         // given
-        let url_str = TEST_DOWNLOAD_URL;
-        let url = Url::parse(url_str)?;
+        let server = MockServer::start(MockConfig::default()).await;
+        let url = server.url("file.bin");
         let filename = parse_filename(&url).unwrap().to_string();
         let directory = PathBuf::new();
         // when creating a download, server data is present in the download struct
@@ -252,10 +1586,360 @@ mod test {
         Ok(())
     }
 
+    #[test(tokio::test)]
+    async fn create_verified_fails_on_mismatch_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig::default()).await;
+        let tmp_dir = tempfile::TempDir::new()?;
+        let create = |expected| {
+            HttpDownload::create_verified(
+                server.url("file.bin"),
+                tmp_dir.path().to_owned(),
+                "file.bin".to_owned(),
+                Client::new(),
+                expected,
+                None,
+            )
+        };
+        let wrong: Checksum = format!("sha256:{}", "0".repeat(64)).parse()?;
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        // when
+        let download = create(Some(wrong)).await?;
+        let result = download.start(update_sender.clone()).await;
+        // then the file is there but not accepted
+        assert!(
+            matches!(result, Err(super::Error::ChecksumMismatch { expected, .. }) if expected == wrong)
+        );
+        let actual = download
+            .compute_checksum(checksum::ChecksumAlgorithm::Sha256)
+            .await?;
+        assert_ne!(actual, wrong);
+        // when the checksum matches
+        let download = create(Some(actual)).await?;
+        // then
+        download.start(update_sender).await?;
+        assert_eq!(
+            download
+                .compute_checksum(checksum::ChecksumAlgorithm::Md5)
+                .await?
+                .algorithm(),
+            checksum::ChecksumAlgorithm::Md5
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn create_segmented_falls_back_without_ranges_test() -> Test<()> {
+        for (accept_ranges, expected) in [(true, 4), (false, 1)] {
+            // given
+            let server = MockServer::start(MockConfig {
+                accept_ranges,
+                ..Default::default()
+            })
+            .await;
+            let tmp_dir = tempfile::TempDir::new()?;
+            // when
+            let download = HttpDownload::create_segmented(
+                server.url("file.bin"),
+                tmp_dir.path().to_owned(),
+                "file.bin".to_owned(),
+                Client::new(),
+                4,
+                None,
+            )
+            .await?;
+            let (update_sender, mut update_receiver) = mpsc::channel::<DownloadUpdate>(1000);
+            download.start(update_sender).await?;
+            // then progress is reported as one total
+            assert_eq!(download.effective_segments(), expected);
+            let mut last = 0;
+            while let Ok(update) = update_receiver.try_recv() {
+                if let State::Running {
+                    bytes_downloaded, ..
+                } = update.state
+                {
+                    assert!(bytes_downloaded >= last);
+                    last = bytes_downloaded;
+                }
+            }
+            assert_eq!(
+                tokio::fs::read(download.file_path()).await?,
+                *server.payload()
+            );
+        }
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn rejected_head_falls_back_to_ranged_get_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig {
+            reject_head: true,
+            ..Default::default()
+        })
+        .await;
+        // when
+        let metadata =
+            HttpDownload::probe(&server.url("file.bin"), &Client::new(), &Default::default())
+                .await?;
+        // then
+        assert_eq!(metadata.content_length, server.payload().len() as u64);
+        assert!(metadata.supports_byte_ranges);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].method, "HEAD");
+        assert_eq!(requests[1].headers.get(RANGE).unwrap(), "bytes=0-0");
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn finalize_moves_part_file_test() -> Test<()> {
+        // given
+        let temp_dir = tempfile::TempDir::new()?;
+        let final_dir = tempfile::TempDir::new()?;
+        let url = Url::parse("http://localhost/file.bin")?;
+        let download = HttpDownload {
+            id: uuid::Uuid::new_v4(),
+            final_url: url.clone(),
+            url,
+            directory: final_dir.path().to_owned(),
+            filename: "file.bin".to_string(),
+            config: HttpDownloadConfig {
+                temp_dir: Some(temp_dir.path().to_owned()),
+                ..Default::default()
+            },
+            content_length: 4,
+            supports_byte_ranges: true,
+            client: Client::new(),
+            probed: true,
+            validators: Validators::default(),
+            stats: Default::default(),
+        };
+        assert_eq!(
+            download.download_path(),
+            temp_dir.path().join("file.bin.part")
+        );
+        tokio::fs::write(download.download_path(), b"data").await?;
+        let (update_sender, mut update_recv) = mpsc::channel::<DownloadUpdate>(1000);
+        // when
+        download.finalize(&update_sender).await?;
+        // then
+        assert!(!download.download_path().exists());
+        assert_eq!(tokio::fs::read(download.file_path()).await?, b"data");
+        assert_eq!(download.get_bytes_on_disk().await, 4);
+        let mut last_state = None;
+        while let Ok(update) = update_recv.try_recv() {
+            last_state = Some(update.state);
+        }
+        assert_eq!(
+            last_state,
+            Some(State::Moving {
+                bytes_moved: 4,
+                total: 4
+            })
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn early_closed_connection_is_not_complete_test() -> Test<()> {
+        // given a server that announces 100 bytes when probed but closes the connection after
+        // sending 50 bytes of an unsized body on download
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}/file.bin", listener.local_addr()?))?;
+        tokio::spawn(async move {
+            use tokio::io::AsyncReadExt;
+            let mut requests = 0;
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let _ = socket.read(&mut buf).await;
+                let head = match requests {
+                    0 => "HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n",
+                    _ => "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n",
+                };
+                requests += 1;
+                let _ = socket.write_all(head.as_bytes()).await;
+                let _ = socket.write_all(&[1u8; 50]).await;
+            }
+        });
+        let tmp_dir = tempfile::TempDir::new()?;
+        let download = HttpDownload::create(
+            url,
+            tmp_dir.path().to_owned(),
+            "file.bin".to_string(),
+            Client::new(),
+            None,
+        )
+        .await?;
+        // when
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        let result = download.start(update_sender).await;
+        // then
+        assert!(
+            matches!(
+                result,
+                Err(super::Error::IncompleteTransfer {
+                    expected: 100,
+                    written: 50
+                })
+            ),
+            "{:?}",
+            result
+        );
+        assert_eq!(
+            download.get_bytes_on_disk().await,
+            50,
+            "Partial file is kept"
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn expired_url_is_refreshed_on_resume_test() -> Test<()> {
+        // given a server that accepts `sig=1` only for the probe and `sig=2` afterwards
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let base = format!("http://{}/file.bin", listener.local_addr()?);
+        tokio::spawn(async move {
+            use tokio::io::AsyncReadExt;
+            let mut probed = false;
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let n = socket.read(&mut buf).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+                let response = if request.starts_with("head /file.bin?sig=1 ") && !probed {
+                    probed = true;
+                    "HTTP/1.1 200 OK\r\nContent-Length: 10\r\nAccept-Ranges: bytes\r\n\r\n0123456789"
+                } else if request.starts_with("get /file.bin?sig=2 ")
+                    && request.contains("range: bytes=4-")
+                {
+                    "HTTP/1.1 206 Partial Content\r\nContent-Length: 6\r\nContent-Range: bytes 4-9/10\r\n\r\n456789"
+                } else {
+                    "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n"
+                };
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        let tmp_dir = tempfile::TempDir::new()?;
+        let refreshed = Url::parse(&format!("{}?sig=2", base))?;
+        let (error_sender, mut errors) = mpsc::unbounded_channel();
+        let config = HttpDownloadConfig {
+            error_events: Some(error_sender),
+            url_refresher: Some(RefreshHook::new({
+                let refreshed = refreshed.clone();
+                move |_expired: Url| {
+                    let refreshed = refreshed.clone();
+                    async move { Some(refreshed) }
+                }
+            })),
+            ..Default::default()
+        };
+        let download = HttpDownload::create(
+            Url::parse(&format!("{}?sig=1", base))?,
+            tmp_dir.path().to_owned(),
+            "file.bin".to_string(),
+            Client::new(),
+            Some(config),
+        )
+        .await?;
+        tokio::fs::write(download.file_path(), b"0123").await?;
+        // when
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        download.resume(update_sender).await?;
+        // then
+        assert_eq!(download.current_url(), refreshed);
+        assert_eq!(tokio::fs::read(download.file_path()).await?, b"0123456789");
+        let event = errors.try_recv()?;
+        assert_eq!((event.status, event.transient), (Some(403), true));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn dropped_connection_is_resumed_test() -> Test<()> {
+        // given a server that drops the connection halfway through the download
+        let server = MockServer::start(MockConfig::default()).await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        server.update(|config| config.drop_at = Some(512 * 1024));
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        assert!(download.start(update_sender.clone()).await.is_err());
+        let bytes_on_disk = download.get_bytes_on_disk().await;
+        assert!(bytes_on_disk > 0 && bytes_on_disk < download.content_length);
+        // when
+        download.resume(update_sender).await?;
+        // then
+        let last_request = server.requests().pop().unwrap();
+        assert_eq!(
+            last_request.headers.get(reqwest::header::RANGE).unwrap(),
+            format!("bytes={}-", bytes_on_disk).as_str()
+        );
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload()
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn headers_are_sent_with_every_request_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig::default()).await;
+        let tmp_dir = tempfile::TempDir::new()?;
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer token"));
+        headers.insert(
+            reqwest::header::USER_AGENT,
+            HeaderValue::from_static("agent"),
+        );
+        headers.insert(
+            reqwest::header::REFERER,
+            HeaderValue::from_static("http://a/"),
+        );
+        let build = |filename: &str, segments| {
+            HttpDownload::builder()
+                .url(server.url("file.bin"))
+                .directory(tmp_dir.path())
+                .filename(filename)
+                .client(Client::new())
+                .headers(headers.clone())
+                .segments(segments)
+                .build()
+        };
+        let resumed = build("resumed.bin", 1).await?;
+        let segmented = build("segmented.bin", 3).await?;
+        server.update(|config| config.drop_at = Some(512 * 1024));
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        // when
+        assert!(resumed.start(update_sender.clone()).await.is_err());
+        server.update(|config| config.drop_at = None);
+        resumed.resume(update_sender.clone()).await?;
+        segmented.start(update_sender).await?;
+        // then the probes, the first request, the resumed one and every segment carry them
+        let requests = server.requests();
+        let ranged = requests
+            .iter()
+            .filter(|req| req.headers.contains_key(reqwest::header::RANGE))
+            .count();
+        assert!(ranged >= 4, "{} ranged requests", ranged);
+        for request in &requests {
+            for (name, value) in &headers {
+                assert_eq!(request.headers.get(name), Some(value), "{:?}", request);
+            }
+        }
+        assert_eq!(
+            tokio::fs::read(resumed.file_path()).await?,
+            *server.payload()
+        );
+        assert_eq!(
+            tokio::fs::read(segmented.file_path()).await?,
+            *server.payload()
+        );
+        Ok(())
+    }
+
     #[test(tokio::test)]
     async fn default_download_test() -> Test<()> {
         // given
-        let (download, _tmp_dir) = setup_test_download(TEST_DOWNLOAD_URL).await?;
+        let server = MockServer::start(MockConfig::default()).await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
         // when
         let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
         let downloaded_bytes = download.start(update_sender).await?;
@@ -276,10 +1960,13 @@ mod test {
     #[test(tokio::test)]
     async fn download_with_custom_chunksize_test() -> Test<()> {
         // given
-        let mut config = HttpDownloadConfig::default();
-        config.chunk_size = 1024 * 1029;
+        let config = HttpDownloadConfig {
+            chunk_size: 1024 * 1029,
+            ..Default::default()
+        };
         // and
-        let (mut download, _tmp_dir) = setup_test_download(TEST_DOWNLOAD_URL).await?;
+        let server = MockServer::start(MockConfig::default()).await;
+        let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
         download.config = config;
         // when
         let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
@@ -297,4 +1984,1062 @@ mod test {
         );
         Ok(())
     }
+
+    #[test(tokio::test)]
+    async fn capped_download_only_requests_prefix_test() -> Test<()> {
+        for segments in [1, 4] {
+            // given
+            let server = MockServer::start(MockConfig::default()).await;
+            let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+            download.config.segments = segments;
+            download.config.max_bytes = Some(100 * 1024);
+            // when
+            let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+            let downloaded_bytes = download.start(update_sender).await?;
+            // then
+            assert!(download.is_capped());
+            assert_eq!(downloaded_bytes, 100 * 1024);
+            assert_eq!(
+                tokio::fs::read(download.file_path()).await?,
+                server.payload()[..100 * 1024]
+            );
+            let last_byte_requested = server
+                .requests()
+                .iter()
+                .skip(1)
+                .filter_map(|req| {
+                    req.headers
+                        .get(reqwest::header::RANGE)?
+                        .to_str()
+                        .ok()?
+                        .rsplit('-')
+                        .next()?
+                        .parse::<u64>()
+                        .ok()
+                })
+                .max();
+            assert_eq!(last_byte_requested, Some(100 * 1024 - 1));
+        }
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn capped_download_without_range_support_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig {
+            accept_ranges: false,
+            ..Default::default()
+        })
+        .await;
+        let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        download.config.max_bytes = Some(1000);
+        // when
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        let downloaded_bytes = download.start(update_sender).await?;
+        // then
+        assert_eq!(downloaded_bytes, 1000);
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            server.payload()[..1000]
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn capped_download_is_throttled_by_what_it_keeps_test() -> Test<()> {
+        // given a server sending 16KiB chunks of which only 1000 bytes are kept
+        let server = MockServer::start(MockConfig {
+            accept_ranges: false,
+            ..Default::default()
+        })
+        .await;
+        let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        download.config.max_bytes = Some(1000);
+        download.config.rate_limit.set_rate(Some(1000));
+        // when
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        let started = std::time::Instant::now();
+        download.start(update_sender).await?;
+        // then the limiter isn't charged the discarded rest of the chunk, that would take 15s
+        assert!(started.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn split_download_resumes_in_the_right_part_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig::default()).await;
+        let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        download.config.split_size = Some(100 * 1024);
+        server.update(|config| config.drop_at = Some(250 * 1024 + 7));
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        assert!(download.start(update_sender.clone()).await.is_err());
+        // when
+        download.resume(update_sender).await?;
+        // then
+        let mut content = Vec::new();
+        let manifest: split::SplitManifest =
+            serde_json::from_slice(&tokio::fs::read(download.manifest_path()).await?)?;
+        assert_eq!(manifest.parts.len(), 11);
+        for part in manifest.parts.iter() {
+            let part_content = tokio::fs::read(download.directory.join(&part.name)).await?;
+            assert_eq!(part_content.len() as u64, part.size);
+            content.extend(part_content);
+        }
+        assert_eq!(content, *server.payload());
+        assert_eq!(download.get_bytes_on_disk().await, download.content_length);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn cancelled_download_is_flushed_and_resumable_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig {
+            chunk_delay: Some(Duration::from_millis(10)),
+            ..Default::default()
+        })
+        .await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        let cancel = CancellationToken::new();
+        // when
+        let canceller = tokio::spawn({
+            let cancel = cancel.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                cancel.cancel();
+            }
+        });
+        let result = download.run(update_sender.clone(), false, cancel).await;
+        canceller.await?;
+        // then
+        let Err(super::Error::Cancelled(downloaded_bytes)) = result else {
+            panic!("Expected the download to be cancelled, got {:?}", result);
+        };
+        assert_ne!(downloaded_bytes, 0);
+        assert_eq!(download.get_bytes_on_disk().await, downloaded_bytes);
+        download
+            .run(update_sender, true, CancellationToken::new())
+            .await?;
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload()
+        );
+        Ok(())
+    }
+
+    async fn gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
+        use async_compression::tokio::write::GzipEncoder;
+        use tokio::io::AsyncWriteExt;
+        let mut encoder = GzipEncoder::new(Vec::new());
+        encoder.write_all(data).await?;
+        encoder.shutdown().await?;
+        Ok(encoder.into_inner())
+    }
+
+    #[test(tokio::test)]
+    async fn uncompressed_download_asks_for_identity_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig::default()).await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        // when
+        let downloaded_bytes = download.start(update_sender).await?;
+        // then
+        for request in server.requests() {
+            assert_eq!(request.headers[ACCEPT_ENCODING], "identity");
+        }
+        assert_eq!(downloaded_bytes, download.get_bytes_on_disk().await);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn compressed_download_is_decoded_test() -> Test<()> {
+        // given
+        let content = mock::payload(256 * 1024);
+        let mut config = MockConfig::new(gzip(&content).await?);
+        config
+            .headers
+            .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        let server = MockServer::start(config).await;
+        let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        download.config.compression = true;
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        // when
+        let downloaded_bytes = download.start(update_sender).await?;
+        // then
+        assert_eq!(
+            server.requests().last().unwrap().headers[ACCEPT_ENCODING],
+            "gzip, deflate"
+        );
+        assert_eq!(downloaded_bytes, content.len() as u64);
+        assert_eq!(tokio::fs::read(download.file_path()).await?, content);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test(tokio::test)]
+    async fn file_mode_survives_finalize_test() -> Test<()> {
+        use std::os::unix::fs::PermissionsExt;
+        // given
+        let server = MockServer::start(MockConfig::default()).await;
+        let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let temp_dir = tempfile::TempDir::new()?;
+        download.config.temp_dir = Some(temp_dir.path().to_owned());
+        download.config.permissions.mode = Some(0o640);
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        // when
+        download.start(update_sender).await?;
+        // then
+        let mode = tokio::fs::metadata(download.file_path())
+            .await?
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o640);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn moved_download_is_relocated_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig::default()).await;
+        let (mut download, tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        server.update(|config| config.drop_at = Some(300 * 1024));
+        assert!(download.start(update_sender.clone()).await.is_err());
+        let new_dir = tempfile::TempDir::new()?;
+        tokio::fs::rename(download.file_path(), new_dir.path().join("file.bin")).await?;
+        drop(tmp_dir);
+        // when the directory is gone, then resuming fails with a clear error
+        let result = download.resume(update_sender.clone()).await;
+        assert!(matches!(result, Err(super::Error::DirectoryMissing(_))));
+        assert!(!download.relocate(Path::new("/nonexistent")).await);
+        // when
+        assert!(download.relocate(new_dir.path()).await);
+        // then
+        assert_eq!(download.directory, new_dir.path());
+        download.resume(update_sender).await?;
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload()
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn sparse_download_fetches_only_ranges_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig::default()).await;
+        let tmp_dir = tempfile::TempDir::new()?;
+        let download = HttpDownload::builder()
+            .url(server.url("file.bin"))
+            .directory(tmp_dir.path())
+            .ranges(vec![ByteRange::new(1000, 1999), ByteRange::new(0, 99)])
+            .build()
+            .await?;
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        // when
+        let written = download.start(update_sender).await?;
+        // then
+        assert_eq!(written, 1100);
+        assert_eq!(download.sparse_length(), Some(1100));
+        let payload = server.payload();
+        let content = tokio::fs::read(download.file_path()).await?;
+        assert_eq!(content.len(), payload.len());
+        assert_eq!(content[..100], payload[..100]);
+        assert_eq!(content[1000..2000], payload[1000..2000]);
+        assert!(content[100..1000].iter().all(|byte| *byte == 0));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn sparse_download_requires_range_support_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig {
+            accept_ranges: false,
+            ..Default::default()
+        })
+        .await;
+        // when
+        let result = HttpDownload::builder()
+            .url(server.url("file.bin"))
+            .ranges(vec![ByteRange::new(0, 99)])
+            .build()
+            .await;
+        // then
+        assert!(matches!(result, Err(super::Error::InvalidConfig(_))));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn segments_wait_for_the_global_limit_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig {
+            chunk_delay: Some(Duration::from_millis(20)),
+            ..Default::default()
+        })
+        .await;
+        let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let limit = std::sync::Arc::new(tokio::sync::Semaphore::new(2));
+        download.config.segments = 4;
+        download.config.segment_limit = Some(limit.clone());
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        // when
+        download.start(update_sender).await?;
+        // then all segments were fetched but never more than two at a time
+        let gets = server
+            .requests()
+            .iter()
+            .filter(|req| req.method == hyper::Method::GET)
+            .count();
+        assert_eq!(gets, 4);
+        assert!(server.max_concurrent_bodies() <= 2);
+        assert_eq!(limit.available_permits(), 2);
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload()
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn pause_at_leaves_download_resumable_test() -> Test<()> {
+        for segments in [1, 4] {
+            // given
+            let server = MockServer::start(MockConfig::default()).await;
+            let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+            download.config.segments = segments;
+            download.config.pause_at = Some(100_000);
+            let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+            // when
+            let result = download.start(update_sender.clone()).await;
+            // then
+            let Err(super::Error::Cancelled(written)) = result else {
+                panic!("Expected the download to pause, got {:?}", result);
+            };
+            match segments {
+                1 => assert_eq!(written, 100_000),
+                _ => assert!(written >= 100_000 && written < download.content_length),
+            }
+            // resuming continues past the threshold
+            download.resume(update_sender).await?;
+            assert_eq!(
+                tokio::fs::read(download.file_path()).await?,
+                *server.payload()
+            );
+        }
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn batched_segment_writes_never_persist_buffered_bytes_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig::default()).await;
+        let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        download.config.segments = 4;
+        download.config.write_batch_size = Some(64 * 1024);
+        download.config.pause_at = Some(100_000);
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        // when
+        let result = download.start(update_sender.clone()).await;
+        // then every byte the sidecar counts is in the file
+        assert!(matches!(result, Err(super::Error::Cancelled(_))));
+        let meta = segmented::PartMeta::load(&download.sidecar_path(), download.content_length)
+            .await
+            .unwrap();
+        assert!(meta.written() >= 100_000);
+        let file = tokio::fs::read(download.download_path()).await?;
+        for segment in &meta.segments {
+            let written =
+                segment.range.start as usize..(segment.range.start + segment.written) as usize;
+            assert_eq!(file[written.clone()], server.payload()[written]);
+        }
+        download.resume(update_sender).await?;
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload()
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn raising_segments_of_running_download_test() -> Test<()> {
+        // given a slow segmented download
+        let server = MockServer::start(MockConfig {
+            chunk_delay: Some(Duration::from_millis(10)),
+            ..Default::default()
+        })
+        .await;
+        let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        download.config.segments = 2;
+        let target = download.config.segment_target.clone();
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        // when more segments are asked for while it runs
+        let raise = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            target.set(4);
+        };
+        let (result, _) = tokio::join!(download.start(update_sender), raise);
+        // then the unclaimed rest was split among new connections
+        result?;
+        let ranges = server
+            .requests()
+            .iter()
+            .filter(|request| request.headers.contains_key(reqwest::header::RANGE))
+            .count();
+        assert_eq!(ranges, 4);
+        assert_eq!(download.effective_segments(), 4);
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload()
+        );
+        assert!(!download.sidecar_path().exists());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn lowering_segments_of_running_download_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig {
+            chunk_delay: Some(Duration::from_millis(10)),
+            ..Default::default()
+        })
+        .await;
+        let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        download.config.segments = 4;
+        let target = download.config.segment_target.clone();
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        // when
+        let lower = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            target.set(1);
+        };
+        let (result, _) = tokio::join!(download.start(update_sender), lower);
+        // then the running segments finished their ranges without new connections
+        result?;
+        let ranges = server
+            .requests()
+            .iter()
+            .filter(|request| request.headers.contains_key(reqwest::header::RANGE))
+            .count();
+        assert_eq!(ranges, 4);
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload()
+        );
+        assert!(download.set_segments(0).is_err());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn empty_download_completes_test() -> Test<()> {
+        for segments in [1, 4] {
+            // given a server answering with an empty 200
+            let server = MockServer::start(MockConfig::new(Vec::new())).await;
+            let (mut download, _tmp_dir) = setup_test_download(server.url("empty.bin")).await?;
+            download.config.segments = segments;
+            download.config.checksum = Some(
+                "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+                    .parse()?,
+            );
+            let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+            // when
+            let written =
+                tokio::time::timeout(Duration::from_secs(5), download.start(update_sender))
+                    .await??;
+            // then
+            assert_eq!(written, 0);
+            assert_eq!(download.content_length, 0);
+            assert_eq!(
+                tokio::fs::read(download.file_path()).await?,
+                Vec::<u8>::new()
+            );
+        }
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn empty_download_fails_if_configured_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig::new(Vec::new())).await;
+        let tmp_dir = tempfile::TempDir::new()?;
+        // when
+        let result = HttpDownload::builder()
+            .url(server.url("empty.bin"))
+            .directory(tmp_dir.path())
+            .empty_response(EmptyResponse::Fail)
+            .build()
+            .await;
+        // then nothing is created
+        let Err(e) = result else {
+            panic!("Expected the empty download to fail");
+        };
+        assert_eq!(e.code(), "empty_response");
+        assert!(!tmp_dir.path().join("empty.bin").exists());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn synced_writes_complete_split_download_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig::default()).await;
+        let (mut download, tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        download.config.sync_writes = true;
+        download.config.split_size = Some(300 * 1024);
+        download.config.temp_dir = Some(tmp_dir.path().join("tmp"));
+        tokio::fs::create_dir(tmp_dir.path().join("tmp")).await?;
+        download.config.pause_at = Some(100_000);
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        // when
+        let result = download.start(update_sender.clone()).await;
+        // then
+        assert!(matches!(result, Err(super::Error::Cancelled(100_000))));
+        download.resume(update_sender).await?;
+        assert_eq!(
+            download.get_bytes_on_disk().await,
+            server.payload().len() as u64
+        );
+        assert!(tokio::fs::try_exists(download.manifest_path()).await?);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn checksum_mismatch_is_retried_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig::default()).await;
+        let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let wrong: checksum::Checksum = format!("md5:{}", "0".repeat(32)).parse()?;
+        download.config.checksum = Some(wrong);
+        download.config.checksum_retries = 2;
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        // when
+        let result = download.start(update_sender.clone()).await;
+        // then every retry downloads the file again, the last one is kept
+        assert!(
+            matches!(result, Err(super::Error::ChecksumMismatch { expected, .. }) if expected == wrong)
+        );
+        let gets = server
+            .requests()
+            .iter()
+            .filter(|req| req.method == hyper::Method::GET)
+            .count();
+        assert_eq!(gets, 3);
+        assert_eq!(download.get_metadata().total_retries, 2);
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload()
+        );
+        // when the checksum matches
+        download.config.checksum = Some(
+            checksum::compute(&[download.file_path()], checksum::ChecksumAlgorithm::Sha256).await?,
+        );
+        // then
+        download.start(update_sender).await?;
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn transient_failures_are_retried_per_policy_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig::default()).await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let fail_twice = || {
+            server.update(|config| {
+                config.fail_next.push_back(StatusCode::SERVICE_UNAVAILABLE);
+                config.fail_next.push_back(StatusCode::SERVICE_UNAVAILABLE);
+            })
+        };
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        // when the default policy doesn't retry
+        fail_twice();
+        let result = download.start(update_sender.clone()).await;
+        // then
+        assert!(matches!(result, Err(super::Error::DownloadNotOk(..))));
+        // when the policy is changed on the existing download
+        server.update(|config| config.fail_next.clear());
+        fail_twice();
+        download.config.retry_policy.set(retry::RetryPolicy {
+            max_attempts: 3,
+            base_delay_ms: 10,
+            max_delay_ms: 20,
+            jitter_percent: 0,
+            ..Default::default()
+        });
+        download.start(update_sender).await?;
+        // then
+        assert_eq!(download.get_metadata().total_retries, 2);
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload()
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn dropped_connection_is_retried_from_offset_test() -> Test<()> {
+        // given a server that drops the connection halfway through the download
+        let server = MockServer::start(MockConfig::default()).await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        server.update(|config| config.drop_at = Some(512 * 1024));
+        download.config.retry_policy.set(retry::RetryPolicy {
+            max_attempts: 5,
+            base_delay_ms: 10,
+            max_delay_ms: 20,
+            jitter_percent: 0,
+            ..Default::default()
+        });
+        let (update_sender, mut update_receiver) = mpsc::channel::<DownloadUpdate>(1000);
+        // when
+        download.start(update_sender).await?;
+        // then the retry is announced and continues where the transfer stopped
+        let mut retrying = None;
+        while let Ok(update) = update_receiver.try_recv() {
+            if let State::Retrying { .. } = update.state {
+                retrying.get_or_insert(update.state);
+            }
+        }
+        let Some(State::Retrying {
+            bytes_downloaded,
+            attempt,
+            max_attempts,
+            delay_ms,
+            retry_after_ms,
+        }) = retrying
+        else {
+            panic!("No retry was reported");
+        };
+        assert_eq!((attempt, max_attempts, delay_ms), (2, 5, 10));
+        assert_eq!(retry_after_ms, None);
+        assert!(bytes_downloaded > 0);
+        let ranged = server
+            .requests()
+            .into_iter()
+            .filter_map(|req| req.headers.get(RANGE).cloned())
+            .next()
+            .unwrap();
+        assert_eq!(ranged, format!("bytes={}-", bytes_downloaded).as_str());
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload()
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn retry_after_overrides_backoff_test() -> Test<()> {
+        // given a rate limited server asking to come back in a second
+        let server = MockServer::start(MockConfig::default()).await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        server.update(|config| {
+            config.fail_next.push_back(StatusCode::TOO_MANY_REQUESTS);
+            config.retry_after = Some("1".to_string());
+        });
+        download.config.retry_policy.set(retry::RetryPolicy {
+            max_attempts: 2,
+            base_delay_ms: 10,
+            max_delay_ms: 20,
+            jitter_percent: 0,
+            ..Default::default()
+        });
+        let (update_sender, mut update_receiver) = mpsc::channel::<DownloadUpdate>(1000);
+        let started = std::time::Instant::now();
+        // when
+        download.start(update_sender).await?;
+        // then
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        let retrying = std::iter::from_fn(|| update_receiver.try_recv().ok())
+            .find(|update| matches!(update.state, State::Retrying { .. }))
+            .unwrap();
+        assert!(matches!(
+            retrying.state,
+            State::Retrying {
+                delay_ms: 1000,
+                retry_after_ms: Some(1000),
+                ..
+            }
+        ));
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload()
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn change_url_compares_downloaded_bytes_test() -> Test<()> {
+        // given a partially downloaded file
+        let server = MockServer::start(MockConfig::default()).await;
+        let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        tokio::fs::write(download.file_path(), &server.payload()[..1000]).await?;
+        // and a source of the same length serving other bytes
+        let mut other_payload = server.payload().to_vec();
+        other_payload.reverse();
+        let other = MockServer::start(MockConfig::new(other_payload)).await;
+        // when, then
+        let result = download.change_url(other.url("file.bin")).await;
+        assert!(
+            matches!(result, Err(super::Error::SourceMismatch(_))),
+            "{:?}",
+            result
+        );
+        let mirror = server.url("file.bin?mirror=1");
+        download.change_url(mirror.clone()).await?;
+        assert_eq!(download.url, mirror);
+        let ranges: Vec<_> = server
+            .requests()
+            .into_iter()
+            .filter_map(|req| req.headers.get(RANGE).cloned())
+            .collect();
+        assert_eq!(ranges, vec!["bytes=0-999"]);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn retry_after_beyond_max_backs_off_test() -> Test<()> {
+        // given a server asking to come back in an hour
+        let server = MockServer::start(MockConfig::default()).await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        server.update(|config| {
+            config.fail_next.push_back(StatusCode::SERVICE_UNAVAILABLE);
+            config.retry_after = Some("3600".to_string());
+        });
+        download.config.retry_policy.set(retry::RetryPolicy {
+            max_attempts: 2,
+            base_delay_ms: 10,
+            max_delay_ms: 20,
+            jitter_percent: 0,
+            max_retry_after_ms: 1000,
+        });
+        let (update_sender, mut update_receiver) = mpsc::channel::<DownloadUpdate>(1000);
+        // when
+        download.start(update_sender).await?;
+        // then the backoff is used instead
+        let retrying = std::iter::from_fn(|| update_receiver.try_recv().ok())
+            .find(|update| matches!(update.state, State::Retrying { .. }))
+            .unwrap();
+        assert!(matches!(
+            retrying.state,
+            State::Retrying {
+                delay_ms: 10,
+                retry_after_ms: None,
+                ..
+            }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn client_errors_are_permanent_test() {
+        let failed = |status| super::Error::DownloadNotOk(status, String::new());
+        assert!(failed(StatusCode::SERVICE_UNAVAILABLE).is_transient());
+        assert!(failed(StatusCode::REQUEST_TIMEOUT).is_transient());
+        assert!(failed(StatusCode::TOO_MANY_REQUESTS).is_transient());
+        assert!(!failed(StatusCode::NOT_FOUND).is_transient());
+        assert!(!failed(StatusCode::FORBIDDEN).is_transient());
+    }
+
+    #[test(tokio::test)]
+    async fn failed_requests_are_repeated_test() -> Test<()> {
+        // given a server failing the probe twice
+        let server = MockServer::start(MockConfig::default()).await;
+        let fail_twice = || {
+            server.update(|config| {
+                config.fail_next.push_back(StatusCode::SERVICE_UNAVAILABLE);
+                config.fail_next.push_back(StatusCode::BAD_GATEWAY);
+            })
+        };
+        let tmp_dir = tempfile::TempDir::new()?;
+        let builder = || {
+            HttpDownload::builder()
+                .url(server.url("file.bin"))
+                .directory(tmp_dir.path().to_path_buf())
+        };
+        fail_twice();
+        // when the requests aren't repeated
+        let result = builder().build().await;
+        // then creating the download fails
+        assert!(matches!(result, Err(super::Error::DownloadNotOk(..))));
+        // when the probe is repeated
+        server.update(|config| config.fail_next.clear());
+        fail_twice();
+        let download = builder().request_retries(2).build().await?;
+        // then
+        assert_eq!(download.content_length, server.payload().len() as u64);
+        // when the transfer's request fails, without whole-download retries
+        fail_twice();
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        download.start(update_sender).await?;
+        // then the request was repeated and the run itself never failed
+        assert_eq!(download.get_metadata().total_retries, 0);
+        assert_eq!(download.get_metadata().resume_count, 0);
+        assert_eq!(
+            tokio::fs::read(download.file_path()).await?,
+            *server.payload()
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn checksum_is_computed_while_downloading_test() -> Test<()> {
+        // given a resumed download whose partial file was corrupted
+        let server = MockServer::start(MockConfig::default()).await;
+        let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let payload = server.payload();
+        tokio::fs::write(download.file_path(), &*payload).await?;
+        download.config.checksum = Some(
+            checksum::compute(&[download.file_path()], checksum::ChecksumAlgorithm::Sha256).await?,
+        );
+        let mut prefix = payload[..1000].to_vec();
+        prefix[10] ^= 0xff;
+        tokio::fs::write(download.file_path(), &prefix).await?;
+        let (update_sender, mut update_receiver) = mpsc::channel::<DownloadUpdate>(1000);
+        // when
+        let result = download.resume(update_sender.clone()).await;
+        // then the bytes on disk seeded the hash
+        assert!(matches!(result, Err(super::Error::ChecksumMismatch { .. })));
+        // when the download is done again
+        tokio::fs::write(download.file_path(), &payload[..1000]).await?;
+        download.resume(update_sender).await?;
+        // then the finished file wasn't read again to verify it
+        while let Ok(update) = update_receiver.try_recv() {
+            assert!(!matches!(update.state, State::Verifying { .. }));
+        }
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn diagnostics_count_requests_and_reconnects_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig::default()).await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let payload = server.payload();
+        tokio::fs::write(download.file_path(), &payload[..1000]).await?;
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        // when
+        download.resume(update_sender).await?;
+        // then
+        let diagnostics = download.diagnostics();
+        assert_eq!(diagnostics.requests, 1);
+        assert_eq!(diagnostics.reconnects, 1);
+        assert_eq!(diagnostics.open_connections, 0);
+        assert!(diagnostics.last_ttfb_ms.is_some());
+        assert_eq!(diagnostics.average_ttfb_ms, diagnostics.last_ttfb_ms);
+        assert_eq!(download.get_metadata().resume_count, 1);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn verifying_is_reported_and_resumable_test() -> Test<()> {
+        // given a download whose transfer finished but wasn't verified yet
+        let server = MockServer::start(MockConfig::default()).await;
+        let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let payload = server.payload();
+        tokio::fs::write(download.file_path(), &*payload).await?;
+        download.config.checksum = Some(
+            checksum::compute(&[download.file_path()], checksum::ChecksumAlgorithm::Md5).await?,
+        );
+        let (update_sender, mut update_receiver) = mpsc::channel::<DownloadUpdate>(1000);
+        // when
+        let written = download.resume(update_sender).await?;
+        // then only the verification runs and its progress ends with all bytes hashed
+        assert_eq!(written, payload.len() as u64);
+        assert!(!server
+            .requests()
+            .iter()
+            .any(|req| req.method == hyper::Method::GET));
+        let mut last = None;
+        while let Ok(update) = update_receiver.try_recv() {
+            last = Some(update.state);
+        }
+        assert_eq!(
+            last,
+            Some(State::Verifying {
+                bytes_hashed: payload.len() as u64,
+                total: payload.len() as u64
+            })
+        );
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn corrupted_piece_is_refetched_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig::default()).await;
+        let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let piece_size = 256 * 1024;
+        let payload = server.payload();
+        let mut hashes = Vec::new();
+        for chunk in payload.chunks(piece_size) {
+            let path = _tmp_dir.path().join("piece");
+            tokio::fs::write(&path, chunk).await?;
+            hashes.push(checksum::compute(&[path], checksum::ChecksumAlgorithm::Sha256).await?);
+        }
+        download.config.pieces = Some(pieces::PieceHashes {
+            piece_size: piece_size as u64,
+            hashes,
+        });
+        download.validate_pieces()?;
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        download.start(update_sender).await?;
+        // when a byte of the second piece gets corrupted
+        let mut content = tokio::fs::read(download.file_path()).await?;
+        content[piece_size + 10] ^= 0xff;
+        tokio::fs::write(download.file_path(), &content).await?;
+        let requests_before = server.requests().len();
+        assert!(download.verify_pieces(&CancellationToken::new()).await?);
+        // then only that piece is requested again
+        let requests = server.requests();
+        assert_eq!(requests.len(), requests_before + 1);
+        assert_eq!(
+            requests.last().unwrap().headers.get(RANGE).unwrap(),
+            &format!("bytes={}-{}", piece_size, 2 * piece_size - 1)
+        );
+        assert_eq!(tokio::fs::read(download.file_path()).await?, *payload);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn active_duration_sums_runs_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig {
+            chunk_delay: Some(Duration::from_millis(20)),
+            ..Default::default()
+        })
+        .await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        let cancel = CancellationToken::new();
+        // when the download is paused after a while
+        let run = download.run(update_sender.clone(), false, cancel.clone());
+        let stop = async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            cancel.cancel();
+        };
+        let (result, _) = tokio::join!(run, stop);
+        assert!(matches!(result, Err(super::Error::Cancelled(_))));
+        let first_run = download.get_metadata().active_duration_ms;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        download.resume(update_sender).await?;
+        // then the paused time isn't counted but both runs are
+        let metadata = download.get_metadata();
+        assert!(first_run >= 200, "first run took {}ms", first_run);
+        assert!(metadata.active_duration_ms > first_run);
+        assert_eq!(metadata.total_retries, 0);
+        assert_eq!(metadata.resume_count, 1);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn deadline_stops_download_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig {
+            chunk_delay: Some(Duration::from_millis(20)),
+            ..Default::default()
+        })
+        .await;
+        let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        download.config.deadline = Some(SystemTime::now() + Duration::from_millis(200));
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        // when
+        let result = download.start(update_sender.clone()).await;
+        // then the partial file is kept and resuming fails right away
+        assert!(matches!(result, Err(super::Error::DeadlineExceeded)));
+        let bytes_on_disk = download.get_bytes_on_disk().await;
+        assert!(bytes_on_disk > 0 && bytes_on_disk < server.payload().len() as u64);
+        let requests = server.requests().len();
+        let result = download.resume(update_sender).await;
+        assert!(matches!(result, Err(super::Error::DeadlineExceeded)));
+        assert_eq!(server.requests().len(), requests);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn partial_file_is_removed_on_failure_if_configured_test() -> Test<()> {
+        // given
+        let server = MockServer::start(MockConfig {
+            chunk_delay: Some(Duration::from_millis(20)),
+            ..Default::default()
+        })
+        .await;
+        let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        download.config.keep_partial_on_failure = false;
+        download.config.deadline = Some(SystemTime::now() + Duration::from_millis(200));
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        // when
+        let result = download.start(update_sender).await;
+        // then
+        assert!(matches!(result, Err(super::Error::DeadlineExceeded)));
+        assert!(!download.download_path().exists());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn login_redirect_is_rejected_if_configured_test() -> Test<()> {
+        // given a gated download redirecting to a login page
+        let mut login_config = MockConfig::new(b"<html>Please log in</html>".to_vec());
+        login_config.headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        let login = MockServer::start(login_config).await;
+        let gated = MockServer::start(MockConfig {
+            redirect: Some(login.url("login")),
+            ..Default::default()
+        })
+        .await;
+        let build = |reject| {
+            HttpDownload::builder()
+                .url(gated.url("file.bin"))
+                .reject_login_redirects(reject)
+                .build()
+        };
+        // when
+        let rejected = build(true).await;
+        let accepted = build(false).await;
+        // then
+        assert!(
+            matches!(rejected, Err(super::Error::LoginRedirect(url)) if url == login.url("login"))
+        );
+        assert_eq!(accepted?.final_url, login.url("login"));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn auth_is_preserved_across_redirects_only_on_request_test() -> Test<()> {
+        // given a server redirecting to another host
+        let target = MockServer::start(MockConfig::default()).await;
+        let origin = MockServer::start(MockConfig {
+            redirect: Some(target.url("signed.bin")),
+            ..Default::default()
+        })
+        .await;
+        let tmp_dir = tempfile::TempDir::new()?;
+        for preserve in [false, true] {
+            let download = HttpDownload::builder()
+                .url(origin.url("file.bin"))
+                .directory(tmp_dir.path())
+                .filename(format!("file-{}.bin", preserve))
+                .auth(config::Auth::Bearer("secret".to_string()))
+                .preserve_auth_on_redirect(preserve)
+                .build()
+                .await?;
+            let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+            // when
+            download.start(update_sender).await?;
+            // then the target only gets the credentials if the download opted in
+            let requests = target.requests();
+            let get = requests
+                .iter()
+                .rev()
+                .find(|req| req.method == hyper::Method::GET)
+                .unwrap();
+            assert_eq!(get.headers.contains_key(AUTHORIZATION), preserve);
+            assert_eq!(download.get_metadata().preserve_auth_on_redirect, preserve);
+            assert_eq!(
+                tokio::fs::read(download.file_path()).await?,
+                *target.payload()
+            );
+        }
+        Ok(())
+    }
 }
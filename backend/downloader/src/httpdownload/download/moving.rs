@@ -0,0 +1,147 @@
+use std::path::Path;
+use std::time::Instant;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc::Sender;
+
+use super::{DownloadUpdate, HttpDownload, Result, State};
+use crate::util::HALF_SECOND;
+
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Publishes `State::Moving` updates while a finished download is moved to its final location,
+/// at most every HALF_SECOND.
+pub(super) struct MoveProgress<'a> {
+    id: uuid::Uuid,
+    bytes_moved: u64,
+    total: u64,
+    last_update: Option<Instant>,
+    update_ch: &'a Sender<DownloadUpdate>,
+}
+
+impl<'a> MoveProgress<'a> {
+    pub(super) fn new(id: uuid::Uuid, total: u64, update_ch: &'a Sender<DownloadUpdate>) -> Self {
+        let mut progress = MoveProgress {
+            id,
+            bytes_moved: 0,
+            total,
+            last_update: None,
+            update_ch,
+        };
+        progress.publish();
+        progress
+    }
+
+    fn record(&mut self, bytes: u64) {
+        self.bytes_moved += bytes;
+        self.publish();
+    }
+
+    fn publish(&mut self) {
+        let due = match self.last_update {
+            Some(last_update) => last_update.elapsed() > HALF_SECOND,
+            None => true,
+        };
+        if due || self.bytes_moved == self.total {
+            let state = State::Moving {
+                bytes_moved: self.bytes_moved,
+                total: self.total,
+            };
+            let _ = self
+                .update_ch
+                .try_send(DownloadUpdate { id: self.id, state });
+            self.last_update = Some(Instant::now());
+        }
+    }
+}
+
+/// Moves `from` to `to`, falls back to copying if a rename isn't possible (e.g. the paths are on
+/// different filesystems). Returns whether the file was copied.
+pub(super) async fn move_file(
+    from: &Path,
+    to: &Path,
+    progress: &mut MoveProgress<'_>,
+) -> Result<bool> {
+    let metadata = tokio::fs::metadata(from).await?;
+    log::info!("Moving {:?} to {:?}", from, to);
+    match tokio::fs::rename(from, to).await {
+        Ok(()) => {
+            progress.record(metadata.len());
+            return Ok(false);
+        }
+        Err(e) => log::info!("Rename failed ({}), copying {:?} instead", e, from),
+    }
+    let mut input = File::open(from).await?;
+    let mut output = File::create(to).await?;
+    let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+    loop {
+        let read = input.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        output.write_all(&buf[..read]).await?;
+        progress.record(read as u64);
+    }
+    output.flush().await?;
+    output.sync_all().await?;
+    tokio::fs::set_permissions(to, metadata.permissions()).await?;
+    tokio::fs::remove_file(from).await?;
+    Ok(true)
+}
+
+impl HttpDownload {
+    /// Moving phase of a finished download, the downloaded files are moved from the temp
+    /// directory to their final location, reported as `State::Moving`.
+    pub(super) async fn move_progress<'a>(
+        &self,
+        update_ch: &'a Sender<DownloadUpdate>,
+    ) -> Result<MoveProgress<'a>> {
+        let mut total = 0;
+        for file in self.downloaded_files() {
+            total += tokio::fs::metadata(file).await?.len();
+        }
+        Ok(MoveProgress::new(self.id, total, update_ch))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn move_reports_progress_test() -> anyhow::Result<()> {
+        // given
+        let tmp_dir = TempDir::new()?;
+        let (from, to) = (tmp_dir.path().join("a.part"), tmp_dir.path().join("a"));
+        tokio::fs::write(&from, vec![7u8; 1000]).await?;
+        let (update_sender, mut update_recv) = mpsc::channel(10);
+        // when
+        let mut progress = MoveProgress::new(uuid::Uuid::new_v4(), 1000, &update_sender);
+        let copied = move_file(&from, &to, &mut progress).await?;
+        // then
+        assert!(!copied);
+        assert!(!from.exists());
+        assert_eq!(tokio::fs::read(&to).await?.len(), 1000);
+        let mut states = Vec::new();
+        while let Ok(update) = update_recv.try_recv() {
+            states.push(update.state);
+        }
+        assert_eq!(
+            states,
+            vec![
+                State::Moving {
+                    bytes_moved: 0,
+                    total: 1000
+                },
+                State::Moving {
+                    bytes_moved: 1000,
+                    total: 1000
+                }
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,306 @@
+use super::{ByteRange, Error, Result};
+use crate::util::parse_content_range;
+
+const CRLF: &[u8] = b"\r\n";
+const HEADERS_END: &[u8] = b"\r\n\r\n";
+
+/// A slice of a part body together with the absolute file offset it has to be written at.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PartChunk {
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+enum ParserState {
+    Preamble,
+    Headers,
+    Body { offset: u64, remaining: u64 },
+    Delimiter,
+    Epilogue,
+}
+
+/// Incremental parser for `multipart/byteranges` bodies.
+/// Bytes are fed as they arrive from the response stream, the parser yields the body of every part
+/// as soon as it is available, tagged with the offset taken from the part's `Content-Range` header.
+/// Part bodies are never scanned for the boundary, their length is known from `Content-Range`.
+#[derive(Debug)]
+pub struct ByteRangesParser {
+    /// `--` followed by the boundary
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    state: ParserState,
+}
+
+impl ByteRangesParser {
+    pub fn new(boundary: &str) -> Self {
+        let mut delimiter = b"--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+        Self {
+            delimiter,
+            buffer: Vec::new(),
+            state: ParserState::Preamble,
+        }
+    }
+
+    /// Builds a parser from a `Content-Type` header value, returns None if the value is not
+    /// `multipart/byteranges` or the boundary parameter is missing.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mut params = content_type.split(';');
+        let mime = params.next()?.trim();
+        if !mime.eq_ignore_ascii_case("multipart/byteranges") {
+            return None;
+        }
+        params
+            .filter_map(|param| param.split_once('='))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case("boundary"))
+            .map(|(_, boundary)| boundary.trim().trim_matches('"'))
+            .filter(|boundary| !boundary.is_empty())
+            .map(Self::new)
+    }
+
+    /// True once the closing delimiter has been consumed.
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, ParserState::Epilogue)
+    }
+
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<PartChunk>> {
+        self.buffer.extend_from_slice(data);
+        let mut chunks = Vec::new();
+        loop {
+            match self.state {
+                ParserState::Preamble => {
+                    let Some(pos) = find(&self.buffer, &self.delimiter) else {
+                        // Keep enough bytes around to match a delimiter split across two reads
+                        let keep = self.delimiter.len().min(self.buffer.len());
+                        self.buffer.drain(..self.buffer.len() - keep);
+                        break;
+                    };
+                    self.buffer.drain(..pos + self.delimiter.len());
+                    self.state = ParserState::Delimiter;
+                    if !self.consume_delimiter_suffix() {
+                        break;
+                    }
+                }
+                ParserState::Delimiter => {
+                    if self.buffer.len() < CRLF.len() + self.delimiter.len() {
+                        break;
+                    }
+                    if !self.buffer.starts_with(CRLF)
+                        || !self.buffer[CRLF.len()..].starts_with(&self.delimiter)
+                    {
+                        return Err(Error::MalformedMultipart(
+                            "part body is not followed by a boundary delimiter".to_string(),
+                        ));
+                    }
+                    self.buffer.drain(..CRLF.len() + self.delimiter.len());
+                    if !self.consume_delimiter_suffix() {
+                        break;
+                    }
+                }
+                ParserState::Headers => {
+                    let Some(pos) = find(&self.buffer, HEADERS_END) else {
+                        break;
+                    };
+                    let headers = String::from_utf8_lossy(&self.buffer[..pos]).to_string();
+                    self.buffer.drain(..pos + HEADERS_END.len());
+                    let range = part_range(&headers)?;
+                    self.state = ParserState::Body {
+                        offset: range.start,
+                        remaining: range.len(),
+                    };
+                }
+                ParserState::Body { offset, remaining } => {
+                    if self.buffer.is_empty() {
+                        break;
+                    }
+                    let take = remaining.min(self.buffer.len() as u64) as usize;
+                    let data: Vec<u8> = self.buffer.drain(..take).collect();
+                    chunks.push(PartChunk { offset, data });
+                    let remaining = remaining - take as u64;
+                    self.state = if remaining == 0 {
+                        ParserState::Delimiter
+                    } else {
+                        ParserState::Body {
+                            offset: offset + take as u64,
+                            remaining,
+                        }
+                    };
+                }
+                ParserState::Epilogue => {
+                    self.buffer.clear();
+                    break;
+                }
+            }
+        }
+        Ok(chunks)
+    }
+
+    /// Called right after a delimiter was consumed, decides whether it was the closing one
+    /// (`--boundary--`) or if a new part follows. Returns false if more data is needed.
+    fn consume_delimiter_suffix(&mut self) -> bool {
+        if self.buffer.starts_with(b"--") {
+            self.state = ParserState::Epilogue;
+            return true;
+        }
+        // Transport padding (whitespace) may follow the delimiter before the line break
+        match find(&self.buffer, CRLF) {
+            Some(pos) => {
+                self.buffer.drain(..pos + CRLF.len());
+                self.state = ParserState::Headers;
+                true
+            }
+            None => {
+                // Rewind so the delimiter is checked again once more bytes arrive
+                let mut restored = CRLF.to_vec();
+                restored.extend_from_slice(&self.delimiter);
+                restored.append(&mut self.buffer);
+                self.buffer = restored;
+                self.state = ParserState::Delimiter;
+                false
+            }
+        }
+    }
+}
+
+fn part_range(headers: &str) -> Result<ByteRange> {
+    headers
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-range"))
+        .and_then(|(_, value)| parse_content_range(value.trim()))
+        .map(|(range, _)| range)
+        .ok_or_else(|| {
+            Error::MalformedMultipart(format!("part without a valid Content-Range: '{headers}'"))
+        })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Clips a chunk of a full (200) response body starting at `offset` to the requested ranges.
+/// Used when a server ignores a multi-range request and sends the whole resource instead.
+pub fn clip_to_ranges(offset: u64, data: &[u8], ranges: &[ByteRange]) -> Vec<PartChunk> {
+    let chunk_end = offset + data.len() as u64;
+    ranges
+        .iter()
+        .filter_map(|range| {
+            let start = range.start.max(offset);
+            let end = (range.end + 1).min(chunk_end);
+            (start < end).then(|| PartChunk {
+                offset: start,
+                data: data[(start - offset) as usize..(end - offset) as usize].to_vec(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn multipart_body(boundary: &str, parts: &[(u64, &[u8])], total: u64) -> Vec<u8> {
+        let mut body = b"this preamble should be ignored\r\n".to_vec();
+        for (offset, data) in parts {
+            body.extend_from_slice(format!("--{boundary}\r\n").as_bytes());
+            body.extend_from_slice(b"Content-Type: application/octet-stream\r\n");
+            body.extend_from_slice(
+                format!(
+                    "Content-Range: bytes {}-{}/{}\r\n\r\n",
+                    offset,
+                    offset + data.len() as u64 - 1,
+                    total
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+        body
+    }
+
+    fn collect(chunks: Vec<PartChunk>) -> Vec<(u64, Vec<u8>)> {
+        // Merge adjacent chunks so the result doesn't depend on how the input was split
+        let mut merged: Vec<(u64, Vec<u8>)> = Vec::new();
+        for chunk in chunks {
+            match merged.last_mut() {
+                Some((offset, data)) if *offset + data.len() as u64 == chunk.offset => {
+                    data.extend_from_slice(&chunk.data)
+                }
+                _ => merged.push((chunk.offset, chunk.data)),
+            }
+        }
+        merged
+    }
+
+    #[test]
+    fn boundary_from_content_type_test() {
+        assert!(
+            ByteRangesParser::from_content_type("multipart/byteranges; boundary=abc").is_some()
+        );
+        assert!(
+            ByteRangesParser::from_content_type("Multipart/ByteRanges; boundary=\"a b\"").is_some()
+        );
+        assert!(ByteRangesParser::from_content_type("multipart/byteranges").is_none());
+        assert!(ByteRangesParser::from_content_type("application/octet-stream").is_none());
+    }
+
+    #[test]
+    fn parse_multipart_in_arbitrary_chunks_test() {
+        // given
+        let parts: [(u64, &[u8]); 2] = [(0, b"hello\r\n--not-a-boundary"), (100, b"world")];
+        let body = multipart_body("THIS_STRING_SEPARATES", &parts, 200);
+        for chunk_size in [1, 2, 3, 7, 64, body.len()] {
+            // when
+            let mut parser = ByteRangesParser::from_content_type(
+                "multipart/byteranges; boundary=THIS_STRING_SEPARATES",
+            )
+            .unwrap();
+            let mut chunks = Vec::new();
+            for piece in body.chunks(chunk_size) {
+                chunks.extend(parser.feed(piece).unwrap());
+            }
+            // then
+            assert!(parser.is_finished(), "chunk size {chunk_size}");
+            assert_eq!(
+                collect(chunks),
+                vec![(0, parts[0].1.to_vec()), (100, parts[1].1.to_vec())],
+                "chunk size {chunk_size}"
+            );
+        }
+    }
+
+    #[test]
+    fn part_without_content_range_test() {
+        let body = b"--b\r\nContent-Type: text/plain\r\n\r\nabc\r\n--b--";
+        let mut parser = ByteRangesParser::new("b");
+        assert!(matches!(
+            parser.feed(body),
+            Err(Error::MalformedMultipart(_))
+        ));
+    }
+
+    #[test]
+    fn clip_full_body_to_ranges_test() {
+        let ranges = [ByteRange::new(2, 4), ByteRange::new(8, 20)];
+        let chunks = clip_to_ranges(3, b"abcdefghij", &ranges);
+        assert_eq!(
+            chunks,
+            vec![
+                PartChunk {
+                    offset: 3,
+                    data: b"ab".to_vec()
+                },
+                PartChunk {
+                    offset: 8,
+                    data: b"fghij".to_vec()
+                }
+            ]
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use super::checksum::{compute_range, Checksum};
+use super::{ByteRange, Error, HttpDownload, Result};
+
+/// Rounds of re-fetching corrupted pieces before the download fails.
+pub const PIECE_REFETCH_ROUNDS: usize = 3;
+
+/// Expected hashes of the fixed-size pieces of a file (e.g. from a metalink), the last piece can
+/// be shorter.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PieceHashes {
+    pub piece_size: u64,
+    pub hashes: Vec<Checksum>,
+}
+
+impl PieceHashes {
+    /// Byte range of the `idx`th piece of a file of `length` bytes.
+    pub fn range(&self, idx: usize, length: u64) -> ByteRange {
+        let start = idx as u64 * self.piece_size;
+        ByteRange::new(start, (start + self.piece_size).min(length) - 1)
+    }
+}
+
+impl HttpDownload {
+    /// Piece hashes must cover exactly the file and only apply to downloads written to a single
+    /// file in full.
+    pub(super) fn validate_pieces(&self) -> Result<()> {
+        let Some(pieces) = &self.config.pieces else {
+            return Ok(());
+        };
+        if pieces.piece_size == 0
+            || pieces.hashes.len() as u64 != self.content_length.div_ceil(pieces.piece_size)
+        {
+            return Err(Error::InvalidConfig(format!(
+                "{} piece hashes of {} bytes don't cover the {} bytes of the file",
+                pieces.hashes.len(),
+                pieces.piece_size,
+                self.content_length
+            )));
+        }
+        if self.is_capped()
+            || self.split_size().is_some()
+            || self.sparse_ranges().is_some()
+            || self.config.compression
+        {
+            return Err(Error::InvalidConfig(
+                "piece hashes can't be combined with max_bytes, split_size, ranges or compression"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Indices of the pieces whose bytes on disk don't match their hash.
+    async fn corrupted_pieces(&self, pieces: &PieceHashes) -> Result<Vec<usize>> {
+        let mut corrupted = Vec::new();
+        for (idx, expected) in pieces.hashes.iter().enumerate() {
+            let range = pieces.range(idx, self.content_length);
+            let actual = compute_range(&self.download_path(), range, expected.algorithm()).await?;
+            if actual != *expected {
+                log::warn!(
+                    "Piece {} ({}) of download {} is corrupted, computed {} but expected {}",
+                    idx,
+                    range,
+                    self.id,
+                    actual,
+                    expected
+                );
+                corrupted.push(idx);
+            }
+        }
+        Ok(corrupted)
+    }
+
+    /// Checks every piece of the finished transfer and re-fetches only the ranges of corrupted
+    /// ones, up to `PIECE_REFETCH_ROUNDS` times. Returns whether any piece was re-fetched, a
+    /// no-op without piece hashes.
+    pub(super) async fn verify_pieces(&self, cancel: &CancellationToken) -> Result<bool> {
+        let Some(pieces) = &self.config.pieces else {
+            return Ok(false);
+        };
+        let mut corrupted = self.corrupted_pieces(pieces).await?;
+        for round in 1..=PIECE_REFETCH_ROUNDS {
+            if corrupted.is_empty() {
+                return Ok(round > 1);
+            }
+            log::info!(
+                "Re-fetching {} corrupted pieces of download {} (round {}/{})",
+                corrupted.len(),
+                self.id,
+                round,
+                PIECE_REFETCH_ROUNDS
+            );
+            let ranges: Vec<ByteRange> = corrupted
+                .iter()
+                .map(|idx| pieces.range(*idx, self.content_length))
+                .collect();
+            // Progress of the re-fetch isn't reported, the download already counts these bytes
+            let (update_ch, _) = mpsc::channel(1);
+            self.fetch_ranges(&ranges, update_ch, cancel).await?;
+            corrupted = self.corrupted_pieces(pieces).await?;
+        }
+        match corrupted.is_empty() {
+            true => Ok(true),
+            false => Err(Error::PieceMismatch(corrupted)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn piece_range_test() {
+        let pieces = PieceHashes {
+            piece_size: 4,
+            hashes: Vec::new(),
+        };
+        assert_eq!(pieces.range(0, 10), ByteRange::new(0, 3));
+        assert_eq!(pieces.range(2, 10), ByteRange::new(8, 9));
+    }
+}
@@ -0,0 +1,62 @@
+use reqwest::header::LOCATION;
+use reqwest::redirect::Policy;
+use reqwest::{Client, Url};
+
+use super::config::HttpDownloadConfig;
+use super::retry::send_with_retries;
+use super::Result;
+use crate::util::parse_filename;
+
+/// Hops followed before giving up on resolving a redirect chain, like reqwest's default policy.
+pub const MAX_REDIRECTS: usize = 10;
+
+/// Follows the redirects of `url` by hand so the headers and credentials of the download are sent
+/// to every hop, cross-host ones included (the client drops them when a redirect leaves the
+/// host). Returns the url the chain ends at. The hops are requested with a client of its own
+/// since the redirect policy can't be changed per request, it has the default connection settings.
+pub async fn resolve_redirects(url: &Url, config: &HttpDownloadConfig) -> Result<Url> {
+    let client = Client::builder().redirect(Policy::none()).build()?;
+    let mut current = url.clone();
+    for _ in 0..MAX_REDIRECTS {
+        let head = config
+            .prepare(client.head(current.as_ref()))
+            .timeout(config.timeout);
+        let resp = send_with_retries(head, config.request_retries).await?;
+        if !resp.status().is_redirection() {
+            return Ok(current);
+        }
+        let next = resp
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| current.join(location).ok());
+        let Some(next) = next else {
+            return Ok(current);
+        };
+        log::info!(
+            "Following redirect from {} to {} with the download's credentials",
+            current,
+            next
+        );
+        current = next;
+    }
+    log::warn!(
+        "Stopped following redirects of {} after {} hops",
+        url,
+        MAX_REDIRECTS
+    );
+    Ok(current)
+}
+
+/// Whether a request for `requested` ended, after redirects, at an HTML page although the url
+/// doesn't name one, the typical response of gated downloads sending the client to their login.
+pub fn is_login_redirect(requested: &Url, final_url: &Url, content_type: Option<&str>) -> bool {
+    let is_html = content_type
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("text/html"));
+    let expects_html = parse_filename(requested).is_some_and(|filename| {
+        let filename = filename.to_ascii_lowercase();
+        filename.ends_with(".html") || filename.ends_with(".htm")
+    });
+    requested != final_url && is_html && !expects_html
+}
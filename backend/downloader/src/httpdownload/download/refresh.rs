@@ -0,0 +1,64 @@
+use async_trait::async_trait;
+use reqwest::{StatusCode, Url};
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+/// Produces a new url for a download whose url stopped working, e.g. a signed CDN url whose
+/// token expired. Returning None means the url can't be refreshed.
+#[async_trait]
+pub trait UrlRefresher {
+    async fn refresh(&self, expired: &Url) -> Option<Url>;
+}
+
+#[async_trait]
+impl<F, Fut> UrlRefresher for F
+where
+    F: Fn(Url) -> Fut + Send + Sync,
+    Fut: Future<Output = Option<Url>> + Send,
+{
+    async fn refresh(&self, expired: &Url) -> Option<Url> {
+        self(expired.clone()).await
+    }
+}
+
+/// Registered refresh callback of a download, it also remembers the latest refreshed url so every
+/// clone of the download keeps using it for following requests.
+#[derive(Clone)]
+pub struct RefreshHook {
+    refresher: Arc<dyn UrlRefresher + Send + Sync>,
+    latest: Arc<Mutex<Option<Url>>>,
+}
+
+impl fmt::Debug for RefreshHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RefreshHook")
+            .field("latest", &self.latest)
+            .finish()
+    }
+}
+
+impl RefreshHook {
+    pub fn new(refresher: impl UrlRefresher + Send + Sync + 'static) -> Self {
+        Self {
+            refresher: Arc::new(refresher),
+            latest: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Url obtained by the last successful refresh
+    pub fn latest(&self) -> Option<Url> {
+        self.latest.lock().unwrap().clone()
+    }
+
+    pub async fn refresh(&self, expired: &Url) -> Option<Url> {
+        let url = self.refresher.refresh(expired).await?;
+        *self.latest.lock().unwrap() = Some(url.clone());
+        Some(url)
+    }
+}
+
+/// Status codes servers answer with once a signed url expired.
+pub fn is_expired(status: StatusCode) -> bool {
+    matches!(status, StatusCode::FORBIDDEN | StatusCode::GONE)
+}
@@ -0,0 +1,238 @@
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use super::jitter::{self, DEFAULT_JITTER_PERCENT};
+use super::Error;
+
+pub const DEFAULT_BASE_DELAY_MS: u64 = 1000;
+pub const DEFAULT_MAX_DELAY_MS: u64 = 30_000;
+pub const DEFAULT_MAX_RETRY_AFTER_MS: u64 = 300_000;
+/// Delay before the first repetition of a single request, doubled for every further one
+pub const REQUEST_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// How often a run of a download is attempted when it fails with a transient error (see
+/// `Error::is_transient`), each retry resumes where the previous attempt stopped. The delay before
+/// a retry doubles from `base_delay_ms` up to `max_delay_ms` and is spread by `jitter_percent`
+/// either way, so downloads failing together (e.g. on an outage of their host) don't retry in
+/// lockstep. Zero jitter keeps the delays exact, e.g. for tests. The default of a single attempt
+/// doesn't retry. A `Retry-After` wait of the server replaces the backoff up to
+/// `max_retry_after_ms`, longer waits are ignored in favor of the backoff so a server can't stall
+/// the download indefinitely. Single requests are first repeated as
+/// `HttpDownloadConfig::request_retries` allows, a run only fails once they are used up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub jitter_percent: u32,
+    pub max_retry_after_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            base_delay_ms: DEFAULT_BASE_DELAY_MS,
+            max_delay_ms: DEFAULT_MAX_DELAY_MS,
+            jitter_percent: DEFAULT_JITTER_PERCENT,
+            max_retry_after_ms: DEFAULT_MAX_RETRY_AFTER_MS,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the `retry`th retry, starting at 1.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 1u64 << retry.saturating_sub(1).min(32);
+        Duration::from_millis(
+            self.base_delay_ms
+                .saturating_mul(factor)
+                .min(self.max_delay_ms),
+        )
+    }
+
+    /// `delay` spread by `jitter_percent`, what a download actually waits before the retry.
+    pub fn jittered_delay(&self, retry: u32) -> Duration {
+        jitter::jittered(self.delay(retry), self.jitter_percent, jitter::seed())
+    }
+
+    /// Longest `Retry-After` wait that is honored.
+    pub fn max_retry_after(&self) -> Duration {
+        Duration::from_millis(self.max_retry_after_ms)
+    }
+}
+
+/// Retry policy of a download that can be changed while it runs, the change applies to its next
+/// retry. Clones share the policy.
+#[derive(Debug, Clone, Default)]
+pub struct SharedRetryPolicy {
+    inner: Arc<Mutex<RetryPolicy>>,
+}
+
+impl SharedRetryPolicy {
+    pub fn new(policy: RetryPolicy) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(policy)),
+        }
+    }
+
+    pub fn get(&self) -> RetryPolicy {
+        *self.inner.lock().unwrap()
+    }
+
+    pub fn set(&self, policy: RetryPolicy) {
+        *self.inner.lock().unwrap() = policy;
+    }
+}
+
+/// How long a `429` or `503` response asks to wait before the next request, from its
+/// `Retry-After` header in seconds or as an HTTP date (a date in the past means right away).
+pub fn retry_after(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    if !matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    ) {
+        return None;
+    }
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse::<u64>() {
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => {
+            let date = httpdate::parse_http_date(value).ok()?;
+            Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+        }
+    }
+}
+
+/// Error of a response with an unexpected status, `Error::RetryAfter` if the server said when to
+/// retry.
+pub async fn status_error(resp: Response) -> Error {
+    let status = resp.status();
+    match retry_after(status, resp.headers()) {
+        Some(wait) => Error::RetryAfter { status, wait },
+        None => Error::DownloadNotOk(status, resp.text().await.unwrap_or_default()),
+    }
+}
+
+/// Whether a single request is worth repeating right away: it couldn't be sent or timed out, or
+/// the server answered with a status that is usually temporary. A response telling when to retry
+/// isn't, it's left to the `RetryPolicy` which waits as long as asked.
+fn is_retryable(result: &reqwest::Result<Response>) -> bool {
+    match result {
+        Ok(resp) if retry_after(resp.status(), resp.headers()).is_some() => false,
+        Ok(resp) => matches!(
+            resp.status(),
+            StatusCode::REQUEST_TIMEOUT
+                | StatusCode::TOO_MANY_REQUESTS
+                | StatusCode::INTERNAL_SERVER_ERROR
+                | StatusCode::BAD_GATEWAY
+                | StatusCode::SERVICE_UNAVAILABLE
+                | StatusCode::GATEWAY_TIMEOUT
+        ),
+        Err(e) => e.is_connect() || e.is_timeout() || e.is_request(),
+    }
+}
+
+/// Sends the request and repeats it up to `retries` times while it fails retryably (see
+/// `is_retryable`), the last outcome is returned. These request level retries ride out short
+/// blips of a probe or a range request, they are independent of the `RetryPolicy` that resumes a
+/// whole run of a download after it failed.
+pub async fn send_with_retries(request: RequestBuilder, retries: u32) -> reqwest::Result<Response> {
+    let mut retry = 0;
+    loop {
+        // Requests with a streamed body can't be repeated, downloads don't send any
+        let Some(attempt) = request.try_clone() else {
+            return request.send().await;
+        };
+        let result = attempt.send().await;
+        if retry >= retries || !is_retryable(&result) {
+            return result;
+        }
+        retry += 1;
+        match &result {
+            Ok(resp) => log::warn!(
+                "Request to {} answered {}, repeating it ({}/{})",
+                resp.url(),
+                resp.status(),
+                retry,
+                retries
+            ),
+            Err(e) => log::warn!(
+                "Request failed: {}, repeating it ({}/{})",
+                e,
+                retry,
+                retries
+            ),
+        }
+        tokio::time::sleep(REQUEST_RETRY_DELAY * (1 << (retry - 1).min(16))).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn retry_after_is_parsed_test() {
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RETRY_AFTER, value.parse().unwrap());
+            headers
+        };
+        let rate_limited = StatusCode::TOO_MANY_REQUESTS;
+        assert_eq!(
+            retry_after(rate_limited, &headers("30")),
+            Some(Duration::from_secs(30))
+        );
+        let in_a_minute = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+        let wait = retry_after(StatusCode::SERVICE_UNAVAILABLE, &headers(&in_a_minute)).unwrap();
+        assert!(wait > Duration::from_secs(55) && wait <= Duration::from_secs(60));
+        let past = httpdate::fmt_http_date(SystemTime::UNIX_EPOCH);
+        assert_eq!(
+            retry_after(rate_limited, &headers(&past)),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after(rate_limited, &headers("soon")), None);
+        assert_eq!(retry_after(rate_limited, &HeaderMap::new()), None);
+        assert_eq!(retry_after(StatusCode::NOT_FOUND, &headers("30")), None);
+    }
+
+    #[test]
+    fn delay_doubles_up_to_max_test() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay_ms: 100,
+            max_delay_ms: 500,
+            jitter_percent: 0,
+            ..Default::default()
+        };
+        let delays: Vec<u64> = (1..=5)
+            .map(|retry| policy.delay(retry).as_millis() as u64)
+            .collect();
+        assert_eq!(delays, vec![100, 200, 400, 500, 500]);
+        assert_eq!(policy.delay(100), Duration::from_millis(500));
+        assert_eq!(policy.jittered_delay(3), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn jittered_delay_stays_in_window_test() {
+        let policy = RetryPolicy {
+            base_delay_ms: 1000,
+            jitter_percent: 20,
+            ..Default::default()
+        };
+        for _ in 0..100 {
+            let delay = policy.jittered_delay(1);
+            assert!(
+                (Duration::from_millis(800)..=Duration::from_millis(1200)).contains(&delay),
+                "{:?}",
+                delay
+            );
+        }
+    }
+}
@@ -0,0 +1,351 @@
+use reqwest::header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RANGE};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use super::jitter::{self, jittered};
+use super::segmented::PartMeta;
+use super::{Error, HttpDownload, Result};
+
+/// Validators a server identifies a version of a resource with.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |name| {
+            headers
+                .get(name)
+                .and_then(|val| val.to_str().ok())
+                .map(str::to_owned)
+        };
+        Self {
+            etag: get(ETAG),
+            last_modified: get(LAST_MODIFIED),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    /// Value of an `If-Range` header, the ETag unless it's weak (those can't be used for ranges)
+    /// and the Last-Modified date otherwise.
+    pub fn if_range(&self) -> Option<&str> {
+        self.etag
+            .as_deref()
+            .filter(|etag| !etag.starts_with("W/"))
+            .or(self.last_modified.as_deref())
+    }
+
+    /// Describes how the resource changed if `current` contradicts these validators. Only
+    /// validators both sides have are compared, a server that stopped sending one isn't taken as
+    /// a change. Weak and strong ETags with the same value are the same version.
+    pub fn changed_to(&self, current: &Validators) -> Option<String> {
+        let weak = |etag: &str| etag.trim_start_matches("W/").to_owned();
+        if let (Some(old), Some(new)) = (&self.etag, &current.etag) {
+            if weak(old) != weak(new) {
+                return Some(format!("ETag changed from {} to {}", old, new));
+            }
+        }
+        if let (Some(old), Some(new)) = (&self.last_modified, &current.last_modified) {
+            if old != new {
+                return Some(format!("Last-Modified changed from {} to {}", old, new));
+            }
+        }
+        None
+    }
+}
+
+/// Extension of the sidecar of single connection downloads, see `HttpDownload::validators_path`
+pub const VALIDATORS_EXTENSION: &str = "ludl";
+
+impl HttpDownload {
+    /// Sidecar (`<file>.ludl`) with the validators of the version a single connection download
+    /// wrote to its partial file, a resume sends them as `If-Range`. Segmented downloads keep
+    /// them in their segment progress instead.
+    pub fn validators_path(&self) -> PathBuf {
+        let mut path = self.download_path().into_os_string();
+        path.push(format!(".{}", VALIDATORS_EXTENSION));
+        PathBuf::from(path)
+    }
+
+    /// Records the validators of the response the partial file is written from. The download
+    /// works without, a failure only costs the check on resume.
+    pub(super) async fn store_validators(&self, validators: &Validators) {
+        let path = self.validators_path();
+        let result = match validators.is_empty() {
+            true => match tokio::fs::remove_file(&path).await {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                result => result,
+            },
+            false => {
+                let json = serde_json::to_vec(validators).expect("Validators always serialize");
+                tokio::fs::write(&path, json).await
+            }
+        };
+        if let Err(e) = result {
+            log::warn!("Couldn't store validators of download {}: {}", self.id, e);
+        }
+    }
+
+    /// Records the current validators as the version of the partial file, refreshing accepts a
+    /// changed resource so a resume continues the file instead of restarting it.
+    pub(super) async fn accept_validators(&self) {
+        if self.validators_path().exists() {
+            self.store_validators(&self.validators).await;
+        }
+        let sidecar = self.sidecar_path();
+        if let Some(mut meta) = PartMeta::load(&sidecar, self.target_length()).await {
+            meta.validators = self.validators.clone();
+            if let Err(e) = meta.store(&sidecar).await {
+                log::warn!("Couldn't store validators of download {}: {}", self.id, e);
+            }
+        }
+    }
+
+    /// Validators of the partial file, the probed ones if it has no sidecar.
+    pub(super) async fn partial_validators(&self) -> Validators {
+        match tokio::fs::read(self.validators_path()).await {
+            Ok(json) => serde_json::from_slice(&json).unwrap_or_else(|e| {
+                log::warn!(
+                    "Ignoring malformed validators of download {}: {}",
+                    self.id,
+                    e
+                );
+                self.validators.clone()
+            }),
+            Err(_) => self.validators.clone(),
+        }
+    }
+
+    /// Revalidates the resource every `revalidate_interval` while the download runs, resolves to
+    /// `Error::SourceChanged` once it changed. Never resolves if revalidation is disabled or the
+    /// server sent no validators. Failed checks are logged and tried again at the next interval,
+    /// the transfer itself notices a server that is gone.
+    pub(super) async fn watch_source(&self) -> Error {
+        let Some(interval) = self.config.revalidate_interval else {
+            return std::future::pending().await;
+        };
+        if self.validators.is_empty() {
+            log::info!(
+                "Server sent no validators for download {}, it isn't revalidated",
+                self.id
+            );
+            return std::future::pending().await;
+        }
+        loop {
+            // Spread so the checks of downloads started together don't hit the server at once
+            tokio::time::sleep(jittered(
+                interval,
+                jitter::DEFAULT_JITTER_PERCENT,
+                jitter::seed(),
+            ))
+            .await;
+            match self.revalidate().await {
+                Ok(None) => log::debug!("Download {} is still up to date", self.id),
+                Ok(Some(change)) => {
+                    log::warn!("Source of download {} changed: {}", self.id, change);
+                    return Error::SourceChanged(change);
+                }
+                Err(e) => log::warn!("Couldn't revalidate download {}: {}", self.id, e),
+            }
+        }
+    }
+
+    /// Asks the server with a conditional request whether the resource still matches the
+    /// validators it was probed with, returns how it changed if it didn't. Servers rejecting HEAD
+    /// are asked with a GET of the first byte.
+    pub async fn revalidate(&self) -> Result<Option<String>> {
+        let url = self.current_url();
+        let conditional = |request: reqwest::RequestBuilder| {
+            let mut request = self.config.prepare(request).timeout(self.config.timeout);
+            if let Some(etag) = &self.validators.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &self.validators.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+            request
+        };
+        let mut resp = conditional(self.client.head(url.as_ref())).send().await?;
+        if matches!(
+            resp.status(),
+            StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED
+        ) {
+            resp = conditional(self.client.get(url.as_ref()))
+                .header(RANGE, "bytes=0-0")
+                .send()
+                .await?;
+        }
+        match resp.status() {
+            StatusCode::NOT_MODIFIED => Ok(None),
+            status if status.is_success() => Ok(self
+                .validators
+                .changed_to(&Validators::from_headers(resp.headers()))),
+            status => Err(Error::DownloadNotOk(status, String::new())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::httpdownload::download::DownloadUpdate;
+    use crate::util::mock::{MockConfig, MockServer};
+    use crate::util::setup_test_download;
+    use pretty_assertions::assert_eq;
+    use reqwest::header::HeaderValue;
+    use std::time::Duration;
+    use test_log::test;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn only_contradicting_validators_are_a_change_test() {
+        let old = Validators {
+            etag: Some("\"v1\"".to_string()),
+            last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+        };
+        let weak = Validators {
+            etag: Some("W/\"v1\"".to_string()),
+            last_modified: None,
+        };
+        assert_eq!(old.changed_to(&weak), None);
+        assert_eq!(old.changed_to(&Validators::default()), None);
+        let changed = Validators {
+            etag: Some("\"v2\"".to_string()),
+            ..old.clone()
+        };
+        assert!(old.changed_to(&changed).is_some());
+        // weak ETags can't validate a range, the date can
+        assert_eq!(old.if_range(), Some("\"v1\""));
+        assert_eq!(weak.if_range(), None);
+        let dated = Validators {
+            etag: Some("W/\"v1\"".to_string()),
+            ..old.clone()
+        };
+        assert_eq!(dated.if_range(), Some("Wed, 21 Oct 2015 07:28:00 GMT"));
+    }
+
+    #[test(tokio::test)]
+    async fn changed_source_stops_download_test() -> anyhow::Result<()> {
+        // given a slow download of a resource with an ETag
+        let mut config = MockConfig {
+            chunk_delay: Some(Duration::from_millis(5)),
+            ..MockConfig::default()
+        };
+        config
+            .headers
+            .insert(ETAG, HeaderValue::from_static("\"v1\""));
+        let server = MockServer::start(config).await;
+        let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        download.config.revalidate_interval = Some(Duration::from_millis(50));
+        assert_eq!(download.validators.etag.as_deref(), Some("\"v1\""));
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        // when the resource changes while it runs
+        let run = download.start(update_sender);
+        tokio::pin!(run);
+        tokio::select! {
+            _ = &mut run => panic!("download finished before the resource changed"),
+            _ = tokio::time::sleep(Duration::from_millis(100)) => {}
+        }
+        assert_eq!(download.revalidate().await?, None);
+        server.update(|config| {
+            config
+                .headers
+                .insert(ETAG, HeaderValue::from_static("\"v2\""));
+        });
+        let result = run.await;
+        // then the download stops and keeps what it wrote
+        assert!(matches!(result, Err(Error::SourceChanged(_))));
+        let bytes_on_disk = download.get_bytes_on_disk().await;
+        assert!(bytes_on_disk > 0 && bytes_on_disk < download.content_length);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn resume_of_changed_source_restarts_from_zero_test() -> anyhow::Result<()> {
+        for segments in [1, 4] {
+            // given a paused download of a resource with an ETag
+            let mut config = MockConfig::default();
+            config
+                .headers
+                .insert(ETAG, HeaderValue::from_static("\"v1\""));
+            let server = MockServer::start(config).await;
+            let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+            download.config.segments = segments;
+            download.config.pause_at = Some(100_000);
+            let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+            let result = download.start(update_sender.clone()).await;
+            assert!(matches!(result, Err(Error::Cancelled(_))));
+            assert_eq!(download.validators_path().exists(), segments == 1);
+            // when the resource changes before it's resumed
+            server.update(|config| {
+                config.payload = config
+                    .payload
+                    .iter()
+                    .map(|byte| byte.wrapping_add(1))
+                    .collect::<Vec<u8>>()
+                    .into();
+                config
+                    .headers
+                    .insert(ETAG, HeaderValue::from_static("\"v2\""));
+            });
+            let (error_sender, mut errors) = mpsc::unbounded_channel();
+            download.config.pause_at = None;
+            download.config.error_events = Some(error_sender);
+            download.resume(update_sender).await?;
+            // then nothing of the old version is kept
+            assert_eq!(
+                tokio::fs::read(download.file_path()).await?,
+                *server.payload()
+            );
+            assert!(server
+                .requests()
+                .iter()
+                .any(|request| request.headers.contains_key(reqwest::header::IF_RANGE)));
+            let event = errors.try_recv()?;
+            assert_eq!(event.code, "resume_validation_failed");
+            assert!(event.transient);
+            assert!(!download.validators_path().exists());
+            assert!(!download.sidecar_path().exists());
+        }
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn refresh_accepts_changed_source_for_resume_test() -> anyhow::Result<()> {
+        for segments in [1, 4] {
+            // given a paused download whose resource changed
+            let mut config = MockConfig::default();
+            config
+                .headers
+                .insert(ETAG, HeaderValue::from_static("\"v1\""));
+            let server = MockServer::start(config).await;
+            let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+            download.config.segments = segments;
+            download.config.pause_at = Some(100_000);
+            let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+            let result = download.start(update_sender.clone()).await;
+            assert!(matches!(result, Err(Error::Cancelled(_))));
+            server.update(|config| {
+                config
+                    .headers
+                    .insert(ETAG, HeaderValue::from_static("\"v2\""));
+            });
+            // when the change is accepted
+            assert_eq!(download.refresh_metadata().await?, vec!["validators"]);
+            let (error_sender, mut errors) = mpsc::unbounded_channel();
+            download.config.pause_at = None;
+            download.config.error_events = Some(error_sender);
+            download.resume(update_sender).await?;
+            // then the resume continues the file
+            assert!(errors.try_recv().is_err());
+            assert_eq!(download.stats.resumes(), 1);
+        }
+        Ok(())
+    }
+}
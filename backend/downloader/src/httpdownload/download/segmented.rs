@@ -0,0 +1,782 @@
+use futures_util::stream::FuturesUnordered;
+use futures_util::StreamExt;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use super::retry;
+use super::revalidate::Validators;
+use super::speed::SpeedMeter;
+use super::{ByteRange, DownloadUpdate, Error, HttpDownload, Result};
+
+pub const SIDECAR_EXTENSION: &str = "part.meta";
+/// Running segments with less than twice this many unclaimed bytes aren't split to open another
+/// connection, see `SegmentTarget`
+pub const MIN_SPLIT_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentProgress {
+    pub range: ByteRange,
+    pub written: u64,
+}
+
+impl SegmentProgress {
+    pub fn is_complete(&self) -> bool {
+        self.written >= self.range.len()
+    }
+
+    /// The part of the range that still has to be fetched, None if the segment is complete.
+    pub fn remaining(&self) -> Option<ByteRange> {
+        (!self.is_complete())
+            .then(|| ByteRange::new(self.range.start + self.written, self.range.end))
+    }
+}
+
+/// Sidecar metadata (`<file>.part.meta`) of a segmented download, records how many bytes of every
+/// segment have been written so a resume only requests what is missing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartMeta {
+    pub content_length: u64,
+    pub segments: Vec<SegmentProgress>,
+    /// Validators of the version the segments were written from, taken from the first segment
+    /// response and sent as `If-Range` by every later segment request
+    #[serde(default)]
+    pub validators: Validators,
+}
+
+impl PartMeta {
+    /// Splits `content_length` bytes in (at most) `segments` contiguous ranges of similar size.
+    pub fn plan(content_length: u64, segments: usize) -> Self {
+        let segments = (segments.max(1) as u64).min(content_length.max(1));
+        let segment_size = content_length.div_ceil(segments).max(1);
+        let segments = (0..content_length)
+            .step_by(segment_size as usize)
+            .map(|start| SegmentProgress {
+                range: ByteRange::new(start, (start + segment_size).min(content_length) - 1),
+                written: 0,
+            })
+            .collect();
+        Self {
+            content_length,
+            segments,
+            validators: Validators::default(),
+        }
+    }
+
+    /// Reads the sidecar, missing, unreadable or inconsistent metadata yields None which means the
+    /// download has to restart from scratch.
+    pub async fn load(path: &Path, content_length: u64) -> Option<Self> {
+        let raw = tokio::fs::read(path).await.ok()?;
+        let meta: PartMeta = match serde_json::from_slice(&raw) {
+            Ok(meta) => meta,
+            Err(e) => {
+                log::warn!("Corrupt segment metadata at {:?}: {}", path, e);
+                return None;
+            }
+        };
+        if !meta.is_consistent(content_length) {
+            log::warn!("Segment metadata at {:?} does not match the download", path);
+            return None;
+        }
+        Some(meta)
+    }
+
+    pub async fn store(&self, path: &Path) -> Result<()> {
+        tokio::fs::write(path, self.encode()).await?;
+        Ok(())
+    }
+
+    fn store_blocking(&self, path: &Path) {
+        if let Err(e) = std::fs::write(path, self.encode()) {
+            log::error!("Failed persisting segment metadata at {:?}: {}", path, e);
+        }
+    }
+
+    /// Segments split off while the download ran are appended, they are stored in the order of
+    /// their ranges.
+    fn encode(&self) -> Vec<u8> {
+        let mut sorted = self.clone();
+        sorted.segments.sort_by_key(|segment| segment.range.start);
+        serde_json::to_vec(&sorted).expect("PartMeta serialization can't fail")
+    }
+
+    /// Segments must cover `0..content_length` without gaps or overlaps.
+    fn is_consistent(&self, content_length: u64) -> bool {
+        let mut next = 0;
+        for segment in self.segments.iter() {
+            if segment.range.start != next
+                || segment.range.is_empty()
+                || segment.written > segment.range.len()
+            {
+                return false;
+            }
+            next = segment.range.end + 1;
+        }
+        self.content_length == content_length && next == content_length
+    }
+
+    pub fn written(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.written).sum()
+    }
+
+    /// Bytes written from the start of the file on without a gap, segments complete in any order.
+    pub fn contiguous(&self) -> u64 {
+        let mut bytes = 0;
+        for segment in &self.segments {
+            bytes += segment.written.min(segment.range.len());
+            if !segment.is_complete() {
+                break;
+            }
+        }
+        bytes
+    }
+}
+
+/// Connections of a segmented download that can be changed while it runs, clones share the
+/// count. Raising it claims segments that haven't started yet and then splits the unclaimed rest
+/// of the largest running segment, lowering it lets the running segments finish their range
+/// without claiming more. Unset, `HttpDownloadConfig::segments` applies.
+#[derive(Debug, Clone, Default)]
+pub struct SegmentTarget {
+    count: Arc<AtomicUsize>,
+    changed: Arc<Notify>,
+}
+
+impl SegmentTarget {
+    pub fn get(&self) -> Option<usize> {
+        match self.count.load(Ordering::Relaxed) {
+            0 => None,
+            count => Some(count),
+        }
+    }
+
+    pub fn set(&self, count: usize) {
+        self.count.store(count.max(1), Ordering::Relaxed);
+        self.changed.notify_one();
+    }
+
+    async fn changed(&self) {
+        self.changed.notified().await;
+    }
+}
+
+/// Chunks of all segments waiting to be written, see `HttpDownloadConfig::write_batch_size`.
+#[derive(Debug, Default)]
+struct WriteBatch {
+    /// Buffered bytes by the offset they go to, with the segment they belong to
+    chunks: BTreeMap<u64, (usize, Vec<u8>)>,
+    size: usize,
+    /// When the oldest buffered chunk arrived
+    since: Option<Instant>,
+}
+
+impl WriteBatch {
+    /// Buffers `data` of segment `idx`, appending it to the previous chunk if it continues it.
+    fn push(&mut self, idx: usize, offset: u64, data: &[u8]) {
+        self.size += data.len();
+        self.since.get_or_insert_with(Instant::now);
+        if let Some((start, (prev_idx, prev))) = self.chunks.range_mut(..offset).next_back() {
+            if *prev_idx == idx && start + prev.len() as u64 == offset {
+                prev.extend_from_slice(data);
+                return;
+            }
+        }
+        self.chunks.insert(offset, (idx, data.to_vec()));
+    }
+
+    fn is_due(&self, size: usize, time: std::time::Duration) -> bool {
+        self.size >= size || self.since.is_some_and(|since| since.elapsed() >= time)
+    }
+
+    /// Empties the batch, the chunks are sorted by offset.
+    fn take(&mut self) -> BTreeMap<u64, (usize, Vec<u8>)> {
+        self.size = 0;
+        self.since = None;
+        std::mem::take(&mut self.chunks)
+    }
+}
+
+struct Progress {
+    meta: PartMeta,
+    /// Offset every segment continues at, ahead of its `written` bytes by what's in flight
+    claimed: Vec<u64>,
+    /// Only bytes written to the file count as written in `meta`, buffered ones wait here
+    batch: WriteBatch,
+    speed: SpeedMeter,
+    last_persist: Instant,
+    /// Bytes written when the sidecar was last persisted
+    persisted_bytes: u64,
+    /// `pause_at` threshold if this run started below it
+    pause_at: Option<u64>,
+}
+
+/// Persists the segment progress when the segmented download future is dropped, this is what
+/// happens when a running download gets stopped.
+struct PersistOnDrop {
+    path: PathBuf,
+    progress: Arc<Mutex<Progress>>,
+    finished: bool,
+}
+
+impl Drop for PersistOnDrop {
+    fn drop(&mut self) {
+        if !self.finished {
+            let meta = self.progress.lock().unwrap().meta.clone();
+            meta.store_blocking(&self.path);
+        }
+    }
+}
+
+impl Progress {
+    /// Bytes of the segment no request has received yet.
+    fn unclaimed(&self, idx: usize) -> u64 {
+        (self.meta.segments[idx].range.end + 1).saturating_sub(self.claimed[idx])
+    }
+
+    /// Gives the second half of the unclaimed bytes of the running segment with the most of them
+    /// to a new segment, the running one stops where the new one starts. Returns the index of the
+    /// new segment, None if no segment is worth splitting.
+    fn split(&mut self, running: &[usize]) -> Option<usize> {
+        let (idx, unclaimed) = running
+            .iter()
+            .map(|idx| (*idx, self.unclaimed(*idx)))
+            .max_by_key(|(_, unclaimed)| *unclaimed)?;
+        if unclaimed < 2 * MIN_SPLIT_SIZE {
+            return None;
+        }
+        let start = self.claimed[idx] + unclaimed / 2;
+        let end = self.meta.segments[idx].range.end;
+        self.meta.segments[idx].range.end = start - 1;
+        self.meta.segments.push(SegmentProgress {
+            range: ByteRange::new(start, end),
+            written: 0,
+        });
+        self.claimed.push(start);
+        Some(self.meta.segments.len() - 1)
+    }
+}
+
+impl HttpDownload {
+    pub fn sidecar_path(&self) -> PathBuf {
+        let mut path = self.download_path().into_os_string();
+        path.push(format!(".{}", SIDECAR_EXTENSION));
+        PathBuf::from(path)
+    }
+
+    /// A download is fetched in segments if it's configured to and the server allows it, split
+    /// output files and mirrored downloads are always written sequentially and sparse downloads
+    /// use a single request.
+    pub fn is_segmented(&self) -> bool {
+        self.config.segments > 1
+            && self.supports_byte_ranges
+            && self.target_length() > 0
+            && self.split_size().is_none()
+            && self.sparse_ranges().is_none()
+            && !self.config.compression
+            && self.config.mirrors.is_empty()
+    }
+
+    /// Number of connections the download is fetched with, 1 unless it's segmented. Tiny
+    /// downloads get fewer segments than configured, one per byte at most.
+    pub fn effective_segments(&self) -> usize {
+        match self.is_segmented() {
+            true => (self.segments() as u64).min(self.target_length()) as usize,
+            false => 1,
+        }
+    }
+
+    /// Connections the download is meant to use, `segment_target` if it was changed.
+    fn segments(&self) -> usize {
+        self.config
+            .segment_target
+            .get()
+            .unwrap_or(self.config.segments)
+    }
+
+    /// Changes the connections of a segmented download, a running one adjusts right away, see
+    /// `SegmentTarget`.
+    pub fn set_segments(&self, segments: usize) -> Result<()> {
+        if !self.is_segmented() {
+            return Err(Error::InvalidConfig(
+                "Only segmented downloads can change their number of segments".to_string(),
+            ));
+        }
+        if segments == 0 {
+            return Err(Error::InvalidConfig(
+                "A download needs at least one segment".to_string(),
+            ));
+        }
+        log::info!("Download {} continues with {} segments", self.id, segments);
+        self.config.segment_target.set(segments);
+        Ok(())
+    }
+
+    /// Runs the segmented download, if `resume` is set the progress recorded in the sidecar
+    /// metadata is picked up, otherwise (or if the sidecar can't be used) it starts from zero.
+    pub(super) async fn download_segmented(
+        &self,
+        update_ch: Sender<DownloadUpdate>,
+        resume: bool,
+        cancel: &CancellationToken,
+    ) -> Result<u64> {
+        let sidecar = self.sidecar_path();
+        let meta = match resume {
+            true => PartMeta::load(&sidecar, self.target_length()).await,
+            false => None,
+        };
+        let meta = match meta {
+            Some(meta) => {
+                log::info!(
+                    "Resuming segmented download {} at {} bytes",
+                    self.id,
+                    meta.written()
+                );
+                if meta.written() > 0 {
+                    self.stats.record_resume();
+                }
+                meta
+            }
+            None => {
+                log::info!(
+                    "Starting segmented download {} with {} segments, creating file at {:?}",
+                    self.id,
+                    self.segments(),
+                    self.download_path()
+                );
+                // Only the capped prefix is planned, so no segment requests bytes past `max_bytes`
+                let meta = PartMeta::plan(self.target_length(), self.segments());
+                let file_handler = File::create(self.download_path()).await?;
+                file_handler.set_len(self.target_length()).await?;
+                self.config.permissions.apply(&self.download_path()).await?;
+                meta.store(&sidecar).await?;
+                meta
+            }
+        };
+
+        let mut pending: VecDeque<usize> = meta
+            .segments
+            .iter()
+            .enumerate()
+            .filter(|(_, segment)| !segment.is_complete())
+            .map(|(idx, _)| idx)
+            .collect();
+        let progress = Arc::new(Mutex::new(Progress {
+            persisted_bytes: meta.written(),
+            pause_at: self.pause_threshold(meta.written()),
+            claimed: meta
+                .segments
+                .iter()
+                .map(|segment| segment.range.start + segment.written)
+                .collect(),
+            meta,
+            batch: WriteBatch::default(),
+            speed: SpeedMeter::new(),
+            last_persist: Instant::now(),
+        }));
+        let mut guard = PersistOnDrop {
+            path: sidecar.clone(),
+            progress: progress.clone(),
+            finished: false,
+        };
+        // A failing segment cancels the others, so every segment gets to flush its file
+        let segments_cancel = cancel.child_token();
+        let mut tasks = FuturesUnordered::new();
+        let mut running: Vec<usize> = Vec::new();
+        let mut errors = Vec::new();
+        loop {
+            // Claims segments up to the target, the target is read again whenever it changes.
+            // Running segments are only split once the target was changed, planned segments
+            // finishing early don't open further connections on their own.
+            while !segments_cancel.is_cancelled() && running.len() < self.segments() {
+                let next = pending.pop_front().or_else(|| {
+                    self.config.segment_target.get()?;
+                    progress.lock().unwrap().split(&running)
+                });
+                let Some(idx) = next else {
+                    break;
+                };
+                running.push(idx);
+                let (segments_cancel, progress) = (&segments_cancel, progress.clone());
+                let update_ch = update_ch.clone();
+                tasks.push(async move {
+                    let result = self
+                        .download_segment(idx, progress, update_ch, segments_cancel)
+                        .await;
+                    (idx, result)
+                });
+            }
+            if tasks.is_empty() {
+                break;
+            }
+            tokio::select! {
+                Some((idx, result)) = tasks.next() => {
+                    running.retain(|running| *running != idx);
+                    if let Err(e) = result {
+                        segments_cancel.cancel();
+                        errors.push(e);
+                    }
+                }
+                _ = self.config.segment_target.changed() => {}
+            }
+        }
+        let written = progress.lock().unwrap().meta.written();
+        if !errors.is_empty() {
+            // The error that made the other segments stop wins over their cancellations
+            return Err(errors
+                .into_iter()
+                .find(|e| !matches!(e, Error::Cancelled(_)))
+                .unwrap_or(Error::Cancelled(written)));
+        }
+
+        guard.finished = true;
+        if written != self.target_length() {
+            return Err(Error::IncompleteTransfer {
+                expected: self.target_length(),
+                written,
+            });
+        }
+        if let Err(e) = tokio::fs::remove_file(&sidecar).await {
+            log::warn!("Couldn't remove segment metadata {:?}: {}", sidecar, e);
+        }
+        self.verify(&update_ch, cancel, None).await?;
+        self.finalize(&update_ch).await?;
+        Ok(written)
+    }
+
+    /// Fetches segment `idx` from its claimed offset on until its end, which moves closer if the
+    /// segment gets split while it runs.
+    async fn download_segment(
+        &self,
+        idx: usize,
+        progress: Arc<Mutex<Progress>>,
+        update_ch: Sender<DownloadUpdate>,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        // Held until the segment is done, segments over the global limit wait for a free slot
+        let _permit = match &self.config.segment_limit {
+            Some(limit) => tokio::select! {
+                permit = limit.clone().acquire_owned() => {
+                    Some(permit.expect("The segment semaphore is never closed"))
+                }
+                _ = cancel.cancelled() => {
+                    let written = progress.lock().unwrap().meta.written();
+                    return Err(Error::Cancelled(written));
+                }
+            },
+            None => None,
+        };
+        let (range, validators) = {
+            let progress = progress.lock().unwrap();
+            if progress.meta.segments[idx].written > 0 {
+                self.stats.record_reconnect();
+            }
+            (
+                ByteRange::new(progress.claimed[idx], progress.meta.segments[idx].range.end),
+                progress.meta.validators.clone(),
+            )
+        };
+        let resp = self
+            .send_request(Some(&format!("bytes={}", range)), validators.if_range())
+            .await?;
+        let status = resp.status();
+        // The whole resource instead of the range, the other segments are of another version
+        if status == StatusCode::OK && validators.if_range().is_some() {
+            return Err(Error::ResumeValidationFailed(self.url.clone()));
+        }
+        if status == StatusCode::PARTIAL_CONTENT && validators.is_empty() {
+            let mut progress = progress.lock().unwrap();
+            if progress.meta.validators.is_empty() {
+                progress.meta.validators = Validators::from_headers(resp.headers());
+            }
+        }
+        if status != StatusCode::PARTIAL_CONTENT {
+            return Err(retry::status_error(resp).await);
+        }
+        let mut file_handler = OpenOptions::new()
+            .write(true)
+            .open(self.download_path())
+            .await?;
+        file_handler.seek(SeekFrom::Start(range.start)).await?;
+
+        let mut done = false;
+        let _connection = self.stats.open_connection();
+        let mut stream = resp.bytes_stream();
+        loop {
+            let chunk = tokio::select! {
+                chunk = stream.next() => chunk,
+                _ = cancel.cancelled() => {
+                    self.write_batch(&mut file_handler, &progress, &update_ch).await?;
+                    file_handler.flush().await?;
+                    if self.config.sync_writes {
+                        file_handler.sync_data().await?;
+                    }
+                    let written = progress.lock().unwrap().meta.written();
+                    return Err(Error::Cancelled(written));
+                }
+            };
+            let Some(chunk) = chunk else {
+                break;
+            };
+            let item = chunk?;
+            // Never write past the end of the segment, even if the server sends more or the
+            // segment was split in the meantime
+            let (offset, len) = {
+                let mut progress = progress.lock().unwrap();
+                let offset = progress.claimed[idx];
+                let len = (item.len() as u64).min(progress.unclaimed(idx));
+                progress.claimed[idx] += len;
+                done = progress.unclaimed(idx) == 0;
+                (offset, len)
+            };
+            let data = &item[..len as usize];
+            match self.config.write_batch_size {
+                Some(size) => {
+                    let due = {
+                        let mut progress = progress.lock().unwrap();
+                        progress.batch.push(idx, offset, data);
+                        progress
+                            .batch
+                            .is_due(size, self.config.persist_interval.time)
+                    };
+                    if due {
+                        self.write_batch(&mut file_handler, &progress, &update_ch)
+                            .await?;
+                    }
+                }
+                None => {
+                    file_handler.write_all(data).await?;
+                    self.account(idx, data.len() as u64, &progress, &update_ch)
+                        .await?;
+                }
+            }
+            tokio::select! {
+                _ = self.throttle(data.len()) => {}
+                _ = cancel.cancelled() => {}
+            }
+            let (written, pause_at) = {
+                let progress = progress.lock().unwrap();
+                (progress.meta.written(), progress.pause_at)
+            };
+            if pause_at.is_some_and(|pause_at| written >= pause_at)
+                && written < self.target_length()
+            {
+                log::info!("Download {} reached {} bytes, pausing it", self.id, written);
+                // The other segments stop at their next chunk
+                cancel.cancel();
+            }
+            if done {
+                break;
+            }
+        }
+        self.write_batch(&mut file_handler, &progress, &update_ch)
+            .await?;
+        file_handler.flush().await?;
+        if !done {
+            let written = progress.lock().unwrap().meta.written();
+            return Err(Error::StreamEndedBeforeCompletion(written));
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered chunks of all segments in offset order and only then counts them as
+    /// written, so the persisted progress never includes bytes still in memory.
+    async fn write_batch(
+        &self,
+        file_handler: &mut File,
+        progress: &Mutex<Progress>,
+        update_ch: &Sender<DownloadUpdate>,
+    ) -> Result<()> {
+        let chunks = progress.lock().unwrap().batch.take();
+        if chunks.is_empty() {
+            return Ok(());
+        }
+        for (offset, (_, data)) in &chunks {
+            file_handler.seek(SeekFrom::Start(*offset)).await?;
+            file_handler.write_all(data).await?;
+        }
+        file_handler.flush().await?;
+        for (idx, data) in chunks.into_values() {
+            self.account(idx, data.len() as u64, progress, update_ch)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Records bytes written by a segment and persists the metadata when it's due.
+    async fn account(
+        &self,
+        idx: usize,
+        bytes: u64,
+        progress: &Mutex<Progress>,
+        update_ch: &Sender<DownloadUpdate>,
+    ) -> Result<()> {
+        if let Some(meta) = self.record_progress(idx, bytes, progress, update_ch) {
+            meta.store(&self.sidecar_path()).await?;
+        }
+        Ok(())
+    }
+
+    /// Accounts bytes written by a segment, emits throttled updates and returns a copy of the
+    /// metadata whenever it's time to persist it.
+    fn record_progress(
+        &self,
+        idx: usize,
+        bytes: u64,
+        progress: &Mutex<Progress>,
+        update_ch: &Sender<DownloadUpdate>,
+    ) -> Option<PartMeta> {
+        let mut progress = progress.lock().unwrap();
+        progress.meta.segments[idx].written += bytes;
+        progress.speed.record(bytes);
+        let written = progress.meta.written();
+        if let Some(state) = progress.speed.tick(written) {
+            let _ = update_ch.try_send(DownloadUpdate { id: self.id, state });
+        }
+        let bytes_since = written - progress.persisted_bytes;
+        if self
+            .config
+            .persist_interval
+            .is_due(progress.last_persist.elapsed(), bytes_since)
+        {
+            progress.last_persist = Instant::now();
+            progress.persisted_bytes = written;
+            return Some(progress.meta.clone());
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    #[test]
+    fn plan_segments_test() {
+        let meta = PartMeta::plan(10, 3);
+        let ranges: Vec<ByteRange> = meta.segments.iter().map(|s| s.range).collect();
+        assert_eq!(
+            ranges,
+            vec![
+                ByteRange::new(0, 3),
+                ByteRange::new(4, 7),
+                ByteRange::new(8, 9)
+            ]
+        );
+        assert!(meta.is_consistent(10));
+        // More segments than bytes
+        assert_eq!(PartMeta::plan(2, 8).segments.len(), 2);
+    }
+
+    #[test]
+    fn remaining_range_test() {
+        let mut segment = SegmentProgress {
+            range: ByteRange::new(100, 199),
+            written: 40,
+        };
+        assert_eq!(segment.remaining(), Some(ByteRange::new(140, 199)));
+        segment.written = 100;
+        assert_eq!(segment.remaining(), None);
+    }
+
+    #[test]
+    fn contiguous_prefix_test() {
+        let mut meta = PartMeta::plan(30, 3);
+        meta.segments[1].written = 10;
+        assert_eq!(meta.contiguous(), 0);
+        meta.segments[0].written = 10;
+        meta.segments[2].written = 4;
+        assert_eq!(meta.contiguous(), 24);
+    }
+
+    #[test]
+    fn write_batch_merges_and_sorts_chunks_test() {
+        // given
+        let mut batch = WriteBatch::default();
+        // when
+        batch.push(1, 100, b"cc");
+        batch.push(0, 0, b"aa");
+        batch.push(0, 2, b"bb");
+        batch.push(1, 102, b"dd");
+        // then
+        assert_eq!(batch.size, 8);
+        assert!(batch.is_due(8, std::time::Duration::from_secs(60)));
+        assert!(!batch.is_due(9, std::time::Duration::from_secs(60)));
+        let chunks: Vec<(u64, usize, Vec<u8>)> = batch
+            .take()
+            .into_iter()
+            .map(|(offset, (idx, data))| (offset, idx, data))
+            .collect();
+        assert_eq!(
+            chunks,
+            vec![(0, 0, b"aabb".to_vec()), (100, 1, b"ccdd".to_vec())]
+        );
+        assert_eq!(batch.size, 0);
+        assert!(!batch.is_due(1, std::time::Duration::ZERO));
+    }
+
+    #[test]
+    fn split_gives_half_of_largest_unclaimed_rest_test() {
+        // given two running segments, the second one with more left to fetch
+        let mut progress = Progress {
+            meta: PartMeta::plan(2_000_000, 2),
+            claimed: vec![900_000, 1_000_000],
+            batch: WriteBatch::default(),
+            speed: SpeedMeter::new(),
+            last_persist: Instant::now(),
+            persisted_bytes: 0,
+            pause_at: None,
+        };
+        // when
+        let split = progress.split(&[0, 1]);
+        // then
+        assert_eq!(split, Some(2));
+        assert_eq!(
+            progress.meta.segments[1].range,
+            ByteRange::new(1_000_000, 1_499_999)
+        );
+        assert_eq!(
+            progress.meta.segments[2].range,
+            ByteRange::new(1_500_000, 1_999_999)
+        );
+        assert_eq!(progress.claimed[2], 1_500_000);
+        // a rest smaller than two splits isn't split
+        progress.claimed = vec![999_999, 1_499_999, 1_999_999];
+        assert_eq!(progress.split(&[0, 1, 2]), None);
+        assert_eq!(progress.split(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn sidecar_roundtrip_and_corruption_test() -> anyhow::Result<()> {
+        // given
+        let tmp_dir = TempDir::new()?;
+        let path = tmp_dir.path().join("file.bin.part.meta");
+        let mut meta = PartMeta::plan(1000, 4);
+        meta.segments[1].written = 100;
+        // when
+        meta.store(&path).await?;
+        // then
+        assert_eq!(PartMeta::load(&path, 1000).await, Some(meta));
+        // a different content length invalidates the sidecar
+        assert_eq!(PartMeta::load(&path, 999).await, None);
+        // corrupt or missing sidecars are not used
+        tokio::fs::write(&path, b"{\"content_length\": 10").await?;
+        assert_eq!(PartMeta::load(&path, 1000).await, None);
+        assert_eq!(
+            PartMeta::load(&tmp_dir.path().join("missing"), 1000).await,
+            None
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use tokio::fs::OpenOptions;
+use tokio::sync::mpsc::Sender;
+use tokio_util::sync::CancellationToken;
+
+use super::{ByteRange, DownloadUpdate, Error, HttpDownload, Result};
+
+/// Sorts the ranges and merges overlapping or adjacent ones so no byte is requested twice.
+pub fn normalize_ranges(ranges: &[ByteRange]) -> Vec<ByteRange> {
+    let mut sorted = ranges.to_vec();
+    sorted.sort_by_key(|range| range.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(sorted.len());
+    for range in sorted {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end)
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+impl HttpDownload {
+    /// Ranges a sparse download is limited to, None if the whole file is downloaded.
+    pub fn sparse_ranges(&self) -> Option<&[ByteRange]> {
+        self.config
+            .ranges
+            .as_deref()
+            .filter(|ranges| !ranges.is_empty())
+    }
+
+    /// Checks the configured ranges against the probed server, they have to be within the file
+    /// and the server has to support byte ranges. The ranges are normalized on success.
+    pub(super) fn validate_ranges(&mut self) -> Result<()> {
+        let Some(ranges) = self.sparse_ranges() else {
+            return Ok(());
+        };
+        if !self.supports_byte_ranges {
+            return Err(Error::InvalidConfig(format!(
+                "{} doesn't support byte ranges, can't download only some ranges",
+                self.url
+            )));
+        }
+        if let Some(range) = ranges
+            .iter()
+            .find(|range| range.is_empty() || range.end >= self.content_length)
+        {
+            return Err(Error::InvalidConfig(format!(
+                "range {} isn't within the {} bytes of the file",
+                range, self.content_length
+            )));
+        }
+        if self.config.max_bytes.is_some_and(|cap| cap > 0)
+            || self.split_size().is_some()
+            || self.config.compression
+        {
+            return Err(Error::InvalidConfig(
+                "ranges can't be combined with max_bytes, split_size or compression".to_string(),
+            ));
+        }
+        self.config.ranges = Some(normalize_ranges(ranges));
+        Ok(())
+    }
+
+    /// Sum of the sizes of the requested ranges, what the progress of a sparse download counts
+    /// up to.
+    pub fn sparse_length(&self) -> Option<u64> {
+        self.sparse_ranges()
+            .map(|ranges| ranges.iter().map(ByteRange::len).sum())
+    }
+
+    /// Fetches all requested ranges into a sparse file of the full size, a resumed sparse
+    /// download fetches them all again.
+    pub(super) async fn download_sparse(
+        &self,
+        ranges: &[ByteRange],
+        update_ch: Sender<DownloadUpdate>,
+        cancel: &CancellationToken,
+    ) -> Result<u64> {
+        let written = tokio::select! {
+            written = self.fetch_ranges(ranges, update_ch.clone(), cancel) => written?,
+            _ = cancel.cancelled() => {
+                log::info!("Sparse download {} was cancelled", self.id);
+                return Err(Error::Cancelled(0));
+            }
+        };
+        let expected = self.sparse_length().unwrap_or_default();
+        if written != expected {
+            return Err(Error::IncompleteTransfer { expected, written });
+        }
+        // The holes between the ranges aren't allocated on filesystems with sparse file support
+        let file = OpenOptions::new()
+            .write(true)
+            .open(self.download_path())
+            .await?;
+        file.set_len(self.content_length).await?;
+        drop(file);
+        self.verify(&update_ch, cancel, None).await?;
+        self.finalize(&update_ch).await?;
+        log::info!(
+            "Sparse download completed: {}, {} ranges, {} bytes",
+            self.url,
+            ranges.len(),
+            written
+        );
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn normalize_ranges_test() {
+        let ranges = [
+            ByteRange::new(100, 199),
+            ByteRange::new(0, 9),
+            ByteRange::new(150, 249),
+            ByteRange::new(10, 19),
+        ];
+        assert_eq!(
+            normalize_ranges(&ranges),
+            vec![ByteRange::new(0, 19), ByteRange::new(100, 249)]
+        );
+    }
+}
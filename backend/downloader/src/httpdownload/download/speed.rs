@@ -0,0 +1,103 @@
+use std::time::{Duration, Instant};
+
+use crate::util::HALF_SECOND;
+
+use super::State;
+
+/// Tracks the bytes written by a download task and produces throttled `State::Running` updates.
+/// The instantaneous speed is measured over the last update interval (at least HALF_SECOND), the
+/// average speed over the whole session since the task started.
+#[derive(Debug)]
+pub struct SpeedMeter {
+    started: Instant,
+    session_bytes: u64,
+    last_update: Instant,
+    interval_bytes: u64,
+}
+
+impl Default for SpeedMeter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpeedMeter {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            session_bytes: 0,
+            last_update: now,
+            interval_bytes: 0,
+        }
+    }
+
+    pub fn record(&mut self, bytes: u64) {
+        self.session_bytes += bytes;
+        self.interval_bytes += bytes;
+    }
+
+    /// Returns the running state if the update interval elapsed, `bytes_downloaded` is the total
+    /// progress of the download including bytes from previous sessions.
+    pub fn tick(&mut self, bytes_downloaded: u64) -> Option<State> {
+        let elapsed = self.last_update.elapsed();
+        if elapsed <= HALF_SECOND {
+            return None;
+        }
+        let state = State::Running {
+            bytes_downloaded,
+            bytes_per_second: per_second(self.interval_bytes, elapsed),
+            average_bytes_per_second: per_second(self.session_bytes, self.started.elapsed()),
+        };
+        self.last_update = Instant::now();
+        self.interval_bytes = 0;
+        Some(state)
+    }
+}
+
+fn per_second(bytes: u64, elapsed: Duration) -> u64 {
+    match elapsed.as_millis() as u64 {
+        0 => 0,
+        millis => bytes * 1000 / millis,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn instant_and_average_speed_test() {
+        // given a session that started 4 seconds ago with the last update 1 second ago
+        let now = Instant::now();
+        let mut meter = SpeedMeter {
+            started: now - Duration::from_secs(4),
+            session_bytes: 1000,
+            last_update: now - Duration::from_secs(1),
+            interval_bytes: 0,
+        };
+        // when
+        meter.record(3000);
+        let state = meter.tick(10_000);
+        // then
+        let Some(State::Running {
+            bytes_downloaded,
+            bytes_per_second,
+            average_bytes_per_second,
+        }) = state
+        else {
+            panic!("Expected a running state, got {:?}", state);
+        };
+        assert_eq!(bytes_downloaded, 10_000);
+        assert!(
+            (2900..=3000).contains(&bytes_per_second),
+            "{bytes_per_second}"
+        );
+        assert!(
+            (980..=1000).contains(&average_bytes_per_second),
+            "{average_bytes_per_second}"
+        );
+        // the interval restarts after an update
+        assert!(meter.tick(10_000).is_none());
+    }
+}
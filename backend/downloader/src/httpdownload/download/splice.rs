@@ -0,0 +1,140 @@
+use reqwest::{Client, Url};
+use std::path::Path;
+use tokio::fs::OpenOptions;
+
+use super::config::HttpDownloadConfig;
+use super::{Error, HttpDownload, Result};
+use crate::util::file_size;
+
+impl HttpDownload {
+    /// Creates a download that continues writing `path` at byte `offset`, e.g. a file another
+    /// tool started or one recovered by hand. The offset is trusted: the file is cut to `offset`
+    /// bytes (what followed would be overwritten anyway) so resuming the download requests
+    /// `bytes=<offset>-` and appends. The file is written in place under its own name, so the
+    /// temp directory, extension inference, segments, splitting and ranges of `config` don't
+    /// apply.
+    pub async fn splice(
+        url: Url,
+        path: &Path,
+        offset: u64,
+        client: Client,
+        mut config: HttpDownloadConfig,
+    ) -> Result<Self> {
+        let (Some(directory), Some(filename)) = (
+            path.parent(),
+            path.file_name().and_then(|name| name.to_str()),
+        ) else {
+            return Err(Error::InvalidConfig(format!(
+                "{:?} isn't a path to a file",
+                path
+            )));
+        };
+        config.temp_dir = None;
+        config.infer_extension = false;
+        config.segments = 1;
+        config.split_size = None;
+        config.ranges = None;
+        config.compression = false;
+        let download = HttpDownload::builder()
+            .url(url)
+            .directory(directory)
+            .filename(filename)
+            .client(client)
+            .config(config)
+            .build()
+            .await?;
+        if offset > download.target_length() {
+            return Err(Error::InvalidConfig(format!(
+                "offset {} is past the end of the download ({} bytes)",
+                offset,
+                download.target_length()
+            )));
+        }
+        if offset > 0 && !download.supports_byte_ranges {
+            return Err(Error::InvalidConfig(format!(
+                "{} doesn't support byte ranges, can't continue at {} bytes",
+                download.url, offset
+            )));
+        }
+        let on_disk = file_size(path).await;
+        if on_disk < offset {
+            return Err(Error::InvalidConfig(format!(
+                "{:?} has only {} bytes, can't continue at {}",
+                path, on_disk, offset
+            )));
+        }
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .await?;
+        file.set_len(offset).await?;
+        log::info!(
+            "Download {} continues {:?} at {} bytes",
+            download.id,
+            path,
+            offset
+        );
+        Ok(download)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::httpdownload::download::DownloadUpdate;
+    use crate::util::mock::{MockConfig, MockServer};
+    use pretty_assertions::assert_eq;
+    use reqwest::header::RANGE;
+    use tempfile::TempDir;
+    use test_log::test;
+    use tokio::sync::mpsc;
+
+    #[test(tokio::test)]
+    async fn splice_continues_file_at_offset_test() -> anyhow::Result<()> {
+        // given a file another tool wrote 1000 correct bytes and some garbage to
+        let server = MockServer::start(MockConfig::default()).await;
+        let payload = server.payload();
+        let tmp_dir = TempDir::new()?;
+        let path = tmp_dir.path().join("other-tool.partial");
+        let mut content = payload[..1000].to_vec();
+        content.extend_from_slice(b"garbage");
+        tokio::fs::write(&path, &content).await?;
+        // when
+        let download = HttpDownload::splice(
+            server.url("file.bin"),
+            &path,
+            1000,
+            Client::new(),
+            HttpDownloadConfig::default(),
+        )
+        .await?;
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        download.resume(update_sender).await?;
+        // then
+        assert_eq!(download.file_path(), path);
+        assert_eq!(tokio::fs::read(&path).await?, *payload);
+        assert_eq!(
+            server
+                .requests()
+                .last()
+                .unwrap()
+                .headers
+                .get(RANGE)
+                .unwrap(),
+            "bytes=1000-"
+        );
+        // an offset past the bytes on disk is refused
+        let result = HttpDownload::splice(
+            server.url("file.bin"),
+            &tmp_dir.path().join("missing"),
+            10,
+            Client::new(),
+            HttpDownloadConfig::default(),
+        )
+        .await;
+        assert!(matches!(result, Err(Error::InvalidConfig(_))));
+        Ok(())
+    }
+}
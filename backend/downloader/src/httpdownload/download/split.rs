@@ -0,0 +1,325 @@
+use serde::{Deserialize, Serialize};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc::Sender;
+
+use super::config::FilePermissions;
+use super::moving::move_file;
+use super::tee::Mirrors;
+use super::{DownloadUpdate, HttpDownload, Result};
+use crate::util::file_size;
+
+pub const MANIFEST_EXTENSION: &str = "manifest";
+
+/// Path of the `idx`th (zero based) part of a split file, parts are numbered from `.001`.
+pub fn part_path(base: &Path, idx: u64) -> PathBuf {
+    let mut path = base.to_owned().into_os_string();
+    path.push(format!(".{:03}", idx + 1));
+    PathBuf::from(path)
+}
+
+/// Lists the parts of a split download in `manifest` next to the parts, used to reassemble the
+/// file by concatenating the parts in order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SplitManifest {
+    pub filename: String,
+    pub total_size: u64,
+    pub part_size: u64,
+    pub parts: Vec<SplitPart>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SplitPart {
+    pub name: String,
+    pub size: u64,
+}
+
+impl SplitManifest {
+    pub fn new(filename: &str, total_size: u64, part_size: u64) -> Self {
+        let parts = (0..total_size.div_ceil(part_size))
+            .map(|idx| SplitPart {
+                name: format!("{}.{:03}", filename, idx + 1),
+                size: part_size.min(total_size - idx * part_size),
+            })
+            .collect();
+        Self {
+            filename: filename.to_owned(),
+            total_size,
+            part_size,
+            parts,
+        }
+    }
+}
+
+/// Writes a stream of bytes into numbered part files of at most `part_size` bytes each, rolling
+/// over to the next part once one is full.
+pub struct SplitWriter {
+    base: PathBuf,
+    part_size: u64,
+    /// Logical offset in the whole file
+    offset: u64,
+    /// Index of the part `file` writes to
+    part_idx: u64,
+    file: File,
+    permissions: FilePermissions,
+}
+
+impl SplitWriter {
+    /// Opens the writer at the logical `offset`, the part containing it is truncated to where the
+    /// offset points and any later parts are removed.
+    pub async fn open(
+        base: PathBuf,
+        part_size: u64,
+        offset: u64,
+        permissions: FilePermissions,
+    ) -> Result<Self> {
+        let idx = offset / part_size;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(part_path(&base, idx))
+            .await?;
+        permissions.apply(&part_path(&base, idx)).await?;
+        file.set_len(offset % part_size).await?;
+        let mut stale = idx + 1;
+        while tokio::fs::remove_file(part_path(&base, stale))
+            .await
+            .is_ok()
+        {
+            stale += 1;
+        }
+        file.seek(SeekFrom::End(0)).await?;
+        Ok(Self {
+            base,
+            part_size,
+            offset,
+            part_idx: idx,
+            file,
+            permissions,
+        })
+    }
+
+    pub async fn write_all(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let idx = self.offset / self.part_size;
+            if idx != self.part_idx {
+                self.file.flush().await?;
+                self.file = File::create(part_path(&self.base, idx)).await?;
+                self.permissions.apply(&part_path(&self.base, idx)).await?;
+                self.part_idx = idx;
+            }
+            let room = self.part_size - self.offset % self.part_size;
+            let len = (data.len() as u64).min(room) as usize;
+            self.file.write_all(&data[..len]).await?;
+            self.offset += len as u64;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> Result<()> {
+        self.file.flush().await?;
+        Ok(())
+    }
+
+    /// Syncs the part written to right now.
+    pub async fn sync_data(&mut self) -> Result<()> {
+        self.file.sync_data().await?;
+        Ok(())
+    }
+}
+
+/// Sum of the sizes of the consecutive parts of `base` on disk.
+pub async fn split_size_on_disk(base: &Path) -> u64 {
+    let mut total = 0;
+    let mut idx = 0;
+    while tokio::fs::try_exists(part_path(base, idx))
+        .await
+        .unwrap_or(false)
+    {
+        total += file_size(&part_path(base, idx)).await;
+        idx += 1;
+    }
+    total
+}
+
+/// Where downloaded bytes go, a single file (possibly copied to mirrors) or numbered parts.
+pub(super) enum Output {
+    Single(File),
+    Mirrored(File, Mirrors),
+    Split(SplitWriter),
+}
+
+impl Output {
+    pub async fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        match self {
+            Output::Single(file) => file.write_all(data).await?,
+            Output::Mirrored(file, mirrors) => {
+                file.write_all(data).await?;
+                mirrors.write_all(data).await?;
+            }
+            Output::Split(writer) => writer.write_all(data).await?,
+        }
+        Ok(())
+    }
+
+    /// Flushes and, if `sync` is set, waits until the written data reached the disk.
+    pub async fn flush_synced(&mut self, sync: bool) -> Result<()> {
+        match self {
+            Output::Single(file) => {
+                file.flush().await?;
+                if sync {
+                    file.sync_data().await?;
+                }
+            }
+            Output::Mirrored(file, mirrors) => {
+                file.flush().await?;
+                if sync {
+                    file.sync_data().await?;
+                }
+                mirrors.flush(sync).await?;
+            }
+            Output::Split(writer) => {
+                writer.flush().await?;
+                if sync {
+                    writer.sync_data().await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl HttpDownload {
+    /// Maximum size of a part file, None if the download is written to a single file.
+    pub fn split_size(&self) -> Option<u64> {
+        self.config.split_size.filter(|size| *size > 0)
+    }
+
+    /// Location of the manifest of a split download.
+    pub fn manifest_path(&self) -> PathBuf {
+        let mut path = self.file_path().into_os_string();
+        path.push(format!(".{}", MANIFEST_EXTENSION));
+        PathBuf::from(path)
+    }
+
+    /// Paths of all files of a split download (parts in the download and final location and the
+    /// manifest), empty if the download isn't split.
+    pub fn split_files(&self) -> Vec<PathBuf> {
+        let Some(part_size) = self.split_size() else {
+            return Vec::new();
+        };
+        let parts = self.target_length().div_ceil(part_size);
+        let mut files: Vec<PathBuf> = (0..parts)
+            .flat_map(|idx| {
+                [
+                    part_path(&self.download_path(), idx),
+                    part_path(&self.file_path(), idx),
+                ]
+            })
+            .collect();
+        files.dedup();
+        files.push(self.manifest_path());
+        files
+    }
+
+    /// Opens the output for writing at `offset`, a new download starts at 0.
+    pub(super) async fn open_output(&self, offset: u64) -> Result<Output> {
+        Ok(match self.split_size() {
+            Some(part_size) => Output::Split(
+                SplitWriter::open(
+                    self.download_path(),
+                    part_size,
+                    offset,
+                    self.config.permissions,
+                )
+                .await?,
+            ),
+            None => {
+                let file = match offset {
+                    0 => {
+                        let file = File::create(self.download_path()).await?;
+                        self.config.permissions.apply(&self.download_path()).await?;
+                        file
+                    }
+                    _ => {
+                        OpenOptions::new()
+                            .append(true)
+                            .open(self.download_path())
+                            .await?
+                    }
+                };
+                match self.config.mirrors.is_empty() {
+                    true => Output::Single(file),
+                    false => Output::Mirrored(file, self.open_mirrors(offset).await?),
+                }
+            }
+        })
+    }
+
+    /// Moves the parts to the final directory and writes the manifest next to them.
+    pub(super) async fn finalize_split(
+        &self,
+        part_size: u64,
+        update_ch: &Sender<DownloadUpdate>,
+    ) -> Result<()> {
+        let manifest = SplitManifest::new(&self.filename, self.target_length(), part_size);
+        if self.download_path() != self.file_path() {
+            let mut progress = self.move_progress(update_ch).await?;
+            for idx in 0..manifest.parts.len() as u64 {
+                let from = part_path(&self.download_path(), idx);
+                let to = part_path(&self.file_path(), idx);
+                if move_file(&from, &to, &mut progress).await? {
+                    self.config.permissions.apply(&to).await?;
+                }
+            }
+        }
+        let raw = serde_json::to_vec_pretty(&manifest).expect("Manifest serialization can't fail");
+        tokio::fs::write(self.manifest_path(), raw).await?;
+        self.config.permissions.apply(&self.manifest_path()).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn writer_rolls_over_and_reopens_test() -> anyhow::Result<()> {
+        // given
+        let tmp_dir = TempDir::new()?;
+        let base = tmp_dir.path().join("file.bin");
+        let mut writer = SplitWriter::open(base.clone(), 4, 0, FilePermissions::default()).await?;
+        // when
+        writer.write_all(b"0123").await?;
+        writer.write_all(b"456789").await?;
+        writer.flush().await?;
+        // then
+        assert_eq!(tokio::fs::read(part_path(&base, 0)).await?, b"0123");
+        assert_eq!(tokio::fs::read(part_path(&base, 1)).await?, b"4567");
+        assert_eq!(tokio::fs::read(part_path(&base, 2)).await?, b"89");
+        assert_eq!(split_size_on_disk(&base).await, 10);
+        // when reopening in the middle of the second part
+        let mut writer = SplitWriter::open(base.clone(), 4, 6, FilePermissions::default()).await?;
+        writer.write_all(b"ab").await?;
+        writer.flush().await?;
+        // then
+        assert_eq!(tokio::fs::read(part_path(&base, 1)).await?, b"45ab");
+        assert!(!part_path(&base, 2).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn manifest_lists_parts_test() {
+        let manifest = SplitManifest::new("file.bin", 10, 4);
+        let sizes: Vec<u64> = manifest.parts.iter().map(|part| part.size).collect();
+        assert_eq!(sizes, vec![4, 4, 2]);
+        assert_eq!(manifest.parts[2].name, "file.bin.003");
+    }
+}
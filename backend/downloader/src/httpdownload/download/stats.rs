@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Diagnostics accumulated by the download task over all runs of a download. The task only holds
+/// a read lock on the download, hence the atomics.
+#[derive(Debug, Default)]
+pub struct DownloadStats {
+    retries: AtomicU32,
+    resumes: AtomicU32,
+    active_ms: AtomicU64,
+    requests: AtomicU32,
+    reconnects: AtomicU32,
+    open_connections: AtomicU32,
+    last_ttfb_ms: AtomicU64,
+    total_ttfb_ms: AtomicU64,
+}
+
+impl Clone for DownloadStats {
+    fn clone(&self) -> Self {
+        DownloadStats {
+            retries: AtomicU32::new(self.retries()),
+            resumes: AtomicU32::new(self.resumes()),
+            active_ms: AtomicU64::new(self.active_ms()),
+            requests: AtomicU32::new(self.requests.load(Ordering::Relaxed)),
+            reconnects: AtomicU32::new(self.reconnects.load(Ordering::Relaxed)),
+            // Connections belong to the running download, not to a copy of it
+            open_connections: AtomicU32::new(0),
+            last_ttfb_ms: AtomicU64::new(self.last_ttfb_ms.load(Ordering::Relaxed)),
+            total_ttfb_ms: AtomicU64::new(self.total_ttfb_ms.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Connection level view of a download, see `HttpDownload::diagnostics`. The time to first byte
+/// spans sending the request until the response headers arrived, it includes DNS, connect and
+/// TLS time which the http client doesn't report separately.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DownloadDiagnostics {
+    /// Data requests sent over all runs, segments and retries included
+    pub requests: u32,
+    /// Requests continuing a transfer (or segment) that already had bytes on disk, e.g. after a
+    /// pause, a dropped connection or a retry
+    pub reconnects: u32,
+    /// Responses whose body is being read right now, one per running segment
+    pub open_connections: u32,
+    pub last_ttfb_ms: Option<u64>,
+    pub average_ttfb_ms: Option<u64>,
+    pub retries: u32,
+    pub active_duration_ms: u64,
+    /// Effective idle timeout of the download's connections, see
+    /// `HttpDownloadConfig::idle_timeout`
+    pub idle_timeout_ms: Option<u64>,
+}
+
+impl DownloadStats {
+    /// Retries done transparently by the download, like restarts after a checksum mismatch or
+    /// requests repeated against a refreshed url.
+    pub fn retries(&self) -> u32 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// Runs and retries that continued from bytes already on disk instead of starting over, zero
+    /// for a download fetched in one go.
+    pub fn resumes(&self) -> u32 {
+        self.resumes.load(Ordering::Relaxed)
+    }
+
+    /// Time spent running, paused time isn't counted.
+    pub fn active_ms(&self) -> u64 {
+        self.active_ms.load(Ordering::Relaxed)
+    }
+
+    pub fn diagnostics(&self) -> DownloadDiagnostics {
+        let requests = self.requests.load(Ordering::Relaxed);
+        DownloadDiagnostics {
+            requests,
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            open_connections: self.open_connections.load(Ordering::Relaxed),
+            last_ttfb_ms: (requests > 0).then(|| self.last_ttfb_ms.load(Ordering::Relaxed)),
+            average_ttfb_ms: (requests > 0)
+                .then(|| self.total_ttfb_ms.load(Ordering::Relaxed) / requests as u64),
+            retries: self.retries(),
+            active_duration_ms: self.active_ms(),
+            idle_timeout_ms: None,
+        }
+    }
+
+    pub(super) fn record_retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Continues the count of a download saved before a restart, see `DownloadManager::restore`.
+    pub fn restore_resumes(&self, resumes: u32) {
+        self.resumes.store(resumes, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_resume(&self) {
+        self.resumes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_active(&self, duration: Duration) {
+        self.active_ms
+            .fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_request(&self, ttfb: Duration) {
+        let ttfb_ms = ttfb.as_millis() as u64;
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.last_ttfb_ms.store(ttfb_ms, Ordering::Relaxed);
+        self.total_ttfb_ms.fetch_add(ttfb_ms, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts an open connection until the returned guard is dropped.
+    pub(super) fn open_connection(&self) -> OpenConnection<'_> {
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        OpenConnection(&self.open_connections)
+    }
+}
+
+pub(super) struct OpenConnection<'a>(&'a AtomicU32);
+
+impl Drop for OpenConnection<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
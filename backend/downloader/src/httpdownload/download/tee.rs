@@ -0,0 +1,204 @@
+use serde::{Deserialize, Serialize};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use uuid::Uuid;
+
+use super::{Error, HttpDownload, Result};
+use crate::util::file_size;
+
+/// What a download does when one of its mirrors can't be written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MirrorFailure {
+    /// The download fails with `Error::MirrorFailed`
+    #[default]
+    Fail,
+    /// The mirror is given up, the download continues with the remaining destinations
+    Drop,
+}
+
+/// Files every written chunk of a download is copied to besides its own file.
+pub(super) struct Mirrors {
+    download_id: Uuid,
+    files: Vec<(PathBuf, File)>,
+    on_failure: MirrorFailure,
+}
+
+impl Mirrors {
+    pub async fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        let mut idx = 0;
+        while idx < self.files.len() {
+            match self.files[idx].1.write_all(data).await {
+                Ok(()) => idx += 1,
+                Err(e) => self.failed(idx, e)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn flush(&mut self, sync: bool) -> Result<()> {
+        let mut idx = 0;
+        while idx < self.files.len() {
+            let file = &mut self.files[idx].1;
+            let mut result = file.flush().await;
+            if sync && result.is_ok() {
+                result = file.sync_data().await;
+            }
+            match result {
+                Ok(()) => idx += 1,
+                Err(e) => self.failed(idx, e)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the mirror at `idx`, see `give_up`.
+    fn failed(&mut self, idx: usize, e: std::io::Error) -> Result<()> {
+        let (path, _) = self.files.remove(idx);
+        self.give_up(path, e)
+    }
+
+    /// Fails unless the policy is to drop failing mirrors.
+    fn give_up(&self, path: PathBuf, e: std::io::Error) -> Result<()> {
+        match self.on_failure {
+            MirrorFailure::Fail => Err(Error::MirrorFailed(path, e)),
+            MirrorFailure::Drop => {
+                log::warn!(
+                    "Dropping mirror {:?} of download {}: {}",
+                    path,
+                    self.download_id,
+                    e
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+impl HttpDownload {
+    /// Mirrors are written sequentially next to the download's own file, they can't be combined
+    /// with layouts that write out of order or rewrite parts of the file.
+    pub(super) fn validate_mirrors(&self) -> Result<()> {
+        let mirrors = &self.config.mirrors;
+        if mirrors.is_empty() {
+            return Ok(());
+        }
+        if self.split_size().is_some()
+            || self.sparse_ranges().is_some()
+            || self.config.pieces.is_some()
+        {
+            return Err(Error::InvalidConfig(
+                "mirrors can't be combined with split_size, ranges or piece hashes".to_string(),
+            ));
+        }
+        if let Some(path) = mirrors
+            .iter()
+            .find(|path| self.target_paths().contains(path))
+        {
+            return Err(Error::InvalidConfig(format!(
+                "mirror {:?} is the download's own file",
+                path
+            )));
+        }
+        Ok(())
+    }
+
+    /// Opens the mirrors at `offset`. A mirror must hold at least `offset` bytes to be continued,
+    /// anything past it is cut off.
+    pub(super) async fn open_mirrors(&self, offset: u64) -> Result<Mirrors> {
+        let mut mirrors = Mirrors {
+            download_id: self.id,
+            files: Vec::new(),
+            on_failure: self.config.mirror_failure,
+        };
+        for path in &self.config.mirrors {
+            match self.open_mirror(path, offset).await {
+                Ok(file) => mirrors.files.push((path.clone(), file)),
+                Err(e) => mirrors.give_up(path.clone(), e)?,
+            }
+        }
+        Ok(mirrors)
+    }
+
+    async fn open_mirror(&self, path: &Path, offset: u64) -> std::io::Result<File> {
+        if offset == 0 {
+            let file = File::create(path).await?;
+            self.config.permissions.apply(path).await?;
+            return Ok(file);
+        }
+        let size = file_size(path).await;
+        if size < offset {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("holds {} bytes, the download continues at {}", size, offset),
+            ));
+        }
+        let mut file = OpenOptions::new().write(true).open(path).await?;
+        file.set_len(offset).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        Ok(file)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::httpdownload::download::DownloadUpdate;
+    use crate::util::mock::{MockConfig, MockServer};
+    use crate::util::setup_test_download;
+    use test_log::test;
+    use tokio::sync::mpsc;
+
+    #[test(tokio::test)]
+    async fn chunks_are_written_to_all_mirrors_test() -> anyhow::Result<()> {
+        // given
+        let server = MockServer::start(MockConfig::default()).await;
+        let payload = server.payload();
+        let (mut download, tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let mirror = tmp_dir.path().join("mirror.bin");
+        download.config.segments = 4;
+        download.config.mirrors = vec![mirror.clone()];
+        download.config.pause_at = Some(100_000);
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        // when
+        let result = download.start(update_sender.clone()).await;
+        // then the mirror is at the same offset, mirrored downloads aren't segmented
+        assert!(matches!(result, Err(Error::Cancelled(100_000))));
+        assert_eq!(file_size(&mirror).await, 100_000);
+        // when resumed with a mirror that got ahead
+        tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&mirror)
+            .await?
+            .write_all(b"garbage")
+            .await?;
+        download.resume(update_sender).await?;
+        // then
+        assert_eq!(tokio::fs::read(download.file_path()).await?, *payload);
+        assert_eq!(tokio::fs::read(&mirror).await?, *payload);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn failing_mirror_is_dropped_or_fails_test() -> anyhow::Result<()> {
+        // given a mirror in a directory that doesn't exist
+        let server = MockServer::start(MockConfig::default()).await;
+        let (mut download, tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let unwritable = tmp_dir.path().join("missing").join("mirror.bin");
+        let mirror = tmp_dir.path().join("mirror.bin");
+        download.config.mirrors = vec![unwritable.clone(), mirror.clone()];
+        let (update_sender, _) = mpsc::channel::<DownloadUpdate>(1000);
+        // when
+        let result = download.start(update_sender.clone()).await;
+        // then
+        assert!(matches!(result, Err(Error::MirrorFailed(path, _)) if path == unwritable));
+        // when
+        download.config.mirror_failure = MirrorFailure::Drop;
+        download.start(update_sender).await?;
+        // then
+        assert_eq!(tokio::fs::read(&mirror).await?, *server.payload());
+        Ok(())
+    }
+}
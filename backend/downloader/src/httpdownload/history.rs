@@ -0,0 +1,232 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use super::download::{ErrorEvent, State};
+use super::DownloadUpdateSubscriber;
+
+pub const DEFAULT_ENTRIES_PER_DOWNLOAD: usize = 100;
+pub const DEFAULT_TOTAL_ENTRIES: usize = 10_000;
+
+/// Something that happened to a download, kept in its history.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum HistoryEvent {
+    /// The download changed its state, progress updates within a state aren't recorded
+    State {
+        state: String,
+    },
+    Error(ErrorEvent),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Milliseconds since the unix epoch
+    pub at_ms: u64,
+    pub event: HistoryEvent,
+}
+
+/// Limits of the retained history entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryLimits {
+    /// Entries of a single download, its oldest entry is dropped for a new one
+    pub per_download: usize,
+    /// Entries of all downloads together, once it's exceeded the oldest entries of the least
+    /// recently used download are dropped
+    pub total: usize,
+}
+
+impl Default for HistoryLimits {
+    fn default() -> Self {
+        Self {
+            per_download: DEFAULT_ENTRIES_PER_DOWNLOAD,
+            total: DEFAULT_TOTAL_ENTRIES,
+        }
+    }
+}
+
+/// Recent events (state changes and errors) of every download. Memory stays bounded by a ring
+/// buffer per download and a global cap with LRU eviction across downloads, a download is used
+/// when an event is recorded for it or its history is read.
+#[derive(Debug, Clone, Default)]
+pub struct EventHistory {
+    inner: Arc<Mutex<HistoryInner>>,
+}
+
+#[derive(Debug, Default)]
+struct HistoryInner {
+    limits: HistoryLimits,
+    downloads: HashMap<Uuid, DownloadHistory>,
+    total: usize,
+    /// Incremented on every use, the download with the lowest `last_used` is evicted first
+    clock: u64,
+}
+
+#[derive(Debug, Default)]
+struct DownloadHistory {
+    entries: VecDeque<HistoryEntry>,
+    /// Name of the last recorded state, kept after its entry was evicted
+    last_state: Option<&'static str>,
+    last_used: u64,
+}
+
+impl EventHistory {
+    pub fn new(limits: HistoryLimits) -> Self {
+        let history = Self::default();
+        history.set_limits(limits);
+        history
+    }
+
+    /// Applies to entries recorded from now on.
+    pub fn set_limits(&self, limits: HistoryLimits) {
+        self.inner.lock().unwrap().limits = limits;
+    }
+
+    /// Retained events of the download, oldest first.
+    pub fn events(&self, id: &Uuid) -> Vec<HistoryEntry> {
+        let mut inner = self.inner.lock().unwrap();
+        let clock = inner.tick();
+        match inner.downloads.get_mut(id) {
+            Some(history) => {
+                history.last_used = clock;
+                history.entries.iter().cloned().collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Drops the history of a removed download.
+    pub fn forget(&self, id: &Uuid) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(history) = inner.downloads.remove(id) {
+            inner.total -= history.entries.len();
+        }
+    }
+
+    /// Records the state unless it's the state the download already was in.
+    pub fn record_state(&self, id: Uuid, state: &State) {
+        let mut inner = self.inner.lock().unwrap();
+        let history = inner.downloads.entry(id).or_default();
+        if history.last_state == Some(state.name()) {
+            return;
+        }
+        history.last_state = Some(state.name());
+        let event = HistoryEvent::State {
+            state: state.name().to_owned(),
+        };
+        inner.record(id, event);
+    }
+
+    fn record_error(&self, event: &ErrorEvent) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.record(event.id, HistoryEvent::Error(event.clone()));
+    }
+}
+
+impl HistoryInner {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn record(&mut self, id: Uuid, event: HistoryEvent) {
+        let clock = self.tick();
+        let per_download = self.limits.per_download;
+        let history = self.downloads.entry(id).or_default();
+        history.last_used = clock;
+        history.entries.push_back(HistoryEntry {
+            at_ms: now_ms(),
+            event,
+        });
+        self.total += 1;
+        while history.entries.len() > per_download {
+            history.entries.pop_front();
+            self.total -= 1;
+        }
+        while self.total > self.limits.total {
+            let Some(lru) = self
+                .downloads
+                .iter_mut()
+                .filter(|(_, history)| !history.entries.is_empty())
+                .min_by_key(|(_, history)| history.last_used)
+            else {
+                break;
+            };
+            lru.1.entries.pop_front();
+            self.total -= 1;
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[async_trait]
+impl DownloadUpdateSubscriber for EventHistory {
+    async fn update(&self, updates: &[(Uuid, State)]) {
+        for (id, state) in updates {
+            self.record_state(*id, state);
+        }
+    }
+
+    async fn error(&self, event: &ErrorEvent) {
+        self.record_error(event);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn states(history: &EventHistory, id: &Uuid) -> Vec<String> {
+        history
+            .events(id)
+            .into_iter()
+            .map(|entry| match entry.event {
+                HistoryEvent::State { state } => state,
+                HistoryEvent::Error(e) => e.code,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn total_cap_evicts_least_recently_used_download_test() {
+        // given
+        let history = EventHistory::new(HistoryLimits {
+            per_download: 3,
+            total: 4,
+        });
+        let (old, new) = (Uuid::new_v4(), Uuid::new_v4());
+        let running = State::Running {
+            bytes_downloaded: 1,
+            bytes_per_second: 1,
+            average_bytes_per_second: 1,
+        };
+        // when
+        history.record_state(old, &State::PausedByUser(0));
+        history.record_state(old, &running);
+        history.record_state(old, &running);
+        history.record_state(new, &State::Created);
+        history.record_state(new, &running);
+        history.record_state(new, &State::PausedByUser(1));
+        history.record_state(new, &State::Complete);
+        // then the ring buffer of the new download and the global cap dropped the oldest entries
+        assert_eq!(
+            states(&history, &new),
+            ["Running", "PausedByUser", "Complete"]
+        );
+        assert_eq!(states(&history, &old), ["Running"]);
+        history.forget(&new);
+        assert!(history.events(&new).is_empty());
+        history.record_state(old, &State::Complete);
+        assert_eq!(states(&history, &old), ["Running", "Complete"]);
+    }
+}
@@ -0,0 +1,96 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How often the capacity estimate of a relative limit is updated.
+pub const ESTIMATE_INTERVAL: Duration = Duration::from_secs(5);
+/// Every this many intervals the limit is lifted for one interval to measure the capacity again.
+pub const PROBE_EVERY: u32 = 12;
+
+/// Where the manager's shared bandwidth limit comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BandwidthLimit {
+    #[default]
+    Unlimited,
+    /// Fixed cap in bytes per second
+    Fixed(u64),
+    /// Fraction (e.g. `0.7`) of the measured capacity of the connection, see `CapacityEstimator`
+    Relative(f64),
+}
+
+/// Estimates the capacity of the connection from the combined speed of the running downloads.
+/// While downloads are limited they can't show how much more the connection could do, so the
+/// limit is lifted for one interval every `PROBE_EVERY` intervals (and until a first estimate
+/// exists) and the speed measured then becomes the estimate, smoothed with the previous one. The
+/// limit is the configured fraction of that estimate, without an estimate nothing is limited.
+#[derive(Debug, Clone)]
+pub struct CapacityEstimator {
+    fraction: f64,
+    estimate: Option<u64>,
+    probing: bool,
+    intervals_since_probe: u32,
+}
+
+impl CapacityEstimator {
+    pub fn new(fraction: f64) -> Self {
+        Self {
+            fraction: fraction.clamp(0.0, 1.0),
+            estimate: None,
+            probing: true,
+            intervals_since_probe: 0,
+        }
+    }
+
+    pub fn estimate(&self) -> Option<u64> {
+        self.estimate
+    }
+
+    /// Takes the combined speed of the running downloads over the last interval, None if nothing
+    /// was running, and returns the rate to limit to for the next one, None for unlimited.
+    pub fn next_rate(&mut self, measured: Option<u64>) -> Option<u64> {
+        let measured = measured.filter(|speed| *speed > 0);
+        match (self.probing, measured) {
+            (true, Some(speed)) => {
+                self.estimate = Some(match self.estimate {
+                    Some(estimate) => (estimate + speed) / 2,
+                    None => speed,
+                });
+                self.probing = false;
+                self.intervals_since_probe = 0;
+            }
+            // Nothing to measure, the probe is repeated once downloads run
+            (true, None) => {}
+            (false, _) => {
+                self.intervals_since_probe += 1;
+                self.probing = self.intervals_since_probe >= PROBE_EVERY;
+            }
+        }
+        match (self.probing, self.estimate) {
+            (false, Some(estimate)) => Some(((estimate as f64 * self.fraction) as u64).max(1)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn limit_follows_probed_capacity_test() {
+        // given
+        let mut estimator = CapacityEstimator::new(0.7);
+        // then nothing is limited until a probe measured something
+        assert_eq!(estimator.next_rate(None), None);
+        assert_eq!(estimator.next_rate(Some(1000)), Some(700));
+        // limited intervals don't change the estimate
+        for _ in 1..PROBE_EVERY {
+            assert_eq!(estimator.next_rate(Some(700)), Some(700));
+        }
+        // the limit is lifted to probe again, the new measurement is smoothed in
+        assert_eq!(estimator.next_rate(Some(700)), None);
+        assert_eq!(estimator.next_rate(Some(2000)), Some(1050));
+        assert_eq!(estimator.estimate(), Some(1500));
+    }
+}
@@ -0,0 +1,216 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::httpdownload::download;
+
+/// When a host counts as dead, see `CircuitBreaker`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakerConfig {
+    /// Failures within `window` that open the circuit of a host, zero disables the breaker
+    pub failure_threshold: usize,
+    pub window: Duration,
+    /// How long the downloads of an open host stay paused before one of them is tried again
+    pub cool_down: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            window: Duration::from_secs(60),
+            cool_down: Duration::from_secs(300),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Circuit {
+    Closed,
+    /// The host's downloads are paused until the cool-down is over
+    Open {
+        until: Instant,
+    },
+    /// A single download of the host is tried, its outcome closes or reopens the circuit
+    HalfOpen,
+}
+
+/// Inspectable state of a host's circuit.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum CircuitStatus {
+    Closed,
+    Open { retry_in_secs: u64 },
+    HalfOpen,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostCircuit {
+    pub host: String,
+    #[serde(flatten)]
+    pub status: CircuitStatus,
+    /// Failures within the current window
+    pub recent_failures: usize,
+}
+
+#[derive(Debug)]
+struct HostState {
+    circuit: Circuit,
+    failures: VecDeque<Instant>,
+}
+
+/// Per host circuit breaker, after `failure_threshold` failed downloads of a host within `window`
+/// the circuit opens: the host's downloads are paused for `cool_down`, then a single one is tried
+/// (half-open). If it succeeds the circuit closes and the others are resumed, if it fails the
+/// circuit opens again.
+#[derive(Debug, Default)]
+pub struct CircuitBreaker {
+    pub config: BreakerConfig,
+    hosts: HashMap<String, HostState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        Self {
+            config,
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// Whether downloads of `host` may run, false while its circuit is open or a trial download
+    /// is running.
+    pub fn allows(&self, host: &str) -> bool {
+        self.hosts
+            .get(host)
+            .is_none_or(|state| state.circuit == Circuit::Closed)
+    }
+
+    /// Records a failed download, returns true if this opened the circuit.
+    pub fn record_failure(&mut self, host: &str, now: Instant) -> bool {
+        if self.config.failure_threshold == 0 {
+            return false;
+        }
+        let config = self.config;
+        let state = self.hosts.entry(host.to_owned()).or_insert(HostState {
+            circuit: Circuit::Closed,
+            failures: VecDeque::new(),
+        });
+        state.failures.push_back(now);
+        while let Some(first) = state.failures.front() {
+            if now.duration_since(*first) <= config.window {
+                break;
+            }
+            state.failures.pop_front();
+        }
+        let open = match state.circuit {
+            Circuit::Closed => state.failures.len() >= config.failure_threshold,
+            Circuit::HalfOpen => true,
+            Circuit::Open { .. } => false,
+        };
+        if open {
+            state.circuit = Circuit::Open {
+                until: now + config.cool_down,
+            };
+        }
+        open
+    }
+
+    /// Records a successful download, returns true if this closed the circuit.
+    pub fn record_success(&mut self, host: &str) -> bool {
+        match self.hosts.remove(host) {
+            Some(state) => state.circuit != Circuit::Closed,
+            None => false,
+        }
+    }
+
+    /// Moves an open circuit whose cool-down is over to half-open, returns true if it did.
+    pub fn half_open(&mut self, host: &str, now: Instant) -> bool {
+        match self.hosts.get_mut(host) {
+            Some(state) => match state.circuit {
+                Circuit::Open { until } if until <= now => {
+                    state.circuit = Circuit::HalfOpen;
+                    true
+                }
+                _ => false,
+            },
+            None => false,
+        }
+    }
+
+    pub fn status(&self, now: Instant) -> Vec<HostCircuit> {
+        let mut hosts: Vec<HostCircuit> = self
+            .hosts
+            .iter()
+            .map(|(host, state)| HostCircuit {
+                host: host.clone(),
+                status: match state.circuit {
+                    Circuit::Closed => CircuitStatus::Closed,
+                    Circuit::Open { until } => CircuitStatus::Open {
+                        retry_in_secs: until.saturating_duration_since(now).as_secs(),
+                    },
+                    Circuit::HalfOpen => CircuitStatus::HalfOpen,
+                },
+                recent_failures: state
+                    .failures
+                    .iter()
+                    .filter(|failure| now.duration_since(**failure) <= self.config.window)
+                    .count(),
+            })
+            .collect();
+        hosts.sort_by(|a, b| a.host.cmp(&b.host));
+        hosts
+    }
+}
+
+/// Whether a failed download says something about the health of its host, errors of our own
+/// (disk, configuration, cancellation) don't.
+pub fn is_host_failure(error: &download::Error) -> bool {
+    error.is_transient()
+}
+
+/// Result of a download run of a host, reported to the manager's breaker task.
+#[derive(Debug)]
+pub(super) enum BreakerEvent {
+    Outcome { host: String, success: bool },
+    CoolDownOver(String),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn circuit_opens_half_opens_and_closes_test() {
+        // given
+        let mut breaker = CircuitBreaker::new(BreakerConfig {
+            failure_threshold: 2,
+            window: Duration::from_secs(10),
+            cool_down: Duration::from_secs(30),
+        });
+        let start = Instant::now();
+        // when failures are further apart than the window, then
+        assert!(!breaker.record_failure("a.com", start));
+        assert!(!breaker.record_failure("a.com", start + Duration::from_secs(11)));
+        assert!(breaker.allows("a.com"));
+        // when a second failure happens within the window, then
+        assert!(breaker.record_failure("a.com", start + Duration::from_secs(12)));
+        assert!(!breaker.allows("a.com"));
+        assert!(breaker.allows("b.com"));
+        assert_eq!(
+            breaker.status(start + Duration::from_secs(12))[0].status,
+            CircuitStatus::Open { retry_in_secs: 30 }
+        );
+        // the cool-down has to be over before a download is tried again
+        assert!(!breaker.half_open("a.com", start + Duration::from_secs(20)));
+        assert!(breaker.half_open("a.com", start + Duration::from_secs(42)));
+        assert!(!breaker.allows("a.com"));
+        // a failing trial reopens the circuit right away
+        assert!(breaker.record_failure("a.com", start + Duration::from_secs(43)));
+        assert!(breaker.half_open("a.com", start + Duration::from_secs(73)));
+        // a successful one closes it
+        assert!(breaker.record_success("a.com"));
+        assert!(breaker.allows("a.com"));
+        assert!(breaker.status(start).is_empty());
+    }
+}
@@ -0,0 +1,166 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+use crate::httpdownload::download::checksum::{self, Checksum, ChecksumAlgorithm};
+use crate::httpdownload::download::HttpDownload;
+
+/// What happens to a completed download with the same content as one that completed before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DedupAction {
+    /// The download is only marked as a duplicate, see `DownloadMetadata::duplicate_of`
+    Flag,
+    /// The download is marked and its file is replaced by a hard link to the earlier file. The
+    /// file is kept as it is if the link can't be made, e.g. across file systems.
+    Hardlink,
+}
+
+/// Content hashes (SHA-256) of the completed downloads, used to find downloads of the same file.
+/// Disabled until an action is set, see `DownloadManager::with_dedup`.
+#[derive(Debug, Clone, Default)]
+pub struct ContentIndex {
+    inner: Arc<Mutex<IndexInner>>,
+}
+
+#[derive(Debug, Default)]
+struct IndexInner {
+    action: Option<DedupAction>,
+    hashes: HashMap<Uuid, Checksum>,
+    /// Downloads with the content in the order they completed, the first one is the original
+    copies: HashMap<Checksum, Vec<(Uuid, PathBuf)>>,
+}
+
+impl ContentIndex {
+    pub fn set_action(&self, action: Option<DedupAction>) {
+        self.inner.lock().unwrap().action = action;
+    }
+
+    /// Download with the same content that completed first, None if the download is the first
+    /// (or only) one with its content or isn't complete.
+    pub fn duplicate_of(&self, id: &Uuid) -> Option<Uuid> {
+        let inner = self.inner.lock().unwrap();
+        let (original, _) = inner.copies.get(inner.hashes.get(id)?)?.first()?;
+        (original != id).then_some(*original)
+    }
+
+    /// Records the content of a completed download and applies the action if another download
+    /// has the same content. A SHA-256 checksum the download was verified against is taken as
+    /// its hash, otherwise the file is hashed. Split and sparse downloads aren't considered.
+    pub async fn completed(&self, download: &HttpDownload) {
+        let Some(action) = self.inner.lock().unwrap().action else {
+            return;
+        };
+        if download.split_size().is_some() || download.sparse_ranges().is_some() {
+            return;
+        }
+        let path = download.file_path();
+        let hash = match download.config.checksum {
+            Some(verified @ Checksum::Sha256(_)) => verified,
+            _ => match checksum::compute(std::slice::from_ref(&path), ChecksumAlgorithm::Sha256)
+                .await
+            {
+                Ok(hash) => hash,
+                Err(e) => {
+                    log::warn!("Couldn't hash download {} for dedup: {}", download.id, e);
+                    return;
+                }
+            },
+        };
+        let Some((original, original_path)) = self.record(download.id, path.clone(), hash) else {
+            return;
+        };
+        log::info!(
+            "Download {} has the same content as download {}",
+            download.id,
+            original
+        );
+        if action == DedupAction::Hardlink {
+            if let Err(e) = link(&original_path, &path).await {
+                log::warn!(
+                    "Couldn't link download {} to the file of download {}: {}",
+                    download.id,
+                    original,
+                    e
+                );
+            }
+        }
+    }
+
+    /// Adds the download to the downloads with `hash`, returns the original if it isn't the
+    /// download itself.
+    fn record(&self, id: Uuid, path: PathBuf, hash: Checksum) -> Option<(Uuid, PathBuf)> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(&id);
+        inner.hashes.insert(id, hash);
+        let copies = inner.copies.entry(hash).or_default();
+        copies.push((id, path));
+        copies
+            .first()
+            .filter(|(original, _)| *original != id)
+            .cloned()
+    }
+
+    /// Forgets a deleted download, the next download with its content becomes the original.
+    pub fn forget(&self, id: &Uuid) {
+        self.inner.lock().unwrap().remove(id);
+    }
+}
+
+impl IndexInner {
+    fn remove(&mut self, id: &Uuid) {
+        let Some(hash) = self.hashes.remove(id) else {
+            return;
+        };
+        if let Some(copies) = self.copies.get_mut(&hash) {
+            copies.retain(|(copy, _)| copy != id);
+            if copies.is_empty() {
+                self.copies.remove(&hash);
+            }
+        }
+    }
+}
+
+/// Replaces `path` with a hard link to `original`. The link is made next to `path` and renamed
+/// over it, so `path` never goes missing.
+async fn link(original: &Path, path: &Path) -> std::io::Result<()> {
+    let mut link_name = path.as_os_str().to_owned();
+    link_name.push(".dedup");
+    let link_path = PathBuf::from(link_name);
+    let _ = tokio::fs::remove_file(&link_path).await;
+    tokio::fs::hard_link(original, &link_path).await?;
+    let result = tokio::fs::rename(&link_path, path).await;
+    // Renaming a link over the same file does nothing, the link is left behind then
+    let _ = tokio::fs::remove_file(&link_path).await;
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn first_completed_download_is_the_original_test() {
+        let index = ContentIndex::default();
+        let hash = Checksum::Sha256([1; 32]);
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        assert_eq!(index.record(a, PathBuf::from("a"), hash), None);
+        assert_eq!(
+            index.record(b, PathBuf::from("b"), hash),
+            Some((a, PathBuf::from("a")))
+        );
+        index.record(c, PathBuf::from("c"), Checksum::Sha256([2; 32]));
+        assert_eq!(index.duplicate_of(&a), None);
+        assert_eq!(index.duplicate_of(&b), Some(a));
+        assert_eq!(index.duplicate_of(&c), None);
+        // a re-completed download is a duplicate of the original, not of itself
+        assert_eq!(
+            index.record(b, PathBuf::from("b"), hash),
+            Some((a, PathBuf::from("a")))
+        );
+        index.forget(&a);
+        assert_eq!(index.duplicate_of(&b), None);
+    }
+}
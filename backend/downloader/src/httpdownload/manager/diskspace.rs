@@ -0,0 +1,83 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Default time between two checks of the free space, see `DiskReserve`
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Space that has to stay free on the filesystems of the downloads. The manager checks it every
+/// `poll_interval`, pauses the running downloads (`PauseReason::LowDiskSpace`) while less is
+/// free on any of the `directories` and resumes them once there's enough space again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskReserve {
+    pub directories: Vec<PathBuf>,
+    /// Bytes
+    pub min_free: u64,
+    pub poll_interval: Duration,
+}
+
+impl DiskReserve {
+    /// Whether a directory has less than `min_free` bytes free according to `free_space`.
+    /// Directories whose free space can't be determined don't count as low.
+    pub fn is_low(&self, free_space: impl Fn(&Path) -> io::Result<u64>) -> bool {
+        self.directories
+            .iter()
+            .any(|directory| match free_space(directory) {
+                Ok(free) => free < self.min_free,
+                Err(e) => {
+                    log::warn!("Couldn't read the free space of {:?}: {}", directory, e);
+                    false
+                }
+            })
+    }
+}
+
+/// Bytes available to unprivileged users on the filesystem of `path`.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is a valid C string and `stat` is a valid statvfs to write to
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Free space can only be read on unix",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reserve_is_low_if_any_directory_is_test() {
+        let reserve = DiskReserve {
+            directories: vec![PathBuf::from("/a"), PathBuf::from("/b")],
+            min_free: 100,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+        };
+        assert!(!reserve.is_low(|_| Ok(100)));
+        assert!(reserve.is_low(|path| Ok(if path == Path::new("/b") { 99 } else { 1000 })));
+        // unknown free space doesn't pause anything
+        assert!(!reserve.is_low(|_| Err(io::Error::other("unsupported"))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn free_space_of_temp_dir_test() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        assert!(free_space(tmp_dir.path()).unwrap() > 0);
+        assert!(free_space(&tmp_dir.path().join("missing")).is_err());
+    }
+}
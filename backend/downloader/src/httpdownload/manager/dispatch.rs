@@ -0,0 +1,91 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// Running downloads counted against the limit of concurrent downloads, see
+/// `DownloadManager::with_max_concurrent`. Every run holds a `Slot`, also the ones the user
+/// started while all slots were taken, so queued downloads only start once the running downloads
+/// are below the limit again.
+#[derive(Debug, Clone)]
+pub struct Slots {
+    inner: Arc<SlotsInner>,
+    /// Notified whenever a slot is freed while there's a limit, without one nothing waits for a
+    /// slot
+    freed: mpsc::UnboundedSender<()>,
+}
+
+#[derive(Debug, Default)]
+struct SlotsInner {
+    /// 0 means unlimited
+    limit: AtomicUsize,
+    taken: AtomicUsize,
+}
+
+impl Slots {
+    pub fn new(freed: mpsc::UnboundedSender<()>) -> Self {
+        Self {
+            inner: Arc::default(),
+            freed,
+        }
+    }
+
+    pub fn set_limit(&self, limit: usize) {
+        self.inner.limit.store(limit, Ordering::Relaxed);
+    }
+
+    /// Whether a download started now would exceed the limit.
+    pub fn is_full(&self) -> bool {
+        let limit = self.inner.limit.load(Ordering::Relaxed);
+        limit > 0 && self.inner.taken.load(Ordering::Relaxed) >= limit
+    }
+
+    /// Takes a slot whether one is free or not.
+    pub fn take(&self) -> Slot {
+        self.inner.taken.fetch_add(1, Ordering::Relaxed);
+        Slot {
+            slots: self.clone(),
+        }
+    }
+}
+
+/// Held by a running download, dropping it at the end of the run frees the slot for the next
+/// queued download.
+#[derive(Debug)]
+pub struct Slot {
+    slots: Slots,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.slots.inner.taken.fetch_sub(1, Ordering::Relaxed);
+        if self.slots.inner.limit.load(Ordering::Relaxed) > 0 {
+            let _ = self.slots.freed.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn slots_are_freed_when_dropped() {
+        // given
+        let (freed, mut freed_recv) = mpsc::unbounded_channel();
+        let slots = Slots::new(freed);
+        slots.set_limit(2);
+        // when
+        let first = slots.take();
+        assert!(!slots.is_full());
+        let second = slots.take();
+        // then
+        assert!(slots.is_full());
+        drop(first);
+        assert!(!slots.is_full());
+        assert!(freed_recv.try_recv().is_ok());
+        drop(second);
+        slots.set_limit(0);
+        let _unlimited: Vec<Slot> = (0..10).map(|_| slots.take()).collect();
+        assert!(!slots.is_full());
+    }
+}
@@ -0,0 +1,98 @@
+use async_trait::async_trait;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Tells whether downloading is fine right now, e.g. the connection isn't metered.
+#[async_trait]
+pub trait StartCondition {
+    async fn allows(&self) -> bool;
+}
+
+#[async_trait]
+impl<F, Fut> StartCondition for F
+where
+    F: Fn() -> Fut + Send + Sync,
+    Fut: Future<Output = bool> + Send,
+{
+    async fn allows(&self) -> bool {
+        self().await
+    }
+}
+
+/// Consulted before the manager starts downloads on its own (resuming system paused downloads,
+/// the circuit breaker resuming a host), those downloads stay paused while it doesn't allow it.
+/// Downloads started by the user aren't affected. Starts are allowed if the flag is set and the
+/// condition, if any, allows them.
+#[derive(Clone)]
+pub struct StartGate {
+    allowed: Arc<AtomicBool>,
+    condition: Arc<Mutex<Option<Arc<dyn StartCondition + Send + Sync>>>>,
+}
+
+impl Default for StartGate {
+    fn default() -> Self {
+        Self {
+            allowed: Arc::new(AtomicBool::new(true)),
+            condition: Arc::default(),
+        }
+    }
+}
+
+impl fmt::Debug for StartGate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StartGate")
+            .field("allowed", &self.allowed)
+            .field("condition", &self.condition.lock().unwrap().is_some())
+            .finish()
+    }
+}
+
+impl StartGate {
+    /// Sets the flag, returns whether it changed.
+    pub fn set_allowed(&self, allowed: bool) -> bool {
+        self.allowed.swap(allowed, Ordering::SeqCst) != allowed
+    }
+
+    pub fn set_condition(&self, condition: impl StartCondition + Send + Sync + 'static) {
+        *self.condition.lock().unwrap() = Some(Arc::new(condition));
+    }
+
+    pub async fn allows(&self) -> bool {
+        if !self.allowed.load(Ordering::SeqCst) {
+            return false;
+        }
+        let condition = self.condition.lock().unwrap().clone();
+        match condition {
+            Some(condition) => condition.allows().await,
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_log::test;
+
+    #[test(tokio::test)]
+    async fn flag_and_condition_must_allow_test() {
+        // given
+        let gate = StartGate::default();
+        let metered = Arc::new(AtomicBool::new(false));
+        let connection = metered.clone();
+        gate.set_condition(move || {
+            let metered = connection.load(Ordering::SeqCst);
+            async move { !metered }
+        });
+        // then
+        assert!(gate.allows().await);
+        metered.store(true, Ordering::SeqCst);
+        assert!(!gate.allows().await);
+        metered.store(false, Ordering::SeqCst);
+        assert!(gate.set_allowed(false));
+        assert!(!gate.set_allowed(false));
+        assert!(!gate.allows().await);
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Remembers the download a request with an idempotency key created, so a retried request with
+/// the same key gets the same download instead of a duplicate. A key expires `ttl` after it was
+/// first used.
+#[derive(Debug, Clone)]
+pub struct IdempotencyKeys {
+    ttl: Duration,
+    keys: Arc<Mutex<HashMap<String, (Uuid, Instant)>>>,
+}
+
+impl IdempotencyKeys {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            keys: Arc::default(),
+        }
+    }
+
+    /// Download created for `key`, None if the key wasn't used or expired at `now`.
+    pub fn get(&self, key: &str, now: Instant) -> Option<Uuid> {
+        let mut keys = self.keys.lock().unwrap();
+        keys.retain(|_, (_, used)| now.duration_since(*used) < self.ttl);
+        keys.get(key).map(|(id, _)| *id)
+    }
+
+    /// Records `id` for `key` unless the key is already used, returns the download the key
+    /// belongs to.
+    pub fn insert(&self, key: &str, id: Uuid, now: Instant) -> Uuid {
+        let mut keys = self.keys.lock().unwrap();
+        keys.retain(|_, (_, used)| now.duration_since(*used) < self.ttl);
+        keys.entry(key.to_owned()).or_insert((id, now)).0
+    }
+
+    /// Frees the keys of a deleted download.
+    pub fn forget(&self, id: &Uuid) {
+        self.keys
+            .lock()
+            .unwrap()
+            .retain(|_, (created, _)| created != id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keys_map_to_the_first_download_until_they_expire_test() {
+        let keys = IdempotencyKeys::new(Duration::from_secs(60));
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Instant::now();
+        assert_eq!(keys.get("key", now), None);
+        assert_eq!(keys.insert("key", a, now), a);
+        assert_eq!(keys.insert("key", b, now + Duration::from_secs(30)), a);
+        assert_eq!(keys.get("key", now + Duration::from_secs(59)), Some(a));
+        assert_eq!(keys.get("key", now + Duration::from_secs(60)), None);
+        assert_eq!(keys.insert("key", b, now + Duration::from_secs(60)), b);
+        keys.forget(&b);
+        assert_eq!(keys.get("key", now + Duration::from_secs(61)), None);
+    }
+}
@@ -1,15 +1,24 @@
-use crate::httpdownload::download::{DownloadUpdate, HttpDownload};
+use crate::downloadable::Downloadable;
+use crate::httpdownload::download::retry::RetryPolicy;
+use crate::httpdownload::download::stats::DownloadDiagnostics;
+use crate::httpdownload::download::{self, DownloadUpdate, ErrorEvent, PauseReason};
 use crate::httpdownload::DownloadMetadata;
 
-use anyhow::anyhow;
 use futures_util::future::join_all;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-use super::item::DownloaderItem;
-use super::{Result, UpdateConsumer};
+use super::breaker::{BreakerEvent, CircuitBreaker};
+use super::dedup::ContentIndex;
+use super::dispatch::Slots;
+use super::item::{http, http_mut, DownloaderItem};
+use super::page::{Cursor, Page};
+use super::{Error, Result, UpdateConsumer};
 
 impl UpdateConsumer for () {
     fn consume(&mut self, update: DownloadUpdate) {
@@ -21,16 +30,50 @@ impl UpdateConsumer for () {
 pub struct ManagerInner {
     pub update_ch: mpsc::Sender<DownloadUpdate>,
     pub items: HashMap<Uuid, DownloaderItem>,
+    pub breaker: Arc<Mutex<CircuitBreaker>>,
+    breaker_events: mpsc::UnboundedSender<BreakerEvent>,
+    error_events: mpsc::UnboundedSender<ErrorEvent>,
+    dedup: ContentIndex,
+    /// Running downloads, queued downloads are started by `dispatch` while one is free
+    pub slots: Slots,
+    /// Dispatch order of queued downloads, they are queued at the back and `reorder_queue` moves
+    /// them. It can hold ids that aren't queued anymore, they are ignored, unlisted ones go last.
+    queue_order: Vec<Uuid>,
+    /// Downloads by the position they were added at, positions aren't reused so the order of the
+    /// remaining downloads never changes
+    order: BTreeMap<u64, Uuid>,
+    next_position: u64,
+    /// Less than the reserved space is free, downloads paused for it stay paused
+    pub low_disk_space: bool,
 }
 
 impl Default for ManagerInner {
     fn default() -> Self {
-        ManagerInner::new(())
+        // Nobody listens to the breaker and error events, circuits never open
+        let (breaker_events, _) = mpsc::unbounded_channel();
+        let (error_events, _) = mpsc::unbounded_channel();
+        // Nobody dispatches when a slot is freed, queued downloads wait for `resume_all`
+        let (freed, _) = mpsc::unbounded_channel();
+        ManagerInner::new(
+            (),
+            Arc::default(),
+            breaker_events,
+            error_events,
+            ContentIndex::default(),
+            Slots::new(freed),
+        )
     }
 }
 
 impl ManagerInner {
-    pub fn new(mut update_consumer: impl UpdateConsumer + Send + Sync + 'static) -> Self {
+    pub fn new(
+        mut update_consumer: impl UpdateConsumer + Send + Sync + 'static,
+        breaker: Arc<Mutex<CircuitBreaker>>,
+        breaker_events: mpsc::UnboundedSender<BreakerEvent>,
+        error_events: mpsc::UnboundedSender<ErrorEvent>,
+        dedup: ContentIndex,
+        slots: Slots,
+    ) -> Self {
         let (update_sender, mut update_recv) = mpsc::channel::<DownloadUpdate>(1000);
         log::info!("Spawning update consumer task");
         tokio::task::spawn(async move {
@@ -45,43 +88,214 @@ impl ManagerInner {
         ManagerInner {
             update_ch: update_sender,
             items: HashMap::new(),
+            breaker,
+            breaker_events,
+            error_events,
+            dedup,
+            slots,
+            queue_order: Vec::new(),
+            order: BTreeMap::new(),
+            next_position: 0,
+            low_disk_space: false,
+        }
+    }
+
+    /// Whether the circuit breaker keeps the download from running.
+    fn host_blocked(&self, item: &DownloaderItem) -> Option<String> {
+        let host = item.download.try_read().ok()?.host()?.to_owned();
+        match self.breaker.lock().unwrap().allows(&host) {
+            true => None,
+            false => Some(host),
+        }
+    }
+
+    fn is_queued(item: &DownloaderItem) -> bool {
+        item.system_pause() == Some(PauseReason::QueueLimit) && !item.is_locked()
+    }
+
+    /// Position of the download in the dispatch order.
+    fn queue_position(&self, id: &Uuid) -> usize {
+        self.queue_order
+            .iter()
+            .position(|queued| queued == id)
+            .unwrap_or(usize::MAX)
+    }
+
+    /// Puts the download at the back of the dispatch order.
+    fn push_to_queue(&mut self, id: &Uuid) {
+        self.queue_order.retain(|queued| queued != id);
+        self.queue_order.push(*id);
+    }
+
+    pub fn queue(&self) -> Vec<Uuid> {
+        let mut queue: Vec<Uuid> = self
+            .items
+            .iter()
+            .filter(|(_, item)| Self::is_queued(item))
+            .map(|(id, _)| *id)
+            .collect();
+        queue.sort_by_key(|id| self.queue_position(id));
+        queue
+    }
+
+    pub fn reorder_queue(&mut self, ids: &[Uuid]) -> Result<Vec<Uuid>> {
+        for id in ids {
+            match self.items.get(id) {
+                Some(item) if Self::is_queued(item) => {}
+                Some(_) => return Err(Error::NotQueued(*id).into()),
+                None => return Err(Error::NotFound(*id).into()),
+            }
+        }
+        let rest = self.queue().into_iter().filter(|id| !ids.contains(id));
+        let mut order: Vec<Uuid> = Vec::new();
+        for id in ids.iter().copied().chain(rest) {
+            if !order.contains(&id) {
+                order.push(id);
+            }
         }
+        log::info!("Reordered the download queue: {:?}", order);
+        self.queue_order = order.clone();
+        Ok(order)
     }
 
-    pub fn add(&mut self, download: HttpDownload) -> Uuid {
+    /// Files the running downloads write to, mapped to the download writing them. It's built from
+    /// the running downloads every time so it can't go stale when a download ends on its own. A
+    /// download that is still being probed has no known paths yet.
+    fn active_paths(&self) -> HashMap<PathBuf, Uuid> {
+        let mut paths = HashMap::new();
+        for (id, item) in self.items.iter().filter(|(_, item)| item.is_running()) {
+            if let Ok(download) = item.download.try_read() {
+                paths.extend(download.target_paths().into_iter().map(|path| (path, *id)));
+            }
+        }
+        paths
+    }
+
+    /// Fails with `PathConflict` if the download would write to a file a running download writes
+    /// to.
+    fn check_paths(&self, id: &Uuid) -> Result<()> {
+        let paths = match self.items.get(id).map(|item| item.download.try_read()) {
+            Some(Ok(download)) => download.target_paths(),
+            _ => return Ok(()),
+        };
+        find_conflict(&self.active_paths(), id, &paths)
+    }
+
+    /// Runs the download unless that would make it write to the file of a running download,
+    /// returns whether it was started.
+    fn run_unless_conflicting(&mut self, id: &Uuid, resume: bool) -> bool {
+        if let Err(e) = self.check_paths(id) {
+            log::warn!("Not starting download {}: {}", id, e);
+            return false;
+        }
+        let update_ch = self.update_ch.clone();
+        match self.items.get_mut(id) {
+            Some(item) => {
+                item.run(update_ch, resume);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn add(&mut self, mut download: Box<dyn Downloadable>) -> Uuid {
         log::info!("Adding download: {:?}", download);
-        let id = download.id;
-        let item = DownloaderItem::new(download);
-        self.items.insert(id, item);
+        let id = download.id();
+        if let Some(download) = download.as_http_mut() {
+            download
+                .config
+                .error_events
+                .get_or_insert_with(|| self.error_events.clone());
+        }
+        let item = DownloaderItem::new(
+            download,
+            self.breaker_events.clone(),
+            self.dedup.clone(),
+            self.slots.clone(),
+        );
+        if self.items.insert(id, item).is_none() {
+            self.order.insert(self.next_position, id);
+            self.next_position += 1;
+        }
         id
     }
 
     pub async fn get_metadata(&self, id: &Uuid) -> Result<DownloadMetadata> {
         if let Some(item) = self.items.get(id) {
-            Ok(item.download.read().await.get_metadata())
+            Ok(item.get_metadata().await)
         } else {
-            Err(anyhow!("Download with id {} does not exist", id))
+            Err(Error::NotFound(*id).into())
+        }
+    }
+
+    pub async fn diagnostics(&self, id: &Uuid) -> Result<DownloadDiagnostics> {
+        match self.items.get(id) {
+            Some(item) => Ok(http(item.download.read().await.as_ref())?.diagnostics()),
+            None => Err(Error::NotFound(*id).into()),
+        }
+    }
+
+    pub async fn readable_prefix(&self, id: &Uuid) -> Result<Option<(PathBuf, u64)>> {
+        match self.items.get(id) {
+            Some(item) => {
+                let download = item.download.read().await;
+                Ok(http(download.as_ref())?.readable_prefix().await)
+            }
+            None => Err(Error::NotFound(*id).into()),
+        }
+    }
+
+    pub async fn final_path(&self, id: &Uuid) -> Result<PathBuf> {
+        match self.items.get(id) {
+            Some(item) => Ok(item.download.read().await.final_path()),
+            None => Err(Error::NotFound(*id).into()),
         }
     }
 
+    /// Downloads in the order they were added.
+    pub fn in_order(&self) -> impl Iterator<Item = (&Uuid, &DownloaderItem)> {
+        self.order
+            .values()
+            .filter_map(|id| Some((id, self.items.get(id)?)))
+    }
+
+    /// Metadata of all downloads in the order they were added.
     pub async fn get_metadata_all(&self) -> Vec<DownloadMetadata> {
-        join_all(
-            self.items
-                .values()
-                .map(|item| async move { item.download.read().await.get_metadata() }),
-        )
-        .await
+        join_all(self.in_order().map(|(_, item)| item.get_metadata())).await
+    }
+
+    /// Up to `limit` downloads added after the one at `after` (from the first one if None).
+    pub async fn get_metadata_page(&self, after: Option<Cursor>, limit: usize) -> Page {
+        let start = after.map_or(0, |Cursor(position)| position.saturating_add(1));
+        let mut positions = self.order.range(start..);
+        let page: Vec<(u64, &DownloaderItem)> = positions
+            .by_ref()
+            .filter_map(|(position, id)| Some((*position, self.items.get(id)?)))
+            .take(limit)
+            .collect();
+        let next_cursor = match (page.last(), positions.next()) {
+            (Some((last, _)), Some(_)) => Some(Cursor(*last)),
+            _ => None,
+        };
+        let items = join_all(page.into_iter().map(|(_, item)| item.get_metadata())).await;
+        Page { items, next_cursor }
     }
 
     pub fn start_all(&mut self) {
         log::info!("Start/Resume all {} downloads", self.items.len());
-        for (id, item) in self.items.iter_mut() {
+        let ids: Vec<Uuid> = self.items.keys().copied().collect();
+        for id in ids {
+            let item = &self.items[&id];
             if item.is_locked() {
                 log::info!("HttpDownload: {} is locked, skipping...", id);
                 continue;
             }
+            if self.host_blocked(item).is_some() {
+                log::info!("HttpDownload: {} has an unavailable host, skipping...", id);
+                continue;
+            }
             log::info!("Starting download: {}", id);
-            item.run(self.update_ch.clone(), true);
+            self.run_unless_conflicting(&id, true);
         }
     }
 
@@ -93,17 +307,299 @@ impl ManagerInner {
         }
     }
 
-    pub fn run(&mut self, id: &Uuid, resume: bool) -> Result<()> {
-        if let Some(item) = self.items.get_mut(id) {
-            let update_ch = self.update_ch.clone();
+    /// Resumes the downloads the system paused, downloads paused by the user stay paused. Queued
+    /// downloads are left to `dispatch`. Returns the ids of the resumed downloads.
+    pub fn resume_all(&mut self) -> Vec<Uuid> {
+        let mut resumed = Vec::new();
+        let ids: Vec<Uuid> = self.items.keys().copied().collect();
+        for id in ids {
+            let item = &self.items[&id];
+            // Downloads of unavailable hosts are resumed by the circuit breaker, unless their host
+            // recovered while automatic starts weren't allowed
+            if item.system_pause().is_none()
+                || item.system_pause() == Some(PauseReason::QueueLimit)
+                || (item.system_pause() == Some(PauseReason::HostUnavailable)
+                    && self.host_blocked(item).is_some())
+                || (item.system_pause() == Some(PauseReason::LowDiskSpace) && self.low_disk_space)
+                || item.is_locked()
+            {
+                continue;
+            }
+            log::info!("Resuming system paused download: {}", id);
+            if self.run_unless_conflicting(&id, true) {
+                resumed.push(id);
+            }
+        }
+        resumed.extend(self.dispatch());
+        resumed
+    }
+
+    /// Starts queued downloads in the order set by `reorder_queue` while slots are free, returns
+    /// their ids. Nothing is started while disk space is low.
+    pub fn dispatch(&mut self) -> Vec<Uuid> {
+        let mut started = Vec::new();
+        if self.low_disk_space {
+            return started;
+        }
+        for id in self.queue() {
+            if self.slots.is_full() {
+                break;
+            }
+            if self.host_blocked(&self.items[&id]).is_some() {
+                continue;
+            }
+            log::info!("Starting queued download: {}", id);
+            if self.run_unless_conflicting(&id, true) {
+                started.push(id);
+            }
+        }
+        started
+    }
+
+    /// Queues a download that isn't running until `dispatch` finds a free slot for it.
+    pub async fn enqueue(&mut self, id: &Uuid) -> Result<()> {
+        let Some(item) = self.items.get_mut(id) else {
+            return Err(Error::NotFound(*id).into());
+        };
+        if item.is_locked() {
+            return Err(Error::Locked.into());
+        }
+        item.queue();
+        let bytes_downloaded = item.download.read().await.get_bytes_on_disk().await;
+        self.push_to_queue(id);
+        log::info!("All download slots are taken, queued download {}", id);
+        let _ = self
+            .update_ch
+            .send(DownloadUpdate {
+                id: *id,
+                state: download::State::PausedBySystem {
+                    bytes_downloaded,
+                    reason: PauseReason::QueueLimit,
+                },
+            })
+            .await;
+        Ok(())
+    }
+
+    /// Stops all running downloads served by `host`, returns the ids of the stopped downloads.
+    pub async fn stop_by_host(&mut self, host: &str) -> Vec<Uuid> {
+        log::info!("Stopping all downloads of host {}", host);
+        let mut stopped = Vec::new();
+        for (id, item) in self.items.iter_mut() {
+            if !host_matches(item.host().await, host) {
+                continue;
+            }
+            if item.stop().is_ok() {
+                log::info!("Stopped download: {}", id);
+                stopped.push(*id);
+            }
+        }
+        stopped
+    }
+
+    /// Pauses all running downloads served by `host` on behalf of the system, returns the ids of
+    /// the paused downloads.
+    pub async fn pause_host(&mut self, host: &str, reason: PauseReason) -> Vec<Uuid> {
+        let mut paused = Vec::new();
+        for (id, item) in self.items.iter_mut() {
+            if host_matches(item.host().await, host) && item.stop_by_system(reason).is_ok() {
+                log::info!("Paused download {} ({:?})", id, reason);
+                paused.push(*id);
+            }
+        }
+        paused
+    }
+
+    /// Resumes up to `limit` downloads of `host` that the system paused for `reason`, returns the
+    /// ids of the resumed downloads.
+    pub async fn resume_host(
+        &mut self,
+        host: &str,
+        reason: PauseReason,
+        limit: usize,
+    ) -> Vec<Uuid> {
+        let mut resumed = Vec::new();
+        let ids: Vec<Uuid> = self.items.keys().copied().collect();
+        for id in ids {
+            if resumed.len() >= limit {
+                break;
+            }
+            let item = &self.items[&id];
+            if item.system_pause() != Some(reason)
+                || item.is_locked()
+                || !host_matches(item.host().await, host)
+            {
+                continue;
+            }
+            log::info!("Resuming download {} of host {}", id, host);
+            if self.run_unless_conflicting(&id, true) {
+                resumed.push(id);
+            }
+        }
+        resumed
+    }
+
+    /// Pauses all running downloads on behalf of the system, returns the ids of the paused
+    /// downloads.
+    pub fn pause_all(&mut self, reason: PauseReason) -> Vec<Uuid> {
+        let mut paused = Vec::new();
+        for (id, item) in self.items.iter_mut() {
+            if item.stop_by_system(reason).is_ok() {
+                log::info!("Paused download {} ({:?})", id, reason);
+                paused.push(*id);
+            }
+        }
+        paused
+    }
+
+    /// Resumes the downloads the system paused for `reason`, returns the ids of the resumed
+    /// downloads.
+    pub fn resume_paused(&mut self, reason: PauseReason) -> Vec<Uuid> {
+        let ids: Vec<Uuid> = self
+            .items
+            .iter()
+            .filter(|(_, item)| item.system_pause() == Some(reason) && !item.is_locked())
+            .map(|(id, _)| *id)
+            .collect();
+        ids.into_iter()
+            .filter(|id| self.run_unless_conflicting(id, true))
+            .collect()
+    }
+
+    /// Resumes the downloads served by `host` that ran before and are among the `paused` ones,
+    /// returns the ids of the resumed downloads.
+    pub async fn start_by_host(&mut self, host: &str, paused: &HashSet<Uuid>) -> Vec<Uuid> {
+        log::info!("Start/Resume all downloads of host {}", host);
+        if !self.breaker.lock().unwrap().allows(host) {
+            log::info!("Host {} is unavailable, not starting its downloads", host);
+            return Vec::new();
+        }
+        let mut started = Vec::new();
+        let ids: Vec<Uuid> = self.items.keys().copied().collect();
+        for id in ids {
+            let item = &self.items[&id];
+            if !item.was_started()
+                || !paused.contains(&id)
+                || !host_matches(item.host().await, host)
+            {
+                continue;
+            }
             if item.is_locked() {
-                return Err(anyhow!("Download is already locked, probably running already or locked up by pending operation!"));
+                log::info!("HttpDownload: {} is locked, skipping...", id);
+                continue;
+            }
+            if self.run_unless_conflicting(&id, true) {
+                started.push(id);
+            }
+        }
+        started
+    }
+
+    /// Stops the download for a url change, returns whether it was running so it can be resumed
+    /// against the new source.
+    pub fn stop_for_url_change(&mut self, id: &Uuid) -> Result<bool> {
+        let Some(item) = self.items.get_mut(id) else {
+            return Err(Error::NotFound(*id).into());
+        };
+        let was_running = item.is_locked();
+        if was_running {
+            item.stop()?;
+        }
+        Ok(was_running)
+    }
+
+    /// Probes a download that isn't running again, see `HttpDownload::refresh_metadata`.
+    pub async fn refresh_metadata(&mut self, id: &Uuid) -> Result<Vec<&'static str>> {
+        let Some(item) = self.items.get(id) else {
+            return Err(Error::NotFound(*id).into());
+        };
+        let Ok(mut download) = item.download.try_write() else {
+            return Err(Error::Locked.into());
+        };
+        Ok(http_mut(download.as_mut())?.refresh_metadata().await?)
+    }
+
+    pub async fn set_retry_policy(&self, id: &Uuid, policy: RetryPolicy) -> Result<()> {
+        match self.items.get(id) {
+            Some(item) => {
+                let download = item.download.read().await;
+                http(download.as_ref())?.config.retry_policy.set(policy);
+                Ok(())
+            }
+            None => Err(Error::NotFound(*id).into()),
+        }
+    }
+
+    pub async fn set_rate_limit(&self, id: &Uuid, bytes_per_second: Option<u64>) -> Result<()> {
+        match self.items.get(id) {
+            Some(item) => {
+                let download = item.download.read().await;
+                http(download.as_ref())?
+                    .config
+                    .rate_limit
+                    .set_rate(bytes_per_second);
+                Ok(())
+            }
+            None => Err(Error::NotFound(*id).into()),
+        }
+    }
+
+    pub async fn set_segments(&self, id: &Uuid, segments: usize) -> Result<()> {
+        match self.items.get(id) {
+            Some(item) => {
+                let download = item.download.read().await;
+                Ok(http(download.as_ref())?.set_segments(segments)?)
             }
+            None => Err(Error::NotFound(*id).into()),
+        }
+    }
+
+    pub async fn set_ignore_global_limit(&self, id: &Uuid, ignore: bool) -> Result<()> {
+        match self.items.get(id) {
+            Some(item) => {
+                let download = item.download.read().await;
+                http(download.as_ref())?
+                    .config
+                    .ignore_global_limit
+                    .set(ignore);
+                Ok(())
+            }
+            None => Err(Error::NotFound(*id).into()),
+        }
+    }
+
+    /// Clears the `pause_at` threshold of a download that isn't running once it reached it, the
+    /// user starting it again means it shouldn't pause there again.
+    pub async fn clear_reached_pause_at(&self, id: &Uuid) {
+        let Some(Ok(mut guard)) = self.items.get(id).map(|item| item.download.try_write()) else {
+            return;
+        };
+        let Some(download) = guard.as_http_mut() else {
+            return;
+        };
+        let Some(pause_at) = download.config.pause_at else {
+            return;
+        };
+        if download.get_bytes_on_disk().await >= pause_at {
+            log::info!("Download {} resumed past its pause threshold", id);
+            download.config.pause_at = None;
+        }
+    }
+
+    pub fn run(&mut self, id: &Uuid, resume: bool) -> Result<()> {
+        if let Some(host) = self.items.get(id).and_then(|item| self.host_blocked(item)) {
+            return Err(Error::HostUnavailable(host).into());
+        }
+        match self.items.get(id) {
+            Some(item) if item.is_locked() => return Err(Error::Locked.into()),
+            Some(_) => self.check_paths(id)?,
+            None => return Err(Error::NotFound(*id).into()),
+        }
+        let update_ch = self.update_ch.clone();
+        if let Some(item) = self.items.get_mut(id) {
             item.run(update_ch, resume);
-            Ok(())
-        } else {
-            Err(anyhow!("Download with id {} not found", id))
         }
+        Ok(())
     }
 
     pub fn stop(&mut self, id: &Uuid) -> Result<()> {
@@ -112,12 +608,100 @@ impl ManagerInner {
             log::info!("Stopping download {}", id);
             item.stop()
         } else {
-            Err(anyhow!("Download with id {} not found", id))
+            Err(Error::NotFound(*id).into())
         }
     }
 
+    pub fn stop_by_system(&mut self, id: &Uuid, reason: PauseReason) -> Result<()> {
+        log::info!("System stop ({:?}) requested for download: {}", reason, id);
+        match self.items.get_mut(id) {
+            Some(item) => item.stop_by_system(reason)?,
+            None => return Err(Error::NotFound(*id).into()),
+        }
+        if reason == PauseReason::QueueLimit {
+            self.push_to_queue(id);
+        }
+        Ok(())
+    }
+
+    pub fn cancellation_token(&self, id: &Uuid) -> Result<CancellationToken> {
+        match self.items.get(id) {
+            Some(item) => item
+                .cancellation_token()
+                .ok_or_else(|| Error::NotRunning.into()),
+            None => Err(Error::NotFound(*id).into()),
+        }
+    }
+
+    /// Moves downloads whose directory is gone to `directory` if their file is found there, see
+    /// `HttpDownload::relocate`. Running downloads are skipped and so are downloads that would end
+    /// up writing to the file of a running one. Returns the ids of moved downloads.
+    pub async fn relocate_all(&mut self, directory: &Path) -> Vec<Uuid> {
+        let active = self.active_paths();
+        let mut relocated = Vec::new();
+        for (id, item) in self.items.iter() {
+            let Ok(mut guard) = item.download.try_write() else {
+                continue;
+            };
+            let Some(download) = guard.as_http_mut() else {
+                continue;
+            };
+            let previous = download.directory.clone();
+            if !download.relocate(directory).await {
+                continue;
+            }
+            if let Err(e) = find_conflict(&active, id, &download.target_paths()) {
+                log::warn!("Not relocating download {}: {}", id, e);
+                download.directory = previous;
+                continue;
+            }
+            relocated.push(*id);
+        }
+        relocated
+    }
+
+    /// Renames the file of a download that isn't running, see `HttpDownload::rename`. Fails with
+    /// `PathConflict` if a running download writes to the new name.
+    pub async fn rename(&mut self, id: &Uuid, filename: String) -> Result<()> {
+        let active = self.active_paths();
+        let Some(item) = self.items.get(id) else {
+            return Err(Error::NotFound(*id).into());
+        };
+        let Ok(mut guard) = item.download.try_write() else {
+            return Err(Error::Locked.into());
+        };
+        let download = http_mut(guard.as_mut())?;
+        let mut renamed = download.clone();
+        renamed.filename = filename.clone();
+        find_conflict(&active, id, &renamed.target_paths())?;
+        Ok(download.rename(filename).await?)
+    }
+
     pub fn remove(&mut self, id: &Uuid) -> Option<DownloaderItem> {
         log::info!("Removing download: {}", id);
+        self.queue_order.retain(|queued| queued != id);
+        self.order.retain(|_, added| added != id);
         self.items.remove(id)
     }
 }
+
+/// Fails with `PathConflict` if a download other than `id` is writing to one of `paths`.
+fn find_conflict(active: &HashMap<PathBuf, Uuid>, id: &Uuid, paths: &[PathBuf]) -> Result<()> {
+    match paths.iter().find_map(|path| {
+        active
+            .get(path)
+            .filter(|other| *other != id)
+            .map(|other| (path, other))
+    }) {
+        Some((path, other)) => Err(Error::PathConflict {
+            path: path.clone(),
+            other: *other,
+        }
+        .into()),
+        None => Ok(()),
+    }
+}
+
+fn host_matches(item_host: Option<String>, host: &str) -> bool {
+    item_host.is_some_and(|item_host| item_host.eq_ignore_ascii_case(host))
+}
@@ -1,99 +1,296 @@
+use super::breaker::{is_host_failure, BreakerEvent};
+use super::dedup::ContentIndex;
+use super::dispatch::Slots;
 use super::download;
-use super::download::{DownloadUpdate, HttpDownload};
-use crate::httpdownload::manager::Result;
+use super::download::{DownloadUpdate, HttpDownload, PauseReason};
+use crate::downloadable::Downloadable;
+use crate::httpdownload::manager::{Error, Result};
 use crate::httpdownload::DownloadMetadata;
-use anyhow::anyhow;
-use std::sync::Arc;
-use tokio::sync::{mpsc, oneshot, Notify, RwLock};
+use crate::util::context;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, RwLock};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
 
-/// Wrapper over HttpDownload to allow multi-threaded managing
+/// Wrapper over a download of any type to allow multi-threaded managing
 /// TODO: add packages to allow batching download commands
 #[derive(Debug)]
 pub struct DownloaderItem {
-    pub(super) download: Arc<RwLock<HttpDownload>>,
-    /// This sender contains the channel to notify the thread to stop the download function
-    notifier: Option<Arc<Notify>>,
+    id: Uuid,
+    pub(super) download: Arc<RwLock<Box<dyn Downloadable>>>,
+    /// The task running the download, None if the download was never started
+    task: Option<RunningTask>,
+    /// Set while the download is paused by the system, cleared when it runs again
+    system_pause: Option<PauseReason>,
+    /// Outcomes of runs are reported here for the host's circuit breaker
+    breaker_events: mpsc::UnboundedSender<BreakerEvent>,
+    /// Number of times the download was started or resumed
+    attempts: u32,
+    /// Completed downloads are recorded here to find duplicates
+    dedup: ContentIndex,
+    /// Every run holds one of them while it runs
+    slots: Slots,
+}
+
+#[derive(Debug)]
+struct RunningTask {
+    cancel: CancellationToken,
+    /// Set before cancelling if the system (not the user) stops the download
+    pause_reason: Arc<Mutex<Option<PauseReason>>>,
+    handle: JoinHandle<()>,
 }
 
 impl DownloaderItem {
-    pub fn new(download: HttpDownload) -> Self {
+    pub fn new(
+        download: Box<dyn Downloadable>,
+        breaker_events: mpsc::UnboundedSender<BreakerEvent>,
+        dedup: ContentIndex,
+        slots: Slots,
+    ) -> Self {
         DownloaderItem {
+            id: download.id(),
             download: Arc::new(RwLock::new(download)),
-            notifier: None,
+            task: None,
+            system_pause: None,
+            breaker_events,
+            attempts: 0,
+            dedup,
+            slots,
         }
     }
 
+    /// Whether the download is in use, a running download task holds a read lock for as long as
+    /// it runs and pending operations like a url change hold the write lock.
     pub fn is_locked(&self) -> bool {
-        self.download.try_read().is_err()
+        self.download.try_write().is_err()
+    }
+
+    /// Whether a task is running the download right now.
+    pub fn is_running(&self) -> bool {
+        self.task
+            .as_ref()
+            .is_some_and(|task| !task.handle.is_finished())
+    }
+
+    /// Whether the download was started or resumed at least once.
+    pub fn was_started(&self) -> bool {
+        self.attempts > 0
     }
 
     pub fn run(&mut self, update_ch: mpsc::Sender<DownloadUpdate>, resume: bool) {
-        let notifier = Arc::new(Notify::new());
-        self.notifier = Some(notifier.clone());
+        let cancel = CancellationToken::new();
+        let pause_reason = Arc::new(Mutex::new(None));
+        self.system_pause = None;
+        self.attempts += 1;
+        let attempt = self.attempts;
         let download_arc = self.download.clone();
-        tokio::spawn(async move {
-            let download = download_arc.read().await;
-            log::info!(
-                "Acquired read lock for download: {}, resume: {}",
-                download.id,
-                resume
-            );
-
-            let update_ch_cl = update_ch.clone();
-            let download_task = async {
-                if resume {
-                    log::info!("Resuming download: {}", download.id);
-                    download.resume(update_ch_cl).await
-                } else {
-                    log::info!("Starting download: {}", download.id);
-                    download.start(update_ch_cl).await
-                }
-            };
-            let update = tokio::select! {
-                _ = notifier.notified() => {
-                    log::info!("Stopping download: {}", download.id);
-                    let downloaded_bytes = download.get_bytes_on_disk().await;
-                    DownloadUpdate {
-                        id: download.id,
-                        state: download::State::Paused(downloaded_bytes),
+        let breaker_events = self.breaker_events.clone();
+        let dedup = self.dedup.clone();
+        let slot = self.slots.take();
+        // Whatever the task logs is attributed to the download, see `context`
+        let handle = tokio::spawn(context::scoped(self.id, {
+            let cancel = cancel.clone();
+            let pause_reason = pause_reason.clone();
+            async move {
+                // Freed whenever the run ends, also if it's aborted
+                let _slot = slot;
+                // Only http downloads can be added without probing them first
+                let probed = download_arc
+                    .read()
+                    .await
+                    .as_http()
+                    .is_none_or(|download| download.probed);
+                let download = match probed {
+                    true => download_arc.read().await,
+                    false => {
+                        let mut guard = download_arc.write().await;
+                        if let Some(download) = guard.as_http_mut() {
+                            if let Err(e) = download.fetch_metadata().await {
+                                log::error!("Couldn't probe download {}: {}", download.id, e);
+                                download.report_error(&e, attempt, false);
+                                let _ = update_ch
+                                    .send(DownloadUpdate {
+                                        id: download.id,
+                                        state: download::State::Error(format!("{}", e)),
+                                    })
+                                    .await;
+                                return;
+                            }
+                        }
+                        guard.downgrade()
                     }
+                };
+                let id = download.id();
+                let http = download.as_http();
+                log::info!(
+                    "Acquired read lock for download: {}, resume: {}",
+                    id,
+                    resume
+                );
+                let result = download.run(update_ch.clone(), resume, cancel).await;
+                let success = match &result {
+                    Ok(_) => Some(true),
+                    Err(e) if is_host_failure(e) => Some(false),
+                    Err(_) => None,
+                };
+                if let (Some(success), Some(host)) = (success, download.host()) {
+                    let _ = breaker_events.send(BreakerEvent::Outcome {
+                        host: host.to_owned(),
+                        success,
+                    });
                 }
-                download_result = download_task => {
-                    match download_result {
-                        Ok(_) => {
-                            DownloadUpdate {
-                                id: download.id,
-                                state: download::State::Complete,
+                let state = match result {
+                    Ok(bytes) if http.is_some_and(HttpDownload::is_capped) => {
+                        download::State::Partial(bytes)
+                    }
+                    Ok(_) => {
+                        match http {
+                            Some(http) => {
+                                log::info!(
+                                    "Completed download {} after {} resumes",
+                                    id,
+                                    http.stats.resumes()
+                                );
+                                dedup.completed(http).await;
                             }
+                            None => log::info!("Completed download {}", id),
                         }
-                        Err(e) => {
-                            log::error!(
-                                "Error encountered while downloading {}, Error: {}",
-                                download.id,
-                                e
-                            );
-                            DownloadUpdate {
-                                id: download.id,
-                                state: download::State::Error(format!("{}", e)),
-                            }
+                        download::State::Complete
+                    }
+                    Err(download::Error::Cancelled(_)) => {
+                        log::info!("Stopped download: {}", id);
+                        let bytes_downloaded = download.get_bytes_on_disk().await;
+                        match pause_reason.lock().unwrap().take() {
+                            Some(reason) => download::State::PausedBySystem {
+                                bytes_downloaded,
+                                reason,
+                            },
+                            None => download::State::PausedByUser(bytes_downloaded),
                         }
                     }
-                }
-            };
-            let _ = update_ch.send(update).await;
+                    Err(e @ download::Error::SourceChanged(_)) => {
+                        report_error(http, &e, attempt);
+                        download::State::SourceChanged {
+                            bytes_downloaded: download.get_bytes_on_disk().await,
+                        }
+                    }
+                    Err(download::Error::ChecksumMismatch { expected, actual }) => {
+                        log::error!(
+                            "Download {} failed its checksum, expected {} but computed {}",
+                            id,
+                            expected,
+                            actual
+                        );
+                        report_error(
+                            http,
+                            &download::Error::ChecksumMismatch { expected, actual },
+                            attempt,
+                        );
+                        download::State::ChecksumFailed {
+                            expected: expected.to_string(),
+                            actual: actual.to_string(),
+                        }
+                    }
+                    Err(e) => {
+                        log::error!("Error encountered while downloading {}, Error: {}", id, e);
+                        report_error(http, &e, attempt);
+                        download::State::Error(format!("{}", e))
+                    }
+                };
+                let _ = update_ch.send(DownloadUpdate { id, state }).await;
+            }
+        }));
+        self.task = Some(RunningTask {
+            cancel,
+            pause_reason,
+            handle,
         });
     }
 
+    /// Token cancelling the current run of the download, cancelling it has the same effect as
+    /// `stop`. None if the download was never started.
+    pub fn cancellation_token(&self) -> Option<CancellationToken> {
+        self.task.as_ref().map(|task| task.cancel.clone())
+    }
+
     pub async fn get_metadata(&self) -> DownloadMetadata {
-        self.download.read().await.get_metadata()
+        let mut metadata = self.download.read().await.metadata();
+        metadata.duplicate_of = self.dedup.duplicate_of(&metadata.id);
+        metadata
+    }
+
+    pub async fn host(&self) -> Option<String> {
+        self.download.read().await.host().map(str::to_owned)
     }
 
+    pub async fn working_files(&self) -> Vec<PathBuf> {
+        self.download.read().await.working_files()
+    }
+
+    /// Stops the download on behalf of the user.
     pub fn stop(&mut self) -> Result<()> {
-        if let Some(notifier) = self.notifier.take() {
-            notifier.notify_one();
-        } else {
-            anyhow::bail!("Can't stop a download that is not running");
-        }
+        self.send_stop(None)
+    }
+
+    /// Stops the download on behalf of the system, it's picked up again by `resume_all`.
+    pub fn stop_by_system(&mut self, reason: PauseReason) -> Result<()> {
+        self.send_stop(Some(reason))?;
+        self.system_pause = Some(reason);
         Ok(())
     }
+
+    /// Marks a download that isn't running as waiting for a free slot, see
+    /// `ManagerInner::dispatch`.
+    pub fn queue(&mut self) {
+        self.system_pause = Some(PauseReason::QueueLimit);
+    }
+
+    /// Reason the system paused this download, None if it wasn't paused by the system.
+    pub fn system_pause(&self) -> Option<PauseReason> {
+        self.system_pause
+    }
+
+    fn send_stop(&mut self, reason: Option<PauseReason>) -> Result<()> {
+        // The task is gone if the download already finished, nothing to stop then
+        match self.task.take() {
+            Some(task) if !task.handle.is_finished() && !task.cancel.is_cancelled() => {
+                *task.pause_reason.lock().unwrap() = reason;
+                task.cancel.cancel();
+                Ok(())
+            }
+            _ => Err(Error::NotRunning.into()),
+        }
+    }
+}
+
+/// Records the error in the diagnostics of an http download, other types keep none.
+fn report_error(http: Option<&HttpDownload>, error: &download::Error, attempt: u32) {
+    if let Some(http) = http {
+        http.report_error(error, attempt, false);
+    }
+}
+
+/// The download as `HttpDownload`, fails with `Unsupported` for other download types.
+pub(super) fn http(download: &dyn Downloadable) -> Result<&HttpDownload> {
+    download
+        .as_http()
+        .ok_or_else(|| Error::Unsupported(download.id()).into())
+}
+
+pub(super) fn http_mut(download: &mut dyn Downloadable) -> Result<&mut HttpDownload> {
+    let id = download.id();
+    download
+        .as_http_mut()
+        .ok_or_else(|| Error::Unsupported(id).into())
+}
+
+impl Drop for DownloaderItem {
+    /// A removed item doesn't keep downloading in the background
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.cancel.cancel();
+        }
+    }
 }
@@ -0,0 +1,55 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Rate limits the checks whether the file of a completed download still exists, a download is
+/// checked at most once per `interval` no matter how often its state is read.
+#[derive(Debug, Clone)]
+pub struct MissingCheck {
+    interval: Duration,
+    last_checked: Arc<Mutex<HashMap<Uuid, Instant>>>,
+}
+
+impl MissingCheck {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_checked: Arc::default(),
+        }
+    }
+
+    /// Whether the download is to be checked at `now`, the check is recorded if it is.
+    pub fn is_due(&self, id: &Uuid, now: Instant) -> bool {
+        let mut last_checked = self.last_checked.lock().unwrap();
+        match last_checked.get(id) {
+            Some(last) if now.duration_since(*last) < self.interval => false,
+            _ => {
+                last_checked.insert(*id, now);
+                true
+            }
+        }
+    }
+
+    pub fn forget(&self, id: &Uuid) {
+        self.last_checked.lock().unwrap().remove(id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checks_are_rate_limited_per_download_test() {
+        let check = MissingCheck::new(Duration::from_secs(60));
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Instant::now();
+        assert!(check.is_due(&a, now));
+        assert!(!check.is_due(&a, now + Duration::from_secs(30)));
+        assert!(check.is_due(&b, now + Duration::from_secs(30)));
+        assert!(check.is_due(&a, now + Duration::from_secs(60)));
+        check.forget(&b);
+        assert!(check.is_due(&b, now + Duration::from_secs(31)));
+    }
+}
@@ -1,19 +1,102 @@
+pub mod bandwidth;
+pub mod breaker;
+pub mod dedup;
+pub mod diskspace;
+mod dispatch;
+pub mod gate;
+pub mod idempotency;
 mod inner;
 mod item;
+pub mod missing;
+pub mod page;
+pub mod persist;
+pub mod probe;
+pub mod reconcile;
 
+use crate::downloadable::Downloadable;
 use crate::httpdownload::download;
-use crate::httpdownload::download::{DownloadUpdate, HttpDownload};
+use crate::httpdownload::download::checksum::Checksum;
+use crate::httpdownload::download::limiter::RateLimiter;
+use crate::httpdownload::download::retry::RetryPolicy;
+use crate::httpdownload::download::stats::DownloadDiagnostics;
+use crate::httpdownload::download::{DownloadUpdate, HttpDownload, PauseReason};
+use reqwest::Url;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, RwLock, RwLockReadGuard, RwLockWriteGuard, Semaphore};
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
+use self::bandwidth::{BandwidthLimit, CapacityEstimator, ESTIMATE_INTERVAL};
+use self::breaker::{BreakerConfig, BreakerEvent, CircuitBreaker, HostCircuit};
+use self::dedup::{ContentIndex, DedupAction};
+use self::diskspace::DiskReserve;
+use self::dispatch::Slots;
+use self::gate::{StartCondition, StartGate};
+use self::idempotency::IdempotencyKeys;
 use self::inner::ManagerInner;
+use self::item::{http, http_mut};
+use self::missing::MissingCheck;
+use self::page::{Cursor, Page};
+use self::persist::{Change, Persister, SavedDownload, StateFile};
+use self::probe::ProbeLimiter;
+use self::reconcile::{reconciled, Reconciliation, Repair};
 
-use super::observer::{DownloadObserver, DownloadUpdateBuffer};
-use super::{DownloadMetadata, Subscribers};
+use super::history::{EventHistory, HistoryEntry, HistoryLimits};
+use super::observer::{AggregateUpdate, DownloadObserver, DownloadUpdateBuffer};
+use super::{DownloadMetadata, DownloadUpdateSubscriber, LifecycleEvent, Subscribers};
 
 pub type Result<T> = anyhow::Result<T>;
 
+/// Default for how long the manager waits for its internal lock, this should never be hit unless
+/// something is stuck.
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Errors of the manager that callers may want to handle specifically, they are returned wrapped
+/// in an `anyhow::Error` and can be recovered with `downcast_ref`.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Timed out after {0:?} waiting for the download manager lock")]
+    LockTimeout(Duration),
+    #[error("Download with id {0} not found")]
+    NotFound(Uuid),
+    #[error("Can't stop a download that is not running")]
+    NotRunning,
+    #[error(
+        "Download is already locked, probably running already or locked up by pending operation!"
+    )]
+    Locked,
+    #[error("Host {0} failed too often, its downloads are paused until it recovers")]
+    HostUnavailable(String),
+    #[error("Download {other} already writes to {path:?}")]
+    PathConflict { path: PathBuf, other: Uuid },
+    #[error("Download {0} isn't queued")]
+    NotQueued(Uuid),
+    #[error("Download {0} isn't complete")]
+    NotComplete(Uuid),
+    #[error("Download {0} doesn't support this operation, only http downloads do")]
+    Unsupported(Uuid),
+}
+
+impl Error {
+    /// Stable identifier of the error for API clients to branch on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::LockTimeout(_) => "lock_timeout",
+            Error::NotFound(_) => "not_found",
+            Error::NotRunning => "not_running",
+            Error::Locked => "locked",
+            Error::HostUnavailable(_) => "host_unavailable",
+            Error::PathConflict { .. } => "path_conflict",
+            Error::NotQueued(_) => "not_queued",
+            Error::NotComplete(_) => "not_complete",
+            Error::Unsupported(_) => "unsupported",
+        }
+    }
+}
+
 /// Trait for a struct that can handle DownloadUpdates.
 pub trait UpdateConsumer {
     fn consume(&mut self, update: DownloadUpdate);
@@ -26,8 +109,36 @@ pub trait UpdateConsumer {
 #[derive(Clone)]
 pub struct DownloadManager {
     inner: Arc<RwLock<ManagerInner>>,
+    /// Where downloads whose directory disappeared are looked for before they are resumed
+    download_dir: Option<PathBuf>,
+    /// Shared by all downloads added to the manager, see `with_segment_limit`
+    segment_limit: Option<Arc<Semaphore>>,
+    /// Shared by all downloads added to the manager, see `with_bandwidth_limit` and
+    /// `set_global_rate_limit`
+    rate_limiter: RateLimiter,
+    /// Where the rate of `rate_limiter` comes from, an estimator stops once it's changed
+    bandwidth_limit: Arc<std::sync::Mutex<BandwidthLimit>>,
+    breaker: Arc<std::sync::Mutex<CircuitBreaker>>,
+    /// Consulted before downloads are started automatically, see `StartGate`
+    gate: StartGate,
+    /// Running downloads, see `with_max_concurrent`
+    slots: Slots,
+    /// Set if completed downloads are checked for a deleted file, see `with_missing_check`
+    missing_check: Option<MissingCheck>,
+    /// Set if downloads can be added with an idempotency key, see `with_idempotency_ttl`
+    idempotency: Option<IdempotencyKeys>,
+    /// Content of completed downloads, see `with_dedup`
+    dedup: ContentIndex,
+    /// Bounds concurrent probes through `probe`, see `with_probe_limits`
+    probe_limiter: ProbeLimiter,
     subscribers: Subscribers,
+    /// Forwarded to the subscribers in order, see `LifecycleEvent`
+    lifecycle_events: mpsc::UnboundedSender<LifecycleEvent>,
+    /// Set if the downloads are saved, see `with_state_file`
+    state_changes: Option<mpsc::UnboundedSender<Change>>,
+    lock_timeout: Duration,
     pub observer: DownloadObserver,
+    pub history: EventHistory,
 }
 
 impl DownloadManager {
@@ -36,101 +147,1097 @@ impl DownloadManager {
         let observer = DownloadObserver::new();
         let buffer = DownloadUpdateBuffer::new();
         buffer.add_subscriber(observer.clone()).await;
+        let history = EventHistory::default();
+        buffer.add_subscriber(history.clone()).await;
         let subscribers = buffer.subscribers.clone();
-        let inner = Arc::new(RwLock::new(ManagerInner::new(buffer)));
+        let breaker = Arc::new(std::sync::Mutex::new(CircuitBreaker::default()));
+        let (breaker_events, breaker_recv) = mpsc::unbounded_channel();
+        let (error_events, error_recv) = mpsc::unbounded_channel();
+        let dedup = ContentIndex::default();
+        let (freed, freed_recv) = mpsc::unbounded_channel();
+        let slots = Slots::new(freed);
+        let inner = Arc::new(RwLock::new(ManagerInner::new(
+            buffer,
+            breaker.clone(),
+            breaker_events.clone(),
+            error_events,
+            dedup.clone(),
+            slots.clone(),
+        )));
+        tokio::spawn(forward_errors(subscribers.clone(), error_recv));
+        let (lifecycle_events, lifecycle_recv) = mpsc::unbounded_channel();
+        tokio::spawn(forward_lifecycle(subscribers.clone(), lifecycle_recv));
+        let gate = StartGate::default();
+        tokio::spawn(run_breaker(
+            inner.clone(),
+            breaker.clone(),
+            gate.clone(),
+            breaker_events,
+            breaker_recv,
+        ));
+        tokio::spawn(run_dispatcher(inner.clone(), gate.clone(), freed_recv));
 
         Self {
             inner,
+            download_dir: None,
+            segment_limit: None,
+            rate_limiter: RateLimiter::default(),
+            bandwidth_limit: Arc::default(),
+            breaker,
+            gate,
+            slots,
+            missing_check: None,
+            idempotency: None,
+            dedup,
+            probe_limiter: ProbeLimiter::default(),
             subscribers,
+            lifecycle_events,
+            state_changes: None,
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
             observer,
+            history,
         }
     }
 
+    /// Only starts downloads automatically while the condition allows it, see `StartGate`.
+    pub fn with_start_condition(
+        self,
+        condition: impl StartCondition + Send + Sync + 'static,
+    ) -> Self {
+        self.gate.set_condition(condition);
+        self
+    }
+
+    pub fn with_lock_timeout(mut self, lock_timeout: Duration) -> Self {
+        self.lock_timeout = lock_timeout;
+        self
+    }
+
+    /// Downloads resumed after their directory disappeared are looked for in `download_dir`, e.g.
+    /// after the configured download directory changed and the files were moved.
+    pub fn with_download_dir(mut self, download_dir: PathBuf) -> Self {
+        self.download_dir = Some(download_dir);
+        self
+    }
+
+    /// Lets at most `limit` downloads run at the same time, downloads started through `dispatch`
+    /// while all of them run are queued until one of them ends. Zero removes the cap.
+    pub fn with_max_concurrent(self, limit: usize) -> Self {
+        self.slots.set_limit(limit);
+        self
+    }
+
+    /// Caps the concurrent segment connections of all downloads added afterwards to `limit`,
+    /// segments over it wait until another segment finishes. Zero removes the cap.
+    pub fn with_segment_limit(mut self, limit: usize) -> Self {
+        self.segment_limit = (limit > 0).then(|| Arc::new(Semaphore::new(limit)));
+        self
+    }
+
+    /// Caps the combined speed of all downloads added afterwards. A relative limit is a fraction
+    /// of the capacity estimated from the speed of the running downloads every
+    /// `ESTIMATE_INTERVAL`, downloads are unlimited while there's no estimate.
+    pub fn with_bandwidth_limit(self, limit: BandwidthLimit) -> Self {
+        *self.bandwidth_limit.lock().unwrap() = limit;
+        match limit {
+            BandwidthLimit::Unlimited => self.rate_limiter.set_rate(None),
+            BandwidthLimit::Fixed(rate) => self.rate_limiter.set_rate(Some(rate)),
+            BandwidthLimit::Relative(fraction) => {
+                self.rate_limiter.set_rate(None);
+                tokio::spawn(estimate_capacity(
+                    self.observer.clone(),
+                    self.rate_limiter.clone(),
+                    self.bandwidth_limit.clone(),
+                    CapacityEstimator::new(fraction),
+                ));
+            }
+        }
+        self
+    }
+
+    /// Bounds the memory of the event history, see `EventHistory`.
+    pub fn with_history_limits(self, limits: HistoryLimits) -> Self {
+        self.history.set_limits(limits);
+        self
+    }
+
+    /// Emits an `AggregateUpdate` of all downloads to the subscribers every `interval`.
+    pub fn with_aggregate_interval(self, interval: Duration) -> Self {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            loop {
+                ticker.tick().await;
+                let Ok(metadata) = manager.get_metadata_all().await else {
+                    continue;
+                };
+                let states = manager.observer.read_state().await;
+                let update = Arc::new(AggregateUpdate::from_states(metadata.iter().filter_map(
+                    |metadata| {
+                        states
+                            .get(&metadata.id)
+                            .map(|state| (state, metadata.download_size.unwrap_or_default()))
+                    },
+                )));
+                drop(states);
+                for subscriber in manager.subscribers.lock().await.iter() {
+                    let (subscriber, update) = (subscriber.clone(), update.clone());
+                    tokio::spawn(async move { subscriber.aggregate(&update).await });
+                }
+            }
+        });
+        self
+    }
+
+    /// Checks whether the file of a completed download still exists when its state is read with
+    /// `get_state`, at most once per `interval` for every download. A download whose file was
+    /// deleted becomes `Missing`.
+    pub fn with_missing_check(mut self, interval: Duration) -> Self {
+        self.missing_check = Some(MissingCheck::new(interval));
+        self
+    }
+
+    /// Remembers for `ttl` which download was added with an idempotency key, see `add_with_key`.
+    pub fn with_idempotency_ttl(mut self, ttl: Duration) -> Self {
+        self.idempotency = Some(IdempotencyKeys::new(ttl));
+        self
+    }
+
+    /// Hashes downloads once they complete and applies `action` to the ones with the same
+    /// content as a download that completed before, see `ContentIndex`.
+    pub fn with_dedup(self, action: DedupAction) -> Self {
+        self.dedup.set_action(Some(action));
+        self
+    }
+
+    /// Lets at most `total` downloads be probed through `probe` at the same time and at most
+    /// `per_host` of them of the same host.
+    pub fn with_probe_limits(mut self, total: usize, per_host: usize) -> Self {
+        self.probe_limiter = ProbeLimiter::new(total, per_host);
+        self
+    }
+
+    /// Saves the downloads to `file` as they change, see `StateFile`. They are restored with
+    /// `restore`.
+    pub fn with_state_file(mut self, file: StateFile) -> Self {
+        let persister = Persister::start(self.clone(), file);
+        self.state_changes = Some(persister.changes());
+        let subscribers = self.subscribers.clone();
+        tokio::spawn(async move { subscribers.lock().await.push(Arc::new(persister)) });
+        self
+    }
+
+    /// Keeps `reserve.min_free` bytes free on the filesystems of `reserve.directories`, see
+    /// `DiskReserve`.
+    pub fn with_disk_reserve(self, reserve: DiskReserve) -> Self {
+        self.watch_disk_space(reserve, diskspace::free_space)
+    }
+
+    fn watch_disk_space(
+        self,
+        reserve: DiskReserve,
+        free_space: impl Fn(&Path) -> std::io::Result<u64> + Send + 'static,
+    ) -> Self {
+        let (inner, gate) = (self.inner.clone(), self.gate.clone());
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(reserve.poll_interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            loop {
+                ticker.tick().await;
+                let low = reserve.is_low(&free_space);
+                // Checked before locking, the condition may take a while
+                let resume = !low && gate.allows().await;
+                let mut inner = inner.write().await;
+                if low {
+                    if !inner.low_disk_space {
+                        log::warn!(
+                            "Less than {} bytes free, pausing the running downloads",
+                            reserve.min_free
+                        );
+                    }
+                    // Also catches downloads started since the last check
+                    inner.pause_all(PauseReason::LowDiskSpace);
+                } else if resume {
+                    let resumed = inner.resume_paused(PauseReason::LowDiskSpace);
+                    if !resumed.is_empty() {
+                        log::info!("Enough space free again, resumed downloads {:?}", resumed);
+                    }
+                }
+                inner.low_disk_space = low;
+            }
+        });
+        self
+    }
+
+    pub fn with_circuit_breaker(self, config: BreakerConfig) -> Self {
+        self.breaker.lock().unwrap().config = config;
+        self
+    }
+
+    /// State of the circuit breaker of every host that failed recently.
+    pub fn circuit_status(&self) -> Vec<HostCircuit> {
+        self.breaker.lock().unwrap().status(Instant::now())
+    }
+
+    async fn read(&self) -> Result<RwLockReadGuard<'_, ManagerInner>> {
+        tokio::time::timeout(self.lock_timeout, self.inner.read())
+            .await
+            .map_err(|_| Error::LockTimeout(self.lock_timeout).into())
+    }
+
+    async fn write(&self) -> Result<RwLockWriteGuard<'_, ManagerInner>> {
+        tokio::time::timeout(self.lock_timeout, self.inner.write())
+            .await
+            .map_err(|_| Error::LockTimeout(self.lock_timeout).into())
+    }
+
+    /// Registers an additional subscriber that will receive the batched download updates.
+    pub async fn subscribe(
+        &self,
+        subscriber: impl DownloadUpdateSubscriber + Send + Sync + 'static,
+    ) {
+        let mut guard = self.subscribers.lock().await;
+        guard.push(Arc::new(subscriber));
+    }
+
+    /// Starts the download from scratch, a `pause_at` threshold it already reached is cleared.
     pub async fn start(&self, id: &Uuid) -> Result<()> {
-        let mut inner = self.inner.write().await;
+        let mut inner = self.write().await?;
+        inner.clear_reached_pause_at(id).await;
         inner.run(id, false)
     }
 
+    /// Starts the download like `start` if fewer than the maximum of concurrent downloads run,
+    /// otherwise it's queued (paused by the system with `PauseReason::QueueLimit`) until a slot
+    /// is free, see `with_max_concurrent`. Returns whether the download was started.
+    pub async fn dispatch(&self, id: &Uuid) -> Result<bool> {
+        let mut inner = self.write().await?;
+        if inner.slots.is_full() {
+            inner.enqueue(id).await?;
+            return Ok(false);
+        }
+        inner.clear_reached_pause_at(id).await;
+        inner.run(id, false)?;
+        Ok(true)
+    }
+
+    /// Resumes the download, a `pause_at` threshold it already reached is cleared.
     pub async fn resume(&self, id: &Uuid) -> Result<()> {
-        let mut inner = self.inner.write().await;
+        let mut inner = self.write().await?;
+        self.relocate(&mut inner).await;
+        inner.clear_reached_pause_at(id).await;
         inner.run(id, true)
     }
 
+    /// Moves downloads whose directory is gone to `directory` if their file is found there,
+    /// returns the ids of the moved downloads.
+    pub async fn relocate_all(&self, directory: &Path) -> Result<Vec<Uuid>> {
+        let mut inner = self.write().await?;
+        let relocated = inner.relocate_all(directory).await;
+        self.publish_moved(&inner, &relocated).await;
+        Ok(relocated)
+    }
+
+    async fn relocate(&self, inner: &mut ManagerInner) {
+        if let Some(download_dir) = &self.download_dir {
+            let relocated = inner.relocate_all(download_dir).await;
+            self.publish_moved(inner, &relocated).await;
+        }
+    }
+
+    async fn publish_moved(&self, inner: &ManagerInner, relocated: &[Uuid]) {
+        for id in relocated {
+            if let Ok(metadata) = inner.get_metadata(id).await {
+                let _ = self
+                    .lifecycle_events
+                    .send(LifecycleEvent::Moved { metadata });
+            }
+        }
+    }
+
     pub async fn stop(&self, id: &Uuid) -> Result<()> {
-        let mut inner = self.inner.write().await;
+        let mut inner = self.write().await?;
         inner.stop(id)
     }
 
-    pub async fn start_all(&self) {
-        let mut inner = self.inner.write().await;
-        inner.start_all()
+    /// Pauses a download on behalf of the system, see `resume_all`.
+    pub async fn stop_by_system(&self, id: &Uuid, reason: PauseReason) -> Result<()> {
+        let mut inner = self.write().await?;
+        inner.stop_by_system(id, reason)
+    }
+
+    /// Resumes every download the system paused, returns their ids. Downloads the user paused are
+    /// left alone, and nothing is resumed while automatic starts aren't allowed.
+    pub async fn resume_all(&self) -> Result<Vec<Uuid>> {
+        if !self.gate.allows().await {
+            log::info!("Automatic starts aren't allowed, not resuming downloads");
+            return Ok(Vec::new());
+        }
+        let mut inner = self.write().await?;
+        self.relocate(&mut inner).await;
+        Ok(inner.resume_all())
+    }
+
+    /// Whether the manager may start downloads on its own right now, see `StartGate`.
+    pub async fn downloads_allowed(&self) -> bool {
+        self.gate.allows().await
+    }
+
+    /// Allows or holds back automatic starts, e.g. toggled by a network monitor. Allowing them
+    /// resumes the downloads that were held back, their ids are returned.
+    pub async fn set_downloads_allowed(&self, allowed: bool) -> Result<Vec<Uuid>> {
+        if self.gate.set_allowed(allowed) {
+            log::info!("Automatic starts of downloads allowed: {}", allowed);
+        }
+        match allowed {
+            true => self.resume_all().await,
+            false => Ok(Vec::new()),
+        }
+    }
+
+    /// Downloads waiting for a free slot (paused by the system with `PauseReason::QueueLimit`) in
+    /// the order they get one.
+    pub async fn queue(&self) -> Result<Vec<Uuid>> {
+        let inner = self.read().await?;
+        Ok(inner.queue())
+    }
+
+    /// Sets the dispatch order of queued downloads, the given ids come first in the given order
+    /// and the rest of the queue keeps its order behind them. Fails with `NotFound` or
+    /// `NotQueued` if an id isn't a queued download, running downloads aren't affected. Returns
+    /// the new queue.
+    pub async fn reorder_queue(&self, ids: &[Uuid]) -> Result<Vec<Uuid>> {
+        let mut inner = self.write().await?;
+        inner.reorder_queue(ids)
+    }
+
+    pub async fn start_all(&self) -> Result<()> {
+        let mut inner = self.write().await?;
+        self.relocate(&mut inner).await;
+        inner.start_all();
+        Ok(())
+    }
+
+    pub async fn stop_all(&self) -> Result<()> {
+        let mut inner = self.write().await?;
+        inner.stop_all();
+        Ok(())
+    }
+
+    pub async fn stop_by_host(&self, host: &str) -> Result<Vec<Uuid>> {
+        let mut inner = self.write().await?;
+        Ok(inner.stop_by_host(host).await)
     }
 
-    pub async fn stop_all(&self) {
-        let mut inner = self.inner.write().await;
-        inner.stop_all()
+    /// Resumes the downloads of `host` that are stopped or paused, complete and never started
+    /// downloads are left alone.
+    pub async fn start_by_host(&self, host: &str) -> Result<Vec<Uuid>> {
+        let paused: HashSet<Uuid> = self
+            .observer
+            .read_state()
+            .await
+            .iter()
+            .filter(|(_, state)| {
+                matches!(
+                    state,
+                    download::State::PausedByUser(_) | download::State::PausedBySystem { .. }
+                )
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        let mut inner = self.write().await?;
+        Ok(inner.start_by_host(host, &paused).await)
+    }
+
+    /// Token of the current run of a download, cancelling it stops the download like `stop` does.
+    /// A new token is created every time the download is started or resumed.
+    pub async fn cancellation_token(&self, id: &Uuid) -> Result<CancellationToken> {
+        let inner = self.read().await?;
+        inner.cancellation_token(id)
+    }
+
+    /// Moves the download to a new url once `HttpDownload::check_source` accepted it, a running
+    /// download is stopped and resumed against the new source. The probe and the wait for the
+    /// stopped task happen without holding the manager lock, other calls aren't held up by them.
+    pub async fn change_url(&self, id: &Uuid, url: Url) -> Result<()> {
+        let download = {
+            let inner = self.read().await?;
+            match inner.items.get(id) {
+                Some(item) => item.download.clone(),
+                None => return Err(Error::NotFound(*id).into()),
+            }
+        };
+        let server_metadata = {
+            let download = download.read().await;
+            http(download.as_ref())?.check_source(&url).await?
+        };
+        let was_running = self.write().await?.stop_for_url_change(id)?;
+        // Waits for a stopped download task to release the download
+        let result = {
+            let mut download = download.write().await;
+            http_mut(download.as_mut())?
+                .set_source(url, server_metadata)
+                .await
+        };
+        if was_running {
+            if let Err(e) = self.write().await?.run(id, true) {
+                log::warn!(
+                    "Couldn't resume download {} after its url changed: {}",
+                    id,
+                    e
+                );
+            }
+        }
+        Ok(result?)
+    }
+
+    /// Renames the file of a stopped download, fails with `PathConflict` if a running download
+    /// writes to the new name and with `Locked` if the download is running.
+    pub async fn rename(&self, id: &Uuid, filename: String) -> Result<()> {
+        let mut inner = self.write().await?;
+        inner.rename(id, filename).await?;
+        let metadata = inner.get_metadata(id).await?;
+        let _ = self
+            .lifecycle_events
+            .send(LifecycleEvent::Renamed { metadata });
+        Ok(())
+    }
+
+    /// Probes a stopped download again and returns the names of the metadata fields that changed,
+    /// fails with `Locked` if the download is running. A lazily created download is probed then,
+    /// one stopped because its source changed accepts the change and can be resumed.
+    pub async fn refresh_metadata(&self, id: &Uuid) -> Result<Vec<&'static str>> {
+        let changed = {
+            let mut inner = self.write().await?;
+            inner.refresh_metadata(id).await?
+        };
+        match self.observer.get_state(id).await {
+            Some(download::State::Created) => {
+                self.observer
+                    .track(*id, download::State::PausedByUser(0))
+                    .await;
+            }
+            Some(download::State::SourceChanged { bytes_downloaded }) => {
+                self.observer
+                    .track(*id, download::State::PausedByUser(bytes_downloaded))
+                    .await;
+            }
+            _ => {}
+        }
+        Ok(changed)
+    }
+
+    /// Hashes the file of a complete download against `expected` without downloading it again and
+    /// returns the computed checksum. On a mismatch the download becomes `ChecksumFailed` and
+    /// keeps its file for inspection, it fails with `NotComplete` unless the download is complete.
+    pub async fn verify(&self, id: &Uuid, expected: Checksum) -> Result<Checksum> {
+        let download = {
+            let inner = self.read().await?;
+            match inner.items.get(id) {
+                Some(item) => item.download.clone(),
+                None => return Err(Error::NotFound(*id).into()),
+            }
+        };
+        if self.observer.get_state(id).await != Some(download::State::Complete) {
+            return Err(Error::NotComplete(*id).into());
+        }
+        let verified = {
+            let download = download.read().await;
+            http(download.as_ref())?.verify_completed(expected).await
+        };
+        match verified {
+            Ok(actual) => Ok(actual),
+            Err(download::Error::ChecksumMismatch { expected, actual }) => {
+                let state = download::State::ChecksumFailed {
+                    expected: expected.to_string(),
+                    actual: actual.to_string(),
+                };
+                self.history.record_state(*id, &state);
+                self.observer.track(*id, state).await;
+                Ok(actual)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Changes how transient failures of the download are retried, a running download uses the
+    /// new policy from its next retry on. The policy is saved with the download, see
+    /// `with_state_file`.
+    pub async fn set_retry_policy(&self, id: &Uuid, policy: RetryPolicy) -> Result<()> {
+        let inner = self.read().await?;
+        inner.set_retry_policy(id, policy).await?;
+        self.edited();
+        Ok(())
+    }
+
+    /// Caps the speed of the download alone, None lifts the cap. A running download follows from
+    /// its next chunk on, over all its segments.
+    pub async fn set_rate_limit(&self, id: &Uuid, bytes_per_second: Option<u64>) -> Result<()> {
+        let inner = self.read().await?;
+        inner.set_rate_limit(id, bytes_per_second).await?;
+        self.edited();
+        Ok(())
+    }
+
+    /// Caps the combined speed of all downloads of the manager, None lifts the cap. The running
+    /// downloads share one token bucket, so they get about the same part of the cap and the
+    /// part of a download that finishes or stops goes to the others without restarting them.
+    /// A download's own `rate_limit` still applies, it gets the lower of both. Replaces a
+    /// relative limit set with `with_bandwidth_limit`.
+    pub fn set_global_rate_limit(&self, bytes_per_second: Option<u64>) {
+        let mut limit = self.bandwidth_limit.lock().unwrap();
+        *limit = match bytes_per_second {
+            Some(rate) => BandwidthLimit::Fixed(rate),
+            None => BandwidthLimit::Unlimited,
+        };
+        self.rate_limiter.set_rate(bytes_per_second);
+    }
+
+    /// Bytes per second all downloads together are currently limited to, None if unlimited.
+    pub fn global_rate_limit(&self) -> Option<u64> {
+        self.rate_limiter.rate()
+    }
+
+    /// Changes the connections of a segmented download, a running download opens more right away
+    /// or lets the extra ones finish their range, see `SegmentTarget`.
+    pub async fn set_segments(&self, id: &Uuid, segments: usize) -> Result<()> {
+        let inner = self.read().await?;
+        inner.set_segments(id, segments).await?;
+        self.edited();
+        Ok(())
+    }
+
+    /// Exempts the download from the bandwidth limit or subjects it to the limit again, a running
+    /// download follows from its next chunk on.
+    pub async fn set_ignore_global_limit(&self, id: &Uuid, ignore: bool) -> Result<()> {
+        let inner = self.read().await?;
+        inner.set_ignore_global_limit(id, ignore).await?;
+        self.edited();
+        Ok(())
+    }
+
+    /// Saves a changed option of a download right away, no update or lifecycle event reports it.
+    fn edited(&self) {
+        if let Some(changes) = &self.state_changes {
+            let _ = changes.send(Change::Edited);
+        }
     }
 
     pub async fn get_metadata(&self, id: &Uuid) -> Result<DownloadMetadata> {
-        let inner = self.inner.read().await;
+        let inner = self.read().await?;
         inner.get_metadata(id).await
     }
 
-    pub async fn get_metadata_all(&self) -> Vec<DownloadMetadata> {
-        let inner = self.inner.read().await;
-        inner.get_metadata_all().await
+    /// Connection level metrics of a download, see `DownloadDiagnostics`.
+    pub async fn diagnostics(&self, id: &Uuid) -> Result<DownloadDiagnostics> {
+        let inner = self.read().await?;
+        inner.diagnostics(id).await
+    }
+
+    /// File of the download and the number of bytes at its start that can be read, also while it
+    /// runs, see `HttpDownload::readable_prefix`.
+    pub async fn readable_prefix(&self, id: &Uuid) -> Result<Option<(PathBuf, u64)>> {
+        let inner = self.read().await?;
+        inner.readable_prefix(id).await
+    }
+
+    /// Where a finished (complete or partial) download ended up on disk, see
+    /// `HttpDownload::final_path`. None while the download isn't finished.
+    pub async fn final_path(&self, id: &Uuid) -> Result<Option<PathBuf>> {
+        let path = {
+            let inner = self.read().await?;
+            inner.final_path(id).await?
+        };
+        let finished = matches!(
+            self.observer.get_state(id).await,
+            Some(download::State::Complete | download::State::Partial(_))
+        );
+        Ok(finished.then_some(path))
+    }
+
+    /// Tracked state of the download. With `with_missing_check` a completed download whose file
+    /// is gone is reported (and from then on tracked) as `Missing`.
+    pub async fn get_state(&self, id: &Uuid) -> Option<download::State> {
+        let state = self.observer.get_state(id).await?;
+        let due = self
+            .missing_check
+            .as_ref()
+            .is_some_and(|check| check.is_due(id, Instant::now()));
+        if state != download::State::Complete || !due {
+            return Some(state);
+        }
+        let Ok(Some(path)) = self.final_path(id).await else {
+            return Some(state);
+        };
+        if tokio::fs::try_exists(&path).await.unwrap_or(true) {
+            return Some(state);
+        }
+        log::warn!("File {:?} of completed download {} is gone", path, id);
+        self.observer.track(*id, download::State::Missing).await;
+        Some(download::State::Missing)
+    }
+
+    /// Waits until the download is complete, partial, failed, failed its checksum or missing and
+    /// returns that state, right away if it already is. Fails with `NotFound` for unknown
+    /// downloads and ones deleted while waiting.
+    pub async fn wait_until_done(&self, id: &Uuid) -> Result<download::State> {
+        self.observer
+            .wait_for_terminal(id)
+            .await
+            .ok_or_else(|| Error::NotFound(*id).into())
+    }
+
+    /// Compares the tracked state of every stopped download with its files and corrects states
+    /// that drifted, e.g. after a crash or files changed outside of the manager, see
+    /// `reconcile::reconciled`. Running downloads and ones locked by a pending operation are
+    /// skipped, none of them can start while the downloads are checked.
+    pub async fn reconcile(&self) -> Result<Reconciliation> {
+        let inner = self.read().await?;
+        let mut reconciliation = Reconciliation::default();
+        for (id, item) in inner.in_order() {
+            let download = match item.is_running() {
+                true => None,
+                false => item.download.try_read().ok(),
+            };
+            let Some(download) = download else {
+                reconciliation.skipped.push(*id);
+                continue;
+            };
+            let Some(state) = self.observer.get_state(id).await else {
+                continue;
+            };
+            reconciliation.checked += 1;
+            let final_exists = tokio::fs::try_exists(download.final_path())
+                .await
+                .unwrap_or(true);
+            let bytes_on_disk = download.get_bytes_on_disk().await;
+            let Some(after) = reconciled(&state, final_exists, bytes_on_disk) else {
+                continue;
+            };
+            log::warn!(
+                "Download {} was {:?} but its files say {:?}, correcting it",
+                id,
+                state,
+                after
+            );
+            self.history.record_state(*id, &after);
+            self.observer.track(*id, after.clone()).await;
+            reconciliation.repairs.push(Repair {
+                id: *id,
+                before: state,
+                after,
+            });
+        }
+        Ok(reconciliation)
+    }
+
+    /// Recent state changes and errors of the download, oldest first. Older events may have been
+    /// evicted, see `with_history_limits`.
+    pub async fn events(&self, id: &Uuid) -> Result<Vec<HistoryEntry>> {
+        let inner = self.read().await?;
+        match inner.items.contains_key(id) {
+            true => Ok(self.history.events(id)),
+            false => Err(Error::NotFound(*id).into()),
+        }
+    }
+
+    /// Metadata of all downloads in the order they were added.
+    pub async fn get_metadata_all(&self) -> Result<Vec<DownloadMetadata>> {
+        let inner = self.read().await?;
+        Ok(inner.get_metadata_all().await)
+    }
+
+    /// Up to `limit` downloads in the order they were added, continuing after `cursor`. Paging
+    /// with the returned cursor neither skips nor repeats downloads while others are added or
+    /// deleted, downloads added meanwhile show up on the last page.
+    pub async fn get_metadata_page(&self, cursor: Option<Cursor>, limit: usize) -> Result<Page> {
+        let inner = self.read().await?;
+        Ok(inner.get_metadata_page(cursor, limit).await)
+    }
+
+    /// Fetches the metadata of a download that isn't added yet once the probe limits allow it,
+    /// meant for probing many downloads at once like a bulk import does.
+    pub async fn probe(&self, download: &mut HttpDownload) -> download::Result<()> {
+        let _permit = self.probe_limiter.acquire(download.host()).await;
+        download.fetch_metadata().await
+    }
+
+    /// Adds a download of any type (http, ftp, ...), see `Downloadable`. Http downloads join the
+    /// segment and bandwidth limits of the manager.
+    pub async fn add(&self, download: impl Downloadable) -> Result<Uuid> {
+        let (id, _) = self.insert(None, Box::new(download), None).await?;
+        Ok(id)
+    }
+
+    /// Adds a download saved by `with_state_file` back in the state it was saved in. One that was
+    /// running or paused by the system is paused with the bytes on disk, like the user paused it,
+    /// since the manager can't tell if the system's reason still holds.
+    pub async fn restore(
+        &self,
+        download: impl Downloadable,
+        state: download::State,
+    ) -> Result<Uuid> {
+        let state = match state {
+            download::State::Running { .. }
+            | download::State::Retrying { .. }
+            | download::State::Verifying { .. }
+            | download::State::Moving { .. }
+            | download::State::PausedBySystem { .. } => {
+                download::State::PausedByUser(download.get_bytes_on_disk().await)
+            }
+            state => state,
+        };
+        let (id, _) = self.insert(None, Box::new(download), Some(state)).await?;
+        Ok(id)
+    }
+
+    /// Every download with its current state, what `with_state_file` saves.
+    pub async fn saved_downloads(&self) -> Result<Vec<SavedDownload>> {
+        let metadata = self.get_metadata_all().await?;
+        let states = self.observer.read_state().await;
+        Ok(metadata
+            .into_iter()
+            .filter_map(|metadata| {
+                let state = states.get(&metadata.id)?.clone();
+                Some(SavedDownload { metadata, state })
+            })
+            .collect())
+    }
+
+    /// Download added with the idempotency key `key`, None if the key wasn't used, expired or its
+    /// download was deleted. Always None without `with_idempotency_ttl`.
+    pub fn added_with_key(&self, key: &str) -> Option<Uuid> {
+        self.idempotency.as_ref()?.get(key, Instant::now())
+    }
+
+    /// Adds the download unless a download was already added with `key`, returns the id of the
+    /// download the key belongs to and whether it was added now. Of concurrent requests with the
+    /// same key the first one to take the manager lock wins, the others' downloads are never
+    /// added.
+    pub async fn add_with_key(
+        &self,
+        key: &str,
+        download: impl Downloadable,
+    ) -> Result<(Uuid, bool)> {
+        self.insert(Some(key), Box::new(download), None).await
     }
 
-    pub async fn add(&self, download: HttpDownload) -> Uuid {
-        let mut inner = self.inner.write().await;
+    /// Adds the download, unless `key` already belongs to another download. The key is checked
+    /// and reserved under the manager's write lock, so nothing is added (and no `Added` event
+    /// sent) for a request that lost the key. A new download starts out paused (or `Created` if
+    /// it wasn't probed) unless it's given a `restored` state.
+    async fn insert(
+        &self,
+        key: Option<&str>,
+        mut download: Box<dyn Downloadable>,
+        restored: Option<download::State>,
+    ) -> Result<(Uuid, bool)> {
+        let mut state = download::State::PausedByUser(0);
+        if let Some(download) = download.as_http_mut() {
+            if let Some(limit) = &self.segment_limit {
+                download
+                    .config
+                    .segment_limit
+                    .get_or_insert_with(|| limit.clone());
+            }
+            download
+                .config
+                .shared_rate_limiter
+                .get_or_insert_with(|| self.rate_limiter.clone());
+            if !download.probed {
+                state = download::State::Created;
+            }
+        }
+        let state = restored.unwrap_or(state);
+        let metadata = download.metadata();
+        let mut inner = self.write().await?;
+        if let (Some(key), Some(keys)) = (key, &self.idempotency) {
+            let owner = keys.insert(key, download.id(), Instant::now());
+            if owner != download.id() {
+                return Ok((owner, false));
+            }
+        }
         let id = inner.add(download);
-        self.observer.track(id, download::State::Paused(0)).await;
-        id
+        self.history.record_state(id, &state);
+        self.observer.track(id, state).await;
+        let _ = self
+            .lifecycle_events
+            .send(LifecycleEvent::Added { metadata });
+        Ok((id, true))
     }
 
+    /// Stops and removes a download, unknown ids are ignored.
     pub async fn delete(&self, id: &Uuid, delete_file: bool) -> Result<()> {
-        let mut inner = self.inner.write().await;
+        self.remove(id, delete_file).await.map(|_| ())
+    }
+
+    /// Deletes the downloads one after another like `delete`, the result of each id is returned in
+    /// the order given. Unknown ids fail with `Error::NotFound`, a failed id doesn't stop the
+    /// others.
+    pub async fn delete_many(&self, ids: &[Uuid], delete_file: bool) -> Vec<Result<()>> {
+        let mut results = Vec::with_capacity(ids.len());
+        for id in ids {
+            results.push(match self.remove(id, delete_file).await {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(Error::NotFound(*id).into()),
+                Err(e) => Err(e),
+            });
+        }
+        results
+    }
+
+    /// Removes the download if the manager has it, returns whether it had.
+    async fn remove(&self, id: &Uuid, delete_file: bool) -> Result<bool> {
+        let mut inner = self.write().await?;
         let _ = inner.stop(id); // ignore error
-        if let Some(item) = inner.remove(id) {
-            if delete_file {
-                let file_path = item.get_metadata().await.file_path;
-                if let Err(e) = tokio::fs::remove_file(file_path).await {
-                    log::warn!(
-                        "Couldn't delete file for httpdownload after removing from manager: {}",
-                        e
-                    );
+        let Some(item) = inner.remove(id) else {
+            return Ok(false);
+        };
+        if delete_file {
+            let file_path = item.get_metadata().await.file_path;
+            if let Err(e) = tokio::fs::remove_file(file_path).await {
+                log::warn!(
+                    "Couldn't delete file for httpdownload after removing from manager: {}",
+                    e
+                );
+            };
+            for path in item.working_files().await {
+                let _ = tokio::fs::remove_file(path).await;
+            }
+        }
+        self.observer.untrack(id).await;
+        self.history.forget(id);
+        self.dedup.forget(id);
+        if let Some(check) = &self.missing_check {
+            check.forget(id);
+        }
+        if let Some(keys) = &self.idempotency {
+            keys.forget(id);
+        }
+        let _ = self
+            .lifecycle_events
+            .send(LifecycleEvent::Removed { id: *id });
+        Ok(true)
+    }
+}
+
+/// Starts the next queued downloads whenever a slot is freed, unless automatic starts aren't
+/// allowed, `resume_all` dispatches them once they are again.
+async fn run_dispatcher(
+    inner: Arc<RwLock<ManagerInner>>,
+    gate: StartGate,
+    mut freed: mpsc::UnboundedReceiver<()>,
+) {
+    while freed.recv().await.is_some() {
+        if !gate.allows().await {
+            continue;
+        }
+        let started = inner.write().await.dispatch();
+        if !started.is_empty() {
+            log::info!("Started queued downloads {:?}", started);
+        }
+    }
+}
+
+/// Sends the error events of all downloads to the subscribers.
+async fn forward_errors(
+    subscribers: Subscribers,
+    mut recv: mpsc::UnboundedReceiver<download::ErrorEvent>,
+) {
+    while let Some(event) = recv.recv().await {
+        let event = Arc::new(event);
+        for subscriber in subscribers.lock().await.iter() {
+            let (subscriber, event) = (subscriber.clone(), event.clone());
+            tokio::spawn(async move { subscriber.error(&event).await });
+        }
+    }
+}
+
+/// Sends the lifecycle events to the subscribers, one after another so every subscriber sees
+/// them in the order they happened.
+async fn forward_lifecycle(
+    subscribers: Subscribers,
+    mut recv: mpsc::UnboundedReceiver<LifecycleEvent>,
+) {
+    while let Some(event) = recv.recv().await {
+        let subscribers = subscribers.lock().await.clone();
+        for subscriber in subscribers {
+            subscriber.lifecycle(&event).await;
+        }
+    }
+}
+
+/// Trips and recovers the circuits of hosts based on the outcomes of their downloads, see
+/// `CircuitBreaker`.
+async fn run_breaker(
+    inner: Arc<RwLock<ManagerInner>>,
+    breaker: Arc<std::sync::Mutex<CircuitBreaker>>,
+    gate: StartGate,
+    events: mpsc::UnboundedSender<BreakerEvent>,
+    mut recv: mpsc::UnboundedReceiver<BreakerEvent>,
+) {
+    while let Some(event) = recv.recv().await {
+        match event {
+            BreakerEvent::Outcome {
+                host,
+                success: false,
+            } => {
+                let (opened, cool_down) = {
+                    let mut breaker = breaker.lock().unwrap();
+                    (
+                        breaker.record_failure(&host, Instant::now()),
+                        breaker.config.cool_down,
+                    )
                 };
+                if !opened {
+                    continue;
+                }
+                log::warn!(
+                    "Host {} failed too often, pausing its downloads for {:?}",
+                    host,
+                    cool_down
+                );
+                let mut inner = inner.write().await;
+                inner.pause_host(&host, PauseReason::HostUnavailable).await;
+                schedule_cool_down(&events, host, cool_down);
             }
-            self.observer.untrack(id).await
-        };
-        Ok(())
+            BreakerEvent::Outcome {
+                host,
+                success: true,
+            } => {
+                if !breaker.lock().unwrap().record_success(&host) {
+                    continue;
+                }
+                if !gate.allows().await {
+                    // They are resumed once automatic starts are allowed again
+                    log::info!("Host {} recovered, its downloads stay paused", host);
+                    continue;
+                }
+                log::info!("Host {} recovered, resuming its downloads", host);
+                let mut inner = inner.write().await;
+                inner
+                    .resume_host(&host, PauseReason::HostUnavailable, usize::MAX)
+                    .await;
+            }
+            BreakerEvent::CoolDownOver(host) => {
+                if !gate.allows().await {
+                    // Trying the host would start a download, wait for another cool-down
+                    let cool_down = breaker.lock().unwrap().config.cool_down;
+                    schedule_cool_down(&events, host, cool_down);
+                    continue;
+                }
+                if !breaker.lock().unwrap().half_open(&host, Instant::now()) {
+                    continue;
+                }
+                let mut inner = inner.write().await;
+                let tried = inner
+                    .resume_host(&host, PauseReason::HostUnavailable, 1)
+                    .await;
+                log::info!("Trying download {:?} of host {}", tried, host);
+                if tried.is_empty() {
+                    // Nothing left to try, the next download of the host decides
+                    breaker.lock().unwrap().record_success(&host);
+                }
+            }
+        }
+    }
+}
+
+fn schedule_cool_down(
+    events: &mpsc::UnboundedSender<BreakerEvent>,
+    host: String,
+    cool_down: Duration,
+) {
+    let events = events.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(cool_down).await;
+        let _ = events.send(BreakerEvent::CoolDownOver(host));
+    });
+}
+
+/// Updates the rate of a relative bandwidth limit from the speed of the running downloads, it
+/// stops once the limiter isn't used by the manager or a download anymore or the limit changed.
+async fn estimate_capacity(
+    observer: DownloadObserver,
+    limiter: RateLimiter,
+    limit: Arc<std::sync::Mutex<BandwidthLimit>>,
+    mut estimator: CapacityEstimator,
+) {
+    let relative = *limit.lock().unwrap();
+    let mut ticker = tokio::time::interval(ESTIMATE_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        if limiter.is_orphaned() || *limit.lock().unwrap() != relative {
+            return;
+        }
+        let speeds: Vec<u64> = observer
+            .read_state()
+            .await
+            .values()
+            .filter_map(|state| match state {
+                download::State::Running {
+                    bytes_per_second, ..
+                } => Some(*bytes_per_second),
+                _ => None,
+            })
+            .collect();
+        let measured = (!speeds.is_empty()).then(|| speeds.iter().sum());
+        let rate = estimator.next_rate(measured);
+        if rate != limiter.rate() {
+            log::info!(
+                "Bandwidth limit set to {:?} bytes/s, estimated capacity {:?} bytes/s",
+                rate,
+                estimator.estimate()
+            );
+        }
+        // Checked again while setting the rate, a limit set meanwhile isn't overwritten
+        let limit = limit.lock().unwrap();
+        if *limit != relative {
+            return;
+        }
+        limiter.set_rate(rate);
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::ftpdownload::FtpDownload;
+    use crate::httpdownload::manager::breaker::CircuitStatus;
+    use crate::util::mock::{self, MockConfig, MockServer};
     use crate::util::{file_size, setup_test_download};
+    use hyper::StatusCode;
     use test_log::test;
     use tokio::time;
 
-    const TEST_DOWNLOAD_URL: &str =
-        "https://dl.google.com/linux/direct/google-chrome-stable_current_amd64.deb";
     type Test<T> = anyhow::Result<T>;
 
+    /// Server slow enough for downloads to still be running when the tests interact with them
+    async fn slow_server() -> MockServer {
+        MockServer::start(MockConfig {
+            chunk_delay: Some(Duration::from_millis(20)),
+            ..Default::default()
+        })
+        .await
+    }
+
     #[test(tokio::test)]
     async fn start_stop_delete_download() -> Test<()> {
         let manager = DownloadManager::new().await;
-        let (download, _tmp_dir) = setup_test_download(TEST_DOWNLOAD_URL).await?;
+        let server = slow_server().await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
         let download_path = download.file_path();
-        let id = manager.add(download).await;
+        let id = manager.add(download).await?;
         manager.start(&id).await?;
         // check metadata as expected
-        let metadata = manager.get_metadata_all().await;
+        let metadata = manager.get_metadata_all().await?;
         assert_eq!(metadata.len(), 1, "There should be one download");
         let metadata = &metadata[0];
         assert_eq!(metadata.id, id);
-        time::sleep(time::Duration::from_secs(1)).await;
+        time::sleep(time::Duration::from_millis(200)).await;
         manager.stop(&id).await?;
         // check size of downloaded file
         let downloaded_bytes = file_size(&download_path).await;
@@ -147,4 +1254,976 @@ mod test {
         );
         Ok(())
     }
+
+    #[test(tokio::test)]
+    async fn cancelled_token_pauses_download() -> Test<()> {
+        // given
+        let manager = DownloadManager::new().await;
+        let server = slow_server().await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let download_path = download.file_path();
+        let id = manager.add(download).await?;
+        manager.start(&id).await?;
+        time::sleep(time::Duration::from_millis(200)).await;
+        // when
+        manager.cancellation_token(&id).await?.cancel();
+        time::sleep(time::Duration::from_millis(700)).await;
+        // then
+        let bytes_on_disk = file_size(&download_path).await;
+        assert_ne!(bytes_on_disk, 0);
+        assert_eq!(
+            manager.observer.get_state(&id).await,
+            Some(download::State::PausedByUser(bytes_on_disk))
+        );
+        assert!(manager.stop(&id).await.is_err());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn change_url_of_running_download() -> Test<()> {
+        let manager = DownloadManager::new()
+            .await
+            .with_lock_timeout(Duration::from_millis(50));
+        let server = slow_server().await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let id = manager.add(download).await?;
+        manager.start(&id).await?;
+        time::sleep(time::Duration::from_millis(200)).await;
+        // same content behind a different url
+        let mirror = server.url("file.bin?mirror=1");
+        let change = tokio::spawn({
+            let manager = manager.clone();
+            let mirror = mirror.clone();
+            async move { manager.change_url(&id, mirror).await }
+        });
+        // the slow probe of the new source doesn't hold up other calls
+        time::sleep(time::Duration::from_millis(10)).await;
+        manager.get_metadata_all().await?;
+        change.await??;
+        assert_eq!(manager.get_metadata(&id).await?.url, mirror.to_string());
+        // a source serving something else is rejected
+        let other_server = MockServer::start(MockConfig::new(mock::payload(1024))).await;
+        let other = other_server.url("file.bin");
+        assert!(manager.change_url(&id, other).await.is_err());
+        manager.stop(&id).await?;
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn lock_acquisition_times_out() -> Test<()> {
+        let manager = DownloadManager::new()
+            .await
+            .with_lock_timeout(Duration::from_millis(10));
+        let _stuck_writer = manager.inner.write().await;
+        let err = manager.get_metadata_all().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::LockTimeout(_))
+        ));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn resume_all_only_resumes_system_paused() -> Test<()> {
+        let manager = DownloadManager::new().await;
+        let server = slow_server().await;
+        let (user_paused, _user_dir) = setup_test_download(server.url("user.bin")).await?;
+        let (system_paused, _system_dir) = setup_test_download(server.url("system.bin")).await?;
+        let user_paused = manager.add(user_paused).await?;
+        let system_paused = manager.add(system_paused).await?;
+        manager.start(&user_paused).await?;
+        manager.start(&system_paused).await?;
+        manager.stop(&user_paused).await?;
+        manager
+            .stop_by_system(&system_paused, PauseReason::QueueLimit)
+            .await?;
+        time::sleep(time::Duration::from_millis(700)).await;
+        assert!(matches!(
+            manager.observer.get_state(&user_paused).await,
+            Some(download::State::PausedByUser(_))
+        ));
+        assert!(matches!(
+            manager.observer.get_state(&system_paused).await,
+            Some(download::State::PausedBySystem {
+                reason: PauseReason::QueueLimit,
+                ..
+            })
+        ));
+        assert_eq!(manager.resume_all().await?, vec![system_paused]);
+        manager.stop(&system_paused).await?;
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn disallowed_downloads_stay_paused_until_allowed() -> Test<()> {
+        // given
+        let manager = DownloadManager::new().await;
+        let server = slow_server().await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let id = manager.add(download).await?;
+        manager.start(&id).await?;
+        manager.stop_by_system(&id, PauseReason::QueueLimit).await?;
+        time::sleep(time::Duration::from_millis(300)).await;
+        // when
+        assert!(manager.set_downloads_allowed(false).await?.is_empty());
+        // then
+        assert!(!manager.downloads_allowed().await);
+        assert!(manager.resume_all().await?.is_empty());
+        assert!(matches!(
+            manager.observer.get_state(&id).await,
+            Some(download::State::PausedBySystem { .. })
+        ));
+        // the user can still start it
+        manager.start(&id).await?;
+        manager.stop_by_system(&id, PauseReason::QueueLimit).await?;
+        time::sleep(time::Duration::from_millis(300)).await;
+        // when
+        let resumed = manager.set_downloads_allowed(true).await?;
+        // then
+        assert_eq!(resumed, vec![id]);
+        manager.stop(&id).await?;
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn pause_at_is_cleared_when_resumed() -> Test<()> {
+        // given
+        let manager = DownloadManager::new().await;
+        let server = MockServer::start(MockConfig::default()).await;
+        let (mut download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        download.config.pause_at = Some(100_000);
+        let id = manager.add(download).await?;
+        // when
+        manager.start(&id).await?;
+        time::sleep(time::Duration::from_millis(300)).await;
+        // then
+        assert_eq!(
+            manager.observer.get_state(&id).await,
+            Some(download::State::PausedByUser(100_000))
+        );
+        // when
+        manager.resume(&id).await?;
+        let state = time::timeout(Duration::from_secs(10), manager.wait_until_done(&id)).await??;
+        // then
+        assert_eq!(state, download::State::Complete);
+        let inner = manager.read().await?;
+        let download = inner.items[&id].download.read().await;
+        assert_eq!(http(download.as_ref())?.config.pause_at, None);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn deleted_file_of_complete_download_is_missing() -> Test<()> {
+        // given
+        let manager = DownloadManager::new()
+            .await
+            .with_missing_check(Duration::from_millis(300));
+        let server = MockServer::start(MockConfig::default()).await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let file_path = download.file_path();
+        let id = manager.add(download).await?;
+        manager.start(&id).await?;
+        time::timeout(Duration::from_secs(10), manager.wait_until_done(&id)).await??;
+        assert_eq!(
+            manager.get_state(&id).await,
+            Some(download::State::Complete)
+        );
+        // when
+        tokio::fs::remove_file(&file_path).await?;
+        // then the file isn't checked again right away
+        assert_eq!(
+            manager.get_state(&id).await,
+            Some(download::State::Complete)
+        );
+        time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(manager.get_state(&id).await, Some(download::State::Missing));
+        // starting it downloads it again
+        manager.start(&id).await?;
+        let completed = time::timeout(Duration::from_secs(10), async {
+            loop {
+                time::sleep(Duration::from_millis(50)).await;
+                if manager.get_state(&id).await == Some(download::State::Complete) {
+                    return;
+                }
+            }
+        })
+        .await;
+        assert!(completed.is_ok());
+        assert_eq!(file_size(&file_path).await, server.payload().len() as u64);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn duplicate_download_is_linked_to_original() -> Test<()> {
+        // given two downloads of the same file
+        let manager = DownloadManager::new()
+            .await
+            .with_dedup(DedupAction::Hardlink);
+        let server = MockServer::start(MockConfig::default()).await;
+        let (first, _first_dir) = setup_test_download(server.url("file.bin")).await?;
+        let (second, _second_dir) = setup_test_download(server.url("file.bin")).await?;
+        let (first_path, second_path) = (first.file_path(), second.file_path());
+        let first = manager.add(first).await?;
+        let second = manager.add(second).await?;
+        // when they complete one after the other
+        manager.start(&first).await?;
+        time::timeout(Duration::from_secs(10), manager.wait_until_done(&first)).await??;
+        manager.start(&second).await?;
+        time::timeout(Duration::from_secs(10), manager.wait_until_done(&second)).await??;
+        // then the second one is flagged and shares the file of the first one
+        assert_eq!(manager.get_metadata(&first).await?.duplicate_of, None);
+        assert_eq!(
+            manager.get_metadata(&second).await?.duplicate_of,
+            Some(first)
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let inode = |path| std::fs::metadata(path).map(|metadata| metadata.ino());
+            assert_eq!(inode(&first_path)?, inode(&second_path)?);
+        }
+        assert_eq!(tokio::fs::read(&second_path).await?, *server.payload());
+        // when the original is deleted the duplicate stands on its own
+        manager.delete(&first, true).await?;
+        assert_eq!(manager.get_metadata(&second).await?.duplicate_of, None);
+        assert_eq!(tokio::fs::read(&second_path).await?, *server.payload());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn complete_download_is_verified_in_place() -> Test<()> {
+        // given a complete download
+        let manager = DownloadManager::new().await;
+        let server = MockServer::start(MockConfig::default()).await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let file_path = download.file_path();
+        let id = manager.add(download).await?;
+        manager.start(&id).await?;
+        time::timeout(Duration::from_secs(10), manager.wait_until_done(&id)).await??;
+        let checksum = download::checksum::compute(
+            std::slice::from_ref(&file_path),
+            download::checksum::ChecksumAlgorithm::Sha256,
+        )
+        .await?;
+        // when verified against its checksum
+        let actual = manager.verify(&id, checksum).await?;
+        // then it stays complete
+        assert_eq!(actual, checksum);
+        assert_eq!(
+            manager.get_state(&id).await,
+            Some(download::State::Complete)
+        );
+        // when verified against another checksum
+        let wrong: Checksum = format!("sha256:{}", "0".repeat(64)).parse().unwrap();
+        let actual = manager.verify(&id, wrong).await?;
+        // then it failed its checksum and kept the file
+        assert_eq!(actual, checksum);
+        assert_eq!(
+            manager.get_state(&id).await,
+            Some(download::State::ChecksumFailed {
+                expected: wrong.to_string(),
+                actual: checksum.to_string(),
+            })
+        );
+        assert_eq!(file_size(&file_path).await, server.payload().len() as u64);
+        let err = manager.verify(&id, checksum).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::NotComplete(_))
+        ));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn reconcile_corrects_states_of_changed_files() -> Test<()> {
+        // given a complete, a paused and a running download
+        let manager = DownloadManager::new().await;
+        let server = MockServer::start(MockConfig::default()).await;
+        let (complete, _complete_dir) = setup_test_download(server.url("complete.bin")).await?;
+        let (mut paused, _paused_dir) = setup_test_download(server.url("paused.bin")).await?;
+        paused.config.pause_at = Some(100_000);
+        let (complete_path, paused_path) = (complete.file_path(), paused.file_path());
+        let complete = manager.add(complete).await?;
+        let paused = manager.add(paused).await?;
+        for id in [complete, paused] {
+            manager.start(&id).await?;
+        }
+        time::timeout(Duration::from_secs(10), manager.wait_until_done(&complete)).await??;
+        let stopped = time::timeout(Duration::from_secs(10), async {
+            while manager.get_state(&paused).await != Some(download::State::PausedByUser(100_000)) {
+                time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await;
+        assert!(stopped.is_ok());
+        let slow = slow_server().await;
+        let (running, _running_dir) = setup_test_download(slow.url("running.bin")).await?;
+        let running = manager.add(running).await?;
+        manager.start(&running).await?;
+        // when their files change behind the manager's back
+        tokio::fs::remove_file(&complete_path).await?;
+        tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(&paused_path)
+            .await?
+            .set_len(40_000)
+            .await?;
+        let reconciliation = manager.reconcile().await?;
+        // then
+        assert_eq!(reconciliation.checked, 2);
+        assert_eq!(reconciliation.skipped, vec![running]);
+        assert_eq!(
+            reconciliation.repairs,
+            vec![
+                Repair {
+                    id: complete,
+                    before: download::State::Complete,
+                    after: download::State::Missing,
+                },
+                Repair {
+                    id: paused,
+                    before: download::State::PausedByUser(100_000),
+                    after: download::State::PausedByUser(40_000),
+                },
+            ]
+        );
+        assert_eq!(
+            manager.get_state(&paused).await,
+            Some(download::State::PausedByUser(40_000))
+        );
+        // nothing is left to repair
+        manager.stop(&running).await?;
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(manager.reconcile().await?.repairs.is_empty());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn exempt_download_ignores_bandwidth_limit() -> Test<()> {
+        // given a limit that would hold a download back for 10 seconds
+        let manager = DownloadManager::new()
+            .await
+            .with_bandwidth_limit(BandwidthLimit::Fixed(100_000));
+        let server = MockServer::start(MockConfig::default()).await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let id = manager.add(download).await?;
+        // when
+        manager.set_ignore_global_limit(&id, true).await?;
+        manager.start(&id).await?;
+        // then
+        time::timeout(Duration::from_secs(3), manager.wait_until_done(&id)).await??;
+        assert_eq!(
+            manager.get_state(&id).await,
+            Some(download::State::Complete)
+        );
+        assert!(manager.get_metadata(&id).await?.ignore_global_limit);
+        manager.set_ignore_global_limit(&id, false).await?;
+        assert!(!manager.get_metadata(&id).await?.ignore_global_limit);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn global_rate_limit_caps_downloads_together() -> Test<()> {
+        // given three downloads of 300 KB added before the limit is set
+        let manager = DownloadManager::new().await;
+        let server = MockServer::start(MockConfig::new(mock::payload(300_000))).await;
+        let mut ids = Vec::new();
+        let mut tmp_dirs = Vec::new();
+        for _ in 0..3 {
+            let (download, tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+            ids.push(manager.add(download).await?);
+            tmp_dirs.push(tmp_dir);
+        }
+        // when
+        manager.set_global_rate_limit(Some(300_000));
+        let started = time::Instant::now();
+        for id in &ids {
+            manager.start(id).await?;
+        }
+        for id in &ids {
+            time::timeout(Duration::from_secs(10), manager.wait_until_done(id)).await??;
+        }
+        // then a second of burst and the other 600 KB at the combined rate
+        let elapsed = started.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(1700) && elapsed < Duration::from_secs(4),
+            "took {:?}",
+            elapsed
+        );
+        for id in &ids {
+            assert_eq!(manager.get_state(id).await, Some(download::State::Complete));
+        }
+        assert_eq!(manager.global_rate_limit(), Some(300_000));
+        manager.set_global_rate_limit(None);
+        assert_eq!(manager.global_rate_limit(), None);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn same_idempotency_key_adds_download_once() -> Test<()> {
+        // given
+        let manager = DownloadManager::new()
+            .await
+            .with_idempotency_ttl(Duration::from_secs(60));
+        let server = MockServer::start(MockConfig::default()).await;
+        let (first, _first_dir) = setup_test_download(server.url("file.bin")).await?;
+        let (retried, _retried_dir) = setup_test_download(server.url("file.bin")).await?;
+        // when
+        let (id, added) = manager.add_with_key("key", first).await?;
+        let (retried_id, retried_added) = manager.add_with_key("key", retried).await?;
+        // then
+        assert!(added && !retried_added);
+        assert_eq!(retried_id, id);
+        assert_eq!(manager.get_metadata_all().await?.len(), 1);
+        assert_eq!(manager.added_with_key("key"), Some(id));
+        // a deleted download frees its key
+        manager.delete(&id, false).await?;
+        assert_eq!(manager.added_with_key("key"), None);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn concurrent_requests_with_the_same_key_add_one_download() -> Test<()> {
+        struct Added(mpsc::UnboundedSender<Uuid>);
+
+        #[async_trait::async_trait]
+        impl DownloadUpdateSubscriber for Added {
+            async fn update(&self, _updates: &[(Uuid, download::State)]) {}
+
+            async fn lifecycle(&self, event: &LifecycleEvent) {
+                if let LifecycleEvent::Added { metadata } = event {
+                    let _ = self.0.send(metadata.id);
+                }
+            }
+        }
+
+        // given two requests that both wait for the manager lock
+        let manager = DownloadManager::new()
+            .await
+            .with_idempotency_ttl(Duration::from_secs(60));
+        let (sender, mut added) = mpsc::unbounded_channel();
+        manager.subscribe(Added(sender)).await;
+        let server = MockServer::start(MockConfig::default()).await;
+        let lock = manager.write().await?;
+        let mut requests = Vec::new();
+        for _ in 0..2 {
+            let (download, tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+            let manager = manager.clone();
+            requests.push(tokio::spawn(async move {
+                let added = manager.add_with_key("key", download).await;
+                drop(tmp_dir);
+                added
+            }));
+        }
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        // when
+        drop(lock);
+        let mut results = Vec::new();
+        for request in requests {
+            results.push(request.await??);
+        }
+        // then both get the same download and only it was ever added
+        let (id, _) = results[0];
+        assert!(results.iter().all(|(owner, _)| *owner == id));
+        assert_eq!(results.iter().filter(|(_, added)| *added).count(), 1);
+        let (sentinel, _sentinel_dir) = setup_test_download(server.url("file.bin")).await?;
+        let sentinel = manager.add(sentinel).await?;
+        assert_eq!(added.recv().await, Some(id));
+        assert_eq!(added.recv().await, Some(sentinel));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn delete_many_reports_each_id() -> Test<()> {
+        // given
+        let manager = DownloadManager::new().await;
+        let server = MockServer::start(MockConfig::default()).await;
+        let (first, _first_dir) = setup_test_download(server.url("a.bin")).await?;
+        let (second, _second_dir) = setup_test_download(server.url("b.bin")).await?;
+        let (first, second) = (manager.add(first).await?, manager.add(second).await?);
+        let unknown = Uuid::new_v4();
+        // when
+        let results = manager.delete_many(&[first, unknown, second], false).await;
+        // then the unknown id fails without stopping the batch
+        assert!(results[0].is_ok() && results[2].is_ok());
+        let error = results[1].as_ref().unwrap_err().downcast_ref::<Error>();
+        assert!(matches!(error, Some(Error::NotFound(id)) if *id == unknown));
+        assert!(manager.get_metadata_all().await?.is_empty());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn pages_neither_skip_nor_repeat_downloads() -> Test<()> {
+        // given
+        let manager = DownloadManager::new().await;
+        let server = MockServer::start(MockConfig::default()).await;
+        let mut ids = Vec::new();
+        let mut tmp_dirs = Vec::new();
+        for name in ["a.bin", "b.bin", "c.bin", "d.bin"] {
+            let (download, tmp_dir) = setup_test_download(server.url(name)).await?;
+            ids.push(manager.add(download).await?);
+            tmp_dirs.push(tmp_dir);
+        }
+        // when
+        let first = manager.get_metadata_page(None, 2).await?;
+        // then
+        let first_ids: Vec<Uuid> = first.items.iter().map(|metadata| metadata.id).collect();
+        assert_eq!(first_ids, ids[..2]);
+        // when a seen and an unseen download are deleted and another one is added
+        manager.delete(&ids[0], false).await?;
+        manager.delete(&ids[2], false).await?;
+        let (download, tmp_dir) = setup_test_download(server.url("e.bin")).await?;
+        let added = manager.add(download).await?;
+        tmp_dirs.push(tmp_dir);
+        let second = manager.get_metadata_page(first.next_cursor, 2).await?;
+        // then
+        let second_ids: Vec<Uuid> = second.items.iter().map(|metadata| metadata.id).collect();
+        assert_eq!(second_ids, vec![ids[3], added]);
+        assert_eq!(second.next_cursor, None);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn reordered_queue_sets_dispatch_order() -> Test<()> {
+        // given a download taking the only slot and three queued behind it
+        let manager = DownloadManager::new().await.with_max_concurrent(1);
+        let server = slow_server().await;
+        let mut ids = Vec::new();
+        let mut tmp_dirs = Vec::new();
+        for name in ["running.bin", "a.bin", "b.bin", "c.bin"] {
+            let (download, tmp_dir) = setup_test_download(server.url(name)).await?;
+            let id = manager.add(download).await?;
+            manager.dispatch(&id).await?;
+            ids.push(id);
+            tmp_dirs.push(tmp_dir);
+        }
+        let (running, a, b, c) = (ids[0], ids[1], ids[2], ids[3]);
+        assert_eq!(manager.queue().await?, vec![a, b, c]);
+        // when
+        let queue = manager.reorder_queue(&[c, a]).await?;
+        // then
+        assert_eq!(queue, vec![c, a, b]);
+        assert_eq!(manager.queue().await?, queue);
+        let err = manager.reorder_queue(&[b, running]).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::NotQueued(id)) if *id == running
+        ));
+        let unknown = Uuid::new_v4();
+        let err = manager.reorder_queue(&[unknown]).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::NotFound(_))
+        ));
+        // every freed slot goes to the head of the queue
+        for (stopped, next, rest) in [(running, c, vec![a, b]), (c, a, vec![b])] {
+            manager.stop(&stopped).await?;
+            for _ in 0..50 {
+                if manager.queue().await? == rest {
+                    break;
+                }
+                time::sleep(time::Duration::from_millis(100)).await;
+            }
+            assert_eq!(manager.queue().await?, rest);
+            assert!(manager.cancellation_token(&next).await.is_ok());
+        }
+        manager.stop_all().await?;
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn start_over_the_limit_is_queued_until_a_slot_frees() -> Test<()> {
+        // given
+        let manager = DownloadManager::new().await.with_max_concurrent(1);
+        let server = slow_server().await;
+        let (first, _first_dir) = setup_test_download(server.url("first.bin")).await?;
+        let (second, _second_dir) = setup_test_download(server.url("second.bin")).await?;
+        let first = manager.add(first).await?;
+        let second = manager.add(second).await?;
+        // when
+        assert!(manager.dispatch(&first).await?);
+        assert!(!manager.dispatch(&second).await?);
+        // then
+        assert_eq!(manager.queue().await?, vec![second]);
+        time::sleep(time::Duration::from_millis(300)).await;
+        assert!(matches!(
+            manager.observer.get_state(&second).await,
+            Some(download::State::PausedBySystem {
+                reason: PauseReason::QueueLimit,
+                ..
+            })
+        ));
+        // the freed slot goes to the queued download
+        manager.stop(&first).await?;
+        for _ in 0..50 {
+            if manager.queue().await?.is_empty() {
+                break;
+            }
+            time::sleep(time::Duration::from_millis(100)).await;
+        }
+        assert!(manager.queue().await?.is_empty());
+        assert!(manager.cancellation_token(&second).await.is_ok());
+        manager.stop_all().await?;
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn stop_start_by_host() -> Test<()> {
+        let manager = DownloadManager::new().await;
+        let server = slow_server().await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let host = download.host().unwrap().to_owned();
+        assert_eq!(host, "127.0.0.1");
+        let id = manager.add(download).await?;
+        let (never_started, _tmp_dir2) = setup_test_download(server.url("other.bin")).await?;
+        manager.add(never_started).await?;
+        manager.start(&id).await?;
+        // a different host doesn't affect the download
+        assert!(manager.stop_by_host("example.com").await?.is_empty());
+        assert_eq!(manager.stop_by_host(&host).await?, vec![id]);
+        time::sleep(time::Duration::from_millis(100)).await;
+        // only the stopped download is resumed, not the one that was never started
+        assert_eq!(manager.start_by_host(&host).await?, vec![id]);
+        manager.stop(&id).await?;
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn failing_host_trips_circuit_breaker() -> Test<()> {
+        // given
+        let manager = DownloadManager::new()
+            .await
+            .with_circuit_breaker(BreakerConfig {
+                failure_threshold: 2,
+                window: Duration::from_secs(10),
+                cool_down: Duration::from_secs(1),
+            });
+        let server = slow_server().await;
+        let mut ids = Vec::new();
+        let mut tmp_dirs = Vec::new();
+        for name in ["running.bin", "failing1.bin", "failing2.bin"] {
+            let (download, tmp_dir) = setup_test_download(server.url(name)).await?;
+            ids.push(manager.add(download).await?);
+            tmp_dirs.push(tmp_dir);
+        }
+        let (running, failing) = (ids[0], &ids[1..]);
+        manager.start(&running).await?;
+        time::sleep(time::Duration::from_millis(100)).await;
+        server.update(|config| {
+            config.fail_next.push_back(StatusCode::SERVICE_UNAVAILABLE);
+            config.fail_next.push_back(StatusCode::SERVICE_UNAVAILABLE);
+        });
+        // when
+        for id in failing {
+            manager.start(id).await?;
+        }
+        time::sleep(time::Duration::from_millis(700)).await;
+        // then the host's running download is paused and nothing of it can be started
+        assert!(matches!(
+            manager.observer.get_state(&running).await,
+            Some(download::State::PausedBySystem {
+                reason: PauseReason::HostUnavailable,
+                ..
+            })
+        ));
+        let err = manager.start(&failing[0]).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::HostUnavailable(_))
+        ));
+        let status = manager.circuit_status();
+        assert_eq!(status[0].host, "127.0.0.1");
+        assert!(matches!(status[0].status, CircuitStatus::Open { .. }));
+        // after the cool-down the paused download is tried and closes the circuit
+        for _ in 0..50 {
+            if manager.observer.get_state(&running).await == Some(download::State::Complete) {
+                break;
+            }
+            time::sleep(time::Duration::from_millis(100)).await;
+        }
+        assert_eq!(
+            manager.observer.get_state(&running).await,
+            Some(download::State::Complete)
+        );
+        assert!(manager.circuit_status().is_empty());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn aggregate_updates_are_emitted() -> Test<()> {
+        struct Aggregates(mpsc::Sender<AggregateUpdate>);
+
+        #[async_trait::async_trait]
+        impl DownloadUpdateSubscriber for Aggregates {
+            async fn update(&self, _updates: &[(Uuid, download::State)]) {}
+
+            async fn aggregate(&self, update: &AggregateUpdate) {
+                let _ = self.0.send(update.clone()).await;
+            }
+        }
+
+        // given
+        let manager = DownloadManager::new()
+            .await
+            .with_aggregate_interval(Duration::from_millis(50));
+        let (sender, mut aggregates) = mpsc::channel(100);
+        manager.subscribe(Aggregates(sender)).await;
+        let server = slow_server().await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let size = download.content_length;
+        // when
+        manager.add(download).await?;
+        // then
+        let update = loop {
+            let update = aggregates.recv().await.unwrap();
+            if update.downloads == 1 {
+                break update;
+            }
+        };
+        assert_eq!(update.bytes_total, size);
+        assert_eq!(update.running, 0);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn lazy_download_is_probed_on_start() -> Test<()> {
+        // given
+        let manager = DownloadManager::new().await;
+        let server = MockServer::start(MockConfig::default()).await;
+        let tmp_dir = tempfile::TempDir::new()?;
+        let download = HttpDownload::builder()
+            .url(server.url("file.bin"))
+            .directory(tmp_dir.path())
+            .lazy(true)
+            .build()
+            .await?;
+        let id = manager.add(download).await?;
+        assert_eq!(
+            manager.observer.get_state(&id).await,
+            Some(download::State::Created)
+        );
+        assert_eq!(manager.final_path(&id).await?, None);
+        // when
+        manager.start(&id).await?;
+        // then
+        for _ in 0..50 {
+            if manager.observer.get_state(&id).await == Some(download::State::Complete) {
+                break;
+            }
+            time::sleep(time::Duration::from_millis(100)).await;
+        }
+        assert_eq!(
+            manager.observer.get_state(&id).await,
+            Some(download::State::Complete)
+        );
+        let metadata = manager.get_metadata(&id).await?;
+        assert_eq!(metadata.download_size, Some(server.payload().len() as u64));
+        assert_eq!(manager.final_path(&id).await?, Some(metadata.file_path));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn errors_are_sent_to_subscribers() -> Test<()> {
+        struct Errors(mpsc::Sender<download::ErrorEvent>);
+
+        #[async_trait::async_trait]
+        impl DownloadUpdateSubscriber for Errors {
+            async fn update(&self, _updates: &[(Uuid, download::State)]) {}
+
+            async fn error(&self, event: &download::ErrorEvent) {
+                let _ = self.0.send(event.clone()).await;
+            }
+        }
+
+        // given
+        let manager = DownloadManager::new().await;
+        let (sender, mut errors) = mpsc::channel(100);
+        manager.subscribe(Errors(sender)).await;
+        let server = MockServer::start(MockConfig::default()).await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let id = manager.add(download).await?;
+        server.update(|config| {
+            config
+                .fail_next
+                .extend([StatusCode::BAD_GATEWAY, StatusCode::BAD_GATEWAY])
+        });
+        // when
+        manager.start(&id).await?;
+        let first = errors.recv().await.unwrap();
+        manager.start(&id).await?;
+        let second = errors.recv().await.unwrap();
+        // then
+        assert_eq!(first.id, id);
+        assert_eq!(first.code, "bad_status");
+        assert_eq!(first.status, Some(502));
+        assert_eq!((first.attempt, first.transient), (1, false));
+        assert_eq!(second.attempt, 2);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn low_disk_space_pauses_and_resumes_downloads() -> Test<()> {
+        // given
+        let free = Arc::new(std::sync::atomic::AtomicU64::new(u64::MAX));
+        let reserve = DiskReserve {
+            directories: vec![PathBuf::from("/downloads")],
+            min_free: 1024,
+            poll_interval: Duration::from_millis(50),
+        };
+        let manager = DownloadManager::new().await.watch_disk_space(reserve, {
+            let free = free.clone();
+            move |_| Ok(free.load(std::sync::atomic::Ordering::Relaxed))
+        });
+        let server = slow_server().await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let id = manager.add(download).await?;
+        manager.start(&id).await?;
+        // when the disk fills up
+        free.store(1000, std::sync::atomic::Ordering::Relaxed);
+        time::sleep(time::Duration::from_millis(200)).await;
+        // then
+        assert!(matches!(
+            manager.observer.get_state(&id).await,
+            Some(download::State::PausedBySystem {
+                reason: PauseReason::LowDiskSpace,
+                ..
+            })
+        ));
+        // resuming the system paused downloads leaves it paused while the disk is full
+        assert_eq!(manager.resume_all().await?, Vec::<Uuid>::new());
+        // when space is freed, then it's resumed and completes
+        free.store(u64::MAX, std::sync::atomic::Ordering::Relaxed);
+        let state = time::timeout(Duration::from_secs(10), manager.wait_until_done(&id)).await??;
+        assert_eq!(state, download::State::Complete);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn lifecycle_events_are_sent_to_subscribers_in_order() -> Test<()> {
+        struct Lifecycle(mpsc::Sender<LifecycleEvent>);
+
+        #[async_trait::async_trait]
+        impl DownloadUpdateSubscriber for Lifecycle {
+            async fn update(&self, _updates: &[(Uuid, download::State)]) {}
+
+            async fn lifecycle(&self, event: &LifecycleEvent) {
+                let _ = self.0.send(event.clone()).await;
+            }
+        }
+
+        // given
+        let manager = DownloadManager::new().await;
+        let (sender, mut events) = mpsc::channel(100);
+        manager.subscribe(Lifecycle(sender)).await;
+        let server = MockServer::start(MockConfig::default()).await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        // when
+        let id = manager.add(download).await?;
+        manager.rename(&id, "renamed.bin".to_string()).await?;
+        manager.delete(&id, false).await?;
+        // then
+        let added = events.recv().await.unwrap();
+        assert!(matches!(added, LifecycleEvent::Added { metadata } if metadata.id == id));
+        let renamed = events.recv().await.unwrap();
+        assert!(
+            matches!(renamed, LifecycleEvent::Renamed { metadata } if metadata.file_path.ends_with("renamed.bin"))
+        );
+        let removed = events.recv().await.unwrap();
+        assert!(matches!(removed, LifecycleEvent::Removed { id: removed } if removed == id));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn wait_until_done_resolves_on_terminal_state() -> Test<()> {
+        // given
+        let manager = DownloadManager::new().await;
+        let server = slow_server().await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let id = manager.add(download).await?;
+        // when
+        manager.start(&id).await?;
+        let state = time::timeout(Duration::from_secs(10), manager.wait_until_done(&id)).await??;
+        // then a finished download returns right away and unknown ones fail
+        assert_eq!(state, download::State::Complete);
+        assert_eq!(
+            manager.wait_until_done(&id).await?,
+            download::State::Complete
+        );
+        let err = manager.wait_until_done(&Uuid::new_v4()).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::NotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn rename_into_running_download_path_is_rejected() -> Test<()> {
+        // given a running download and a stopped one in the same directory
+        let manager = DownloadManager::new().await;
+        let server = slow_server().await;
+        let (running, tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let running_path = running.file_path();
+        let other = HttpDownload::create(
+            server.url("file.bin"),
+            tmp_dir.path().to_owned(),
+            "other.bin".to_string(),
+            reqwest::Client::new(),
+            None,
+        )
+        .await?;
+        let running_id = manager.add(running).await?;
+        let other_id = manager.add(other).await?;
+        manager.start(&running_id).await?;
+        // when
+        let err = manager
+            .rename(&other_id, "file.bin".to_string())
+            .await
+            .unwrap_err();
+        // then
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::PathConflict { path, other }) if *path == running_path && *other == running_id
+        ));
+        assert_eq!(
+            manager.get_metadata(&other_id).await?.file_path,
+            tmp_dir.path().join("other.bin")
+        );
+        manager.rename(&other_id, "renamed.bin".to_string()).await?;
+        assert_eq!(
+            manager.get_metadata(&other_id).await?.file_path,
+            tmp_dir.path().join("renamed.bin")
+        );
+        manager.stop(&running_id).await?;
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn ftp_download_is_managed_like_http_ones() -> Test<()> {
+        // given
+        let manager = DownloadManager::new().await;
+        let payload = mock::payload(100_000);
+        let (port, _) = mock::ftp_server(payload.clone()).await;
+        let tmp_dir = tempfile::TempDir::new()?;
+        let url = Url::parse(&format!("ftp://127.0.0.1:{}/file.bin", port))?;
+        let download = FtpDownload::create(url, tmp_dir.path().to_owned(), None).await?;
+        let id = manager.add(download).await?;
+        // when
+        manager.start(&id).await?;
+        let state = time::timeout(Duration::from_secs(10), manager.wait_until_done(&id)).await??;
+        // then
+        assert_eq!(state, download::State::Complete);
+        let metadata = manager.get_metadata(&id).await?;
+        assert_eq!(tokio::fs::read(&metadata.file_path).await?, payload);
+        let err = manager.set_segments(&id, 4).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Unsupported(_))
+        ));
+        manager.delete(&id, true).await?;
+        assert!(!metadata.file_path.exists());
+        Ok(())
+    }
 }
@@ -0,0 +1,45 @@
+use std::fmt;
+use std::num::ParseIntError;
+use std::str::FromStr;
+
+use crate::httpdownload::DownloadMetadata;
+
+/// Position of the last download of a page in the order downloads were added to the manager.
+/// Clients treat it as opaque, the next page starts right after it no matter which downloads were
+/// added or removed in between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor(pub(super) u64);
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:x}", self.0)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        u64::from_str_radix(s, 16).map(Cursor)
+    }
+}
+
+/// Downloads in the order they were added, see `DownloadManager::get_metadata_page`.
+#[derive(Debug)]
+pub struct Page {
+    pub items: Vec<DownloadMetadata>,
+    /// Where the next page starts, None if this is the last one
+    pub next_cursor: Option<Cursor>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cursor_round_trips_test() {
+        let cursor = Cursor(1234);
+        assert_eq!(cursor.to_string().parse::<Cursor>(), Ok(cursor));
+        assert!("not a cursor".parse::<Cursor>().is_err());
+    }
+}
@@ -0,0 +1,328 @@
+use async_trait::async_trait;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use super::DownloadManager;
+use crate::httpdownload::download::config::PersistInterval;
+use crate::httpdownload::download::State;
+use crate::httpdownload::{DownloadMetadata, DownloadUpdateSubscriber, LifecycleEvent};
+
+/// File the manager saves its downloads to, so they can be restored after a restart with
+/// `DownloadManager::restore`. It's written every `interval` while downloads progress and right
+/// away when one is added, removed, paused, stopped or finishes.
+#[derive(Debug, Clone)]
+pub struct StateFile {
+    pub path: PathBuf,
+    pub interval: PersistInterval,
+    /// Gzip the file when saving. Loading recognizes either format, so toggling it keeps the
+    /// saved downloads.
+    pub compress: bool,
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// What's saved of a download, its metadata and the state it was last in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedDownload {
+    pub metadata: DownloadMetadata,
+    pub state: State,
+}
+
+impl StateFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            interval: PersistInterval::default(),
+            compress: false,
+        }
+    }
+
+    /// Saved downloads, none if the file doesn't exist. An unreadable file is logged and treated
+    /// as empty, it's overwritten by the next save.
+    pub async fn load(&self) -> Vec<SavedDownload> {
+        let raw = match tokio::fs::read(&self.path).await {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Vec::new(),
+            Err(e) => {
+                log::warn!("Couldn't read the state file {:?}: {}", self.path, e);
+                return Vec::new();
+            }
+        };
+        match decode(&raw) {
+            Ok(downloads) => downloads,
+            Err(e) => {
+                log::warn!(
+                    "State file {:?} is corrupt, starting without downloads: {}",
+                    self.path,
+                    e
+                );
+                Vec::new()
+            }
+        }
+    }
+
+    /// Replaces the saved downloads, the file is written next to the old one and renamed over
+    /// it so a crash can't leave half of it behind.
+    pub async fn save(&self, downloads: &[SavedDownload]) -> io::Result<()> {
+        let mut raw = serde_json::to_vec(downloads)?;
+        if self.compress {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&raw)?;
+            raw = encoder.finish()?;
+        }
+        let mut partial = self.path.clone().into_os_string();
+        partial.push(".tmp");
+        tokio::fs::write(&partial, raw).await?;
+        tokio::fs::rename(&partial, &self.path).await
+    }
+}
+
+/// A gzipped file is recognized by its magic bytes, JSON can't start with them.
+fn decode(raw: &[u8]) -> io::Result<Vec<SavedDownload>> {
+    if !raw.starts_with(&GZIP_MAGIC) {
+        return Ok(serde_json::from_slice(raw)?);
+    }
+    let mut json = Vec::new();
+    GzDecoder::new(raw).read_to_end(&mut json)?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+/// Whether a change has to be saved right away or can wait for the persist interval.
+#[derive(Debug)]
+pub(super) enum Change {
+    /// A download was added, removed, renamed or moved, or an option that is saved with it changed
+    Edited,
+    /// The download paused, stopped or finished in this state. The observer may not have it yet,
+    /// subscribers are updated concurrently.
+    Settled(Uuid, State),
+    /// Bytes downloaded since the previous update
+    Progress(u64),
+}
+
+/// Subscriber telling `run_persister` what changed.
+pub(super) struct Persister {
+    changes: mpsc::UnboundedSender<Change>,
+    /// Bytes of the running downloads at their last update
+    downloaded: Mutex<HashMap<Uuid, u64>>,
+}
+
+impl Persister {
+    pub(super) fn changes(&self) -> mpsc::UnboundedSender<Change> {
+        self.changes.clone()
+    }
+
+    /// The persister and the task saving `manager` to `file`
+    pub(super) fn start(manager: DownloadManager, file: StateFile) -> Self {
+        let (changes, changes_recv) = mpsc::unbounded_channel();
+        tokio::spawn(run_persister(manager, file, changes_recv));
+        Self {
+            changes,
+            downloaded: Mutex::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl DownloadUpdateSubscriber for Persister {
+    async fn update(&self, updates: &[(Uuid, State)]) {
+        let mut downloaded = self.downloaded.lock().unwrap();
+        for (id, state) in updates {
+            let change = match state {
+                State::Running {
+                    bytes_downloaded, ..
+                }
+                | State::Retrying {
+                    bytes_downloaded, ..
+                } => {
+                    let previous = downloaded
+                        .insert(*id, *bytes_downloaded)
+                        .unwrap_or_default();
+                    Change::Progress(bytes_downloaded.saturating_sub(previous))
+                }
+                // Still finishing, the state it ends in is saved
+                State::Verifying { .. } | State::Moving { .. } => continue,
+                _ => {
+                    downloaded.remove(id);
+                    Change::Settled(*id, state.clone())
+                }
+            };
+            let _ = self.changes.send(change);
+        }
+    }
+
+    async fn lifecycle(&self, _event: &LifecycleEvent) {
+        let _ = self.changes.send(Change::Edited);
+    }
+}
+
+/// Saves the downloads of `manager` whenever a download settled and otherwise once `interval`
+/// passed or enough bytes were downloaded since the last save.
+async fn run_persister(
+    manager: DownloadManager,
+    file: StateFile,
+    mut changes: mpsc::UnboundedReceiver<Change>,
+) {
+    let mut ticker = tokio::time::interval(file.interval.time);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut last_save = Instant::now();
+    let mut bytes_since = 0;
+    let mut dirty = false;
+    let mut settled = HashMap::new();
+    loop {
+        let due = tokio::select! {
+            _ = ticker.tick() => dirty,
+            change = changes.recv() => {
+                let Some(mut change) = change else {
+                    return;
+                };
+                let mut now = false;
+                // Changes come in bursts, e.g. all downloads restored at startup, one save covers them
+                loop {
+                    match change {
+                        Change::Edited => now = true,
+                        Change::Settled(id, state) => {
+                            settled.insert(id, state);
+                            now = true;
+                        }
+                        Change::Progress(bytes) => bytes_since += bytes,
+                    }
+                    match changes.try_recv() {
+                        Ok(next) => change = next,
+                        Err(_) => break,
+                    }
+                }
+                dirty = true;
+                now || file.interval.is_due(last_save.elapsed(), bytes_since)
+            }
+        };
+        if !due {
+            continue;
+        }
+        match manager.saved_downloads().await {
+            Ok(mut downloads) => {
+                for download in downloads.iter_mut() {
+                    if let Some(state) = settled.remove(&download.metadata.id) {
+                        download.state = state;
+                    }
+                }
+                if let Err(e) = file.save(&downloads).await {
+                    log::error!("Couldn't save the downloads to {:?}: {}", file.path, e);
+                }
+            }
+            Err(e) => log::error!("Couldn't collect the downloads to save: {}", e),
+        }
+        settled.clear();
+        last_save = Instant::now();
+        bytes_since = 0;
+        dirty = false;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::mock::{MockConfig, MockServer};
+    use crate::util::setup_test_download;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn paused_download_is_saved_right_away_test() -> anyhow::Result<()> {
+        // given a state file that is otherwise written once an hour
+        let tmp_dir = tempfile::tempdir()?;
+        let file = StateFile {
+            path: tmp_dir.path().join("downloads.json"),
+            interval: PersistInterval {
+                time: Duration::from_secs(3600),
+                bytes: None,
+            },
+            compress: false,
+        };
+        let manager = DownloadManager::new().await.with_state_file(file.clone());
+        let server = MockServer::start(MockConfig {
+            chunk_delay: Some(Duration::from_millis(20)),
+            ..Default::default()
+        })
+        .await;
+        let (download, _download_dir) = setup_test_download(server.url("file.bin")).await?;
+        let id = manager.add(download).await?;
+        manager.start(&id).await?;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        // when
+        manager.stop(&id).await?;
+        // then
+        let mut saved = None;
+        for _ in 0..50 {
+            saved = file
+                .load()
+                .await
+                .into_iter()
+                .find(|saved| saved.metadata.id == id)
+                .map(|saved| saved.state);
+            if matches!(saved, Some(State::PausedByUser(bytes)) if bytes > 0) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(
+            matches!(saved, Some(State::PausedByUser(bytes)) if bytes > 0),
+            "saved {:?}",
+            saved
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrupt_state_file_loads_empty_test() -> anyhow::Result<()> {
+        // given
+        let tmp_dir = tempfile::tempdir()?;
+        let file = StateFile::new(tmp_dir.path().join("downloads.json"));
+        assert!(file.load().await.is_empty());
+        // when
+        tokio::fs::write(&file.path, "[{\"metadata\":").await?;
+        // then
+        assert!(file.load().await.is_empty());
+        file.save(&[]).await?;
+        assert!(file.load().await.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compressed_state_file_is_read_either_way_test() -> anyhow::Result<()> {
+        // given a saved download
+        let tmp_dir = tempfile::tempdir()?;
+        let mut file = StateFile::new(tmp_dir.path().join("downloads.json"));
+        let manager = DownloadManager::new().await;
+        let server = MockServer::start(MockConfig::default()).await;
+        let (download, _download_dir) = setup_test_download(server.url("file.bin")).await?;
+        let id = manager.add(download).await?;
+        let saved = manager.saved_downloads().await?;
+        // when
+        file.compress = true;
+        file.save(&saved).await?;
+        // then it's gzipped and loads like a plain one
+        assert!(tokio::fs::read(&file.path).await?.starts_with(&GZIP_MAGIC));
+        let loaded = file.load().await;
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].metadata.id, id);
+        // a plain file still loads with compression on and the other way round
+        file.compress = false;
+        file.save(&saved).await?;
+        file.compress = true;
+        assert_eq!(file.load().await[0].metadata.id, id);
+        // a truncated compressed file loads empty
+        file.save(&saved).await?;
+        let compressed = tokio::fs::read(&file.path).await?;
+        tokio::fs::write(&file.path, &compressed[..compressed.len() / 2]).await?;
+        assert!(file.load().await.is_empty());
+        Ok(())
+    }
+}
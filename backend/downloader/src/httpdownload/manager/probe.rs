@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub const DEFAULT_PROBE_CONCURRENCY: usize = 8;
+pub const DEFAULT_PROBE_CONCURRENCY_PER_HOST: usize = 2;
+
+/// Bounds the metadata probes running at the same time, overall and per host, so probing a bulk
+/// import doesn't send a burst of requests to one server. Probes over a limit wait for a slot.
+#[derive(Debug, Clone)]
+pub struct ProbeLimiter {
+    total: Arc<Semaphore>,
+    per_host: usize,
+    hosts: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+}
+
+/// Slot of a running probe, freed when dropped.
+#[derive(Debug)]
+pub struct ProbePermit {
+    _host: Option<OwnedSemaphorePermit>,
+    _total: OwnedSemaphorePermit,
+}
+
+impl Default for ProbeLimiter {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_PROBE_CONCURRENCY,
+            DEFAULT_PROBE_CONCURRENCY_PER_HOST,
+        )
+    }
+}
+
+impl ProbeLimiter {
+    /// Limits of zero are raised to one.
+    pub fn new(total: usize, per_host: usize) -> Self {
+        Self {
+            total: Arc::new(Semaphore::new(total.max(1))),
+            per_host: per_host.max(1),
+            hosts: Arc::default(),
+        }
+    }
+
+    /// Waits for a slot of the host (if the url has one) and then for one of the total, so a probe
+    /// waiting on its host doesn't hold back probes of other hosts.
+    pub async fn acquire(&self, host: Option<&str>) -> ProbePermit {
+        let host = match host {
+            Some(host) => {
+                let semaphore = self.host_semaphore(host);
+                Some(semaphore.acquire_owned().await.expect("never closed"))
+            }
+            None => None,
+        };
+        let total = self
+            .total
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("never closed");
+        ProbePermit {
+            _host: host,
+            _total: total,
+        }
+    }
+
+    fn host_semaphore(&self, host: &str) -> Arc<Semaphore> {
+        let mut hosts = self.hosts.lock().unwrap();
+        // Hosts nobody probes or waits for are dropped
+        hosts.retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+        hosts
+            .entry(host.to_ascii_lowercase())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.per_host)))
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use test_log::test;
+    use tokio::time::timeout;
+
+    #[test(tokio::test)]
+    async fn probes_wait_for_host_and_total_slots_test() {
+        // given
+        let limiter = ProbeLimiter::new(2, 1);
+        let wait = Duration::from_millis(50);
+        // when a probe of a host runs
+        let first = limiter.acquire(Some("a.example")).await;
+        // then the same host waits, other hosts don't
+        assert!(timeout(wait, limiter.acquire(Some("A.example")))
+            .await
+            .is_err());
+        let _second = limiter.acquire(Some("b.example")).await;
+        // and beyond the total everybody waits
+        assert!(timeout(wait, limiter.acquire(Some("c.example")))
+            .await
+            .is_err());
+        drop(first);
+        assert!(timeout(wait, limiter.acquire(Some("c.example")))
+            .await
+            .is_ok());
+    }
+}
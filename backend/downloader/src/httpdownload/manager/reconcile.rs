@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::httpdownload::download::State;
+
+/// Tracked state of a download that didn't match its files and the state it was corrected to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Repair {
+    pub id: Uuid,
+    pub before: State,
+    pub after: State,
+}
+
+/// Outcome of `DownloadManager::reconcile`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Reconciliation {
+    /// Downloads whose state was compared with their files
+    pub checked: usize,
+    /// Running downloads and ones locked by a pending operation, they were left alone
+    pub skipped: Vec<Uuid>,
+    pub repairs: Vec<Repair>,
+}
+
+/// State a stopped download should be in given its files, None if its state is right. A complete
+/// download needs its final file and a missing one is complete again once the file is back,
+/// paused downloads resume from the bytes actually on disk.
+pub(super) fn reconciled(state: &State, final_exists: bool, bytes_on_disk: u64) -> Option<State> {
+    match state {
+        State::Complete if !final_exists => Some(State::Missing),
+        State::Missing if final_exists => Some(State::Complete),
+        State::PausedByUser(bytes) if *bytes != bytes_on_disk => {
+            Some(State::PausedByUser(bytes_on_disk))
+        }
+        State::PausedBySystem {
+            bytes_downloaded,
+            reason,
+        } if *bytes_downloaded != bytes_on_disk => Some(State::PausedBySystem {
+            bytes_downloaded: bytes_on_disk,
+            reason: *reason,
+        }),
+        State::SourceChanged { bytes_downloaded } if *bytes_downloaded != bytes_on_disk => {
+            Some(State::SourceChanged {
+                bytes_downloaded: bytes_on_disk,
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::httpdownload::download::PauseReason;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn states_follow_the_files_test() {
+        assert_eq!(reconciled(&State::Complete, false, 0), Some(State::Missing));
+        assert_eq!(reconciled(&State::Complete, true, 0), None);
+        assert_eq!(reconciled(&State::Missing, true, 10), Some(State::Complete));
+        assert_eq!(
+            reconciled(&State::PausedByUser(100), false, 40),
+            Some(State::PausedByUser(40))
+        );
+        assert_eq!(reconciled(&State::PausedByUser(40), false, 40), None);
+        let paused = State::PausedBySystem {
+            bytes_downloaded: 100,
+            reason: PauseReason::QueueLimit,
+        };
+        assert_eq!(
+            reconciled(&paused, false, 0),
+            Some(State::PausedBySystem {
+                bytes_downloaded: 0,
+                reason: PauseReason::QueueLimit,
+            })
+        );
+        assert_eq!(
+            reconciled(&State::Error("boom".to_string()), false, 0),
+            None
+        );
+    }
+}
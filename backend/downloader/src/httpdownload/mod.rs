@@ -5,7 +5,9 @@ use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
+pub mod client;
 pub mod download;
+pub mod history;
 pub mod manager;
 pub mod observer;
 
@@ -14,13 +16,80 @@ pub struct DownloadMetadata {
     pub id: Uuid,
     pub url: String,
     pub file_path: PathBuf,
-    pub download_size: u64,
+    /// None until the server was probed, see `HttpDownloadBuilder::lazy`
+    pub download_size: Option<u64>,
+    /// Retries done by the download itself, see `DownloadStats::retries`
+    pub total_retries: u32,
+    /// Times the download continued from bytes already on disk, see `DownloadStats::resumes`
+    #[serde(default)]
+    pub resume_count: u32,
+    /// Time spent transferring over all runs, excluding paused time
+    pub active_duration_ms: u64,
+    /// Headers and credentials are sent across cross-host redirects
+    #[serde(default)]
+    pub preserve_auth_on_redirect: bool,
+    /// Retries of transient failures
+    #[serde(default)]
+    pub retry_policy: download::retry::RetryPolicy,
+    /// Further files the download is written to
+    #[serde(default)]
+    pub mirrors: Vec<PathBuf>,
+    /// Exempt from the manager's bandwidth limit
+    #[serde(default)]
+    pub ignore_global_limit: bool,
+    /// Bytes per second the download alone is limited to, None if unlimited
+    #[serde(default)]
+    pub rate_limit: Option<u64>,
+    /// Connections the download is fetched with, 1 if the server doesn't support byte ranges
+    /// whatever was configured
+    #[serde(default = "single_segment")]
+    pub segments: usize,
+    /// Download with the same content that completed first, see `DownloadManager::with_dedup`
+    #[serde(default)]
+    pub duplicate_of: Option<Uuid>,
+}
+
+fn single_segment() -> usize {
+    1
+}
+
+/// A download joined or left the manager or its file changed place, so clients can keep their
+/// list of downloads in sync without fetching it again. Serialized with a `kind` tag, e.g.
+/// `{"kind": "added", "metadata": {...}}` or `{"kind": "removed", "id": "<uuid>"}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LifecycleEvent {
+    Added {
+        metadata: DownloadMetadata,
+    },
+    Removed {
+        id: Uuid,
+    },
+    /// The file got a new name, see `DownloadManager::rename`
+    Renamed {
+        metadata: DownloadMetadata,
+    },
+    /// The download was found in another directory, see `DownloadManager::relocate_all`
+    Moved {
+        metadata: DownloadMetadata,
+    },
 }
 
 /// This trait is used to subscribe to state updates of downloads
 #[async_trait]
 pub trait DownloadUpdateSubscriber {
     async fn update(&self, updates: &[(Uuid, download::State)]);
+
+    /// Overall progress, sent periodically if the manager has an aggregate interval. Ignored
+    /// unless implemented.
+    async fn aggregate(&self, _update: &observer::AggregateUpdate) {}
+
+    /// Failure of a download, final and transient (retried) ones. Ignored unless implemented.
+    async fn error(&self, _event: &download::ErrorEvent) {}
+
+    /// A download was added, removed, renamed or moved, in the order it happened. Ignored unless
+    /// implemented.
+    async fn lifecycle(&self, _event: &LifecycleEvent) {}
 }
 
 // Fuck this type, later on just remove the wrapping Arc<Mutex> and instead create a simple channel
@@ -32,26 +101,32 @@ pub type Subscribers = Arc<Mutex<Vec<Arc<dyn DownloadUpdateSubscriber + Send + S
 mod test {
     use crate::{
         httpdownload::manager::DownloadManager,
-        util::{setup_test_download, TestResult},
+        util::{
+            mock::{MockConfig, MockServer},
+            setup_test_download, TestResult,
+        },
     };
 
     use super::*;
+    use std::time::Duration;
     use test_log::test;
 
-    const TEST_DOWNLOAD_URL: &str =
-        "https://dl.google.com/linux/direct/google-chrome-stable_current_amd64.deb";
-
     #[test(tokio::test)]
     async fn test_download_with_observability() -> TestResult<()> {
         let manager = DownloadManager::new().await;
-        let (download, _tmp_dir) = setup_test_download(TEST_DOWNLOAD_URL).await?;
-        let id = manager.add(download).await;
+        let server = MockServer::start(MockConfig {
+            chunk_delay: Some(Duration::from_millis(20)),
+            ..Default::default()
+        })
+        .await;
+        let (download, _tmp_dir) = setup_test_download(server.url("file.bin")).await?;
+        let id = manager.add(download).await?;
 
         manager.start(&id).await?;
         manager.stop(&id).await?;
         let state = manager.observer.read_state().await;
         let download_state = state.get(&id).unwrap();
-        assert!(matches!(download_state, download::State::Paused(_)));
+        assert!(matches!(download_state, download::State::PausedByUser(_)));
         Ok(())
     }
 }
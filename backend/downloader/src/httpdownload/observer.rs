@@ -1,8 +1,9 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use tokio::{
-    sync::{Mutex, RwLock, RwLockReadGuard},
+    sync::{mpsc, Mutex, Notify, RwLock, RwLockReadGuard},
     time::Instant,
 };
 use uuid::Uuid;
@@ -22,15 +23,18 @@ use super::{
 #[derive(Clone)]
 pub struct DownloadObserver {
     pub state: Arc<RwLock<HashMap<Uuid, download::State>>>,
+    /// Woken whenever a tracked state changes
+    changed: Arc<Notify>,
 }
 
 impl DownloadObserver {
     pub fn new() -> Self {
         Self {
             state: Arc::new(RwLock::new(HashMap::new())),
+            changed: Arc::new(Notify::new()),
         }
     }
-    pub async fn read_state(&self) -> RwLockReadGuard<HashMap<Uuid, download::State>> {
+    pub async fn read_state(&self) -> RwLockReadGuard<'_, HashMap<Uuid, download::State>> {
         self.state.read().await
     }
 
@@ -49,10 +53,34 @@ impl DownloadObserver {
 
     pub async fn track(&self, id: Uuid, state: download::State) {
         self.state.write().await.insert(id, state);
+        self.changed.notify_waiters();
     }
 
     pub async fn untrack(&self, id: &Uuid) {
         self.state.write().await.remove(id);
+        self.changed.notify_waiters();
+    }
+
+    /// Waits until the download is in a terminal state (see `State::is_terminal`) and returns
+    /// it, right away if it already is. None if the download isn't tracked (anymore).
+    pub async fn wait_for_terminal(&self, id: &Uuid) -> Option<State> {
+        loop {
+            // Registered before the state is read so a change in between isn't missed
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            match self.get_state(id).await {
+                Some(state) if state.is_terminal() => return Some(state),
+                Some(_) => changed.await,
+                None => return None,
+            }
+        }
+    }
+}
+
+impl Default for DownloadObserver {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -70,6 +98,7 @@ impl DownloadUpdateSubscriber for DownloadObserver {
                 guard.insert(*id, state.clone());
             }
         }
+        self.changed.notify_waiters();
     }
 }
 
@@ -105,10 +134,19 @@ impl DownloadUpdateBuffer {
     }
 }
 
+impl Default for DownloadUpdateBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl UpdateConsumer for DownloadUpdateBuffer {
     fn consume(&mut self, update: DownloadUpdate) {
         let flush = self.last_flush.elapsed() > HALF_SECOND
-            && !matches!(update.state, State::Running { .. });
+            || !matches!(
+                update.state,
+                State::Running { .. } | State::Verifying { .. } | State::Moving { .. }
+            );
         let state = update.state;
         self.cache.insert(update.id, state);
         // If more than HALF_SECOND has elapsed or the download triggered an event
@@ -116,8 +154,8 @@ impl UpdateConsumer for DownloadUpdateBuffer {
         // thread that called consume for too long (just the time to create an update array, wrap
         // it in Arc and spawn the tokio task).
         if flush {
-            let updates: Arc<[(Uuid, download::State)]> =
-                self.cache.drain().map(|(id, state)| (id, state)).collect();
+            self.last_flush = Instant::now();
+            let updates: Arc<[(Uuid, download::State)]> = self.cache.drain().collect();
             let subscribers = self.subscribers.clone();
             tokio::task::spawn(async move {
                 log::info!(
@@ -139,3 +177,290 @@ impl UpdateConsumer for DownloadUpdateBuffer {
         }
     }
 }
+
+/// Change of a download's state since the previous emission to a subscriber, the format sent to
+/// clients of live event streams. Serialized with a `kind` tag:
+///
+/// - `{"kind": "full", "id": "<uuid>", "state": <State>}`: the complete state, clients replace
+///   whatever they know about the download with it.
+/// - `{"kind": "running", "id": "<uuid>", "bytes_downloaded": 42}`: the download was and still
+///   is `Running`, only the fields present changed. Clients merge them into the `Running` state
+///   they already have.
+///
+/// Any other transition, in particular to a terminal state (`Complete`, `Partial`, `Error`), is
+/// always sent as `full`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum StateDelta {
+    Full {
+        id: Uuid,
+        state: State,
+    },
+    Running {
+        id: Uuid,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        bytes_downloaded: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        bytes_per_second: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        average_bytes_per_second: Option<u64>,
+    },
+}
+
+/// Remembers what was last emitted per download to turn state updates into `StateDelta`s. Every
+/// `snapshot_interval` all known states are sent in full so clients that missed or misapplied a
+/// delta resynchronize.
+#[derive(Debug)]
+pub struct StateDiffer {
+    last: HashMap<Uuid, State>,
+    snapshot_interval: Duration,
+    last_snapshot: Instant,
+}
+
+impl StateDiffer {
+    pub fn new(snapshot_interval: Duration) -> Self {
+        Self {
+            last: HashMap::new(),
+            snapshot_interval,
+            last_snapshot: Instant::now(),
+        }
+    }
+
+    pub fn diff(&mut self, updates: &[(Uuid, State)]) -> Vec<StateDelta> {
+        if self.last_snapshot.elapsed() >= self.snapshot_interval {
+            self.last_snapshot = Instant::now();
+            for (id, state) in updates.iter() {
+                self.last.insert(*id, state.clone());
+            }
+            return self
+                .last
+                .iter()
+                .map(|(id, state)| StateDelta::Full {
+                    id: *id,
+                    state: state.clone(),
+                })
+                .collect();
+        }
+        updates
+            .iter()
+            .filter_map(|(id, state)| {
+                let previous = self.last.insert(*id, state.clone());
+                delta(*id, previous.as_ref(), state)
+            })
+            .collect()
+    }
+
+    /// Stops tracking a download, e.g. after it was deleted.
+    pub fn forget(&mut self, id: &Uuid) {
+        self.last.remove(id);
+    }
+}
+
+fn delta(id: Uuid, previous: Option<&State>, state: &State) -> Option<StateDelta> {
+    let changed = |old: u64, new: u64| (old != new).then_some(new);
+    match (previous, state) {
+        (Some(previous), state) if previous == state => None,
+        (
+            Some(State::Running {
+                bytes_downloaded: old_bytes,
+                bytes_per_second: old_speed,
+                average_bytes_per_second: old_average,
+            }),
+            State::Running {
+                bytes_downloaded,
+                bytes_per_second,
+                average_bytes_per_second,
+            },
+        ) => Some(StateDelta::Running {
+            id,
+            bytes_downloaded: changed(*old_bytes, *bytes_downloaded),
+            bytes_per_second: changed(*old_speed, *bytes_per_second),
+            average_bytes_per_second: changed(*old_average, *average_bytes_per_second),
+        }),
+        (_, state) => Some(StateDelta::Full {
+            id,
+            state: state.clone(),
+        }),
+    }
+}
+
+/// Subscriber that forwards the updates as deltas over a channel, every subscriber (e.g. every
+/// connected event stream client) keeps its own `StateDiffer`.
+pub struct DeltaSubscriber {
+    differ: Mutex<StateDiffer>,
+    sender: mpsc::Sender<Vec<StateDelta>>,
+}
+
+impl DeltaSubscriber {
+    pub fn new(snapshot_interval: Duration) -> (Self, mpsc::Receiver<Vec<StateDelta>>) {
+        let (sender, receiver) = mpsc::channel(100);
+        let subscriber = Self {
+            differ: Mutex::new(StateDiffer::new(snapshot_interval)),
+            sender,
+        };
+        (subscriber, receiver)
+    }
+}
+
+#[async_trait]
+impl DownloadUpdateSubscriber for DeltaSubscriber {
+    async fn update(&self, updates: &[(Uuid, download::State)]) {
+        let deltas = self.differ.lock().await.diff(updates);
+        if !deltas.is_empty() {
+            let _ = self.sender.send(deltas).await;
+        }
+    }
+}
+
+/// Overall progress of all downloads, emitted periodically to subscribers so dashboards don't
+/// have to sum up every download themselves.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AggregateUpdate {
+    pub bytes_downloaded: u64,
+    /// Sum of the sizes of all downloads
+    pub bytes_total: u64,
+    /// Sum of the current speeds of the running downloads
+    pub bytes_per_second: u64,
+    pub running: usize,
+    pub downloads: usize,
+}
+
+impl AggregateUpdate {
+    /// Sums up the states of downloads given with their size.
+    pub fn from_states<'a>(states: impl IntoIterator<Item = (&'a State, u64)>) -> Self {
+        let mut aggregate = Self::default();
+        for (state, size) in states {
+            aggregate.downloads += 1;
+            aggregate.bytes_total += size;
+            aggregate.bytes_downloaded += match state {
+                State::Complete => size,
+                // The transfer is done, only hashing or moving the file is left
+                State::Verifying { .. } | State::Moving { .. } => {
+                    aggregate.running += 1;
+                    size
+                }
+                State::Partial(bytes) | State::PausedByUser(bytes) => *bytes,
+                State::Retrying {
+                    bytes_downloaded, ..
+                } => {
+                    aggregate.running += 1;
+                    *bytes_downloaded
+                }
+                State::PausedBySystem {
+                    bytes_downloaded, ..
+                }
+                | State::SourceChanged { bytes_downloaded } => *bytes_downloaded,
+                State::Running {
+                    bytes_downloaded,
+                    bytes_per_second,
+                    ..
+                } => {
+                    aggregate.running += 1;
+                    aggregate.bytes_per_second += bytes_per_second;
+                    *bytes_downloaded
+                }
+                State::Created
+                | State::Error(_)
+                | State::ChecksumFailed { .. }
+                | State::Missing => 0,
+            };
+        }
+        aggregate
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    fn running(bytes_downloaded: u64, bytes_per_second: u64) -> State {
+        State::Running {
+            bytes_downloaded,
+            bytes_per_second,
+            average_bytes_per_second: 10,
+        }
+    }
+
+    #[test]
+    fn only_changed_fields_are_emitted_test() {
+        // given
+        let id = Uuid::new_v4();
+        let mut differ = StateDiffer::new(Duration::from_secs(3600));
+        // when a download is seen for the first time, then it's sent in full
+        assert_eq!(
+            differ.diff(&[(id, running(0, 0))]),
+            vec![StateDelta::Full {
+                id,
+                state: running(0, 0)
+            }]
+        );
+        // when only the byte count changes
+        let deltas = differ.diff(&[(id, running(100, 0))]);
+        // then
+        assert_eq!(
+            serde_json::to_value(&deltas).unwrap(),
+            json!([{"kind": "running", "id": id, "bytes_downloaded": 100}])
+        );
+        // unchanged states aren't emitted
+        assert_eq!(differ.diff(&[(id, running(100, 0))]), vec![]);
+        // terminal transitions are full updates
+        assert_eq!(
+            differ.diff(&[(id, State::Complete)]),
+            vec![StateDelta::Full {
+                id,
+                state: State::Complete
+            }]
+        );
+    }
+
+    #[test]
+    fn periodic_full_snapshot_test() {
+        // given
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut differ = StateDiffer::new(Duration::ZERO);
+        differ.diff(&[(first, running(0, 0)), (second, State::PausedByUser(5))]);
+        // when
+        let mut deltas = differ.diff(&[(first, running(100, 1))]);
+        // then every known download is sent in full
+        deltas.sort_by_key(|delta| match delta {
+            StateDelta::Full { id, .. } | StateDelta::Running { id, .. } => (*id != first) as u8,
+        });
+        assert_eq!(
+            deltas,
+            vec![
+                StateDelta::Full {
+                    id: first,
+                    state: running(100, 1)
+                },
+                StateDelta::Full {
+                    id: second,
+                    state: State::PausedByUser(5)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn aggregate_sums_states_test() {
+        let states = [
+            (running(100, 20), 1000),
+            (running(50, 5), 1000),
+            (State::Complete, 300),
+            (State::PausedByUser(7), 10),
+            (State::Error("failed".to_string()), 10),
+        ];
+        let aggregate = AggregateUpdate::from_states(states.iter().map(|(s, size)| (s, *size)));
+        assert_eq!(
+            aggregate,
+            AggregateUpdate {
+                bytes_downloaded: 457,
+                bytes_total: 2320,
+                bytes_per_second: 25,
+                running: 2,
+                downloads: 5,
+            }
+        );
+    }
+}
@@ -1,2 +1,4 @@
+pub mod downloadable;
+pub mod ftpdownload;
 pub mod httpdownload;
 pub mod util;
@@ -0,0 +1,18 @@
+//! Download the running task works for, so log lines can be attributed to a download without
+//! looking at their text.
+use std::future::Future;
+use uuid::Uuid;
+
+tokio::task_local! {
+    static DOWNLOAD: Uuid;
+}
+
+/// Runs `future` on behalf of the download `id`, everything it logs belongs to that download.
+pub async fn scoped<F: Future>(id: Uuid, future: F) -> F::Output {
+    DOWNLOAD.scope(id, future).await
+}
+
+/// Download of the task that's running, None outside of `scoped`.
+pub fn current_download() -> Option<Uuid> {
+    DOWNLOAD.try_with(|id| *id).ok()
+}
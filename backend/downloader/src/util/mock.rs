@@ -0,0 +1,481 @@
+//! Local HTTP server for tests, it serves a configurable payload and can simulate the server
+//! behaviours downloads have to deal with (missing range support, slow transfers, failing
+//! requests and dropped connections). `ftp_server` is a minimal FTP counterpart.
+use hyper::body::{Body, Bytes};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Method, Request, Response, Server, StatusCode};
+use reqwest::Url;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+use crate::httpdownload::download::ByteRange;
+
+const BOUNDARY: &str = "MOCK_BYTERANGES_BOUNDARY";
+
+/// Behaviour of the mock server, can be changed while the server is running with
+/// `MockServer::update`.
+#[derive(Debug, Clone)]
+pub struct MockConfig {
+    pub payload: Arc<Vec<u8>>,
+    /// Send `Accept-Ranges: bytes` and answer `Range` requests with 206
+    pub accept_ranges: bool,
+    /// Send a `Content-Length` header, otherwise the body is chunked
+    pub content_length: bool,
+    /// Size of the chunks the body is streamed in
+    pub chunk_size: usize,
+    /// Pause between two chunks of the body
+    pub chunk_delay: Option<Duration>,
+    /// Answer HEAD requests with 405 like servers that only implement GET
+    pub reject_head: bool,
+    /// Every request pops one status and fails with it until the queue is empty
+    pub fail_next: VecDeque<StatusCode>,
+    /// `Retry-After` sent with the failures of `fail_next`
+    pub retry_after: Option<String>,
+    /// Aborts the connection once a body reached this offset of the payload, only once
+    pub drop_at: Option<u64>,
+    /// Additional headers added to every response
+    pub headers: HeaderMap,
+    /// Answers every request with a `302 Found` to this url
+    pub redirect: Option<Url>,
+}
+
+impl MockConfig {
+    pub fn new(payload: Vec<u8>) -> Self {
+        Self {
+            payload: Arc::new(payload),
+            accept_ranges: true,
+            content_length: true,
+            chunk_size: 16 * 1024,
+            chunk_delay: None,
+            reject_head: false,
+            fail_next: VecDeque::new(),
+            retry_after: None,
+            drop_at: None,
+            headers: HeaderMap::new(),
+            redirect: None,
+        }
+    }
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        Self::new(payload(1024 * 1024))
+    }
+}
+
+/// Deterministic, non repeating-per-kilobyte test payload of `len` bytes.
+pub fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+/// A request received by the mock server.
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: Method,
+    pub path_and_query: String,
+    pub headers: HeaderMap,
+}
+
+#[derive(Debug)]
+struct MockState {
+    config: MockConfig,
+    requests: Vec<RecordedRequest>,
+    /// Bodies being sent right now and the most that were sent at once
+    streaming: usize,
+    max_streaming: usize,
+}
+
+/// Counts a body as being sent for as long as it lives.
+struct Streaming(Arc<Mutex<MockState>>);
+
+impl Streaming {
+    fn start(state: Arc<Mutex<MockState>>) -> Self {
+        {
+            let mut state = state.lock().unwrap();
+            state.streaming += 1;
+            state.max_streaming = state.max_streaming.max(state.streaming);
+        }
+        Streaming(state)
+    }
+}
+
+impl Drop for Streaming {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().streaming -= 1;
+    }
+}
+
+/// Handle to a running mock server, the server is shut down when the handle is dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    handle: JoinHandle<()>,
+}
+
+impl MockServer {
+    pub async fn start(config: MockConfig) -> Self {
+        let state = Arc::new(Mutex::new(MockState {
+            config,
+            requests: Vec::new(),
+            streaming: 0,
+            max_streaming: 0,
+        }));
+        let make_service = make_service_fn({
+            let state = state.clone();
+            move |_conn| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        let handle = tokio::spawn(async move {
+            if let Err(e) = server.await {
+                log::error!("Mock server failed: {}", e);
+            }
+        });
+        Self {
+            addr,
+            state,
+            handle,
+        }
+    }
+
+    /// Url of a file on the mock server, every path serves the same payload.
+    pub fn url(&self, path: &str) -> Url {
+        Url::parse(&format!(
+            "http://{}/{}",
+            self.addr,
+            path.trim_start_matches('/')
+        ))
+        .unwrap()
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut MockConfig)) {
+        f(&mut self.state.lock().unwrap().config);
+    }
+
+    pub fn payload(&self) -> Arc<Vec<u8>> {
+        self.state.lock().unwrap().config.payload.clone()
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Most response bodies that were being sent at the same time, i.e. concurrent transfers.
+    pub fn max_concurrent_bodies(&self) -> usize {
+        self.state.lock().unwrap().max_streaming
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+async fn handle(
+    state: Arc<Mutex<MockState>>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let (config, failure, drop_at) = {
+        let mut state = state.lock().unwrap();
+        state.requests.push(RecordedRequest {
+            method: req.method().clone(),
+            path_and_query: req
+                .uri()
+                .path_and_query()
+                .map(|p| p.to_string())
+                .unwrap_or_default(),
+            headers: req.headers().clone(),
+        });
+        let failure = match req.method() == Method::HEAD && state.config.reject_head {
+            true => Some(StatusCode::METHOD_NOT_ALLOWED),
+            false => state.config.fail_next.pop_front(),
+        };
+        let drop_at = match failure {
+            Some(_) => None,
+            None if req.method() == Method::HEAD => None,
+            None => state.config.drop_at.take(),
+        };
+        (state.config.clone(), failure, drop_at)
+    };
+
+    let mut resp = Response::builder();
+    for (name, value) in config.headers.iter() {
+        resp = resp.header(name, value);
+    }
+    if let Some(status) = failure {
+        if let Some(retry_after) = &config.retry_after {
+            resp = resp.header(header::RETRY_AFTER, retry_after.as_str());
+        }
+        return Ok(resp.status(status).body(Body::empty()).unwrap());
+    }
+    if let Some(location) = &config.redirect {
+        return Ok(resp
+            .status(StatusCode::FOUND)
+            .header(header::LOCATION, location.as_str())
+            .body(Body::empty())
+            .unwrap());
+    }
+    if config.accept_ranges {
+        resp = resp.header(header::ACCEPT_RANGES, "bytes");
+    }
+
+    let total = config.payload.len() as u64;
+    // A stale `If-Range` validator gets the whole payload like from real servers
+    let if_range_matches = req.headers().get(header::IF_RANGE).is_none_or(|validator| {
+        [header::ETAG, header::LAST_MODIFIED]
+            .iter()
+            .any(|name| config.headers.get(name) == Some(validator))
+    });
+    let ranges = match req.headers().get(header::RANGE) {
+        Some(value) if config.accept_ranges && if_range_matches => match parse_ranges(value, total)
+        {
+            Some(ranges) => ranges,
+            None => {
+                return Ok(resp
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(header::CONTENT_RANGE, format!("bytes */{}", total))
+                    .body(Body::empty())
+                    .unwrap())
+            }
+        },
+        _ => Vec::new(),
+    };
+
+    // (offset in payload, bytes) pieces the body is made of
+    let (resp, pieces) = match ranges.as_slice() {
+        [] => (
+            resp.status(StatusCode::OK),
+            vec![(0, config.payload.to_vec())],
+        ),
+        [range] => (
+            resp.status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, format!("bytes {}/{}", range, total)),
+            vec![(range.start, slice(&config.payload, range).to_vec())],
+        ),
+        ranges => {
+            let mut pieces = Vec::new();
+            for range in ranges {
+                let part_head = format!(
+                    "\r\n--{}\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes {}/{}\r\n\r\n",
+                    BOUNDARY, range, total
+                );
+                pieces.push((u64::MAX, part_head.into_bytes()));
+                pieces.push((range.start, slice(&config.payload, range).to_vec()));
+            }
+            pieces.push((u64::MAX, format!("\r\n--{}--\r\n", BOUNDARY).into_bytes()));
+            (
+                resp.status(StatusCode::PARTIAL_CONTENT).header(
+                    header::CONTENT_TYPE,
+                    format!("multipart/byteranges; boundary={}", BOUNDARY),
+                ),
+                pieces,
+            )
+        }
+    };
+    let body_len: usize = pieces.iter().map(|(_, data)| data.len()).sum();
+    let resp = match config.content_length {
+        true => resp.header(header::CONTENT_LENGTH, body_len),
+        false => resp,
+    };
+    if req.method() == Method::HEAD {
+        return Ok(resp.body(Body::empty()).unwrap());
+    }
+
+    let (mut sender, body) = Body::channel();
+    let streaming = Streaming::start(state);
+    tokio::spawn(async move {
+        let _streaming = streaming;
+        for (offset, data) in pieces {
+            for (idx, chunk) in data.chunks(config.chunk_size.max(1)).enumerate() {
+                let chunk_offset = offset.saturating_add((idx * config.chunk_size) as u64);
+                if let Some(drop_at) = drop_at {
+                    if offset != u64::MAX && chunk_offset + chunk.len() as u64 > drop_at {
+                        let keep = drop_at.saturating_sub(chunk_offset) as usize;
+                        let _ = sender
+                            .send_data(Bytes::copy_from_slice(&chunk[..keep]))
+                            .await;
+                        sender.abort();
+                        return;
+                    }
+                }
+                if let Some(delay) = config.chunk_delay {
+                    tokio::time::sleep(delay).await;
+                }
+                if sender
+                    .send_data(Bytes::copy_from_slice(chunk))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    });
+    Ok(resp.body(body).unwrap())
+}
+
+fn slice<'a>(payload: &'a [u8], range: &ByteRange) -> &'a [u8] {
+    &payload[range.start as usize..=range.end as usize]
+}
+
+/// Parses `bytes=0-9,20-,-5` style headers, None if no range is satisfiable.
+fn parse_ranges(value: &HeaderValue, total: u64) -> Option<Vec<ByteRange>> {
+    let specs = value.to_str().ok()?.trim().strip_prefix("bytes=")?;
+    let ranges: Vec<ByteRange> = specs
+        .split(',')
+        .filter_map(|spec| {
+            let (start, end) = spec.trim().split_once('-')?;
+            let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+                (Ok(start), Ok(end)) => ByteRange::new(start, end.min(total.checked_sub(1)?)),
+                (Ok(start), Err(_)) if end.is_empty() => {
+                    ByteRange::new(start, total.checked_sub(1)?)
+                }
+                (Err(_), Ok(suffix)) if start.is_empty() => {
+                    ByteRange::new(total.saturating_sub(suffix), total.checked_sub(1)?)
+                }
+                _ => return None,
+            };
+            (range.start < total && !range.is_empty()).then_some(range)
+        })
+        .collect();
+    (!ranges.is_empty()).then_some(ranges)
+}
+
+/// Serves `payload` as `/file.bin` to any login, records the commands it received.
+pub async fn ftp_server(payload: Vec<u8>) -> (u16, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let commands = Arc::new(Mutex::new(Vec::new()));
+    let recorded = commands.clone();
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                return;
+            };
+            let payload = payload.clone();
+            let commands = recorded.clone();
+            tokio::spawn(async move {
+                let (reader, mut writer) = stream.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                let mut data_listener = None;
+                let mut offset = 0;
+                writer.write_all(b"220-Welcome\r\n220 ready\r\n").await?;
+                while let Some(line) = lines.next_line().await? {
+                    commands.lock().unwrap().push(line.clone());
+                    let (command, arg) = line.split_once(' ').unwrap_or((&line, ""));
+                    let reply = match command {
+                        "USER" => "331 password please".to_string(),
+                        "PASS" => "230 logged in".to_string(),
+                        "TYPE" => "200 binary".to_string(),
+                        "SIZE" => format!("213 {}", payload.len()),
+                        "PASV" => {
+                            let listener = TcpListener::bind("127.0.0.1:0").await?;
+                            let port = listener.local_addr()?.port();
+                            data_listener = Some(listener);
+                            format!(
+                                "227 Entering Passive Mode (127,0,0,1,{},{})",
+                                port / 256,
+                                port % 256
+                            )
+                        }
+                        "REST" => {
+                            offset = arg.parse().unwrap();
+                            "350 restarting".to_string()
+                        }
+                        "RETR" => {
+                            writer.write_all(b"150 sending\r\n").await?;
+                            let (mut data, _) = data_listener.take().unwrap().accept().await?;
+                            data.write_all(&payload[offset..]).await?;
+                            drop(data);
+                            "226 done".to_string()
+                        }
+                        "QUIT" => "221 bye".to_string(),
+                        _ => "502 not implemented".to_string(),
+                    };
+                    writer
+                        .write_all(format!("{}\r\n", reply).as_bytes())
+                        .await?;
+                }
+                std::io::Result::Ok(())
+            });
+        }
+    });
+    (port, commands)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use reqwest::Client;
+    use test_log::test;
+
+    #[test(tokio::test)]
+    async fn serves_single_and_multiple_ranges() -> anyhow::Result<()> {
+        // given
+        let server = MockServer::start(MockConfig::new(payload(100))).await;
+        let client = Client::new();
+        // when
+        let single = client
+            .get(server.url("file.bin"))
+            .header(header::RANGE, "bytes=10-19")
+            .send()
+            .await?;
+        let multiple = client
+            .get(server.url("file.bin"))
+            .header(header::RANGE, "bytes=0-1,-2")
+            .send()
+            .await?;
+        let unsatisfiable = client
+            .get(server.url("file.bin"))
+            .header(header::RANGE, "bytes=200-")
+            .send()
+            .await?;
+        // then
+        assert_eq!(single.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(single.headers()[header::CONTENT_RANGE], "bytes 10-19/100");
+        assert_eq!(single.bytes().await?.as_ref(), &payload(100)[10..20]);
+        assert!(multiple.headers()[header::CONTENT_TYPE]
+            .to_str()?
+            .starts_with("multipart/byteranges"));
+        assert_eq!(unsatisfiable.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(server.requests().len(), 3);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn injected_failures_happen_once() -> anyhow::Result<()> {
+        // given
+        let server = MockServer::start(MockConfig::new(payload(100))).await;
+        server.update(|config| {
+            config.fail_next.push_back(StatusCode::SERVICE_UNAVAILABLE);
+            config.drop_at = Some(50);
+        });
+        let client = Client::new();
+        // when
+        let failed = client.get(server.url("file.bin")).send().await?;
+        let dropped = client
+            .get(server.url("file.bin"))
+            .send()
+            .await?
+            .bytes()
+            .await;
+        let ok = client.get(server.url("file.bin")).send().await?;
+        // then
+        assert_eq!(failed.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(dropped.is_err());
+        assert_eq!(ok.bytes().await?.len(), 100);
+        Ok(())
+    }
+}
@@ -1,10 +1,20 @@
 use reqwest::header::HeaderMap;
-use reqwest::{header, Client, Url};
+use reqwest::{header, Url};
 use std::error::Error;
 use std::path::Path;
-use tempfile::TempDir;
 
+use crate::httpdownload::download::ByteRange;
+
+pub mod context;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+
+#[cfg(test)]
 use crate::httpdownload::download::HttpDownload;
+#[cfg(test)]
+use reqwest::Client;
+#[cfg(test)]
+use tempfile::TempDir;
 
 /// Extracts filesize from path, if file does not exist or read fails the function returns 0
 pub async fn file_size(fpath: &Path) -> u64 {
@@ -20,8 +30,8 @@ pub type TestResult<T> = std::result::Result<T, Box<dyn Error>>;
  * Returns None if there is no filename or if url.path_segments() fails
  */
 pub fn parse_filename(url: &Url) -> Option<&str> {
-    let segments = url.path_segments()?;
-    let filename = segments.last()?;
+    let mut segments = url.path_segments()?;
+    let filename = segments.next_back()?;
     if filename.is_empty() {
         None
     } else {
@@ -29,6 +39,58 @@ pub fn parse_filename(url: &Url) -> Option<&str> {
     }
 }
 
+/// Canonical extension of a mime type, e.g. `jpg` for `image/jpeg`. Parameters like `charset` are
+/// ignored, generic types like `application/octet-stream` have no extension.
+pub fn mime_extension(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+    let extension = match mime.as_str() {
+        "text/html" => "html",
+        "text/plain" => "txt",
+        "text/css" => "css",
+        "text/csv" => "csv",
+        "text/markdown" => "md",
+        "text/javascript" | "application/javascript" => "js",
+        "application/json" => "json",
+        "text/xml" | "application/xml" => "xml",
+        "application/pdf" => "pdf",
+        "application/zip" => "zip",
+        "application/gzip" | "application/x-gzip" => "gz",
+        "application/x-tar" => "tar",
+        "application/x-bzip2" => "bz2",
+        "application/x-xz" => "xz",
+        "application/x-7z-compressed" => "7z",
+        "application/vnd.rar" | "application/x-rar-compressed" => "rar",
+        "application/x-iso9660-image" => "iso",
+        "application/vnd.debian.binary-package" => "deb",
+        "application/x-msdownload" => "exe",
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/svg+xml" => "svg",
+        "audio/mpeg" => "mp3",
+        "audio/ogg" => "ogg",
+        "audio/flac" => "flac",
+        "video/mp4" => "mp4",
+        "video/webm" => "webm",
+        "video/x-matroska" => "mkv",
+        _ => return None,
+    };
+    Some(extension)
+}
+
+/// Appends the extension of `content_type` to a filename without extension, filenames that
+/// already have one (even a different one) are returned as they are.
+pub fn with_inferred_extension(filename: &str, content_type: Option<&str>) -> String {
+    let extension = content_type.and_then(mime_extension);
+    match extension {
+        Some(extension) if Path::new(filename).extension().is_none() => {
+            format!("{}.{}", filename, extension)
+        }
+        _ => filename.to_owned(),
+    }
+}
+
 pub fn kb(bytes: u64) -> f64 {
     bytes as f64 / 1024.0
 }
@@ -43,6 +105,18 @@ pub fn gb(bytes: u64) -> f64 {
 /**
  * Given a HeaderMap checks if the server that sent the headers supports byte ranges
  */
+/// Value of the `Content-Length` header, unlike `Response::content_length` this is also set for
+/// responses to HEAD requests.
+pub fn header_content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
 pub fn supports_byte_ranges(headers: &HeaderMap) -> bool {
     if let Some(val) = headers.get(header::ACCEPT_RANGES) {
         val == "bytes"
@@ -51,11 +125,32 @@ pub fn supports_byte_ranges(headers: &HeaderMap) -> bool {
     }
 }
 
+/**
+ * Parses a `Content-Range` header value like `bytes 0-499/1234`, the length may be unknown
+ * Returns the range and the complete length if known, None for unsatisfied or malformed values
+ */
+pub fn parse_content_range(value: &str) -> Option<(ByteRange, Option<u64>)> {
+    let (unit, rest) = value.trim().split_once(' ')?;
+    if !unit.eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let (range, total) = rest.trim().split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let range = ByteRange::new(start.trim().parse().ok()?, end.trim().parse().ok()?);
+    if range.is_empty() {
+        return None;
+    }
+    let total = match total.trim() {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+    Some((range, total))
+}
+
 #[cfg(test)]
-pub async fn setup_test_download(url_str: &str) -> anyhow::Result<(HttpDownload, TempDir)> {
+pub async fn setup_test_download(url: Url) -> anyhow::Result<(HttpDownload, TempDir)> {
     let tmp_dir = TempDir::new()?;
     let tmp_path = tmp_dir.path().to_owned();
-    let url = Url::parse(url_str)?;
     let filename = parse_filename(&url).unwrap().to_string();
     let client = Client::new();
     let download = HttpDownload::create(url, tmp_path, filename, client, None).await?;
@@ -95,6 +190,42 @@ mod test {
         );
     }
 
+    #[test]
+    fn parse_content_range_test() {
+        assert_eq!(
+            parse_content_range("bytes 0-499/1234"),
+            Some((ByteRange::new(0, 499), Some(1234)))
+        );
+        assert_eq!(
+            parse_content_range("bytes 500-999/*"),
+            Some((ByteRange::new(500, 999), None))
+        );
+        assert_eq!(parse_content_range("bytes */1234"), None);
+        assert_eq!(parse_content_range("items 0-1/2"), None);
+        assert_eq!(parse_content_range("bytes 10-1/20"), None);
+    }
+
+    #[test]
+    fn with_inferred_extension_test() {
+        assert_eq!(
+            with_inferred_extension("report", Some("application/pdf")),
+            "report.pdf"
+        );
+        assert_eq!(
+            with_inferred_extension("photo", Some("IMAGE/JPEG; charset=binary")),
+            "photo.jpg"
+        );
+        assert_eq!(
+            with_inferred_extension("data.csv", Some("text/plain")),
+            "data.csv"
+        );
+        assert_eq!(
+            with_inferred_extension("blob", Some("application/octet-stream")),
+            "blob"
+        );
+        assert_eq!(with_inferred_extension("blob", None), "blob");
+    }
+
     #[test]
     fn parse_filename_test() -> Result<(), Box<dyn Error>> {
         // Result<(), Box<dyn Error>> success
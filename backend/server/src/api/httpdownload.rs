@@ -0,0 +1,1498 @@
+use axum::{
+    body::StreamBody,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Json, Router,
+};
+use bytes::Bytes;
+use downloader::{
+    downloadable::Downloadable,
+    ftpdownload::{self, FtpDownload},
+    httpdownload::{
+        download::{
+            self, checksum::Checksum, config::HttpDownloadConfig, connectivity::test_connection,
+            retry::RetryPolicy, tee::MirrorFailure, ByteRange, HttpDownload,
+        },
+        manager::{self, breaker::HostCircuit, page::Cursor},
+        DownloadMetadata,
+    },
+    util::parse_filename,
+};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use uuid::Uuid;
+
+use super::{error_code, json_error, manager_error, AppState};
+use crate::logs;
+use crate::settings::{ensure_dir, parse_headers, DownloadPreset};
+
+/// Fallback for urls that don't end with a filename
+const DEFAULT_FILENAME: &str = "download";
+/// Size of the reads streaming the content of a download
+const CONTENT_CHUNK_SIZE: usize = 64 * 1024;
+/// Downloads per page of the metadata listing if a cursor but no limit is given
+const DEFAULT_PAGE_LIMIT: usize = 100;
+/// Response header of a metadata page carrying the cursor of the next page
+const NEXT_CURSOR_HEADER: &str = "x-next-cursor";
+/// Request header making download creation idempotent
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// Response header set when a create request was answered with an existing download
+const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/", post(create_download))
+        .route("/splice", post(splice_download))
+        .route("/import", post(import_downloads))
+        .route("/test", post(test_download))
+        .route("/delete", post(delete_downloads))
+        .route("/metadata", get(get_metadata_all))
+        .route("/state", get(get_state_all))
+        .route("/active", get(get_active))
+        .route("/stats", get(get_stats))
+        .route("/start_all", get(start_all))
+        .route("/stop_all", get(stop_all))
+        .route("/start_host", post(start_host))
+        .route("/stop_host", post(stop_host))
+        .route("/presets", get(get_presets))
+        .route("/presets/:name", put(set_preset).delete(delete_preset))
+        .route("/queue", get(get_queue))
+        .route("/queue/reorder", post(reorder_queue))
+        .route("/maintenance/reconcile", post(reconcile))
+        .route(
+            "/allow_downloads",
+            get(get_allow_downloads).post(set_allow_downloads),
+        )
+        .route("/:id", get(get_download).delete(delete_download))
+        .route("/:id/summary", get(get_summary))
+        .route("/:id/start", get(start_download))
+        .route("/:id/resume", get(resume_download))
+        .route("/:id/stop", get(stop_download))
+        .route("/:id/refresh", post(refresh_download))
+        .route("/:id/verify", post(verify_download))
+        .route("/:id/events", get(get_events))
+        .route("/:id/logs", get(get_logs))
+        .route("/:id/diagnostics", get(get_diagnostics))
+        .route("/:id/content", get(get_content))
+        .route("/:id/retry_policy", post(set_retry_policy))
+        .route("/:id/segments", post(set_segments))
+        .route("/:id/ignore_global_limit", post(set_ignore_global_limit))
+        .route("/:id/rate_limit", post(set_rate_limit))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownloadData {
+    pub metadata: DownloadMetadata,
+    pub state: download::State,
+    /// Where the finished download is on disk, only set once it's finished
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub final_path: Option<PathBuf>,
+}
+
+/// Metadata of a download after probing it again.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshedMetadata {
+    /// Fields that changed, e.g. `content_length`, empty if nothing did
+    pub changed: Vec<String>,
+    pub metadata: DownloadMetadata,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Stats {
+    /// Hosts that failed recently and the state of their circuit breaker
+    pub circuits: Vec<HostCircuit>,
+}
+
+/// Progress of a running or queued download, just what a progress display needs.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ActiveDownload {
+    pub id: Uuid,
+    /// Waiting for a free slot instead of running
+    pub queued: bool,
+    pub bytes_downloaded: u64,
+    pub download_size: u64,
+    pub bytes_per_second: u64,
+    /// Exempt from the bandwidth limit
+    pub ignore_global_limit: bool,
+}
+
+/// Flat view of a download with everything a row in a table of downloads shows.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownloadSummary {
+    pub id: Uuid,
+    pub filename: String,
+    pub url: String,
+    /// Name of the state, e.g. `Running` or `Complete`
+    pub state: String,
+    /// Message of the `Error` state
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub bytes_downloaded: u64,
+    /// None until the server was probed
+    pub download_size: Option<u64>,
+    /// 0 to 100, None while the size is unknown
+    pub percent: Option<f64>,
+    pub bytes_per_second: u64,
+    /// Seconds left at the current speed, only known while running
+    pub eta_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub final_path: Option<PathBuf>,
+    /// Exempt from the bandwidth limit
+    #[serde(default)]
+    pub ignore_global_limit: bool,
+}
+
+impl DownloadSummary {
+    pub fn new(
+        metadata: DownloadMetadata,
+        download_state: &download::State,
+        final_path: Option<PathBuf>,
+    ) -> Self {
+        let size = metadata.download_size;
+        let (bytes_downloaded, bytes_per_second) = match download_state {
+            download::State::Running {
+                bytes_downloaded,
+                bytes_per_second,
+                ..
+            } => (*bytes_downloaded, *bytes_per_second),
+            download::State::Partial(bytes) | download::State::PausedByUser(bytes) => (*bytes, 0),
+            download::State::PausedBySystem {
+                bytes_downloaded, ..
+            }
+            | download::State::Retrying {
+                bytes_downloaded, ..
+            }
+            | download::State::SourceChanged { bytes_downloaded } => (*bytes_downloaded, 0),
+            download::State::Complete
+            | download::State::Verifying { .. }
+            | download::State::Moving { .. } => (size.unwrap_or_default(), 0),
+            download::State::Created
+            | download::State::Error(_)
+            | download::State::ChecksumFailed { .. }
+            | download::State::Missing => (0, 0),
+        };
+        let percent = size.map(|size| match size {
+            0 => 100.0,
+            size => (bytes_downloaded as f64 * 100.0 / size as f64).min(100.0),
+        });
+        let eta_secs = match (size, bytes_per_second) {
+            (Some(size), speed) if speed > 0 => Some(size.saturating_sub(bytes_downloaded) / speed),
+            _ => None,
+        };
+        let error = match download_state {
+            download::State::Error(message) => Some(message.clone()),
+            _ => None,
+        };
+        DownloadSummary {
+            id: metadata.id,
+            filename: metadata
+                .file_path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            url: metadata.url,
+            ignore_global_limit: metadata.ignore_global_limit,
+            state: download_state.name().to_string(),
+            error,
+            bytes_downloaded,
+            download_size: size,
+            percent,
+            bytes_per_second,
+            eta_secs,
+            final_path,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateParams {
+    /// Only download the first `max_bytes` bytes, the download ends as `Partial`
+    pub max_bytes: Option<u64>,
+    /// Pause the download once it wrote `pause_at` bytes, it stays resumable
+    pub pause_at: Option<u64>,
+    /// Accept compressed responses, byte counts are then the decompressed size and the download
+    /// can't be resumed
+    #[serde(default)]
+    pub compression: bool,
+    /// Comma separated inclusive ranges (`0-99,4096-8191`), only these are downloaded into a
+    /// sparse file
+    pub ranges: Option<String>,
+    /// Comma separated paths of further files the download is written to
+    pub mirrors: Option<String>,
+    /// Whether a mirror that can't be written fails the download (the default) or is dropped
+    #[serde(default)]
+    pub mirror_failure: MirrorFailure,
+    /// Create the download without contacting the server, it's probed when first started
+    #[serde(default)]
+    pub lazy: bool,
+    /// `Accept` header of the probe and the download requests, e.g. `application/octet-stream` to
+    /// get the raw file from a content negotiated endpoint. `*/*` if not given.
+    pub accept: Option<String>,
+    /// `sha256:<hex>` or `md5:<hex>` the finished download has to match
+    pub checksum: Option<String>,
+    /// Seconds from now the download has to be finished in, it fails once they passed
+    pub deadline_secs: Option<u64>,
+    /// Seconds between checks whether the resource changed on the server, off if not given
+    pub revalidate_secs: Option<u64>,
+    /// Overrides the `keep_partial_on_failure` setting for this download
+    pub keep_partial_on_failure: Option<bool>,
+    /// Overrides the `default_segments` setting for this download
+    pub segments: Option<usize>,
+    /// Download at full speed regardless of the bandwidth limit
+    #[serde(default)]
+    pub ignore_global_limit: bool,
+    /// Bytes per second this download alone is limited to, on top of the bandwidth limit
+    pub rate_limit: Option<u64>,
+    /// Start the download right away instead of adding it paused
+    #[serde(default)]
+    pub start: bool,
+    /// Override the `retry_policy` setting for this download, each field on its own
+    pub retry_max_attempts: Option<u32>,
+    pub retry_base_delay_ms: Option<u64>,
+    pub retry_max_delay_ms: Option<u64>,
+    pub retry_jitter_percent: Option<u32>,
+    /// Override the `request_retries` setting for this download
+    pub request_retries: Option<u32>,
+    /// Override the `idle_connection_timeout_secs` setting for this download
+    pub idle_timeout_secs: Option<u64>,
+    /// `http://` or `https://` proxy the requests of this download go through, SOCKS isn't
+    /// supported yet
+    pub proxy: Option<String>,
+    /// Name of a preset (see `/presets`) whose options apply unless given here
+    pub preset: Option<String>,
+}
+
+/// JSON body of a create request, the body can also be just the url.
+#[derive(Debug, Deserialize)]
+pub struct CreateBody {
+    pub url: String,
+    /// Sent with every request of the download, e.g. `Authorization`, in addition to (and
+    /// replacing) the headers of the preset
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Parses `start-end` pairs separated by commas.
+fn parse_ranges(value: &str) -> Option<Vec<ByteRange>> {
+    value
+        .split(',')
+        .map(|range| {
+            let (start, end) = range.trim().split_once('-')?;
+            Some(ByteRange::new(
+                start.trim().parse().ok()?,
+                end.trim().parse().ok()?,
+            ))
+        })
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TestParams {
+    /// Like the `preset` of a create request
+    pub preset: Option<String>,
+    /// Like the `accept` of a create request
+    pub accept: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SpliceParams {
+    /// Existing file to continue, it's written in place
+    pub path: PathBuf,
+    /// Byte of the file the download continues at, the file is cut to it
+    pub offset: u64,
+    /// Resume the download right away
+    #[serde(default)]
+    pub start: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportParams {
+    /// Start every download that was added
+    #[serde(default)]
+    pub start: bool,
+}
+
+/// Outcome of one url of a bulk import, either the added download or why it wasn't added.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportResult {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<DownloadMetadata>,
+    /// Stable error code like the `code` of error responses
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ImportResult {
+    fn failed(url: String, code: &str, error: impl std::fmt::Display) -> Self {
+        Self {
+            url,
+            metadata: None,
+            code: Some(code.to_owned()),
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PageParams {
+    /// `X-Next-Cursor` of the previous page, the listing starts at the first download without it
+    pub cursor: Option<String>,
+    /// Maximum number of downloads on the page
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteParams {
+    #[serde(default, alias = "delete_files")]
+    pub delete_file: bool,
+}
+
+/// Outcome of one id of a bulk delete.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteResult {
+    pub id: Uuid,
+    pub deleted: bool,
+    /// Stable error code like the `code` of error responses
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HostParams {
+    pub host: String,
+}
+
+/// Whether the manager may start downloads on its own, e.g. resume the ones the system paused.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AllowDownloads {
+    pub allowed: bool,
+}
+
+/// Creates a download of the url in the body, either plain or as a `CreateBody` with the headers
+/// to send. With an `Idempotency-Key` header a retried request gets the download the
+/// first request with that key created (which isn't started again) instead of a duplicate.
+async fn create_download(
+    State(state): State<AppState>,
+    Query(params): Query<CreateParams>,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    let idempotency_key = headers
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    if let Some(id) = idempotency_key
+        .as_deref()
+        .and_then(|key| state.manager.added_with_key(key))
+    {
+        return replayed(&state, &id).await;
+    }
+    let body = match body.trim_start().starts_with('{') {
+        true => match serde_json::from_str(&body) {
+            Ok(body) => body,
+            Err(e) => {
+                return json_error(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_body",
+                    format!("Invalid body: {}", e),
+                )
+            }
+        },
+        false => CreateBody {
+            url: body,
+            headers: BTreeMap::new(),
+        },
+    };
+    let url = match Url::parse(body.url.trim()) {
+        Ok(url) => url,
+        Err(e) => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "invalid_url",
+                format!("Invalid URL: {}", e),
+            )
+        }
+    };
+    let (directory, mut config, create_dirs) =
+        match configured(&state, params.preset.as_deref()).await {
+            Ok(configured) => configured,
+            Err(response) => return response,
+        };
+    match parse_headers(&body.headers) {
+        Ok(headers) => config.headers.extend(headers),
+        Err(e) => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "invalid_header",
+                format!("{:#}", e),
+            )
+        }
+    }
+    // The directory might have been removed since startup
+    if let Err(e) = ensure_dir(&directory, create_dirs).await {
+        return json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "download_dir_unusable",
+            format!("Download directory can't be used: {:#}", e),
+        );
+    }
+    if url.scheme() == "ftp" {
+        return create_ftp_download(&state, url, directory, idempotency_key, params.start).await;
+    }
+    config.max_bytes = params.max_bytes;
+    config.pause_at = params.pause_at;
+    config.compression = params.compression;
+    if let Some(keep) = params.keep_partial_on_failure {
+        config.keep_partial_on_failure = keep;
+    }
+    let mut retry_policy = config.retry_policy.get();
+    if let Some(max_attempts) = params.retry_max_attempts {
+        retry_policy.max_attempts = max_attempts;
+    }
+    if let Some(base_delay_ms) = params.retry_base_delay_ms {
+        retry_policy.base_delay_ms = base_delay_ms;
+    }
+    if let Some(max_delay_ms) = params.retry_max_delay_ms {
+        retry_policy.max_delay_ms = max_delay_ms;
+    }
+    if let Some(jitter_percent) = params.retry_jitter_percent {
+        retry_policy.jitter_percent = jitter_percent;
+    }
+    config.retry_policy.set(retry_policy);
+    if let Some(retries) = params.request_retries {
+        config.request_retries = retries;
+    }
+    config.idle_timeout = params.idle_timeout_secs.map(Duration::from_secs);
+    if let Some(proxy) = &params.proxy {
+        match Url::parse(proxy) {
+            Ok(url) => config.proxy = Some(url),
+            Err(e) => {
+                return json_error(
+                    StatusCode::BAD_REQUEST,
+                    "proxy_error",
+                    // The value may hold credentials, it's not echoed back
+                    format!("Invalid proxy url: {}", e),
+                );
+            }
+        }
+    }
+    config.ignore_global_limit.set(params.ignore_global_limit);
+    if let Some(rate_limit) = params.rate_limit {
+        config.rate_limit.set_rate(Some(rate_limit));
+    }
+    if let Some(segments) = params.segments {
+        config.segments = segments.max(1);
+    }
+    config.deadline = params
+        .deadline_secs
+        .map(|secs| SystemTime::now() + Duration::from_secs(secs));
+    config.revalidate_interval = params
+        .revalidate_secs
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs);
+    if let Some(accept) = &params.accept {
+        match header::HeaderValue::from_str(accept) {
+            Ok(value) => {
+                config.headers.insert(header::ACCEPT, value);
+            }
+            Err(e) => {
+                return json_error(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_accept",
+                    format!("Invalid Accept header '{}': {}", accept, e),
+                )
+            }
+        }
+    }
+    if let Some(checksum) = &params.checksum {
+        match checksum.parse() {
+            Ok(checksum) => config.checksum = Some(checksum),
+            Err(e) => {
+                return json_error(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_checksum",
+                    format!("Invalid checksum '{}': {}", checksum, e),
+                )
+            }
+        }
+    }
+    if let Some(ranges) = &params.ranges {
+        match parse_ranges(ranges) {
+            Some(ranges) => config.ranges = Some(ranges),
+            None => {
+                return json_error(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_ranges",
+                    format!("Invalid ranges '{}', expected e.g. 0-99,200-299", ranges),
+                )
+            }
+        }
+    }
+    if let Some(mirrors) = &params.mirrors {
+        config.mirrors = mirrors
+            .split(',')
+            .map(|path| PathBuf::from(path.trim()))
+            .collect();
+        config.mirror_failure = params.mirror_failure;
+    }
+    let filename = parse_filename(&url).unwrap_or(DEFAULT_FILENAME).to_owned();
+    let download = match HttpDownload::builder()
+        .url(url)
+        .directory(directory)
+        .filename(filename)
+        .client(state.client.clone())
+        .client_config(state.client_config.clone())
+        .config(config)
+        .lazy(params.lazy)
+        .build()
+        .await
+    {
+        Ok(download) => download,
+        Err(e) => {
+            let status = match e {
+                download::Error::InvalidConfig(_)
+                | download::Error::LoginRedirect(_)
+                | download::Error::ProxyError(..) => StatusCode::BAD_REQUEST,
+                download::Error::ForbiddenFileType(..) => StatusCode::FORBIDDEN,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            return json_error(status, e.code(), format!("Error creating download: {}", e));
+        }
+    };
+    add_download(&state, download, idempotency_key, params.start).await
+}
+
+/// Creates a download of an `ftp://` url, the options that only apply to http downloads are
+/// ignored. Credentials are taken from the url.
+async fn create_ftp_download(
+    state: &AppState,
+    url: Url,
+    directory: PathBuf,
+    idempotency_key: Option<String>,
+    start: bool,
+) -> Response {
+    match FtpDownload::create(url, directory, None).await {
+        Ok(download) => add_download(state, download, idempotency_key, start).await,
+        Err(ftpdownload::Error::InvalidUrl(e)) => json_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_url",
+            format!("Invalid URL: {}", e),
+        ),
+        Err(e) => {
+            let e = download::Error::from(e);
+            json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                e.code(),
+                format!("Error creating download: {}", e),
+            )
+        }
+    }
+}
+
+/// Adds a created download, once per idempotency key, and starts it if asked to. It's queued
+/// instead if `max_concurrent_downloads` downloads run already.
+async fn add_download(
+    state: &AppState,
+    download: impl Downloadable,
+    idempotency_key: Option<String>,
+    start: bool,
+) -> Response {
+    let metadata = download.metadata();
+    let added = match &idempotency_key {
+        Some(key) => state.manager.add_with_key(key, download).await,
+        None => state.manager.add(download).await.map(|id| (id, true)),
+    };
+    let id = match added {
+        Ok((id, true)) => id,
+        Ok((id, false)) => return replayed(state, &id).await,
+        Err(e) => return manager_error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    // Started like through /:id/start, the download stays added if that fails
+    if start {
+        if let Err(e) = state.manager.dispatch(&id).await {
+            return manager_error(StatusCode::BAD_REQUEST, e);
+        }
+    }
+    (StatusCode::CREATED, Json(metadata)).into_response()
+}
+
+/// Download directory, configuration and `create_dirs` setting of a new download according to
+/// the settings and the preset, fails if there's no preset of that name.
+async fn configured(
+    state: &AppState,
+    preset: Option<&str>,
+) -> Result<(PathBuf, HttpDownloadConfig, bool), Response> {
+    let settings = state.settings.read().await;
+    let mut directory = settings.default_download_dir.clone();
+    let mut config = settings.download_config();
+    if let Some(name) = preset {
+        let Some(preset) = settings.presets.get(name) else {
+            return Err(json_error(
+                StatusCode::BAD_REQUEST,
+                "unknown_preset",
+                format!("No preset named '{}'", name),
+            ));
+        };
+        // Validated when it was saved
+        if let Err(e) = preset.apply(&mut config) {
+            return Err(json_error(
+                StatusCode::BAD_REQUEST,
+                "invalid_preset",
+                format!("{:#}", e),
+            ));
+        }
+        if let Some(preset_directory) = &preset.directory {
+            directory = preset_directory.clone();
+        }
+    }
+    Ok((directory, config, settings.create_dirs))
+}
+
+/// Checks whether the url of the body can be downloaded, with the headers and credentials of the
+/// preset and settings a created download would get, see `test_connection`. Only the first byte
+/// is requested and nothing is added. A failed check is still a 200, its `ok` is false.
+async fn test_download(
+    State(state): State<AppState>,
+    Query(params): Query<TestParams>,
+    body: String,
+) -> Response {
+    let url = match Url::parse(body.trim()) {
+        Ok(url) => url,
+        Err(e) => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "invalid_url",
+                format!("Invalid URL: {}", e),
+            )
+        }
+    };
+    let mut config = match configured(&state, params.preset.as_deref()).await {
+        Ok((_, config, _)) => config,
+        Err(response) => return response,
+    };
+    if let Some(accept) = &params.accept {
+        match header::HeaderValue::from_str(accept) {
+            Ok(value) => {
+                config.headers.insert(header::ACCEPT, value);
+            }
+            Err(e) => {
+                return json_error(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_accept",
+                    format!("Invalid Accept header '{}': {}", accept, e),
+                )
+            }
+        }
+    }
+    Json(test_connection(&url, &state.client, &config).await).into_response()
+}
+
+/// Adds a download for every url of the body (one per line, blank lines and lines starting with
+/// `#` are skipped) with the default settings. The urls are probed concurrently within the
+/// `probe_concurrency` settings, the response lists the outcome of every url in the order of the
+/// body once all are probed. A url that fails doesn't keep the others from being added.
+async fn import_downloads(
+    State(state): State<AppState>,
+    Query(params): Query<ImportParams>,
+    body: String,
+) -> Response {
+    let (directory, create_dirs) = {
+        let settings = state.settings.read().await;
+        (settings.default_download_dir.clone(), settings.create_dirs)
+    };
+    if let Err(e) = ensure_dir(&directory, create_dirs).await {
+        return json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "download_dir_unusable",
+            format!("Download directory can't be used: {:#}", e),
+        );
+    }
+    match import_all(&state, &directory, url_lines(&body), params.start).await {
+        Ok(results) => Json(results).into_response(),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, "internal", e),
+    }
+}
+
+/// Urls of a bulk import body, one per line, blank lines and lines starting with `#` are skipped.
+pub(crate) fn url_lines(body: &str) -> Vec<String> {
+    body.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Adds a download for every url to `directory`, see `import_downloads`. The results are in the
+/// order of the urls.
+pub(crate) async fn import_all(
+    state: &AppState,
+    directory: &std::path::Path,
+    urls: Vec<String>,
+    start: bool,
+) -> Result<Vec<ImportResult>, tokio::task::JoinError> {
+    let imports: Vec<_> = urls
+        .into_iter()
+        .map(|url| {
+            tokio::spawn(import_download(
+                state.clone(),
+                directory.to_owned(),
+                url,
+                start,
+            ))
+        })
+        .collect();
+    let mut results = Vec::with_capacity(imports.len());
+    for import in imports {
+        results.push(import.await?);
+    }
+    Ok(results)
+}
+
+async fn import_download(
+    state: AppState,
+    directory: PathBuf,
+    line: String,
+    start: bool,
+) -> ImportResult {
+    let url = match Url::parse(&line) {
+        Ok(url) => url,
+        Err(e) => return ImportResult::failed(line, "invalid_url", format!("Invalid URL: {}", e)),
+    };
+    let config = state.settings.read().await.download_config();
+    let filename = parse_filename(&url).unwrap_or(DEFAULT_FILENAME).to_owned();
+    let built = HttpDownload::builder()
+        .url(url)
+        .directory(directory)
+        .filename(filename)
+        .client(state.client.clone())
+        .client_config(state.client_config.clone())
+        .config(config)
+        .lazy(true)
+        .build()
+        .await;
+    let mut download = match built {
+        Ok(download) => download,
+        Err(e) => return ImportResult::failed(line, e.code(), e),
+    };
+    if let Err(e) = state.manager.probe(&mut download).await {
+        return ImportResult::failed(line, e.code(), format!("Error probing download: {}", e));
+    }
+    let metadata = download.get_metadata();
+    if let Err(e) = state.manager.add(download).await {
+        let code = error_code(&e).unwrap_or("internal");
+        return ImportResult::failed(line, code, e);
+    }
+    if start {
+        if let Err(e) = state.manager.dispatch(&metadata.id).await {
+            log::warn!("Couldn't start imported download {}: {}", metadata.id, e);
+        }
+    }
+    ImportResult {
+        url: line,
+        metadata: Some(metadata),
+        code: None,
+        error: None,
+    }
+}
+
+/// Answers a repeated create request with the download the first one created.
+async fn replayed(state: &AppState, id: &Uuid) -> Response {
+    match state.manager.get_metadata(id).await {
+        Ok(metadata) => (
+            StatusCode::CREATED,
+            [(IDEMPOTENT_REPLAYED_HEADER, "true")],
+            Json(metadata),
+        )
+            .into_response(),
+        Err(e) => manager_error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Lists downloads in the order they were added, all of them unless a page is asked for with a
+/// `cursor` or `limit`. The cursor of the next page is sent in the `X-Next-Cursor` header.
+async fn get_metadata_all(
+    State(state): State<AppState>,
+    Query(params): Query<PageParams>,
+) -> Response {
+    if params.cursor.is_none() && params.limit.is_none() {
+        return match state.manager.get_metadata_all().await {
+            Ok(metadata) => Json(metadata).into_response(),
+            Err(e) => manager_error(StatusCode::INTERNAL_SERVER_ERROR, e),
+        };
+    }
+    let cursor = match params.cursor.as_deref().map(str::parse::<Cursor>) {
+        Some(Err(_)) => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "invalid_cursor",
+                format!("Invalid cursor '{}'", params.cursor.unwrap_or_default()),
+            )
+        }
+        Some(Ok(cursor)) => Some(cursor),
+        None => None,
+    };
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_LIMIT).max(1);
+    match state.manager.get_metadata_page(cursor, limit).await {
+        Ok(page) => {
+            let mut headers = HeaderMap::new();
+            if let Some(next) = page.next_cursor {
+                headers.insert(NEXT_CURSOR_HEADER, next.to_string().parse().unwrap());
+            }
+            (headers, Json(page.items)).into_response()
+        }
+        Err(e) => manager_error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Corrects the states of stopped downloads whose files changed behind the manager's back, see
+/// `DownloadManager::reconcile`.
+async fn reconcile(State(state): State<AppState>) -> Response {
+    match state.manager.reconcile().await {
+        Ok(reconciliation) => Json(reconciliation).into_response(),
+        Err(e) => manager_error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Creates a download continuing an existing file at a given offset, see `HttpDownload::splice`.
+/// The path is taken as is, it's not checked against the download directory or other downloads.
+async fn splice_download(
+    State(state): State<AppState>,
+    Query(params): Query<SpliceParams>,
+    body: String,
+) -> Response {
+    let url = match Url::parse(body.trim()) {
+        Ok(url) => url,
+        Err(e) => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "invalid_url",
+                format!("Invalid URL: {}", e),
+            )
+        }
+    };
+    let config = state.settings.read().await.download_config();
+    let download = match HttpDownload::splice(
+        url,
+        &params.path,
+        params.offset,
+        state.client.clone(),
+        config,
+    )
+    .await
+    {
+        Ok(download) => download,
+        Err(e) => {
+            let status = match e {
+                download::Error::InvalidConfig(_) | download::Error::LoginRedirect(_) => {
+                    StatusCode::BAD_REQUEST
+                }
+                download::Error::ForbiddenFileType(..) => StatusCode::FORBIDDEN,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            return json_error(status, e.code(), format!("Error creating download: {}", e));
+        }
+    };
+    let metadata = download.get_metadata();
+    let id = match state.manager.add(download).await {
+        Ok(id) => id,
+        Err(e) => return manager_error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    // Starting would download from scratch
+    if params.start {
+        if let Err(e) = state.manager.resume(&id).await {
+            return manager_error(StatusCode::BAD_REQUEST, e);
+        }
+    }
+    (StatusCode::CREATED, Json(metadata)).into_response()
+}
+
+async fn get_state_all(State(state): State<AppState>) -> Json<Vec<(Uuid, download::State)>> {
+    Json(state.manager.observer.get_state_all().await)
+}
+
+async fn get_active(State(state): State<AppState>) -> Response {
+    let active: Vec<(Uuid, u64, u64, bool)> = state
+        .manager
+        .observer
+        .get_state_all()
+        .await
+        .into_iter()
+        .filter_map(|(id, download_state)| match download_state {
+            download::State::Running {
+                bytes_downloaded,
+                bytes_per_second,
+                ..
+            } => Some((id, bytes_downloaded, bytes_per_second, false)),
+            download::State::Retrying {
+                bytes_downloaded, ..
+            } => Some((id, bytes_downloaded, 0, false)),
+            download::State::Verifying { total, .. } | download::State::Moving { total, .. } => {
+                Some((id, total, 0, false))
+            }
+            download::State::PausedBySystem {
+                bytes_downloaded,
+                reason: download::PauseReason::QueueLimit,
+            } => Some((id, bytes_downloaded, 0, true)),
+            _ => None,
+        })
+        .collect();
+    let mut downloads = Vec::with_capacity(active.len());
+    for (id, bytes_downloaded, bytes_per_second, queued) in active {
+        match state.manager.get_metadata(&id).await {
+            Ok(metadata) => downloads.push(ActiveDownload {
+                id,
+                queued,
+                bytes_downloaded,
+                // Running downloads were probed
+                download_size: metadata.download_size.unwrap_or_default(),
+                bytes_per_second,
+                ignore_global_limit: metadata.ignore_global_limit,
+            }),
+            // Deleted in the meantime
+            Err(e) if matches!(e.downcast_ref(), Some(manager::Error::NotFound(_))) => continue,
+            Err(e) => return manager_error(StatusCode::INTERNAL_SERVER_ERROR, e),
+        }
+    }
+    Json(downloads).into_response()
+}
+
+async fn get_stats(State(state): State<AppState>) -> Json<Stats> {
+    Json(Stats {
+        circuits: state.manager.circuit_status(),
+    })
+}
+
+async fn get_download(State(state): State<AppState>, Path(id): Path<Uuid>) -> Response {
+    let metadata = match state.manager.get_metadata(&id).await {
+        Ok(metadata) => metadata,
+        Err(e) => return manager_error(StatusCode::NOT_FOUND, e),
+    };
+    let final_path = match state.manager.final_path(&id).await {
+        Ok(final_path) => final_path,
+        Err(e) => return manager_error(StatusCode::NOT_FOUND, e),
+    };
+    match state.manager.get_state(&id).await {
+        Some(download_state) => Json(DownloadData {
+            metadata,
+            state: download_state,
+            final_path,
+        })
+        .into_response(),
+        None => json_error(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("No state tracked for download {}", id),
+        ),
+    }
+}
+
+async fn get_summary(State(state): State<AppState>, Path(id): Path<Uuid>) -> Response {
+    let metadata = match state.manager.get_metadata(&id).await {
+        Ok(metadata) => metadata,
+        Err(e) => return manager_error(StatusCode::NOT_FOUND, e),
+    };
+    let final_path = match state.manager.final_path(&id).await {
+        Ok(final_path) => final_path,
+        Err(e) => return manager_error(StatusCode::NOT_FOUND, e),
+    };
+    match state.manager.get_state(&id).await {
+        Some(download_state) => {
+            Json(DownloadSummary::new(metadata, &download_state, final_path)).into_response()
+        }
+        None => json_error(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("No state tracked for download {}", id),
+        ),
+    }
+}
+
+async fn refresh_download(State(state): State<AppState>, Path(id): Path<Uuid>) -> Response {
+    let changed = match state.manager.refresh_metadata(&id).await {
+        Ok(changed) => changed,
+        Err(e) => return manager_error(StatusCode::BAD_REQUEST, e),
+    };
+    match state.manager.get_metadata(&id).await {
+        Ok(metadata) => Json(RefreshedMetadata {
+            changed: changed.into_iter().map(str::to_owned).collect(),
+            metadata,
+        })
+        .into_response(),
+        Err(e) => manager_error(StatusCode::NOT_FOUND, e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyParams {
+    /// Expected checksum, `<algorithm>:<hex digest>`
+    pub checksum: String,
+}
+
+/// Outcome of verifying a complete download against a checksum.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Verification {
+    pub matches: bool,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// Hashes the file of a complete download against a checksum that became known afterwards. A
+/// mismatch turns the download `ChecksumFailed`, its file is kept.
+async fn verify_download(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Query(params): Query<VerifyParams>,
+) -> Response {
+    let expected: Checksum = match params.checksum.parse() {
+        Ok(checksum) => checksum,
+        Err(e) => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "invalid_checksum",
+                format!("Invalid checksum '{}': {}", params.checksum, e),
+            )
+        }
+    };
+    match state.manager.verify(&id, expected).await {
+        Ok(actual) => Json(Verification {
+            matches: actual == expected,
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+        })
+        .into_response(),
+        Err(e) => manager_error(StatusCode::NOT_FOUND, e),
+    }
+}
+
+/// Replaces the retry policy of the download, omitted fields take their defaults. A running
+/// download uses it from its next retry on.
+async fn set_retry_policy(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(policy): Json<RetryPolicy>,
+) -> Response {
+    if let Err(e) = state.manager.set_retry_policy(&id, policy).await {
+        return manager_error(StatusCode::NOT_FOUND, e);
+    }
+    match state.manager.get_metadata(&id).await {
+        Ok(metadata) => Json(metadata).into_response(),
+        Err(e) => manager_error(StatusCode::NOT_FOUND, e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SegmentsParams {
+    pub segments: usize,
+}
+
+/// Changes the connections of a segmented download, a running download opens more or lets the
+/// extra ones finish the range they are on.
+async fn set_segments(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Query(params): Query<SegmentsParams>,
+) -> Response {
+    if let Err(e) = state.manager.set_segments(&id, params.segments).await {
+        let status = match e.downcast_ref::<manager::Error>() {
+            Some(manager::Error::NotFound(_)) => StatusCode::NOT_FOUND,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        return manager_error(status, e);
+    }
+    match state.manager.get_metadata(&id).await {
+        Ok(metadata) => Json(metadata).into_response(),
+        Err(e) => manager_error(StatusCode::NOT_FOUND, e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IgnoreGlobalLimit {
+    pub ignore: bool,
+}
+
+/// Exempts the download from the bandwidth limit or subjects it to the limit again, a running
+/// download follows right away.
+async fn set_ignore_global_limit(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Query(params): Query<IgnoreGlobalLimit>,
+) -> Response {
+    if let Err(e) = state
+        .manager
+        .set_ignore_global_limit(&id, params.ignore)
+        .await
+    {
+        return manager_error(StatusCode::NOT_FOUND, e);
+    }
+    match state.manager.get_metadata(&id).await {
+        Ok(metadata) => Json(metadata).into_response(),
+        Err(e) => manager_error(StatusCode::NOT_FOUND, e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RateLimitParams {
+    /// Unlimited if not given
+    pub bytes_per_second: Option<u64>,
+}
+
+/// Caps the speed of the download alone or lifts the cap, a running download follows right away.
+async fn set_rate_limit(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Query(params): Query<RateLimitParams>,
+) -> Response {
+    if let Err(e) = state
+        .manager
+        .set_rate_limit(&id, params.bytes_per_second)
+        .await
+    {
+        return manager_error(StatusCode::NOT_FOUND, e);
+    }
+    match state.manager.get_metadata(&id).await {
+        Ok(metadata) => Json(metadata).into_response(),
+        Err(e) => manager_error(StatusCode::NOT_FOUND, e),
+    }
+}
+
+async fn get_diagnostics(State(state): State<AppState>, Path(id): Path<Uuid>) -> Response {
+    match state.manager.diagnostics(&id).await {
+        Ok(diagnostics) => Json(diagnostics).into_response(),
+        Err(e) => manager_error(StatusCode::NOT_FOUND, e),
+    }
+}
+
+/// Resolves a single range `Range` header (`bytes=0-99`, `bytes=100-`, `bytes=-100`) against the
+/// `available` bytes, an end past them is cut to the last available byte. Ok(None) if the header
+/// is to be ignored because it's malformed or asks for several ranges, Err if no requested byte is
+/// available.
+fn resolve_range(value: &str, available: u64) -> Result<Option<ByteRange>, ()> {
+    let Some((start, end)) = value
+        .trim()
+        .strip_prefix("bytes=")
+        .filter(|spec| !spec.contains(','))
+        .and_then(|spec| spec.split_once('-'))
+    else {
+        return Ok(None);
+    };
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", "") => return Ok(None),
+        // The last `suffix` bytes
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return Err(()),
+            Ok(suffix) => (available.saturating_sub(suffix), u64::MAX),
+            Err(_) => return Ok(None),
+        },
+        (start, "") => match start.parse() {
+            Ok(start) => (start, u64::MAX),
+            Err(_) => return Ok(None),
+        },
+        (start, end) => match (start.parse(), end.parse()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end),
+            _ => return Ok(None),
+        },
+    };
+    if start >= available {
+        return Err(());
+    }
+    Ok(Some(ByteRange::new(start, end.min(available - 1))))
+}
+
+/// Streams the bytes of the download that are on disk, also while it's still running. Only the
+/// bytes written when the request arrived are sent, a single range can be requested.
+async fn get_content(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+) -> Response {
+    let (path, available) = match state.manager.readable_prefix(&id).await {
+        Ok(Some(prefix)) => prefix,
+        Ok(None) => {
+            return json_error(
+                StatusCode::CONFLICT,
+                "content_unavailable",
+                "Split and sparse downloads don't have a contiguous file to read",
+            )
+        }
+        Err(e) => return manager_error(StatusCode::NOT_FOUND, e),
+    };
+    let requested = headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| resolve_range(value, available));
+    let range = match requested {
+        Some(Ok(range)) => range,
+        Some(Err(())) => {
+            return (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", available))],
+            )
+                .into_response()
+        }
+        None => None,
+    };
+    let (start, len) = range.map_or((0, available), |range| (range.start, range.len()));
+    let mut file = None;
+    if len > 0 {
+        let opened = match tokio::fs::File::open(&path).await {
+            Ok(mut opened) => opened.seek(SeekFrom::Start(start)).await.map(|_| opened),
+            Err(e) => Err(e),
+        };
+        match opened {
+            Ok(opened) => file = Some(opened),
+            Err(e) => {
+                return json_error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "content_unavailable",
+                    format!("Couldn't read {:?}: {}", path, e),
+                )
+            }
+        }
+    }
+    let body = StreamBody::new(async_stream::stream! {
+        let Some(mut file) = file else {
+            return;
+        };
+        let mut remaining = len;
+        let mut buf = vec![0u8; CONTENT_CHUNK_SIZE];
+        while remaining > 0 {
+            let to_read = (remaining as usize).min(buf.len());
+            match file.read(&mut buf[..to_read]).await {
+                // The file got shorter, e.g. because the download was restarted
+                Ok(0) => break,
+                Ok(read) => {
+                    remaining -= read as u64;
+                    yield Ok::<_, std::io::Error>(Bytes::copy_from_slice(&buf[..read]));
+                }
+                Err(e) => {
+                    yield Err(e);
+                    break;
+                }
+            }
+        }
+    });
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ACCEPT_RANGES, "bytes".parse().unwrap());
+    headers.insert(header::CONTENT_LENGTH, len.into());
+    headers.insert(
+        header::CONTENT_TYPE,
+        "application/octet-stream".parse().unwrap(),
+    );
+    match range {
+        Some(range) => {
+            headers.insert(
+                header::CONTENT_RANGE,
+                format!("bytes {}/{}", range, available).parse().unwrap(),
+            );
+            (StatusCode::PARTIAL_CONTENT, headers, body).into_response()
+        }
+        None => (StatusCode::OK, headers, body).into_response(),
+    }
+}
+
+async fn get_events(State(state): State<AppState>, Path(id): Path<Uuid>) -> Response {
+    match state.manager.events(&id).await {
+        Ok(events) => Json(events).into_response(),
+        Err(e) => manager_error(StatusCode::NOT_FOUND, e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogParams {
+    /// Only the most recent lines
+    pub limit: Option<usize>,
+}
+
+/// Recent log lines the download's task logged, oldest first, see `logs::LogCapture`.
+async fn get_logs(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Query(params): Query<LogParams>,
+) -> Response {
+    if let Err(e) = state.manager.get_metadata(&id).await {
+        return manager_error(StatusCode::NOT_FOUND, e);
+    }
+    Json(logs::capture().lines(&id, params.limit)).into_response()
+}
+
+async fn start_download(State(state): State<AppState>, Path(id): Path<Uuid>) -> Response {
+    match state.manager.start(&id).await {
+        Ok(_) => StatusCode::OK.into_response(),
+        Err(e) => manager_error(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+async fn resume_download(State(state): State<AppState>, Path(id): Path<Uuid>) -> Response {
+    match state.manager.resume(&id).await {
+        Ok(_) => StatusCode::OK.into_response(),
+        Err(e) => manager_error(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+async fn stop_download(State(state): State<AppState>, Path(id): Path<Uuid>) -> Response {
+    match state.manager.stop(&id).await {
+        Ok(_) => StatusCode::OK.into_response(),
+        Err(e) => manager_error(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+async fn delete_download(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Query(params): Query<DeleteParams>,
+) -> Response {
+    match state.manager.delete(&id, params.delete_file).await {
+        Ok(_) => {
+            logs::capture().forget(&id);
+            StatusCode::OK.into_response()
+        }
+        Err(e) => manager_error(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+/// Presets by name.
+async fn get_presets(State(state): State<AppState>) -> Response {
+    Json(state.settings.read().await.presets.clone()).into_response()
+}
+
+/// Creates or replaces the preset `name` and saves it to the settings file. 201 if it's new.
+async fn set_preset(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(preset): Json<DownloadPreset>,
+) -> Response {
+    if let Err(e) = preset.apply(&mut HttpDownloadConfig::default()) {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_preset",
+            format!("Invalid preset '{}': {:#}", name, e),
+        );
+    }
+    let mut settings = state.settings.read().await.clone();
+    let status = match settings.presets.insert(name, preset.clone()) {
+        Some(_) => StatusCode::OK,
+        None => StatusCode::CREATED,
+    };
+    state.settings.write(settings).await;
+    (status, Json(preset)).into_response()
+}
+
+async fn delete_preset(State(state): State<AppState>, Path(name): Path<String>) -> Response {
+    let mut settings = state.settings.read().await.clone();
+    if settings.presets.remove(&name).is_none() {
+        return json_error(
+            StatusCode::NOT_FOUND,
+            "unknown_preset",
+            format!("No preset named '{}'", name),
+        );
+    }
+    state.settings.write(settings).await;
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Deletes the downloads of a JSON array of ids like `DELETE /{id}`, running ones are stopped
+/// first. Every id gets a result in the order given, a failed id doesn't stop the others.
+async fn delete_downloads(
+    State(state): State<AppState>,
+    Query(params): Query<DeleteParams>,
+    Json(ids): Json<Vec<Uuid>>,
+) -> Response {
+    let results = state.manager.delete_many(&ids, params.delete_file).await;
+    let results: Vec<DeleteResult> = ids
+        .into_iter()
+        .zip(results)
+        .map(|(id, result)| match result {
+            Ok(()) => {
+                logs::capture().forget(&id);
+                DeleteResult {
+                    id,
+                    deleted: true,
+                    code: None,
+                    error: None,
+                }
+            }
+            Err(e) => DeleteResult {
+                id,
+                deleted: false,
+                code: Some(error_code(&e).unwrap_or("internal").to_owned()),
+                error: Some(e.to_string()),
+            },
+        })
+        .collect();
+    Json(results).into_response()
+}
+
+async fn start_all(State(state): State<AppState>) -> Response {
+    match state.manager.start_all().await {
+        Ok(_) => StatusCode::OK.into_response(),
+        Err(e) => manager_error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+async fn stop_all(State(state): State<AppState>) -> Response {
+    match state.manager.stop_all().await {
+        Ok(_) => StatusCode::OK.into_response(),
+        Err(e) => manager_error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+async fn start_host(State(state): State<AppState>, Query(params): Query<HostParams>) -> Response {
+    match state.manager.start_by_host(&params.host).await {
+        Ok(started) => Json(started).into_response(),
+        Err(e) => manager_error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+async fn get_queue(State(state): State<AppState>) -> Response {
+    match state.manager.queue().await {
+        Ok(queue) => Json(queue).into_response(),
+        Err(e) => manager_error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Takes the ids of queued downloads in the order they should be dispatched, returns the new
+/// queue.
+async fn reorder_queue(State(state): State<AppState>, Json(ids): Json<Vec<Uuid>>) -> Response {
+    match state.manager.reorder_queue(&ids).await {
+        Ok(queue) => Json(queue).into_response(),
+        Err(e) => manager_error(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+async fn get_allow_downloads(State(state): State<AppState>) -> Response {
+    Json(AllowDownloads {
+        allowed: state.manager.downloads_allowed().await,
+    })
+    .into_response()
+}
+
+/// Toggles automatic starts, e.g. from a network monitor. Allowing them resumes the downloads
+/// that were held back and returns their ids.
+async fn set_allow_downloads(
+    State(state): State<AppState>,
+    Query(params): Query<AllowDownloads>,
+) -> Response {
+    match state.manager.set_downloads_allowed(params.allowed).await {
+        Ok(resumed) => Json(resumed).into_response(),
+        Err(e) => manager_error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+async fn stop_host(State(state): State<AppState>, Query(params): Query<HostParams>) -> Response {
+    match state.manager.stop_by_host(&params.host).await {
+        Ok(stopped) => Json(stopped).into_response(),
+        Err(e) => manager_error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
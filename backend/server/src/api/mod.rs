@@ -0,0 +1,72 @@
+pub mod httpdownload;
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use downloader::httpdownload::{
+    client::ClientConfig,
+    download,
+    manager::{self, DownloadManager},
+};
+use serde_json::json;
+
+use crate::settings::SettingManager;
+
+/// Shared state of all API handlers, every member is cheap to clone.
+#[derive(Clone)]
+pub struct AppState {
+    pub manager: DownloadManager,
+    pub settings: SettingManager,
+    pub client: reqwest::Client,
+    /// Configuration `client` was built with, downloads with an idle timeout of their own get a
+    /// client derived from it
+    pub client_config: ClientConfig,
+}
+
+/// Error body of all endpoints, `code` is stable and meant for clients to branch on, `error` is a
+/// human readable message that can change.
+pub fn json_error(status: StatusCode, code: &str, error: impl std::fmt::Display) -> Response {
+    (
+        status,
+        Json(json!({ "code": code, "error": error.to_string() })),
+    )
+        .into_response()
+}
+
+/// Responds with `status` unless the manager couldn't be locked in time, that is reported as 503
+/// since the request can be retried later. The code is taken from the manager or download error
+/// if there is one.
+pub fn manager_error(status: StatusCode, error: anyhow::Error) -> Response {
+    let status = match error.downcast_ref::<manager::Error>() {
+        Some(manager::Error::LockTimeout(_)) => StatusCode::SERVICE_UNAVAILABLE,
+        Some(manager::Error::PathConflict { .. } | manager::Error::NotComplete(_)) => {
+            StatusCode::CONFLICT
+        }
+        Some(manager::Error::Unsupported(_)) => StatusCode::BAD_REQUEST,
+        _ => status,
+    };
+    let code = error_code(&error).unwrap_or_else(|| status_code(status));
+    json_error(status, code, error)
+}
+
+/// Code of the manager or download error, None for other errors.
+pub fn error_code(error: &anyhow::Error) -> Option<&'static str> {
+    if let Some(e) = error.downcast_ref::<manager::Error>() {
+        return Some(e.code());
+    }
+    error
+        .downcast_ref::<download::Error>()
+        .map(download::Error::code)
+}
+
+/// Code of errors that aren't more specific than their status.
+pub fn status_code(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::BAD_REQUEST => "bad_request",
+        StatusCode::NOT_FOUND => "not_found",
+        StatusCode::SERVICE_UNAVAILABLE => "unavailable",
+        _ => "internal",
+    }
+}
@@ -1,8 +1,213 @@
-mod settings;
+mod api;
+pub mod logs;
+mod restore;
+pub mod settings;
+mod watch;
 use std::net::TcpListener;
+use std::path::Path;
+use std::time::Duration;
+
+use axum::{http::StatusCode, http::Uri, response::Response, routing::any, Router};
+use downloader::httpdownload::client;
+use downloader::httpdownload::manager::{persist::StateFile, DownloadManager};
+use tower_http::services::{ServeDir, ServeFile};
+
+use crate::api::AppState;
+use crate::settings::SettingManager;
 
 pub async fn launch_app(listener: TcpListener) {
-    // let httpdownload_routes = routes().with_state(state);
-    // let app = Router::new().nest("/api/v1/httpdownload", httpdownload_routes);
-    todo!()
+    let settings = SettingManager::load(None)
+        .await
+        .expect("Couldn't load settings");
+    launch_app_with_settings(listener, settings).await
+}
+
+/// Same as `launch_app` with settings loaded by the caller, e.g. from another settings file.
+pub async fn launch_app_with_settings(listener: TcpListener, settings: SettingManager) {
+    let gate_settings = settings.clone();
+    let state_path = settings.state_file_path().await;
+    let (manager, client_config, static_dir, saved) = {
+        let settings = settings.read().await;
+        let mut manager = DownloadManager::new()
+            .await
+            .with_lock_timeout(Duration::from_secs(settings.lock_timeout_secs))
+            .with_download_dir(settings.default_download_dir.clone())
+            .with_circuit_breaker(settings.breaker_config())
+            .with_max_concurrent(settings.max_concurrent_downloads)
+            .with_segment_limit(settings.max_segment_connections)
+            .with_bandwidth_limit(settings.bandwidth_limit)
+            .with_history_limits(settings.history_limits())
+            .with_probe_limits(
+                settings.probe_concurrency,
+                settings.probe_concurrency_per_host,
+            )
+            .with_start_condition(move || downloads_allowed(gate_settings.clone()));
+        let state_file = StateFile {
+            path: state_path,
+            interval: settings.persist_interval(),
+            compress: settings.compress_state,
+        };
+        let saved = state_file.load().await;
+        manager = manager.with_state_file(state_file);
+        if let Some(secs) = settings.missing_file_check_secs {
+            manager = manager.with_missing_check(Duration::from_secs(secs));
+        }
+        if let Some(reserve) = settings.disk_reserve() {
+            manager = manager.with_disk_reserve(reserve);
+        }
+        if settings.idempotency_key_ttl_secs > 0 {
+            manager = manager
+                .with_idempotency_ttl(Duration::from_secs(settings.idempotency_key_ttl_secs));
+        }
+        if let Some(action) = settings.dedup {
+            manager = manager.with_dedup(action);
+        }
+        if settings.aggregate_interval_ms > 0 {
+            manager = manager
+                .with_aggregate_interval(Duration::from_millis(settings.aggregate_interval_ms));
+        }
+        let client_config = settings
+            .client_config()
+            .expect("Settings are validated on load");
+        (manager, client_config, settings.static_dir.clone(), saved)
+    };
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(settings.clone()));
+    let state = AppState {
+        manager,
+        settings,
+        client: client::build_client(&client_config).expect("Couldn't build http client"),
+        client_config,
+    };
+    restore::restore_downloads(&state, saved).await;
+    tokio::spawn(watch::DropFolder::default().watch(state.clone()));
+    let httpdownload_routes = api::httpdownload::routes().with_state(state);
+    let mut app = Router::new().nest("/api/v1/httpdownload", httpdownload_routes);
+    if let Some(dir) = static_dir {
+        if !dir.is_dir() {
+            log::warn!("Static directory {:?} doesn't exist", dir);
+        }
+        log::info!("Serving static files from {:?}", dir);
+        app = with_static_dir(app, &dir);
+    }
+    listener
+        .set_nonblocking(true)
+        .expect("Couldn't set listener to non-blocking mode");
+    log::info!("Serving API on {:?}", listener.local_addr());
+    axum::Server::from_tcp(listener)
+        .expect("Couldn't create server from listener")
+        .serve(app.into_make_service())
+        .await
+        .expect("Server crashed");
+}
+
+/// Serves the files of `dir` next to the API. Paths without a file get `dir/index.html` so a
+/// single page app can route on the client, except for unknown API paths which stay 404s.
+fn with_static_dir(app: Router, dir: &Path) -> Router {
+    let index = ServeFile::new(dir.join("index.html"));
+    app.route("/api/*path", any(api_not_found))
+        .fallback_service(ServeDir::new(dir).fallback(index))
+}
+
+async fn api_not_found(uri: Uri) -> Response {
+    api::json_error(
+        StatusCode::NOT_FOUND,
+        "not_found",
+        format!("No endpoint at {}", uri.path()),
+    )
+}
+
+/// Runs the `allow_downloads_command` setting, it's read every time so reloading the settings
+/// applies it. Without a command downloads are allowed.
+async fn downloads_allowed(settings: SettingManager) -> bool {
+    let Some(command) = settings.read().await.allow_downloads_command.clone() else {
+        return true;
+    };
+    match tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .status()
+        .await
+    {
+        Ok(status) => status.success(),
+        Err(e) => {
+            log::error!("Couldn't run allow_downloads_command {:?}: {}", command, e);
+            false
+        }
+    }
+}
+
+/// Reloads the settings from disk whenever the process receives SIGHUP (e.g. `systemctl reload`),
+/// running downloads aren't affected.
+#[cfg(unix)]
+async fn reload_on_hangup(settings: SettingManager) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            log::error!(
+                "Couldn't listen for SIGHUP, settings can't be reloaded: {}",
+                e
+            );
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        log::info!("Received SIGHUP, reloading settings");
+        match settings.reload().await {
+            Ok(restart_required) if restart_required.is_empty() => {
+                log::info!("Settings reloaded")
+            }
+            Ok(restart_required) => log::warn!(
+                "Settings reloaded, changes to {} take effect after a restart",
+                restart_required.join(", ")
+            ),
+            Err(e) => log::error!(
+                "Couldn't reload settings, keeping the current ones: {:#}",
+                e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::routing::get;
+    use test_log::test;
+
+    #[test(tokio::test)]
+    async fn static_files_are_served_next_to_the_api() {
+        // given
+        let dir = tempfile::tempdir().unwrap();
+        tokio::fs::write(dir.path().join("index.html"), "<html>app</html>")
+            .await
+            .unwrap();
+        tokio::fs::write(dir.path().join("app.js"), "console.log(1)")
+            .await
+            .unwrap();
+        let api = Router::new().route("/metadata", get(|| async { "[]" }));
+        let app = with_static_dir(Router::new().nest("/api/v1/httpdownload", api), dir.path());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        let get = |path: &str| reqwest::get(format!("{}{}", url, path));
+        // when / then
+        let resp = get("/app.js").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.text().await.unwrap(), "console.log(1)");
+        let resp = get("/downloads/some-id").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.text().await.unwrap(), "<html>app</html>");
+        let resp = get("/api/v1/httpdownload/metadata").await.unwrap();
+        assert_eq!(resp.text().await.unwrap(), "[]");
+        let resp = get("/api/v1/httpdownload/unknown/path").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = get("/api/v2/anything").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
 }
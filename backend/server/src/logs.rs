@@ -0,0 +1,212 @@
+//! Keeps the recent log lines of every download in memory so they can be read through the API.
+//! A line belongs to the download whose task logged it (see `downloader::util::context`), what
+//! the line says doesn't matter.
+use downloader::util::context;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+pub const DEFAULT_LINES_PER_DOWNLOAD: usize = 200;
+pub const DEFAULT_MAX_DOWNLOADS: usize = 1000;
+/// Least severe level that is captured, independent of what `RUST_LOG` prints
+const CAPTURE_LEVEL: Level = Level::Info;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogLine {
+    /// Milliseconds since the unix epoch
+    pub at_ms: u64,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+}
+
+/// Ring buffer of log lines per download. Once more than `max_downloads` downloads have lines the
+/// least recently logged one is forgotten.
+#[derive(Debug)]
+pub struct LogCapture {
+    lines_per_download: usize,
+    max_downloads: usize,
+    inner: Mutex<CaptureInner>,
+}
+
+#[derive(Debug, Default)]
+struct CaptureInner {
+    downloads: HashMap<Uuid, CapturedLines>,
+    /// Incremented on every captured line, the download with the lowest `last_used` is evicted
+    clock: u64,
+}
+
+#[derive(Debug, Default)]
+struct CapturedLines {
+    lines: VecDeque<LogLine>,
+    last_used: u64,
+}
+
+impl LogCapture {
+    pub fn new(lines_per_download: usize, max_downloads: usize) -> Self {
+        Self {
+            lines_per_download,
+            max_downloads,
+            inner: Mutex::default(),
+        }
+    }
+
+    /// Keeps the record if it was logged by a download's task.
+    pub fn capture(&self, record: &Record) {
+        if record.level() > CAPTURE_LEVEL {
+            return;
+        }
+        let Some(id) = context::current_download() else {
+            return;
+        };
+        let line = LogLine {
+            at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            level: record.level().to_string(),
+            target: record.target().to_owned(),
+            message: record.args().to_string(),
+        };
+        self.push(id, line);
+    }
+
+    pub fn push(&self, id: Uuid, line: LogLine) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let clock = inner.clock;
+        let captured = inner.downloads.entry(id).or_default();
+        captured.last_used = clock;
+        captured.lines.push_back(line);
+        while captured.lines.len() > self.lines_per_download {
+            captured.lines.pop_front();
+        }
+        if inner.downloads.len() > self.max_downloads {
+            let evicted = inner
+                .downloads
+                .iter()
+                .min_by_key(|(_, captured)| captured.last_used)
+                .map(|(id, _)| *id);
+            if let Some(evicted) = evicted {
+                inner.downloads.remove(&evicted);
+            }
+        }
+    }
+
+    /// The last `limit` (all if None) lines of the download, oldest first.
+    pub fn lines(&self, id: &Uuid, limit: Option<usize>) -> Vec<LogLine> {
+        let inner = self.inner.lock().unwrap();
+        let Some(captured) = inner.downloads.get(id) else {
+            return Vec::new();
+        };
+        let skip = limit.map_or(0, |limit| captured.lines.len().saturating_sub(limit));
+        captured.lines.iter().skip(skip).cloned().collect()
+    }
+
+    pub fn forget(&self, id: &Uuid) {
+        self.inner.lock().unwrap().downloads.remove(id);
+    }
+}
+
+/// Capture all log lines go to, `init` feeds it.
+pub fn capture() -> &'static LogCapture {
+    static CAPTURE: OnceLock<LogCapture> = OnceLock::new();
+    CAPTURE.get_or_init(|| LogCapture::new(DEFAULT_LINES_PER_DOWNLOAD, DEFAULT_MAX_DOWNLOADS))
+}
+
+/// Prints what `RUST_LOG` asks for like `env_logger` and captures the lines of downloads.
+struct CapturingLogger {
+    inner: env_logger::Logger,
+}
+
+impl Log for CapturingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= CAPTURE_LEVEL || self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.inner.matches(record) {
+            self.inner.log(record);
+        }
+        capture().capture(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Installs the logger, replaces `env_logger::init`.
+pub fn init() {
+    let inner = env_logger::Builder::from_default_env().build();
+    let max_level = inner.filter().max(LevelFilter::Info);
+    log::set_boxed_logger(Box::new(CapturingLogger { inner })).expect("Logger was already set");
+    log::set_max_level(max_level);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn line(message: &str) -> LogLine {
+        LogLine {
+            at_ms: 0,
+            level: "INFO".to_string(),
+            target: "test".to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    fn capture_info(capture: &LogCapture, message: &str) {
+        capture.capture(
+            &Record::builder()
+                .args(format_args!("{}", message))
+                .level(Level::Info)
+                .target("test")
+                .build(),
+        );
+    }
+
+    #[tokio::test]
+    async fn lines_belong_to_the_download_logging_them() {
+        let capture = LogCapture::new(10, 10);
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        context::scoped(a, async {
+            capture_info(&capture, &format!("Download {} conflicts with {}", a, b));
+            capture_info(&capture, "Retrying example.com in 1s");
+        })
+        .await;
+        capture_info(&capture, &format!("Stopping download {}", a));
+        let messages: Vec<String> = capture
+            .lines(&a, None)
+            .into_iter()
+            .map(|line| line.message)
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                format!("Download {} conflicts with {}", a, b),
+                "Retrying example.com in 1s".to_string()
+            ]
+        );
+        assert!(capture.lines(&b, None).is_empty());
+    }
+
+    #[test]
+    fn captured_lines_are_bounded() {
+        let capture = LogCapture::new(2, 1);
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        for message in ["first", "second", "third"] {
+            capture.push(a, line(message));
+        }
+        assert_eq!(capture.lines(&a, None), vec![line("second"), line("third")]);
+        assert_eq!(capture.lines(&a, Some(1)), vec![line("third")]);
+        // a is evicted for b
+        capture.push(b, line("other"));
+        assert!(capture.lines(&a, None).is_empty());
+        assert_eq!(capture.lines(&b, None), vec![line("other")]);
+    }
+}
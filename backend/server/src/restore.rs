@@ -0,0 +1,89 @@
+//! Downloads saved by the manager (see the `state_file` setting) are added back when the server
+//! starts. They are created lazily like imported ones, nothing is probed until they run again.
+use std::path::Path;
+
+use downloader::ftpdownload::FtpDownload;
+use downloader::httpdownload::download::config::HttpDownloadConfig;
+use downloader::httpdownload::download::HttpDownload;
+use downloader::httpdownload::manager::persist::SavedDownload;
+use downloader::httpdownload::DownloadMetadata;
+use reqwest::Url;
+
+use crate::api::AppState;
+
+/// Adds the saved downloads to the manager in the state they were saved in, the ones that can't
+/// be created again are logged and left out.
+pub async fn restore_downloads(state: &AppState, saved: Vec<SavedDownload>) {
+    if saved.is_empty() {
+        return;
+    }
+    log::info!("Restoring {} saved downloads", saved.len());
+    let config = state.settings.read().await.download_config();
+    for SavedDownload {
+        metadata,
+        state: saved_state,
+    } in saved
+    {
+        let id = metadata.id;
+        let restored = match metadata.url.starts_with("ftp://") {
+            true => match ftp_download(&metadata) {
+                Ok(download) => state.manager.restore(download, saved_state).await,
+                Err(e) => Err(e),
+            },
+            false => match http_download(state, &metadata, config.clone()).await {
+                Ok(download) => state.manager.restore(download, saved_state).await,
+                Err(e) => Err(e),
+            },
+        };
+        if let Err(e) = restored {
+            log::error!("Couldn't restore download {}: {:#}", id, e);
+        }
+    }
+}
+
+/// Directory and filename of the saved file path
+fn split_path(path: &Path) -> anyhow::Result<(&Path, String)> {
+    match (path.parent(), path.file_name()) {
+        (Some(directory), Some(filename)) => {
+            Ok((directory, filename.to_string_lossy().into_owned()))
+        }
+        _ => anyhow::bail!("Saved path {:?} has no filename", path),
+    }
+}
+
+async fn http_download(
+    state: &AppState,
+    metadata: &DownloadMetadata,
+    config: HttpDownloadConfig,
+) -> anyhow::Result<HttpDownload> {
+    let (directory, filename) = split_path(&metadata.file_path)?;
+    let mut download = HttpDownload::builder()
+        .url(Url::parse(&metadata.url)?)
+        .directory(directory)
+        .filename(filename)
+        .client(state.client.clone())
+        .client_config(state.client_config.clone())
+        .config(config)
+        .retry_policy(metadata.retry_policy)
+        .segments(metadata.segments)
+        .ignore_global_limit(metadata.ignore_global_limit)
+        .rate_limit(metadata.rate_limit)
+        .preserve_auth_on_redirect(metadata.preserve_auth_on_redirect)
+        .lazy(true)
+        .build()
+        .await?;
+    download.id = metadata.id;
+    download.stats.restore_resumes(metadata.resume_count);
+    Ok(download)
+}
+
+fn ftp_download(metadata: &DownloadMetadata) -> anyhow::Result<FtpDownload> {
+    let (directory, filename) = split_path(&metadata.file_path)?;
+    Ok(FtpDownload {
+        id: metadata.id,
+        url: Url::parse(&metadata.url)?,
+        directory: directory.to_owned(),
+        filename,
+        content_length: metadata.download_size.unwrap_or_default(),
+    })
+}
@@ -1,8 +1,32 @@
+use anyhow::{bail, Context};
 use dirs::{download_dir, home_dir};
-use downloader::httpdownload::DownloadMetadata;
+use downloader::httpdownload::{
+    client::{self, ClientConfig, IpFamily},
+    download::config::{
+        self, Auth, EmptyResponse, FilePermissions, HttpDownloadConfig, PersistInterval,
+    },
+    download::filetype::FileTypePolicy,
+    download::limiter::RateLimiter,
+    download::retry::{RetryPolicy, SharedRetryPolicy},
+    history::{self, HistoryLimits},
+    manager::{
+        self,
+        bandwidth::BandwidthLimit,
+        breaker::BreakerConfig,
+        dedup::DedupAction,
+        diskspace::{self, DiskReserve},
+        probe,
+    },
+    DownloadMetadata,
+};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::{
     io::AsyncWriteExt,
     sync::{RwLock, RwLockReadGuard},
@@ -12,16 +36,348 @@ fn user_download_dir() -> PathBuf {
     dirs::download_dir().unwrap_or(PathBuf::from("/"))
 }
 
+fn default_lock_timeout_secs() -> u64 {
+    manager::DEFAULT_LOCK_TIMEOUT.as_secs()
+}
+
+fn default_create_dirs() -> bool {
+    true
+}
+
+fn default_persist_interval_secs() -> u64 {
+    config::DEFAULT_PERSIST_INTERVAL.as_secs()
+}
+
+fn default_breaker_failures() -> usize {
+    BreakerConfig::default().failure_threshold
+}
+
+fn default_breaker_window_secs() -> u64 {
+    BreakerConfig::default().window.as_secs()
+}
+
+fn default_breaker_cool_down_secs() -> u64 {
+    BreakerConfig::default().cool_down.as_secs()
+}
+
+fn default_aggregate_interval_ms() -> u64 {
+    1000
+}
+
+fn default_max_segment_connections() -> usize {
+    16
+}
+
+fn default_probe_concurrency() -> usize {
+    probe::DEFAULT_PROBE_CONCURRENCY
+}
+
+fn default_probe_concurrency_per_host() -> usize {
+    probe::DEFAULT_PROBE_CONCURRENCY_PER_HOST
+}
+
+fn default_idempotency_key_ttl_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_free_space_poll_secs() -> u64 {
+    diskspace::DEFAULT_POLL_INTERVAL.as_secs()
+}
+
+fn default_watch_poll_secs() -> u64 {
+    5
+}
+
+fn default_watch_start() -> bool {
+    true
+}
+
+fn default_segments() -> usize {
+    1
+}
+
+fn default_infer_extension() -> bool {
+    true
+}
+
+fn default_keep_partial_on_failure() -> bool {
+    true
+}
+
+fn default_history_per_download() -> usize {
+    history::DEFAULT_ENTRIES_PER_DOWNLOAD
+}
+
+fn default_history_total() -> usize {
+    history::DEFAULT_TOTAL_ENTRIES
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    client::DEFAULT_CONNECT_TIMEOUT.as_millis() as u64
+}
+
+fn default_idle_connection_timeout_secs() -> u64 {
+    client::DEFAULT_IDLE_TIMEOUT.as_secs()
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Settings {
     #[serde(default = "user_download_dir")]
     pub default_download_dir: PathBuf,
+    /// Downloads are written here while in progress and moved to their directory once complete
+    #[serde(default)]
+    pub temp_dir: Option<PathBuf>,
+    /// Downloads running at the same time, downloads started on creation or import over it are
+    /// queued until one of them ends. 0 removes the cap.
     #[serde(default)]
     pub max_concurrent_downloads: usize,
+    /// Total concurrent segment connections of all segmented downloads, segments over it wait
+    /// for a free slot. 0 removes the cap.
+    #[serde(default = "default_max_segment_connections")]
+    pub max_segment_connections: usize,
+    /// Seconds an API call waits for the download manager before giving up with a 503
+    #[serde(default = "default_lock_timeout_secs")]
+    pub lock_timeout_secs: u64,
+    /// Milliseconds a connection attempt to a single address can take before falling back
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    /// `any` for dual-stack connections, `v4` or `v6` to only use one address family
+    #[serde(default)]
+    pub ip_family: IpFamily,
+    /// `hostname: ip` pairs connected to without resolving the hostname, like entries in
+    /// /etc/hosts. Invalid entries stop the server from starting.
+    #[serde(default)]
+    pub dns_overrides: BTreeMap<String, String>,
+    /// Seconds an unused connection is kept open for the next request, 0 closes connections once
+    /// their response was read. Downloads can override it when they are created.
+    #[serde(default = "default_idle_connection_timeout_secs")]
+    pub idle_connection_timeout_secs: u64,
+    /// Create the download and temp directories (recursively) if they don't exist, if disabled
+    /// missing directories are an error
+    #[serde(default = "default_create_dirs")]
+    pub create_dirs: bool,
+    /// Seconds between writes of the `state_file` and of the progress of running segmented
+    /// downloads (their `.part.meta` sidecar) while downloads progress. Shorter intervals lose
+    /// less progress when the process crashes but write to disk more often, adding, removing,
+    /// pausing, stopping and completing a download always persist immediately.
+    #[serde(default = "default_persist_interval_secs")]
+    pub persist_interval_secs: u64,
+    /// Additionally persist every this many megabytes downloaded, useful on fast connections
+    /// where a lot of data arrives between two timed writes.
+    #[serde(default)]
+    pub persist_interval_mb: Option<u64>,
+    /// File the downloads are saved to and restored from when the server starts, defaults to
+    /// `downloads.json` next to the settings file
+    #[serde(default)]
+    pub state_file: Option<PathBuf>,
+    /// Gzip the `state_file`, worth it with many downloads. A file of either format is read, so
+    /// toggling it keeps the saved downloads.
+    #[serde(default)]
+    pub compress_state: bool,
+    /// Failed downloads of a host within `circuit_breaker_window_secs` after which all its
+    /// downloads are paused for `circuit_breaker_cool_down_secs`, 0 disables the breaker
+    #[serde(default = "default_breaker_failures")]
+    pub circuit_breaker_failures: usize,
+    #[serde(default = "default_breaker_window_secs")]
+    pub circuit_breaker_window_secs: u64,
+    #[serde(default = "default_breaker_cool_down_secs")]
+    pub circuit_breaker_cool_down_secs: u64,
+    /// Milliseconds between overall progress updates sent to subscribers, 0 disables them
+    #[serde(default = "default_aggregate_interval_ms")]
+    pub aggregate_interval_ms: u64,
+    /// Octal mode of downloaded files (e.g. `"0644"`), Unix only
+    #[serde(default)]
+    pub file_mode: Option<String>,
+    /// Uid and gid downloaded files are owned by, changing them requires running as root
+    #[serde(default)]
+    pub file_owner: Option<u32>,
+    #[serde(default)]
+    pub file_group: Option<u32>,
+    /// Times a download failing its checksum is downloaded again before it's marked as failed
+    #[serde(default)]
+    pub checksum_retries: u32,
+    /// Append an extension matching the served content type to filenames without one
+    #[serde(default = "default_infer_extension")]
+    pub infer_extension: bool,
+    /// Keep the partial file of a download that failed for good so it can be resumed, otherwise
+    /// it's removed. Downloads can override it when they are created.
+    #[serde(default = "default_keep_partial_on_failure")]
+    pub keep_partial_on_failure: bool,
+    /// Combined speed limit of all downloads: `"unlimited"`, `{"fixed": <bytes per second>}` or
+    /// `{"relative": 0.7}` for a fraction of the measured capacity of the connection
+    #[serde(default)]
+    pub bandwidth_limit: BandwidthLimit,
+    /// Events (state changes and errors) kept per download for `/:id/events`
+    #[serde(default = "default_history_per_download")]
+    pub event_history_per_download: usize,
+    /// Events kept of all downloads together, the oldest events of the least recently used
+    /// downloads are dropped beyond it
+    #[serde(default = "default_history_total")]
+    pub event_history_total: usize,
+    /// Fail downloads whose url redirects to an HTML page (usually a login) with the code
+    /// `login_redirect` instead of saving the page
+    #[serde(default)]
+    pub reject_login_redirects: bool,
+    /// Shell command consulted before downloads are started automatically (e.g. system paused
+    /// downloads resumed), they stay paused unless it exits successfully. Useful to hold them back
+    /// on a metered connection, see also `/allow_downloads`.
+    #[serde(default)]
+    pub allow_downloads_command: Option<String>,
+    /// How transient failures (network errors, 5xx, 408 and 429 responses) of new downloads are
+    /// retried, downloads can override it when they are created or later on. A retry resumes the
+    /// whole run of the download after `request_retries` gave up.
+    #[serde(default)]
+    pub retry_policy: RetryPolicy,
+    /// How often a single request of a new download (its probe when it's created and every GET
+    /// of the transfer) is repeated right away after a network error, 408, 429 or 5xx before the
+    /// download sees the failure. Rides out short blips without a `retry_policy` retry and keeps
+    /// a flaky probe from failing the creation.
+    #[serde(default)]
+    pub request_retries: u32,
+    /// Checks at most this often whether the file of a completed download still exists when
+    /// the download is read, downloads whose file was deleted become `Missing`. Unset disables
+    /// the check.
+    #[serde(default)]
+    pub missing_file_check_secs: Option<u64>,
+    /// Megabytes that have to stay free on the disks of the download and temp directories. The
+    /// running downloads are paused (`LowDiskSpace`) while less is free and resumed once there's
+    /// enough space again. Unset disables the check.
+    #[serde(default)]
+    pub min_free_space_mb: Option<u64>,
+    /// Seconds between two checks of `min_free_space_mb`
+    #[serde(default = "default_free_space_poll_secs")]
+    pub free_space_poll_secs: u64,
+    /// Sync downloaded files to disk when a download is paused and before it's reported
+    /// complete. Protects finished downloads against a power loss at the cost of throughput,
+    /// noticeably so on network storage. Disabled, the OS decides when buffered writes hit the
+    /// disk.
+    #[serde(default)]
+    pub sync_writes: bool,
+    /// Extensions and content types downloads may have, e.g. `denied_extensions: [exe, msi]` or
+    /// `allowed_content_types: ["image/*"]`. Checked when a download is probed, forbidden ones
+    /// are rejected before any bytes are fetched. Everything is allowed by default.
+    #[serde(default)]
+    pub file_types: FileTypePolicy,
+    /// `complete` saves resources the server reports as empty as an empty file, `fail` rejects
+    /// them with `empty_response` when the download is created, for sources that answer errors
+    /// with an empty 200.
+    #[serde(default)]
+    pub empty_downloads: EmptyResponse,
+    /// Buffer the writes of segmented downloads and write them in batches of this many
+    /// kilobytes sorted by offset, which spares spinning disks most seeks. Buffered bytes aren't
+    /// recorded as downloaded until written. Unset writes every chunk as it arrives.
+    #[serde(default)]
+    pub write_batch_kb: Option<usize>,
+    /// Segments downloads are fetched with unless they are created with a count of their own.
+    /// Servers without byte range support are always downloaded over a single connection.
+    #[serde(default = "default_segments")]
+    pub default_segments: usize,
+    /// Seconds a create request's `Idempotency-Key` is remembered, a request repeating it within
+    /// them gets the download the first one created. 0 ignores the header.
+    #[serde(default = "default_idempotency_key_ttl_secs")]
+    pub idempotency_key_ttl_secs: u64,
+    /// What happens to a completed download with the same content as an earlier one: `flag`
+    /// only marks it (`duplicate_of` in its metadata), `hardlink` also replaces its file by a
+    /// hard link to the earlier file. Unset, completed downloads aren't hashed.
+    #[serde(default)]
+    pub dedup: Option<DedupAction>,
+    /// Downloads of a bulk import (`/import`) probed at the same time, the others wait
+    #[serde(default = "default_probe_concurrency")]
+    pub probe_concurrency: usize,
+    /// Downloads of the same host probed at the same time during a bulk import, keeps a long list
+    /// of urls of one server from looking like an attack
+    #[serde(default = "default_probe_concurrency_per_host")]
+    pub probe_concurrency_per_host: usize,
+    /// Directory of a web frontend served at the root next to the API, e.g. a built single page
+    /// app with an `index.html`. Unset, only the API is served.
+    #[serde(default)]
+    pub static_dir: Option<PathBuf>,
+    /// Drop folder: instruction files appearing in it are imported like a bulk import and moved
+    /// to its `imported` subfolder, malformed ones to its `failed` subfolder. `.txt` files list
+    /// one url per line, `.json` files hold an array of urls or `{"urls": [...], "start": bool}`,
+    /// `.metalink` files add the first url of every file they describe. Unset, no folder is
+    /// watched.
+    #[serde(default)]
+    pub watch_dir: Option<PathBuf>,
+    /// Seconds between two looks into `watch_dir`, a file is only imported once its size didn't
+    /// change between two of them
+    #[serde(default = "default_watch_poll_secs")]
+    pub watch_poll_secs: u64,
+    /// Start the downloads imported from `watch_dir`, a `.json` file can decide for itself
+    #[serde(default = "default_watch_start")]
+    pub watch_start: bool,
+    /// Delete imported files instead of moving them to the `imported` subfolder
+    #[serde(default)]
+    pub watch_delete_imported: bool,
+    /// Named bundles of download options, a create request naming one with `preset` gets its
+    /// options unless the request sets them itself. Editable over the `/presets` API.
+    #[serde(default)]
+    pub presets: BTreeMap<String, DownloadPreset>,
     #[serde(default = "Vec::new")]
     pub downloads: Vec<DownloadMetadata>,
 }
 
+/// Options of downloads of the same source, e.g. the headers and credentials a site needs. Unset
+/// options are left to the settings and the create request.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DownloadPreset {
+    /// Directory the downloads are saved to instead of `default_download_dir`
+    #[serde(default)]
+    pub directory: Option<PathBuf>,
+    /// Headers sent with every request, e.g. `Referer` or `Cookie`
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Basic auth, the password is optional
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Bearer token, ignored if `username` is set
+    #[serde(default)]
+    pub bearer_token: Option<String>,
+    /// Bytes per second each download is limited to, instead of the `bandwidth_limit` shared by
+    /// all downloads
+    #[serde(default)]
+    pub speed_limit: Option<u64>,
+    #[serde(default)]
+    pub segments: Option<usize>,
+}
+
+/// Headers given by name and value, fails if one can't be sent.
+pub fn parse_headers(headers: &BTreeMap<String, String>) -> anyhow::Result<HeaderMap> {
+    let mut parsed = HeaderMap::new();
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("Invalid header name '{}'", name))?;
+        let value = HeaderValue::from_str(value)
+            .with_context(|| format!("Invalid value of header {}", name))?;
+        parsed.insert(name, value);
+    }
+    Ok(parsed)
+}
+
+impl DownloadPreset {
+    /// Applies the options to `config`, fails if a header can't be sent.
+    pub fn apply(&self, config: &mut HttpDownloadConfig) -> anyhow::Result<()> {
+        config.headers.extend(parse_headers(&self.headers)?);
+        config.auth = match (&self.username, &self.bearer_token) {
+            (Some(username), _) => Some(Auth::Basic {
+                username: username.clone(),
+                password: self.password.clone(),
+            }),
+            (None, Some(token)) => Some(Auth::Bearer(token.clone())),
+            (None, None) => config.auth.take(),
+        };
+        if let Some(limit) = self.speed_limit {
+            config.shared_rate_limiter = Some(RateLimiter::new(Some(limit)));
+        }
+        if let Some(segments) = self.segments {
+            config.segments = segments.max(1);
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SettingManager {
     inner: Arc<RwLock<Settings>>,
@@ -34,19 +390,46 @@ fn default_settings_path() -> PathBuf {
 }
 
 impl SettingManager {
-    pub async fn load(p: Option<PathBuf>) -> Self {
+    /// Loads the settings and makes sure the directories they point at can be used, fails if
+    /// they are missing (and `create_dirs` is off), can't be created or aren't writable.
+    pub async fn load(p: Option<PathBuf>) -> anyhow::Result<Self> {
         let path = p.unwrap_or_else(default_settings_path);
         if let Some(parent) = path.parent() {
             tokio::fs::create_dir_all(parent).await.unwrap();
         }
         let settings = load_settings(&path).await;
-        Self {
+        settings.validate().await?;
+        Ok(Self {
             inner: Arc::new(RwLock::new(settings)),
             settings_path: path,
+        })
+    }
+
+    /// Reads the settings file again and replaces the current settings with it, they are kept if
+    /// the file can't be read or is invalid. Settings only read when the server starts are
+    /// replaced as well but take effect after a restart, their names are returned.
+    pub async fn reload(&self) -> anyhow::Result<Vec<&'static str>> {
+        let raw = tokio::fs::read_to_string(&self.settings_path)
+            .await
+            .with_context(|| format!("Couldn't read {}", self.settings_path.to_string_lossy()))?;
+        let settings: Settings = serde_yaml::from_str(&raw).context("Malformed settings file")?;
+        settings.validate().await?;
+        let mut guard = self.inner.write().await;
+        let restart_required = guard.restart_required(&settings);
+        log::info!("Reloaded settings, new value: {:?}", settings);
+        *guard = settings;
+        Ok(restart_required)
+    }
+
+    /// Where the downloads are saved, see `Settings::state_file`.
+    pub async fn state_file_path(&self) -> PathBuf {
+        match &self.read().await.state_file {
+            Some(path) => path.clone(),
+            None => self.settings_path.with_file_name("downloads.json"),
         }
     }
 
-    pub async fn read(&self) -> RwLockReadGuard<Settings> {
+    pub async fn read(&self) -> RwLockReadGuard<'_, Settings> {
         self.inner.read().await
     }
 
@@ -68,18 +451,326 @@ impl SettingManager {
     }
 }
 
+impl Settings {
+    /// Makes sure the directories can be used (see `ensure_dir`) and the values parse.
+    async fn validate(&self) -> anyhow::Result<()> {
+        ensure_dir(&self.default_download_dir, self.create_dirs)
+            .await
+            .context("Default download directory can't be used")?;
+        if let Some(temp_dir) = &self.temp_dir {
+            ensure_dir(temp_dir, self.create_dirs)
+                .await
+                .context("Temp directory can't be used")?;
+        }
+        self.client_config()?;
+        self.file_permissions()?;
+        for (name, preset) in &self.presets {
+            preset
+                .apply(&mut HttpDownloadConfig::default())
+                .with_context(|| format!("Invalid preset '{}'", name))?;
+        }
+        Ok(())
+    }
+
+    /// Free space the download and temp directories need, None if it isn't checked.
+    pub fn disk_reserve(&self) -> Option<DiskReserve> {
+        let mb = self.min_free_space_mb?;
+        Some(DiskReserve {
+            directories: [Some(&self.default_download_dir), self.temp_dir.as_ref()]
+                .into_iter()
+                .flatten()
+                .cloned()
+                .collect(),
+            min_free: mb * 1024 * 1024,
+            poll_interval: Duration::from_secs(self.free_space_poll_secs.max(1)),
+        })
+    }
+
+    /// Names of the settings that differ in `other` and are only applied when the server starts
+    /// (the download manager and the http client are built from them), the others are read
+    /// whenever they are used.
+    pub fn restart_required(&self, other: &Settings) -> Vec<&'static str> {
+        [
+            (
+                "max_concurrent_downloads",
+                self.max_concurrent_downloads != other.max_concurrent_downloads,
+            ),
+            (
+                "max_segment_connections",
+                self.max_segment_connections != other.max_segment_connections,
+            ),
+            (
+                "lock_timeout_secs",
+                self.lock_timeout_secs != other.lock_timeout_secs,
+            ),
+            (
+                "connect_timeout_ms",
+                self.connect_timeout_ms != other.connect_timeout_ms,
+            ),
+            ("ip_family", self.ip_family != other.ip_family),
+            ("dns_overrides", self.dns_overrides != other.dns_overrides),
+            (
+                "idle_connection_timeout_secs",
+                self.idle_connection_timeout_secs != other.idle_connection_timeout_secs,
+            ),
+            (
+                "circuit_breaker_failures",
+                self.circuit_breaker_failures != other.circuit_breaker_failures,
+            ),
+            (
+                "circuit_breaker_window_secs",
+                self.circuit_breaker_window_secs != other.circuit_breaker_window_secs,
+            ),
+            (
+                "circuit_breaker_cool_down_secs",
+                self.circuit_breaker_cool_down_secs != other.circuit_breaker_cool_down_secs,
+            ),
+            (
+                "aggregate_interval_ms",
+                self.aggregate_interval_ms != other.aggregate_interval_ms,
+            ),
+            (
+                "bandwidth_limit",
+                self.bandwidth_limit != other.bandwidth_limit,
+            ),
+            (
+                "event_history_per_download",
+                self.event_history_per_download != other.event_history_per_download,
+            ),
+            (
+                "event_history_total",
+                self.event_history_total != other.event_history_total,
+            ),
+            (
+                "missing_file_check_secs",
+                self.missing_file_check_secs != other.missing_file_check_secs,
+            ),
+            (
+                "min_free_space_mb",
+                self.min_free_space_mb != other.min_free_space_mb,
+            ),
+            (
+                "free_space_poll_secs",
+                self.free_space_poll_secs != other.free_space_poll_secs,
+            ),
+            (
+                "idempotency_key_ttl_secs",
+                self.idempotency_key_ttl_secs != other.idempotency_key_ttl_secs,
+            ),
+            ("dedup", self.dedup != other.dedup),
+            (
+                "probe_concurrency",
+                self.probe_concurrency != other.probe_concurrency,
+            ),
+            (
+                "probe_concurrency_per_host",
+                self.probe_concurrency_per_host != other.probe_concurrency_per_host,
+            ),
+            ("static_dir", self.static_dir != other.static_dir),
+            ("state_file", self.state_file != other.state_file),
+            (
+                "compress_state",
+                self.compress_state != other.compress_state,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, changed)| changed.then_some(name))
+        .collect()
+    }
+
+    /// Configuration applied to newly created downloads
+    pub fn download_config(&self) -> HttpDownloadConfig {
+        HttpDownloadConfig {
+            temp_dir: self.temp_dir.clone(),
+            persist_interval: self.persist_interval(),
+            // Validated when the settings are loaded
+            permissions: self.file_permissions().unwrap_or_default(),
+            infer_extension: self.infer_extension,
+            checksum_retries: self.checksum_retries,
+            keep_partial_on_failure: self.keep_partial_on_failure,
+            retry_policy: SharedRetryPolicy::new(self.retry_policy),
+            request_retries: self.request_retries,
+            reject_login_redirects: self.reject_login_redirects,
+            sync_writes: self.sync_writes,
+            write_batch_size: self.write_batch_kb.map(|kb| kb * 1024),
+            file_types: self.file_types.clone(),
+            empty_response: self.empty_downloads,
+            segments: self.default_segments.max(1),
+            ..Default::default()
+        }
+    }
+
+    pub fn persist_interval(&self) -> PersistInterval {
+        PersistInterval {
+            time: Duration::from_secs(self.persist_interval_secs),
+            bytes: self.persist_interval_mb.map(|mb| mb * 1024 * 1024),
+        }
+    }
+
+    pub fn history_limits(&self) -> HistoryLimits {
+        HistoryLimits {
+            per_download: self.event_history_per_download,
+            total: self.event_history_total,
+        }
+    }
+
+    /// Mode and owner of downloaded files, fails on a malformed mode
+    pub fn file_permissions(&self) -> anyhow::Result<FilePermissions> {
+        let mode = match &self.file_mode {
+            Some(mode) => Some(
+                u32::from_str_radix(mode.trim().trim_start_matches("0o"), 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o7777)
+                    .with_context(|| format!("Invalid file mode '{}', expected e.g. 0644", mode))?,
+            ),
+            None => None,
+        };
+        Ok(FilePermissions {
+            mode,
+            uid: self.file_owner,
+            gid: self.file_group,
+        })
+    }
+
+    /// Configuration of the manager's per host circuit breaker
+    pub fn breaker_config(&self) -> BreakerConfig {
+        BreakerConfig {
+            failure_threshold: self.circuit_breaker_failures,
+            window: Duration::from_secs(self.circuit_breaker_window_secs),
+            cool_down: Duration::from_secs(self.circuit_breaker_cool_down_secs),
+        }
+    }
+
+    /// Configuration of the http client shared by all downloads, fails on malformed dns
+    /// overrides
+    pub fn client_config(&self) -> anyhow::Result<ClientConfig> {
+        Ok(ClientConfig {
+            connect_timeout: Duration::from_millis(self.connect_timeout_ms),
+            ip_family: self.ip_family,
+            dns_overrides: parse_dns_overrides(&self.dns_overrides)?,
+            idle_timeout: Duration::from_secs(self.idle_connection_timeout_secs),
+            proxy: None,
+        })
+    }
+}
+
 impl Default for Settings {
     fn default() -> Self {
         Self {
             default_download_dir: download_dir()
                 .map(|p| p.join("ludownloader"))
                 .unwrap_or_default(),
+            temp_dir: None,
             max_concurrent_downloads: 0,
+            max_segment_connections: default_max_segment_connections(),
+            lock_timeout_secs: default_lock_timeout_secs(),
+            connect_timeout_ms: default_connect_timeout_ms(),
+            ip_family: IpFamily::default(),
+            dns_overrides: BTreeMap::new(),
+            idle_connection_timeout_secs: default_idle_connection_timeout_secs(),
+            create_dirs: default_create_dirs(),
+            persist_interval_secs: default_persist_interval_secs(),
+            persist_interval_mb: None,
+            state_file: None,
+            circuit_breaker_failures: default_breaker_failures(),
+            circuit_breaker_window_secs: default_breaker_window_secs(),
+            circuit_breaker_cool_down_secs: default_breaker_cool_down_secs(),
+            aggregate_interval_ms: default_aggregate_interval_ms(),
+            file_mode: None,
+            file_owner: None,
+            file_group: None,
+            checksum_retries: 0,
+            infer_extension: default_infer_extension(),
+            keep_partial_on_failure: default_keep_partial_on_failure(),
+            bandwidth_limit: BandwidthLimit::Unlimited,
+            event_history_per_download: default_history_per_download(),
+            event_history_total: default_history_total(),
+            reject_login_redirects: false,
+            allow_downloads_command: None,
+            retry_policy: RetryPolicy::default(),
+            request_retries: 0,
+            missing_file_check_secs: None,
+            min_free_space_mb: None,
+            free_space_poll_secs: default_free_space_poll_secs(),
+            sync_writes: false,
+            compress_state: false,
+            write_batch_kb: None,
+            file_types: FileTypePolicy::default(),
+            empty_downloads: EmptyResponse::default(),
+            default_segments: default_segments(),
+            idempotency_key_ttl_secs: default_idempotency_key_ttl_secs(),
+            dedup: None,
+            probe_concurrency: default_probe_concurrency(),
+            probe_concurrency_per_host: default_probe_concurrency_per_host(),
+            static_dir: None,
+            watch_dir: None,
+            watch_poll_secs: default_watch_poll_secs(),
+            watch_start: default_watch_start(),
+            watch_delete_imported: false,
+            presets: BTreeMap::new(),
             downloads: Vec::new(),
         }
     }
 }
 
+fn parse_dns_overrides(
+    overrides: &BTreeMap<String, String>,
+) -> anyhow::Result<HashMap<String, IpAddr>> {
+    overrides
+        .iter()
+        .map(|(host, ip)| {
+            let valid_host = Url::parse(&format!("http://{}/", host))
+                .ok()
+                .and_then(|url| {
+                    url.host_str()
+                        .map(|parsed| parsed.eq_ignore_ascii_case(host))
+                })
+                .unwrap_or(false);
+            if !valid_host {
+                bail!("Invalid dns override: '{}' is not a hostname", host);
+            }
+            let ip = ip.trim().parse::<IpAddr>().with_context(|| {
+                format!(
+                    "Invalid dns override for {}: '{}' is not an IP address",
+                    host, ip
+                )
+            })?;
+            Ok((host.to_ascii_lowercase(), ip))
+        })
+        .collect()
+}
+
+/// Makes sure downloads can be written to `dir`, creating it first if it's missing and `create`
+/// is set.
+pub async fn ensure_dir(dir: &Path, create: bool) -> anyhow::Result<()> {
+    // An empty path is relative to the working directory, like the download paths joined to it
+    let dir = match dir.as_os_str().is_empty() {
+        true => Path::new("."),
+        false => dir,
+    };
+    if !tokio::fs::try_exists(dir).await.unwrap_or(false) {
+        if !create {
+            bail!(
+                "{} does not exist and creating directories is disabled",
+                dir.to_string_lossy()
+            );
+        }
+        log::info!("Creating missing directory {}", dir.to_string_lossy());
+        tokio::fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("Couldn't create {}", dir.to_string_lossy()))?;
+    }
+    if !tokio::fs::metadata(dir).await?.is_dir() {
+        bail!("{} is not a directory", dir.to_string_lossy());
+    }
+    let probe = dir.join(".ludownloader-write-test");
+    tokio::fs::write(&probe, b"")
+        .await
+        .with_context(|| format!("{} is not writable", dir.to_string_lossy()))?;
+    let _ = tokio::fs::remove_file(&probe).await;
+    Ok(())
+}
+
 async fn load_settings(p: &PathBuf) -> Settings {
     let file_exists = tokio::fs::try_exists(p).await.unwrap_or(false);
     if file_exists {
@@ -88,15 +779,6 @@ async fn load_settings(p: &PathBuf) -> Settings {
             Ok(file) => {
                 let settings: Settings = serde_yaml::from_str(&file).unwrap();
                 log::info!("Settings loaded: {:?}", settings);
-                if !tokio::fs::try_exists(&settings.default_download_dir)
-                    .await
-                    .unwrap()
-                {
-                    log::info!("default download directory pointed at by settings does not exist, creating...");
-                    tokio::fs::create_dir_all(&settings.default_download_dir)
-                        .await
-                        .unwrap();
-                }
                 return settings;
             }
             Err(e) => {
@@ -118,3 +800,123 @@ async fn load_settings(p: &PathBuf) -> Settings {
         .expect("Couldn't write to file");
     settings
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_log::test;
+
+    #[test(tokio::test)]
+    async fn missing_dir_is_created_only_if_allowed() -> anyhow::Result<()> {
+        // given
+        let tmp_dir = tempfile::TempDir::new()?;
+        let dir = tmp_dir.path().join("nested/downloads");
+        // when creating is disabled, then
+        assert!(ensure_dir(&dir, false).await.is_err());
+        assert!(!dir.exists());
+        // when creating is allowed, then
+        ensure_dir(&dir, true).await?;
+        assert!(dir.is_dir());
+        // a file isn't a usable directory
+        let file = tmp_dir.path().join("file");
+        tokio::fs::write(&file, b"").await?;
+        assert!(ensure_dir(&file, true).await.is_err());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn reload_applies_file_and_reports_restart_required() -> anyhow::Result<()> {
+        // given
+        let tmp_dir = tempfile::TempDir::new()?;
+        let path = tmp_dir.path().join("settings.yaml");
+        let settings = Settings {
+            default_download_dir: tmp_dir.path().to_owned(),
+            ..Default::default()
+        };
+        tokio::fs::write(&path, serde_yaml::to_string(&settings)?).await?;
+        let manager = SettingManager::load(Some(path.clone())).await?;
+        // when
+        let changed = Settings {
+            checksum_retries: 3,
+            lock_timeout_secs: settings.lock_timeout_secs + 1,
+            ..settings.clone()
+        };
+        tokio::fs::write(&path, serde_yaml::to_string(&changed)?).await?;
+        let restart_required = manager.reload().await?;
+        // then
+        assert_eq!(restart_required, vec!["lock_timeout_secs"]);
+        assert_eq!(manager.read().await.checksum_retries, 3);
+        // a broken file keeps the current settings
+        tokio::fs::write(&path, "checksum_retries: [").await?;
+        assert!(manager.reload().await.is_err());
+        assert_eq!(manager.read().await.checksum_retries, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_dns_overrides_are_rejected() {
+        let settings = |host: &str, ip: &str| Settings {
+            dns_overrides: BTreeMap::from([(host.to_string(), ip.to_string())]),
+            ..Default::default()
+        };
+        let config = settings("Staging.example.com", "10.0.0.1")
+            .client_config()
+            .unwrap();
+        assert_eq!(
+            config.dns_overrides["staging.example.com"],
+            IpAddr::from([10, 0, 0, 1])
+        );
+        assert!(settings("example.com", "::1").client_config().is_ok());
+        assert!(settings("example.com", "10.0.0").client_config().is_err());
+        assert!(settings("example.com:80", "10.0.0.1")
+            .client_config()
+            .is_err());
+        assert!(settings("exa mple.com", "10.0.0.1")
+            .client_config()
+            .is_err());
+    }
+
+    #[test]
+    fn preset_fills_in_download_options() {
+        // given
+        let preset = DownloadPreset {
+            headers: BTreeMap::from([("Referer".to_string(), "https://example.com".to_string())]),
+            bearer_token: Some("secret".to_string()),
+            speed_limit: Some(1024),
+            segments: Some(0),
+            ..Default::default()
+        };
+        let mut config = HttpDownloadConfig::default();
+        // when
+        preset.apply(&mut config).unwrap();
+        // then
+        assert_eq!(config.headers["referer"], "https://example.com");
+        assert!(config.auth == Some(Auth::Bearer("secret".to_string())));
+        assert_eq!(config.shared_rate_limiter.unwrap().rate(), Some(1024));
+        assert_eq!(config.segments, 1);
+        let invalid = DownloadPreset {
+            headers: BTreeMap::from([("Bad Header".to_string(), "value".to_string())]),
+            ..Default::default()
+        };
+        assert!(invalid.apply(&mut HttpDownloadConfig::default()).is_err());
+    }
+
+    #[test]
+    fn file_mode_is_parsed_as_octal() {
+        let settings = |mode: &str| Settings {
+            file_mode: Some(mode.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings("0644").file_permissions().unwrap().mode,
+            Some(0o644)
+        );
+        assert_eq!(
+            settings("640").file_permissions().unwrap().mode,
+            Some(0o640)
+        );
+        assert!(settings("0698").file_permissions().is_err());
+        assert!(settings("rw-r--r--").file_permissions().is_err());
+        assert!(settings("77777").file_permissions().is_err());
+    }
+}
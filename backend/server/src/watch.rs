@@ -0,0 +1,332 @@
+//! Drop folder, see the `watch_dir` setting. Tools that can't talk to the API write instruction
+//! files into the folder, every new one is imported like a bulk import (`/import`) and moved out
+//! of the way so it's imported once. The folder is polled, which works the same on every platform
+//! and on network shares.
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context};
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::api::httpdownload::{import_all, url_lines};
+use crate::api::AppState;
+use crate::settings::ensure_dir;
+
+/// Subfolder of the watched folder imported files are moved to
+pub const IMPORTED_DIR: &str = "imported";
+/// Subfolder of the watched folder malformed files are moved to, they aren't looked at again
+pub const FAILED_DIR: &str = "failed";
+
+/// What a dropped file asks for.
+#[derive(Debug, PartialEq, Eq)]
+struct Instructions {
+    urls: Vec<String>,
+    /// Overrides the `watch_start` setting
+    start: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonInstructions {
+    Urls(Vec<String>),
+    Object {
+        urls: Vec<String>,
+        #[serde(default)]
+        start: Option<bool>,
+    },
+}
+
+/// Whether the file is one the watcher imports, other files are left alone.
+fn is_instruction_file(path: &Path) -> bool {
+    let hidden = path
+        .file_name()
+        .is_none_or(|name| name.to_string_lossy().starts_with('.'));
+    let extension = path.extension().and_then(|ext| ext.to_str());
+    !hidden && matches!(extension, Some("txt" | "json" | "metalink" | "meta4"))
+}
+
+/// Reads the instructions of a dropped file, fails if it's malformed or any of its urls is
+/// invalid, nothing of such a file is imported.
+fn parse(path: &Path, content: &[u8]) -> anyhow::Result<Instructions> {
+    let text = std::str::from_utf8(content).context("Not UTF-8")?;
+    let instructions = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => match serde_json::from_str(text).context("Malformed JSON")? {
+            JsonInstructions::Urls(urls) => Instructions { urls, start: None },
+            JsonInstructions::Object { urls, start } => Instructions { urls, start },
+        },
+        Some("metalink" | "meta4") => Instructions {
+            urls: metalink_urls(text),
+            start: None,
+        },
+        _ => Instructions {
+            urls: url_lines(text),
+            start: None,
+        },
+    };
+    if instructions.urls.is_empty() {
+        bail!("No urls");
+    }
+    for url in &instructions.urls {
+        Url::parse(url).with_context(|| format!("Invalid URL {:?}", url))?;
+    }
+    Ok(instructions)
+}
+
+/// First `<url>` of every `<file>` of a metalink (v3 and v4), the others are mirrors of the same
+/// file. A plain scan is enough for the urls, nothing else of the file is used.
+fn metalink_urls(xml: &str) -> Vec<String> {
+    xml.split("<file")
+        .skip(1)
+        .filter_map(|file| {
+            let file = file.split("</file>").next()?;
+            let url = &file[file.find("<url")?..];
+            let url = &url[url.find('>')? + 1..];
+            let url = &url[..url.find("</url>")?];
+            Some(unescape(url.trim()))
+        })
+        .collect()
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Moves `path` into the `subfolder` next to it, a file of the same name already there is kept
+/// by prefixing the moved one with the current time.
+async fn move_into(path: &Path, subfolder: &str) -> std::io::Result<PathBuf> {
+    let dir = path.with_file_name(subfolder);
+    tokio::fs::create_dir_all(&dir).await?;
+    let name = path.file_name().unwrap_or_default();
+    let mut target = dir.join(name);
+    if target.exists() {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        target = dir.join(format!("{}-{}", millis, name.to_string_lossy()));
+    }
+    tokio::fs::rename(path, &target).await?;
+    Ok(target)
+}
+
+/// Files of the watched folder seen so far.
+#[derive(Debug, Default)]
+pub struct DropFolder {
+    /// Size at the last poll, a file is imported once it stopped growing
+    sizes: HashMap<PathBuf, u64>,
+    /// Imported files that couldn't be moved or deleted, importing them again would duplicate
+    /// their downloads
+    consumed: HashSet<PathBuf>,
+}
+
+impl DropFolder {
+    /// Looks into the watched folder of the settings at every `watch_poll_secs`, the settings are
+    /// read every time so reloading them applies.
+    pub async fn watch(mut self, state: AppState) {
+        loop {
+            let poll_secs = state.settings.read().await.watch_poll_secs;
+            self.poll(&state).await;
+            tokio::time::sleep(Duration::from_secs(poll_secs.max(1))).await;
+        }
+    }
+
+    /// Imports the files of the watched folder that didn't change since the last poll.
+    pub async fn poll(&mut self, state: &AppState) {
+        let Some(dir) = state.settings.read().await.watch_dir.clone() else {
+            self.sizes.clear();
+            return;
+        };
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::warn!("Couldn't read watched folder {:?}: {}", dir, e);
+                return;
+            }
+        };
+        let mut sizes = HashMap::new();
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if !is_instruction_file(&path) || self.consumed.contains(&path) {
+                continue;
+            }
+            match entry.metadata().await {
+                Ok(metadata) if metadata.is_file() => {
+                    sizes.insert(path, metadata.len());
+                }
+                _ => continue,
+            }
+        }
+        let stable: Vec<PathBuf> = sizes
+            .iter()
+            .filter(|(path, size)| self.sizes.get(*path) == Some(size))
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in stable {
+            sizes.remove(&path);
+            self.import(state, &path).await;
+        }
+        self.sizes = sizes;
+    }
+
+    async fn import(&mut self, state: &AppState, path: &Path) {
+        let content = match tokio::fs::read(path).await {
+            Ok(content) => content,
+            Err(e) => {
+                log::warn!("Couldn't read dropped file {:?}: {}", path, e);
+                return;
+            }
+        };
+        let instructions = match parse(path, &content) {
+            Ok(instructions) => instructions,
+            Err(e) => {
+                log::warn!("Dropped file {:?} is malformed: {:#}", path, e);
+                if let Err(e) = move_into(path, FAILED_DIR).await {
+                    log::error!("Couldn't move malformed file {:?}: {}", path, e);
+                    self.consumed.insert(path.to_owned());
+                }
+                return;
+            }
+        };
+        let (directory, create_dirs, start, delete) = {
+            let settings = state.settings.read().await;
+            (
+                settings.default_download_dir.clone(),
+                settings.create_dirs,
+                instructions.start.unwrap_or(settings.watch_start),
+                settings.watch_delete_imported,
+            )
+        };
+        // The file isn't to blame, it's imported once the directory can be used again
+        if let Err(e) = ensure_dir(&directory, create_dirs).await {
+            log::error!("Download directory can't be used: {:#}", e);
+            return;
+        }
+        let results = match import_all(state, &directory, instructions.urls, start).await {
+            Ok(results) => results,
+            Err(e) => {
+                log::error!("Import of dropped file {:?} failed: {}", path, e);
+                return;
+            }
+        };
+        for result in results {
+            match (result.metadata, result.error) {
+                (Some(metadata), _) => log::info!(
+                    "Added download {} of {} from dropped file {:?}",
+                    metadata.id,
+                    result.url,
+                    path
+                ),
+                (None, error) => log::warn!(
+                    "Couldn't add {} from dropped file {:?}: {}",
+                    result.url,
+                    path,
+                    error.unwrap_or_default()
+                ),
+            }
+        }
+        let consumed = match delete {
+            true => tokio::fs::remove_file(path).await,
+            false => move_into(path, IMPORTED_DIR).await.map(|_| ()),
+        };
+        if let Err(e) = consumed {
+            log::error!("Couldn't clear imported file {:?}: {}", path, e);
+            self.consumed.insert(path.to_owned());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::settings::{SettingManager, Settings};
+    use downloader::httpdownload::{client, manager::DownloadManager};
+    use downloader::util::mock::{MockConfig, MockServer};
+    use test_log::test;
+
+    #[test]
+    fn instruction_files_are_parsed() {
+        let parsed = |name: &str, content: &str| parse(Path::new(name), content.as_bytes());
+        // text files list urls like a bulk import
+        assert_eq!(
+            parsed("a.txt", "# comment\nhttp://a/1\n\n http://a/2 \n").unwrap(),
+            Instructions {
+                urls: vec!["http://a/1".to_owned(), "http://a/2".to_owned()],
+                start: None,
+            }
+        );
+        // json either as a list or with options
+        assert_eq!(parsed("a.json", r#"["http://a/1"]"#).unwrap().urls.len(), 1);
+        let json = parsed("a.json", r#"{"urls": ["http://a/1"], "start": false}"#).unwrap();
+        assert_eq!(json.start, Some(false));
+        // metalinks add one url per file
+        let metalink = r#"<?xml version="1.0"?>
+            <metalink xmlns="urn:ietf:params:xml:ns:metalink">
+              <file name="a.iso">
+                <url priority="1">http://a/a.iso?x=1&amp;y=2</url>
+                <url priority="2">http://mirror/a.iso</url>
+              </file>
+              <file name="b.iso"><url>http://a/b.iso</url></file>
+            </metalink>"#;
+        assert_eq!(
+            parsed("a.meta4", metalink).unwrap().urls,
+            vec!["http://a/a.iso?x=1&y=2", "http://a/b.iso"]
+        );
+        // malformed files
+        assert!(parsed("a.json", r#"{"url": "http://a/1"}"#).is_err());
+        assert!(parsed("a.txt", "http://a/1\nnot a url").is_err());
+        assert!(parsed("a.txt", "# nothing\n").is_err());
+        assert!(parse(Path::new("a.txt"), &[0xff, 0xfe]).is_err());
+        assert!(!is_instruction_file(Path::new("dir/.hidden.txt")));
+        assert!(!is_instruction_file(Path::new("dir/file.bin")));
+    }
+
+    #[test(tokio::test)]
+    async fn dropped_files_are_imported_once_and_malformed_ones_set_aside() -> anyhow::Result<()> {
+        // given
+        let tmp_dir = tempfile::TempDir::new()?;
+        let watch_dir = tmp_dir.path().join("drop");
+        tokio::fs::create_dir(&watch_dir).await?;
+        let settings = SettingManager::load(Some(tmp_dir.path().join("settings.yaml"))).await?;
+        settings
+            .write(Settings {
+                default_download_dir: tmp_dir.path().join("downloads"),
+                watch_dir: Some(watch_dir.clone()),
+                watch_start: false,
+                ..Default::default()
+            })
+            .await;
+        let client_config = client::ClientConfig::default();
+        let state = AppState {
+            manager: DownloadManager::new().await,
+            settings,
+            client: client::build_client(&client_config)?,
+            client_config,
+        };
+        let mock = MockServer::start(MockConfig::default()).await;
+        tokio::fs::write(watch_dir.join("list.txt"), mock.url("file.bin").as_str()).await?;
+        tokio::fs::write(watch_dir.join("broken.json"), "[").await?;
+        tokio::fs::write(watch_dir.join("notes.md"), "not an instruction").await?;
+        let mut folder = DropFolder::default();
+        // when the files are first seen, then they aren't imported yet
+        folder.poll(&state).await;
+        assert!(state.manager.get_metadata_all().await?.is_empty());
+        // when they didn't change until the next poll
+        folder.poll(&state).await;
+        folder.poll(&state).await;
+        // then
+        let downloads = state.manager.get_metadata_all().await?;
+        assert_eq!(downloads.len(), 1);
+        assert_eq!(downloads[0].url, mock.url("file.bin").as_str());
+        assert!(watch_dir.join(IMPORTED_DIR).join("list.txt").is_file());
+        assert!(watch_dir.join(FAILED_DIR).join("broken.json").is_file());
+        assert!(!watch_dir.join("list.txt").exists());
+        assert!(watch_dir.join("notes.md").is_file());
+        Ok(())
+    }
+}